@@ -0,0 +1,784 @@
+// The append-only file, laid out like Redis 7 in `appenddirname`: a base
+// file holding the dataset as of the last rewrite (an RDB file, or write
+// commands), then incremental files of write commands in RESP form, each
+// preceded by a SELECT whenever the database changes. The manifest lists
+// them in order. BGREWRITEAOF starts a new incremental file, writes a new
+// base, and deletes the files it replaces.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use thiserror::Error;
+
+use crate::command::{self, Command};
+use crate::rdb::{self, Rdb, RdbError};
+use crate::reply::Reply;
+use crate::scripting::Scripts;
+use crate::value::Value;
+
+/// When writes to the AOF are flushed to disk (`appendfsync`).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fsync {
+    /// After every write command, before replying.
+    Always,
+    /// Once per second, from a background thread.
+    EverySec,
+    /// Whenever the OS decides to.
+    No,
+}
+
+impl Fsync {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::EverySec),
+            "no" => Some(Fsync::No),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AofError {
+    #[error("Can't read the append only file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid AOF manifest: {0}")]
+    Manifest(String),
+    #[error("Bad RDB data in {file}: {source}")]
+    Rdb { file: String, source: RdbError },
+    #[error("Bad file format reading {file} at offset {offset}")]
+    Format { file: String, offset: usize },
+    #[error("Unexpected end of file reading {file} at offset {offset}")]
+    Truncated { file: String, offset: usize },
+    #[error("Invalid command in {file} at offset {offset}: {message}")]
+    Command {
+        file: String,
+        offset: usize,
+        message: &'static str,
+    },
+}
+
+/// The contents of an AOF file: the dataset saved by a rewrite, for a base
+/// file in RDB format, and the write commands to run on top of it.
+#[derive(Debug)]
+pub struct AofContents {
+    pub preamble: Option<Rdb>,
+    pub commands: Vec<(Command, Vec<Vec<u8>>)>,
+}
+
+/// A file listed in the manifest.
+#[derive(Debug, Clone, PartialEq)]
+struct AofFile {
+    name: String,
+    seq: u64,
+}
+
+/// Which files make up the AOF. History files were replaced by a rewrite
+/// and are only listed until they are deleted.
+#[derive(Debug, Default, PartialEq)]
+struct Manifest {
+    base: Option<AofFile>,
+    history: Vec<AofFile>,
+    incrs: Vec<AofFile>,
+    // the highest incremental file sequence number used so far
+    incr_seq: u64,
+}
+
+impl Manifest {
+    /// Parses lines like `file appendonly.aof.1.base.rdb seq 1 type b`.
+    fn parse(text: &str) -> Result<Self, AofError> {
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let field = |key| {
+                fields
+                    .chunks(2)
+                    .find(|pair| pair[0] == key && pair.len() == 2)
+                    .map(|pair| pair[1])
+                    .ok_or_else(|| AofError::Manifest(format!("missing {} in '{}'", key, line)))
+            };
+            let name = field("file")?.to_string();
+            let seq = field("seq")?
+                .parse()
+                .map_err(|_| AofError::Manifest(format!("invalid seq in '{}'", line)))?;
+            let file = AofFile { name, seq };
+            match field("type")? {
+                "b" if manifest.base.is_none() => manifest.base = Some(file),
+                "b" => return Err(AofError::Manifest("more than one base file".to_string())),
+                "h" => manifest.history.push(file),
+                "i" => {
+                    if seq <= manifest.incr_seq {
+                        return Err(AofError::Manifest(format!(
+                            "incremental file {} is out of order",
+                            file.name
+                        )));
+                    }
+                    manifest.incr_seq = seq;
+                    manifest.incrs.push(file);
+                }
+                other => {
+                    return Err(AofError::Manifest(format!("unknown file type '{}'", other)));
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let files = (self.base.iter().map(|file| (file, 'b')))
+            .chain(self.history.iter().map(|file| (file, 'h')))
+            .chain(self.incrs.iter().map(|file| (file, 'i')));
+        for (file, kind) in files {
+            writeln!(f, "file {} seq {} type {}", file.name, file.seq, kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an AOF file named `file`, returning its contents and the length
+/// of the part that holds complete commands. A transaction without its
+/// EXEC is not complete either.
+fn parse_aof(buf: &[u8], file: &str, sanitize: bool) -> Result<(AofContents, usize), AofError> {
+    let (preamble, mut rest) = if buf.starts_with(b"REDIS") {
+        let (rdb, rest) = rdb::parse_rdb(buf, sanitize).map_err(|source| AofError::Rdb {
+            file: file.to_string(),
+            source,
+        })?;
+        (Some(rdb), rest)
+    } else {
+        (None, buf)
+    };
+    let mut commands = Vec::new();
+    // where the open transaction starts, in the file and in `commands`
+    let mut multi = None;
+    let mut offset;
+    loop {
+        offset = buf.len() - rest.len();
+        if rest.is_empty() {
+            break;
+        }
+        let parsed = parse_command(rest).map_err(|_| AofError::Format {
+            file: file.to_string(),
+            offset,
+        })?;
+        let Some((argv, r)) = parsed else {
+            break;
+        };
+        let command = Command::from_args(&argv).map_err(|message| AofError::Command {
+            file: file.to_string(),
+            offset,
+            message,
+        })?;
+        match command {
+            Command::Multi => multi = Some((offset, commands.len())),
+            Command::Exec | Command::Discard => multi = None,
+            _ => {}
+        }
+        commands.push((command, argv));
+        rest = r;
+    }
+    if let Some((start, len)) = multi {
+        offset = start;
+        commands.truncate(len);
+    }
+    Ok((AofContents { preamble, commands }, offset))
+}
+
+/// Parses one command: *<argc>\r\n, then $<len>\r\n<arg>\r\n for each
+/// argument. `None` if `buf` ends before the command does.
+#[allow(clippy::type_complexity)]
+pub fn parse_command(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, &[u8])>, &'static str> {
+    const INVALID: &str = "invalid multibulk";
+    fn line(buf: &[u8], prefix: u8) -> Result<Option<(usize, &[u8])>, &'static str> {
+        let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        if buf[0] != prefix {
+            return Err(INVALID);
+        }
+        let n = std::str::from_utf8(&buf[1..end])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .ok_or(INVALID)?;
+        Ok(Some((n, &buf[end + 2..])))
+    }
+    let Some((argc, mut rest)) = line(buf, b'*')? else {
+        return Ok(None);
+    };
+    if argc == 0 {
+        return Err(INVALID);
+    }
+    let mut argv = Vec::new();
+    for _ in 0..argc {
+        let Some((len, r)) = line(rest, b'$')? else {
+            return Ok(None);
+        };
+        if r.len() < len + 2 {
+            return Ok(None);
+        }
+        if &r[len..len + 2] != b"\r\n" {
+            return Err(INVALID);
+        }
+        argv.push(r[..len].to_vec());
+        rest = &r[len + 2..];
+    }
+    Ok(Some((argv, rest)))
+}
+
+pub fn encode_command(argv: &[Vec<u8>], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", argv.len()).as_bytes());
+    for arg in argv {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// The arguments a write is logged with, which must have the same effect
+/// when replayed later: TTLs become Unix times, the ID XADD generated
+/// replaces `*`, and MIGRATE deletes the keys it moved.
+pub fn propagated_args(mut argv: Vec<Vec<u8>>, reply: &Reply) -> Vec<Vec<u8>> {
+    let name = argv.first().map(|name| name.to_ascii_lowercase());
+    match name.as_deref() {
+        Some(b"set") if argv.len() == 5 && argv[3].eq_ignore_ascii_case(b"px") => {
+            let px = String::from_utf8_lossy(&argv[4])
+                .parse::<u64>()
+                .unwrap_or(0);
+            argv[3] = b"PXAT".to_vec();
+            argv[4] = (rdb::unix_time_ms() + px).to_string().into_bytes();
+        }
+        Some(b"xadd") => {
+            if let (Some(i), Reply::Bulk(id)) = (command::xadd_id_position(&argv[1..]), reply) {
+                argv[i + 1] = id.clone().into_bytes();
+            }
+        }
+        Some(b"restore" | b"restore-asking") => {
+            argv[0] = b"RESTORE".to_vec();
+            if let Ok(Command::Restore(restore)) = Command::from_args(&argv) {
+                if restore.ttl > 0 && !restore.absttl {
+                    argv[2] = (rdb::unix_time_ms() + restore.ttl).to_string().into_bytes();
+                    argv.push(b"ABSTTL".to_vec());
+                }
+            }
+        }
+        Some(b"migrate") => {
+            if let Ok(Command::Migrate(migrate)) = Command::from_args(&argv) {
+                argv = std::iter::once(b"DEL".to_vec())
+                    .chain(migrate.keys)
+                    .collect();
+            }
+        }
+        _ => {}
+    }
+    argv
+}
+
+/// The AOF writer shared by all connections. Writes are only logged with
+/// `appendonly yes`, but BGREWRITEAOF works either way.
+pub struct Aof {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // `appenddirname`, within `dir`
+    dir: PathBuf,
+    // `appendfilename`, the prefix of the files in `dir`
+    name: String,
+    fsync: Fsync,
+    manifest: Manifest,
+    // the last incremental file, open while appendonly is on
+    file: Option<File>,
+    // the database of the last command written to `file`
+    selected: Option<usize>,
+    rewriting: bool,
+    // the replication offset the writes so far reach, and the one reached
+    // by those fsynced, for WAITAOF
+    reploff: u64,
+    fsynced_reploff: u64,
+    // written to since the last fsync
+    dirty: bool,
+}
+
+pub type AofState = Arc<Aof>;
+
+impl Aof {
+    pub fn new(dir: PathBuf, name: String, fsync: Fsync) -> Self {
+        Aof {
+            inner: Mutex::new(Inner {
+                dir,
+                name,
+                fsync,
+                manifest: Manifest::default(),
+                file: None,
+                selected: None,
+                rewriting: false,
+                reploff: 0,
+                fsynced_reploff: 0,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Reads the files of the AOF, base first, or `None` if there is no
+    /// AOF yet. A single-file AOF from before manifests, at `legacy`, is
+    /// first moved into the directory as the base. A command or transaction
+    /// cut short at the end of the last file (e.g. by a crash in the middle
+    /// of a write) is an error unless `load_truncated`: it is then dropped,
+    /// from the file too.
+    pub fn load(
+        &self,
+        legacy: &Path,
+        sanitize: bool,
+        load_truncated: bool,
+    ) -> Result<Option<Vec<AofContents>>, AofError> {
+        let mut inner = self.inner.lock().unwrap();
+        match std::fs::read_to_string(inner.manifest_path()) {
+            Ok(text) => inner.manifest = Manifest::parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !legacy.exists() {
+                    return Ok(None);
+                }
+                std::fs::create_dir_all(&inner.dir)?;
+                let name = inner.name.clone();
+                std::fs::rename(legacy, inner.dir.join(&name))?;
+                inner.manifest.base = Some(AofFile { name, seq: 1 });
+                inner.persist_manifest()?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let files: Vec<&AofFile> = inner
+            .manifest
+            .base
+            .iter()
+            .chain(&inner.manifest.incrs)
+            .collect();
+        let mut contents = Vec::new();
+        for (i, file) in files.iter().enumerate() {
+            let path = inner.dir.join(&file.name);
+            let buf = match std::fs::read(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(AofError::Manifest(format!("{} doesn't exist", file.name)));
+                }
+                result => result?,
+            };
+            let (file_contents, valid) = parse_aof(&buf, &file.name, sanitize)?;
+            if valid < buf.len() {
+                if !load_truncated || i + 1 < files.len() {
+                    return Err(AofError::Truncated {
+                        file: file.name.clone(),
+                        offset: valid,
+                    });
+                }
+                eprintln!(
+                    "!!! Warning: short read while loading the AOF file {}, truncating it to {} bytes !!!",
+                    path.display(),
+                    valid
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid as u64)?;
+            }
+            contents.push(file_contents);
+        }
+        Ok(Some(contents))
+    }
+
+    /// Starts logging writes, to the last incremental file. A new AOF gets
+    /// a base file first, from `snapshot` (the dataset loaded otherwise).
+    pub fn open(&self, snapshot: impl FnOnce() -> Rdb, compress: bool) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        std::fs::create_dir_all(&inner.dir)?;
+        if inner.manifest.base.is_none() && inner.manifest.incrs.is_empty() {
+            let name = format!("{}.1.base.rdb", inner.name);
+            rdb::save_to_rdb(&inner.dir.join(&name), &snapshot(), compress)?;
+            inner.manifest.base = Some(AofFile { name, seq: 1 });
+        }
+        // left over if the server stopped before deleting them
+        inner.delete_history();
+        match inner.manifest.incrs.last() {
+            Some(last) => {
+                let path = inner.dir.join(&last.name);
+                inner.file = Some(OpenOptions::new().append(true).open(path)?);
+            }
+            None => inner.open_incr()?,
+        }
+        inner.persist_manifest()
+    }
+
+    /// Logs write commands, each run in the database it comes with, in one
+    /// write so a transaction is never split.
+    pub fn feed<'a>(&self, commands: impl IntoIterator<Item = (usize, &'a [Vec<u8>])>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.file.is_none() {
+            return;
+        }
+        let mut buf = Vec::new();
+        for (db, argv) in commands {
+            if inner.selected != Some(db) {
+                encode_command(&[b"SELECT".to_vec(), db.to_string().into_bytes()], &mut buf);
+                inner.selected = Some(db);
+            }
+            encode_command(argv, &mut buf);
+        }
+        let always = inner.fsync == Fsync::Always;
+        let file = inner.file.as_mut().unwrap();
+        let result = file
+            .write_all(&buf)
+            .and_then(|_| if always { file.sync_data() } else { Ok(()) });
+        if let Err(e) = result {
+            eprintln!("Error writing to the AOF: {}", e);
+        }
+        // with `appendfsync no`, the OS flushes the file when it sees fit
+        inner.dirty = inner.fsync == Fsync::EverySec;
+    }
+
+    /// Records that the writes so far reach replication offset `reploff`.
+    /// Returns the offset reached by the writes on disk, while logging.
+    pub fn mark(&self, reploff: u64) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.file.as_ref()?;
+        inner.reploff = reploff;
+        if !inner.dirty {
+            inner.fsynced_reploff = reploff;
+        }
+        Some(inner.fsynced_reploff)
+    }
+
+    /// Flushes the file to disk, with `appendfsync everysec`. Returns the
+    /// replication offset reached by the writes on disk.
+    pub fn fsync_everysec(&self) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        if let (Some(file), Fsync::EverySec) = (&inner.file, inner.fsync) {
+            if let Err(e) = file.sync_data() {
+                eprintln!("Error syncing the AOF: {}", e);
+                return None;
+            }
+            inner.dirty = false;
+            inner.fsynced_reploff = inner.reploff;
+            return Some(inner.fsynced_reploff);
+        }
+        None
+    }
+
+    /// Flushes the file to disk whatever `appendfsync` says, before
+    /// shutting down.
+    pub fn fsync(&self) {
+        let inner = self.inner.lock().unwrap();
+        if let Some(file) = &inner.file {
+            if let Err(e) = file.sync_data() {
+                eprintln!("Error syncing the AOF: {}", e);
+            }
+        }
+    }
+
+    /// BGREWRITEAOF: copies the dataset while the caller holds the
+    /// keyspace, and writes it as the new base from another thread. Writes
+    /// made in the meantime go to a new incremental file, which is all that
+    /// is kept of the old ones.
+    pub fn rewrite(
+        self: &Arc<Self>,
+        state: &[HashMap<Vec<u8>, Value>],
+        durations: &[HashMap<Vec<u8>, Instant>],
+        scripts: &Scripts,
+        compress: bool,
+    ) -> Reply<'static> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rewriting {
+            return Reply::Error("Background append only file rewriting already in progress");
+        }
+        let result = std::fs::create_dir_all(&inner.dir).and_then(|_| {
+            if inner.file.is_some() {
+                inner.open_incr()?;
+                inner.persist_manifest()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            return Reply::ErrorCode("ERR", format!("Can't open a new AOF file: {}", e));
+        }
+        // the incremental files the new base replaces
+        let replaced = inner.manifest.incrs.len() - inner.file.is_some() as usize;
+        let snapshot = rdb::snapshot(state, durations, scripts.library_codes());
+        inner.rewriting = true;
+        let tmp = inner
+            .dir
+            .join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+        let aof = Arc::clone(self);
+        std::thread::spawn(move || {
+            let result = File::create(&tmp)
+                .and_then(|mut file| {
+                    file.write_all(&rdb::encode_rdb(&snapshot, compress))?;
+                    file.sync_all()
+                })
+                .and_then(|_| aof.finish_rewrite(&tmp, replaced));
+            if let Err(e) = result {
+                eprintln!("Background AOF rewrite error: {}", e);
+                let _ = std::fs::remove_file(&tmp);
+            }
+            aof.inner.lock().unwrap().rewriting = false;
+        });
+        Reply::Simple("Background append only file rewriting started".to_string())
+    }
+
+    /// Makes the rewritten file the base, in place of the old base and the
+    /// first `replaced` incremental files, which are then deleted.
+    fn finish_rewrite(&self, tmp: &Path, replaced: usize) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.manifest.base.as_ref().map_or(0, |base| base.seq) + 1;
+        let name = format!("{}.{}.base.rdb", inner.name, seq);
+        std::fs::rename(tmp, inner.dir.join(&name))?;
+        let manifest = &mut inner.manifest;
+        let old = manifest.base.replace(AofFile { name, seq });
+        manifest.history.extend(old);
+        manifest.history.extend(manifest.incrs.drain(..replaced));
+        inner.persist_manifest()?;
+        inner.delete_history();
+        inner.persist_manifest()
+    }
+}
+
+impl Inner {
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.name))
+    }
+
+    /// Switches to a new incremental file, not yet in the saved manifest.
+    fn open_incr(&mut self) -> io::Result<()> {
+        let seq = self.manifest.incr_seq + 1;
+        let name = format!("{}.{}.incr.aof", self.name, seq);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(&name))?;
+        if let Some(old) = &self.file {
+            old.sync_data()?;
+        }
+        self.manifest.incr_seq = seq;
+        self.manifest.incrs.push(AofFile { name, seq });
+        self.file = Some(file);
+        // each file is loaded starting in database 0
+        self.selected = None;
+        Ok(())
+    }
+
+    /// Replaces the manifest atomically.
+    fn persist_manifest(&self) -> io::Result<()> {
+        let path = self.manifest_path();
+        let tmp = self.dir.join(format!("temp-{}.manifest", self.name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.manifest.to_string().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Deletes the files a rewrite replaced; the caller saves the manifest.
+    fn delete_history(&mut self) {
+        for file in self.manifest.history.drain(..) {
+            if let Err(e) = std::fs::remove_file(self.dir.join(&file.name)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("Can't delete the AOF history file {}: {}", file.name, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_parse_aof() {
+        let mut buf = Vec::new();
+        encode_command(&args(&["SET", "a", "1"]), &mut buf);
+        encode_command(&args(&["select", "2"]), &mut buf);
+        let (contents, valid) = parse_aof(&buf, "f", false).unwrap();
+        assert_eq!(valid, buf.len());
+        assert!(contents.preamble.is_none());
+        assert_eq!(contents.commands[0].1, args(&["SET", "a", "1"]));
+        assert_eq!(contents.commands[1].0, Command::Select(2));
+
+        // a command cut short is left out
+        let complete = buf.len();
+        encode_command(&args(&["del", "a"]), &mut buf);
+        let (contents, valid) = parse_aof(&buf[..buf.len() - 3], "f", false).unwrap();
+        assert_eq!(valid, complete);
+        assert_eq!(contents.commands.len(), 2);
+
+        // and so is a transaction without its EXEC
+        let complete = buf.len();
+        encode_command(&args(&["MULTI"]), &mut buf);
+        encode_command(&args(&["del", "a"]), &mut buf);
+        let (contents, valid) = parse_aof(&buf, "f", false).unwrap();
+        assert_eq!(valid, complete);
+        assert_eq!(contents.commands.len(), 3);
+        encode_command(&args(&["EXEC"]), &mut buf);
+        let (contents, valid) = parse_aof(&buf, "f", false).unwrap();
+        assert_eq!(valid, buf.len());
+        assert_eq!(contents.commands.len(), 6);
+
+        assert!(matches!(
+            parse_aof(b"*1\r\n$4\r\nping\r\n+OK\r\n", "f", false),
+            Err(AofError::Format { offset: 14, .. })
+        ));
+        assert!(matches!(
+            parse_aof(b"*1\r\n$4\r\nnope\r\n", "f", false),
+            Err(AofError::Command { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_rdb_preamble() {
+        let rdb = rdb::snapshot(
+            &[HashMap::from([(
+                b"k".to_vec(),
+                Value::String(b"v".to_vec().into()),
+            )])],
+            &[HashMap::new()],
+            vec![],
+        );
+        let mut buf = rdb::encode_rdb(&rdb, false);
+        encode_command(&args(&["del", "k"]), &mut buf);
+        let (contents, valid) = parse_aof(&buf, "f", true).unwrap();
+        assert_eq!(valid, buf.len());
+        assert_eq!(contents.preamble.unwrap().entries.len(), 1);
+        assert_eq!(contents.commands.len(), 1);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.1.base.rdb seq 1 type h\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(manifest.history.len(), 1);
+        assert_eq!(manifest.incr_seq, 3);
+        assert_eq!(manifest.to_string(), text);
+
+        // fields may come in any order
+        let manifest = Manifest::parse("type i seq 1 file a.1.incr.aof\n").unwrap();
+        assert_eq!(manifest.incrs[0].name, "a.1.incr.aof");
+
+        assert!(Manifest::parse("file a seq 1 type x").is_err());
+        assert!(Manifest::parse("file a seq 1").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b").is_err());
+        assert!(Manifest::parse("file a seq 2 type i\nfile b seq 1 type i").is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let aof = Arc::new(Aof::new(
+            dir.clone(),
+            "appendonly.aof".to_string(),
+            Fsync::No,
+        ));
+        assert!(aof.load(&dir.join("none"), false, true).unwrap().is_none());
+        aof.open(Rdb::default, false).unwrap();
+        aof.feed([(0, args(&["set", "a", "1"]).as_slice())]);
+
+        let state = [HashMap::from([(
+            b"a".to_vec(),
+            Value::String(b"1".to_vec().into()),
+        )])];
+        let reply = aof.rewrite(&state, &[HashMap::new()], &Scripts::default(), false);
+        assert!(!reply.is_error());
+        aof.feed([(1, args(&["set", "b", "2"]).as_slice())]);
+        while aof.inner.lock().unwrap().rewriting {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let manifest = std::fs::read_to_string(dir.join("appendonly.aof.manifest")).unwrap();
+        assert_eq!(
+            manifest,
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "appendonly.aof.2.base.rdb",
+                "appendonly.aof.2.incr.aof",
+                "appendonly.aof.manifest"
+            ]
+        );
+
+        let reloaded = Aof::new(dir.clone(), "appendonly.aof".to_string(), Fsync::No);
+        let contents = reloaded
+            .load(&dir.join("none"), false, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(contents[0].preamble.as_ref().unwrap().entries.len(), 1);
+        assert_eq!(contents[1].commands[0].1, args(&["SELECT", "1"]));
+        assert_eq!(contents[1].commands[1].1, args(&["set", "b", "2"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fsynced_offset() {
+        let dir = std::env::temp_dir().join(format!("aof-fsync-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let aof = Aof::new(dir.clone(), "appendonly.aof".to_string(), Fsync::EverySec);
+        assert_eq!(aof.mark(10), None);
+        aof.open(Rdb::default, false).unwrap();
+        aof.feed([(0, args(&["set", "a", "1"]).as_slice())]);
+        assert_eq!(aof.mark(10), Some(0));
+        assert_eq!(aof.fsync_everysec(), Some(10));
+        // nothing was written since
+        assert_eq!(aof.mark(20), Some(20));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_propagated_args() {
+        let argv = propagated_args(
+            args(&["XADD", "s", "MAXLEN", "~", "10", "*", "f", "v"]),
+            &Reply::Bulk("5-0".to_string()),
+        );
+        assert_eq!(
+            argv,
+            args(&["XADD", "s", "MAXLEN", "~", "10", "5-0", "f", "v"])
+        );
+
+        let argv = propagated_args(args(&["set", "k", "v", "px", "1000"]), &Reply::Null);
+        assert_eq!(argv[3], b"PXAT");
+        let at: u64 = String::from_utf8(argv[4].clone()).unwrap().parse().unwrap();
+        assert!(at > rdb::unix_time_ms());
+        let Command::Set(_, _, Some(px)) = Command::from_args(&argv).unwrap() else {
+            panic!("not a SET with a TTL")
+        };
+        assert!(px > 900 && px <= 1000);
+
+        let argv = propagated_args(args(&["restore", "k", "1000", "p"]), &Reply::Null);
+        assert_eq!(argv[4], b"ABSTTL");
+        let argv = propagated_args(
+            args(&["MIGRATE", "h", "1", "", "0", "0", "KEYS", "a", "b"]),
+            &Reply::Null,
+        );
+        assert_eq!(argv, args(&["DEL", "a", "b"]));
+    }
+}
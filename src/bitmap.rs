@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+
+use crate::reply::{Reply, WRONGTYPE};
+use crate::value::Value;
+
+// Bitmaps are plain string values; bit 0 is the most significant bit of the
+// first byte.
+
+/// Strings are limited to 512MB, so bit offsets must be below 2^32.
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: Option<i64>,
+    // start and end are bit indices rather than byte indices
+    pub bit: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+impl BitFieldType {
+    /// Parses `i1`..`i64` and `u1`..`u63`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let signed = match s.as_bytes().first()? {
+            b'i' | b'I' => true,
+            b'u' | b'U' => false,
+            _ => return None,
+        };
+        let bits: u32 = s[1..].parse().ok()?;
+        let max = if signed { 64 } else { 63 };
+        (1..=max)
+            .contains(&bits)
+            .then_some(BitFieldType { signed, bits })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitFieldOp {
+    Get(BitFieldType, u64),
+    Set(BitFieldType, u64, i64),
+    IncrBy(BitFieldType, u64, i64),
+    // applies to the SET and INCRBY operations that follow it
+    Overflow(Overflow),
+}
+
+fn get_bytes<'a>(
+    state: &'a HashMap<Vec<u8>, Value>,
+    key: &[u8],
+) -> Result<Option<&'a Vec<u8>>, Reply<'static>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(Reply::ErrorCode("WRONGTYPE", WRONGTYPE.to_string())),
+    }
+}
+
+/// Like `get_bytes`, but creates an empty string if the key does not exist.
+fn get_bytes_mut<'a>(
+    state: &'a mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+) -> Result<&'a mut Vec<u8>, Reply<'static>> {
+    match state
+        .entry(key.to_vec())
+        .or_insert_with(|| Value::String(Vec::new().into()))
+    {
+        Value::String(s) => Ok(s),
+        _ => Err(Reply::ErrorCode("WRONGTYPE", WRONGTYPE.to_string())),
+    }
+}
+
+fn bit_at(bytes: &[u8], offset: u64) -> bool {
+    bytes
+        .get((offset >> 3) as usize)
+        .is_some_and(|b| b & (0x80 >> (offset & 7)) != 0)
+}
+
+/// Sets a bit, growing the string with zero bytes as needed. Returns the
+/// previous value of the bit.
+fn set_bit(bytes: &mut Vec<u8>, offset: u64, value: bool) -> bool {
+    let byte = (offset >> 3) as usize;
+    if bytes.len() <= byte {
+        bytes.resize(byte + 1, 0);
+    }
+    let mask = 0x80 >> (offset & 7);
+    let old = bytes[byte] & mask != 0;
+    if value {
+        bytes[byte] |= mask;
+    } else {
+        bytes[byte] &= !mask;
+    }
+    old
+}
+
+/// Resolves a (possibly negative) inclusive range over a string of `len`
+/// bytes into byte indices, plus masks of the bits of the first and last
+/// byte that fall outside of it (only non-zero for bit ranges).
+fn resolve_range(len: usize, start: i64, end: i64, bit: bool) -> Option<(usize, usize, u8, u8)> {
+    let total = if bit { len as i64 * 8 } else { len as i64 };
+    let start = if start < 0 { total + start } else { start }.max(0);
+    let end = if end < 0 { total + end } else { end }
+        .max(0)
+        .min(total - 1);
+    if start > end {
+        return None;
+    }
+    if !bit {
+        return Some((start as usize, end as usize, 0, 0));
+    }
+    let first_mask = !(0xFFu8 >> (start & 7));
+    let last_mask = ((1u16 << (7 - (end & 7))) - 1) as u8;
+    Some((
+        (start >> 3) as usize,
+        (end >> 3) as usize,
+        first_mask,
+        last_mask,
+    ))
+}
+
+pub fn setbit(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    offset: u64,
+    value: bool,
+) -> Reply<'static> {
+    match get_bytes_mut(state, key) {
+        Err(reply) => reply,
+        Ok(bytes) => Reply::Integer(set_bit(bytes, offset, value) as i64),
+    }
+}
+
+pub fn getbit(state: &HashMap<Vec<u8>, Value>, key: &[u8], offset: u64) -> Reply<'static> {
+    match get_bytes(state, key) {
+        Err(reply) => reply,
+        Ok(bytes) => Reply::Integer(bytes.is_some_and(|b| bit_at(b, offset)) as i64),
+    }
+}
+
+pub fn bitcount(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    range: Option<BitRange>,
+) -> Reply<'static> {
+    let bytes = match get_bytes(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Integer(0),
+        Ok(Some(bytes)) => bytes,
+    };
+    let range = match range {
+        None => resolve_range(bytes.len(), 0, -1, false),
+        Some(r) => resolve_range(bytes.len(), r.start, r.end.unwrap_or(-1), r.bit),
+    };
+    let Some((start, end, first_mask, last_mask)) = range else {
+        return Reply::Integer(0);
+    };
+    let count: u32 = bytes[start..=end]
+        .iter()
+        .map(|b| b.count_ones())
+        .sum::<u32>()
+        - (bytes[start] & first_mask).count_ones()
+        - (bytes[end] & last_mask).count_ones();
+    Reply::Integer(count as i64)
+}
+
+pub fn bitpos(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    bit: bool,
+    range: Option<BitRange>,
+) -> Reply<'static> {
+    let bytes = match get_bytes(state, key) {
+        Err(reply) => return reply,
+        // a missing key is an infinite string of zeros
+        Ok(None) => return Reply::Integer(if bit { -1 } else { 0 }),
+        Ok(Some(bytes)) => bytes,
+    };
+    let end_given = range.is_some_and(|r| r.end.is_some());
+    let range = match range {
+        None => resolve_range(bytes.len(), 0, -1, false),
+        Some(r) => resolve_range(bytes.len(), r.start, r.end.unwrap_or(-1), r.bit),
+    };
+    let Some((start, end, first_mask, last_mask)) = range else {
+        return Reply::Integer(-1);
+    };
+    for (i, &byte) in bytes[start..=end].iter().enumerate() {
+        // bits outside of the range must never match
+        let mut byte = byte;
+        for (at, mask) in [(start, first_mask), (end, last_mask)] {
+            if start + i == at {
+                byte = if bit { byte & !mask } else { byte | mask };
+            }
+        }
+        let byte = if bit { byte } else { !byte };
+        if byte != 0 {
+            return Reply::Integer(((start + i) * 8) as i64 + byte.leading_zeros() as i64);
+        }
+    }
+    // without an explicit end, the string is considered padded with zeros
+    if bit || end_given {
+        Reply::Integer(-1)
+    } else {
+        Reply::Integer(((end + 1) * 8) as i64)
+    }
+}
+
+pub fn bitop(
+    state: &mut HashMap<Vec<u8>, Value>,
+    op: BitOperation,
+    dest: &[u8],
+    keys: &[Vec<u8>],
+) -> Reply<'static> {
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in keys {
+        match get_bytes(state, key) {
+            Err(reply) => return reply,
+            Ok(bytes) => sources.push(bytes.map(Vec::as_slice).unwrap_or_default()),
+        }
+    }
+    let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+    // missing bytes of shorter strings count as zeros
+    let byte = |src: &[u8], i: usize| src.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let first = byte(sources[0], i);
+            sources[1..].iter().fold(first, |acc, src| match op {
+                BitOperation::And => acc & byte(src, i),
+                BitOperation::Or => acc | byte(src, i),
+                BitOperation::Xor => acc ^ byte(src, i),
+                BitOperation::Not => acc,
+            })
+        })
+        .map(|b| if op == BitOperation::Not { !b } else { b })
+        .collect();
+
+    if result.is_empty() {
+        state.remove(dest);
+    } else {
+        state.insert(dest.to_vec(), Value::String(result.into()));
+    }
+    Reply::Integer(len as i64)
+}
+
+fn get_field(bytes: &[u8], ty: BitFieldType, offset: u64) -> i64 {
+    let mut value: u64 = 0;
+    for j in 0..ty.bits as u64 {
+        value = (value << 1) | bit_at(bytes, offset + j) as u64;
+    }
+    if ty.signed && ty.bits < 64 && value & (1 << (ty.bits - 1)) != 0 {
+        value |= u64::MAX << ty.bits;
+    }
+    value as i64
+}
+
+fn set_field(bytes: &mut Vec<u8>, ty: BitFieldType, offset: u64, value: i64) {
+    let value = value as u64;
+    for j in 0..ty.bits as u64 {
+        let bit = (value >> (ty.bits as u64 - 1 - j)) & 1 == 1;
+        set_bit(bytes, offset + j, bit);
+    }
+}
+
+/// Computes `value + incr` as stored in a field of type `ty`, handling
+/// overflows according to `overflow`. Returns None if the operation fails.
+fn field_add(ty: BitFieldType, value: i128, incr: i128, overflow: Overflow) -> Option<i64> {
+    let (min, max) = if ty.signed {
+        (-(1i128 << (ty.bits - 1)), (1i128 << (ty.bits - 1)) - 1)
+    } else {
+        (0, (1i128 << ty.bits) - 1)
+    };
+    let sum = value + incr;
+    if (min..=max).contains(&sum) {
+        return Some(sum as i64);
+    }
+    match overflow {
+        Overflow::Fail => None,
+        Overflow::Sat => Some(if sum > max { max } else { min } as i64),
+        Overflow::Wrap => {
+            let wrapped = sum as u64;
+            if ty.bits == 64 {
+                return Some(wrapped as i64);
+            }
+            let high = u64::MAX << ty.bits;
+            if ty.signed && wrapped & (1 << (ty.bits - 1)) != 0 {
+                Some((wrapped | high) as i64)
+            } else {
+                Some((wrapped & !high) as i64)
+            }
+        }
+    }
+}
+
+pub fn bitfield(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    ops: &[BitFieldOp],
+) -> Reply<'static> {
+    let writes = ops
+        .iter()
+        .any(|op| matches!(op, BitFieldOp::Set(..) | BitFieldOp::IncrBy(..)));
+    if !writes {
+        let bytes = match get_bytes(state, key) {
+            Err(reply) => return reply,
+            Ok(bytes) => bytes.map(Vec::as_slice).unwrap_or_default(),
+        };
+        let replies = ops
+            .iter()
+            .filter_map(|op| match op {
+                BitFieldOp::Get(ty, offset) => Some(Reply::Integer(get_field(bytes, *ty, *offset))),
+                _ => None,
+            })
+            .collect();
+        return Reply::Nested(replies);
+    }
+
+    let bytes = match get_bytes_mut(state, key) {
+        Err(reply) => return reply,
+        Ok(bytes) => bytes,
+    };
+    let mut overflow = Overflow::Wrap;
+    let mut replies = Vec::new();
+    for op in ops {
+        let reply = match *op {
+            BitFieldOp::Overflow(o) => {
+                overflow = o;
+                continue;
+            }
+            BitFieldOp::Get(ty, offset) => Reply::Integer(get_field(bytes, ty, offset)),
+            BitFieldOp::Set(ty, offset, value) => {
+                // unsigned fields see the value as a two's complement u64
+                let value = if ty.signed {
+                    value as i128
+                } else {
+                    value as u64 as i128
+                };
+                match field_add(ty, value, 0, overflow) {
+                    None => Reply::NullBulk,
+                    Some(new) => {
+                        let old = get_field(bytes, ty, offset);
+                        set_field(bytes, ty, offset, new);
+                        Reply::Integer(old)
+                    }
+                }
+            }
+            BitFieldOp::IncrBy(ty, offset, incr) => {
+                let old = get_field(bytes, ty, offset);
+                match field_add(ty, old as i128, incr as i128, overflow) {
+                    None => Reply::NullBulk,
+                    Some(new) => {
+                        set_field(bytes, ty, offset, new);
+                        Reply::Integer(new)
+                    }
+                }
+            }
+        };
+        replies.push(reply);
+    }
+    Reply::Nested(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(key: &[u8], bytes: &[u8]) -> HashMap<Vec<u8>, Value> {
+        HashMap::from([(key.to_vec(), Value::String(bytes.to_vec().into()))])
+    }
+
+    fn integer(reply: Reply) -> i64 {
+        match reply {
+            Reply::Integer(i) => i,
+            _ => panic!("expected an integer reply"),
+        }
+    }
+
+    #[test]
+    fn test_bitcount_and_bitpos_ranges() {
+        let state = state_with(b"k", b"\xff\xf0\x00");
+        let range = |start, end, bit| Some(BitRange { start, end, bit });
+        assert_eq!(integer(bitcount(&state, b"k", None)), 12);
+        assert_eq!(integer(bitcount(&state, b"k", range(1, Some(1), false))), 4);
+        assert_eq!(
+            integer(bitcount(&state, b"k", range(5, Some(-13), true))),
+            7
+        );
+        assert_eq!(integer(bitcount(&state, b"k", range(2, Some(1), false))), 0);
+
+        assert_eq!(integer(bitpos(&state, b"k", false, None)), 12);
+        assert_eq!(
+            integer(bitpos(&state, b"k", true, range(2, None, false))),
+            -1
+        );
+        assert_eq!(
+            integer(bitpos(&state, b"k", true, range(-12, None, true))),
+            -1
+        );
+        assert_eq!(
+            integer(bitpos(&state, b"k", true, range(9, Some(11), true))),
+            9
+        );
+
+        let state = state_with(b"k", b"\xff\xff");
+        assert_eq!(integer(bitpos(&state, b"k", false, None)), 16);
+        assert_eq!(
+            integer(bitpos(&state, b"k", false, range(0, Some(-1), false))),
+            -1
+        );
+    }
+
+    #[test]
+    fn test_bitop() {
+        let mut state = state_with(b"a", b"\x0f\xff");
+        state.insert(b"b".to_vec(), Value::String(b"\xf0".to_vec().into()));
+        let keys = [b"a".to_vec(), b"b".to_vec()];
+        assert_eq!(integer(bitop(&mut state, BitOperation::Or, b"d", &keys)), 2);
+        assert_eq!(
+            state.get(b"d".as_slice()),
+            Some(&Value::String(b"\xff\xff".to_vec().into()))
+        );
+        bitop(&mut state, BitOperation::And, b"d", &keys);
+        assert_eq!(
+            state.get(b"d".as_slice()),
+            Some(&Value::String(b"\x00\x00".to_vec().into()))
+        );
+        bitop(&mut state, BitOperation::Not, b"d", &keys[1..]);
+        assert_eq!(
+            state.get(b"d".as_slice()),
+            Some(&Value::String(b"\x0f".to_vec().into()))
+        );
+        bitop(&mut state, BitOperation::Xor, b"d", &[b"missing".to_vec()]);
+        assert_eq!(state.get(b"d".as_slice()), None);
+    }
+
+    #[test]
+    fn test_bitfield_overflow() {
+        let mut state = HashMap::new();
+        let u2 = BitFieldType::parse("u2").unwrap();
+        let i8 = BitFieldType::parse("i8").unwrap();
+        let ops = [
+            BitFieldOp::IncrBy(u2, 100, 1),
+            BitFieldOp::Overflow(Overflow::Sat),
+            BitFieldOp::IncrBy(u2, 102, 5),
+            BitFieldOp::Overflow(Overflow::Fail),
+            BitFieldOp::IncrBy(u2, 104, 4),
+            BitFieldOp::Overflow(Overflow::Wrap),
+            BitFieldOp::Set(i8, 0, 200),
+            BitFieldOp::Get(i8, 0),
+        ];
+        let replies = match bitfield(&mut state, b"k", &ops) {
+            Reply::Nested(replies) => replies,
+            _ => panic!("expected an array reply"),
+        };
+        let replies: Vec<Option<i64>> = replies
+            .into_iter()
+            .map(|r| match r {
+                Reply::Integer(i) => Some(i),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(replies, vec![Some(1), Some(3), None, Some(0), Some(-56)]);
+        assert_eq!(BitFieldType::parse("u64"), None);
+        assert_eq!(field_add(i8, 127, 1, Overflow::Wrap), Some(-128));
+        assert_eq!(field_add(i8, -100, -100, Overflow::Sat), Some(-128));
+    }
+}
@@ -0,0 +1,280 @@
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::replication::parse_size;
+use crate::reply::Reply;
+
+/// How much data pushed to a connection may wait for it to be read, as set
+/// by `client-output-buffer-limit`: past `hard` bytes, or past `soft` bytes
+/// for `soft_seconds` in a row, the connection is closed. Zero means no
+/// limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+pub const DEFAULT_OUTPUT_LIMITS: &str = "normal 0 0 0 replica 256mb 64mb 60 pubsub 32mb 8mb 60";
+
+/// Parses `client-output-buffer-limit`: a class (normal, replica or pubsub)
+/// followed by its hard and soft limits and soft seconds, for any number of
+/// classes. Returns the limits of pubsub and replica connections, starting
+/// from `limits`; the replies of normal connections are never held back.
+pub fn parse_output_limits(
+    s: &str,
+    mut limits: (OutputLimit, OutputLimit),
+) -> Option<(OutputLimit, OutputLimit)> {
+    let words: Vec<&str> = s.split_whitespace().collect();
+    if !words.len().is_multiple_of(4) {
+        return None;
+    }
+    for class in words.chunks(4) {
+        let limit = OutputLimit {
+            hard: parse_size(class[1])?,
+            soft: parse_size(class[2])?,
+            soft_seconds: class[3].parse().ok()?,
+        };
+        match class[0].to_lowercase().as_str() {
+            "normal" => {}
+            "pubsub" => limits.0 = limit,
+            "replica" | "slave" => limits.1 = limit,
+            _ => return None,
+        }
+    }
+    Some(limits)
+}
+
+/// Data pushed to a connection, until it is written.
+#[derive(Default)]
+struct Output {
+    buf: Vec<u8>,
+    // since when it has been over the soft limit
+    soft_since: Option<Instant>,
+    closed: bool,
+}
+
+/// The outbound side of a connection. It is shared so that other connections
+/// can push data to it (e.g. pub/sub messages) without interleaving with its
+/// own replies. Pushed data is buffered, and written by the connection's own
+/// threads, so a client that doesn't read never blocks the one pushing.
+pub struct Client {
+    pub id: u64,
+    // the peer, for connections over TCP
+    pub addr: Option<SocketAddr>,
+    // the socket, to close the connection from elsewhere
+    pub socket: Option<TcpStream>,
+    resp3: AtomicBool,
+    writer: Mutex<Box<dyn Write + Send>>,
+    output: Mutex<Output>,
+    pushed: Condvar,
+}
+
+impl Client {
+    pub fn new(id: u64, writer: Box<dyn Write + Send>) -> Self {
+        Client {
+            id,
+            addr: None,
+            socket: None,
+            resp3: AtomicBool::new(false),
+            writer: Mutex::new(writer),
+            output: Mutex::default(),
+            pushed: Condvar::new(),
+        }
+    }
+
+    pub fn resp3(&self) -> bool {
+        self.resp3.load(Ordering::Relaxed)
+    }
+
+    pub fn set_resp3(&self, resp3: bool) {
+        self.resp3.store(resp3, Ordering::Relaxed);
+    }
+
+    /// Writes a reply of the connection's own, after what was pushed to it.
+    pub fn send(&self, reply: Reply) -> io::Result<()> {
+        self.send_raw(&reply.encode(self.resp3()))
+    }
+
+    /// Writes bytes that are not a reply, e.g. a snapshot for a replica.
+    pub fn send_raw(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let pushed = self.take_pushed();
+        writer.write_all(&pushed)?;
+        writer.write_all(bytes)
+    }
+
+    /// Queues a message from another connection, without waiting for it to
+    /// be written. The connection is closed if it is over `limit`.
+    pub fn push(&self, reply: Reply, limit: OutputLimit) {
+        self.push_raw(&reply.encode(self.resp3()), limit);
+    }
+
+    /// Queues bytes that are not a reply, e.g. the replication stream.
+    pub fn push_raw(&self, bytes: &[u8], limit: OutputLimit) {
+        let mut output = self.output.lock().unwrap();
+        if output.closed {
+            return;
+        }
+        output.buf.extend_from_slice(bytes);
+        let len = output.buf.len();
+        let over_soft = limit.soft > 0 && len > limit.soft;
+        if !over_soft {
+            output.soft_since = None;
+        }
+        let soft_since = over_soft.then(|| *output.soft_since.get_or_insert_with(Instant::now));
+        let over = (limit.hard > 0 && len > limit.hard)
+            || soft_since
+                .is_some_and(|since| since.elapsed() >= Duration::from_secs(limit.soft_seconds));
+        if over {
+            eprintln!(
+                "Client id={} closed for overcoming of output buffer limits.",
+                self.id
+            );
+            output.closed = true;
+            output.buf = Vec::new();
+            self.close();
+        }
+        self.pushed.notify_all();
+    }
+
+    /// Writes what was pushed so far.
+    pub fn flush(&self) -> io::Result<()> {
+        self.send_raw(&[])
+    }
+
+    /// Writes what is pushed as it comes, until `stop`: run on a thread of
+    /// the connection's own.
+    pub fn drain(&self) {
+        loop {
+            {
+                let output = self.output.lock().unwrap();
+                let output = self
+                    .pushed
+                    .wait_while(output, |output| output.buf.is_empty() && !output.closed)
+                    .unwrap();
+                if output.closed {
+                    return;
+                }
+            }
+            if self.flush().is_err() {
+                self.stop();
+                self.close();
+                return;
+            }
+        }
+    }
+
+    /// Drops what was pushed and not written yet, and ends `drain`.
+    pub fn stop(&self) {
+        let mut output = self.output.lock().unwrap();
+        output.closed = true;
+        output.buf = Vec::new();
+        self.pushed.notify_all();
+    }
+
+    fn take_pushed(&self) -> Vec<u8> {
+        let mut output = self.output.lock().unwrap();
+        output.soft_since = None;
+        std::mem::take(&mut output.buf)
+    }
+
+    /// Closes the connection, which ends the loop serving it.
+    pub fn close(&self) {
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// HELLO: switches the protocol version and describes the server.
+    pub fn hello(&self, version: Option<u32>) -> Reply<'static> {
+        match version {
+            Some(2) => self.set_resp3(false),
+            Some(3) => self.set_resp3(true),
+            Some(_) => {
+                return Reply::ErrorCode("NOPROTO", "unsupported protocol version".to_string())
+            }
+            None => {}
+        }
+        let bulk = |s: &str| Reply::Bulk(s.to_string());
+        Reply::Map(vec![
+            ("server".to_string(), bulk("redis")),
+            ("version".to_string(), bulk("7.2.0")),
+            (
+                "proto".to_string(),
+                Reply::Integer(if self.resp3() { 3 } else { 2 }),
+            ),
+            ("id".to_string(), Reply::Integer(self.id as i64)),
+            ("mode".to_string(), bulk("standalone")),
+            ("role".to_string(), bulk("master")),
+            ("modules".to_string(), Reply::Nested(vec![])),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_push() {
+        let buffer = Buffer::default();
+        let client = Client::new(1, Box::new(buffer.clone()));
+        let limit = OutputLimit {
+            hard: 8,
+            soft: 0,
+            soft_seconds: 0,
+        };
+        client.push_raw(b"+a\r\n", limit);
+        assert!(buffer.0.lock().unwrap().is_empty());
+        // pushed data goes first
+        client.send(Reply::Simple("b".to_string())).unwrap();
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"+a\r\n+b\r\n");
+
+        // over the limit, the rest is dropped
+        client.push_raw(b"+c\r\n", limit);
+        client.push_raw(b"+d\r\n", limit);
+        client.push_raw(b"+e\r\n", limit);
+        client.flush().unwrap();
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"+a\r\n+b\r\n");
+        // and the drainer stops
+        client.drain();
+    }
+
+    #[test]
+    fn test_parse_output_limits() {
+        let (pubsub, replica) =
+            parse_output_limits(DEFAULT_OUTPUT_LIMITS, Default::default()).unwrap();
+        assert_eq!(
+            pubsub,
+            OutputLimit {
+                hard: 32 << 20,
+                soft: 8 << 20,
+                soft_seconds: 60,
+            }
+        );
+        assert_eq!(replica.hard, 256 << 20);
+        let (pubsub, _) = parse_output_limits("pubsub 0 0 0", (pubsub, replica)).unwrap();
+        assert_eq!(pubsub, OutputLimit::default());
+        assert!(parse_output_limits("pubsub 1mb", Default::default()).is_none());
+        assert!(parse_output_limits("other 0 0 0", Default::default()).is_none());
+    }
+}
@@ -0,0 +1,880 @@
+// Cluster mode. The keyspace is split into 16384 hash slots, each served by
+// one node. The slot of a key is the CRC16 of the key, or of its hash tag
+// (what is between the first `{` and the next `}`, when not empty), so that
+// related keys can be kept together. Commands on keys of a slot served by
+// another node get a -MOVED redirection to it; while a slot migrates, keys
+// that already left get an -ASK redirection to the importing node, which
+// serves them to clients that send ASKING first.
+//
+// There is no cluster bus: a node learns about another with CLUSTER MEET,
+// which asks it for its CLUSTER NODES, and slots are assigned with CLUSTER
+// ADDSLOTS and SETSLOT. A node whose slots change has every node it knows
+// meet it again, to learn of the change. The configuration is kept in
+// `cluster-config-file`, in the format of CLUSTER NODES.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::aof;
+use crate::reply::Reply;
+use crate::sha1::sha1_hex;
+use crate::value::Value;
+
+pub const SLOTS: usize = 16384;
+/// How long CLUSTER MEET waits on the other node.
+const MEET_TIMEOUT: Duration = Duration::from_secs(5);
+/// The cluster bus port of a node is its port plus this, as in Redis.
+const BUS_PORT_OFFSET: u32 = 10000;
+
+/// The CRC16 of Redis Cluster (XMODEM: polynomial 0x1021, no reflection).
+pub fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The hash slot of a key.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|start| {
+        let len = key[start + 1..].iter().position(|&b| b == b'}')?;
+        (len > 0).then(|| &key[start + 1..start + 1 + len])
+    });
+    crc16(tag.unwrap_or(key)) % SLOTS as u16
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Subcommand {
+    Info,
+    MyId,
+    KeySlot(Vec<u8>),
+    CountKeysInSlot(u16),
+    GetKeysInSlot(u16, usize),
+    Slots,
+    Shards,
+    Nodes,
+    AddSlots(Vec<u16>),
+    DelSlots(Vec<u16>),
+    SetSlot(u16, SetSlot),
+    Meet(String, u16),
+}
+
+/// CLUSTER SETSLOT: what happens to a slot, with the ID of the node
+/// concerned.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SetSlot {
+    Importing(String),
+    Migrating(String),
+    Stable,
+    Node(String),
+}
+
+struct Node {
+    id: String,
+    host: String,
+    port: u16,
+}
+
+pub struct Cluster {
+    enabled: bool,
+    // `cluster-config-file`, rewritten on every change
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // this node first; nodes are never removed, so indices stay valid
+    nodes: Vec<Node>,
+    // the index of the node serving each slot
+    slots: Vec<Option<usize>>,
+    // slots of this node moving to another one, and slots of another node
+    // moving to this one
+    migrating: HashMap<u16, usize>,
+    importing: HashMap<u16, usize>,
+}
+
+pub type ClusterState = Arc<Cluster>;
+
+/// A new node ID: 40 random-looking hex characters.
+fn new_node_id(port: u16) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    sha1_hex(format!("node:{}:{}:{}", now, std::process::id(), port).as_bytes())
+}
+
+impl Cluster {
+    /// Without cluster mode, a placeholder that refuses CLUSTER commands.
+    pub fn disabled() -> Self {
+        Cluster {
+            enabled: false,
+            path: PathBuf::new(),
+            inner: Mutex::new(Inner::new(String::new(), 0)),
+        }
+    }
+
+    /// Loads the configuration from `path`, or starts as a new node that
+    /// serves no slots if there is none yet.
+    pub fn load(path: PathBuf, port: u16) -> Result<Self, String> {
+        let inner = match std::fs::read_to_string(&path) {
+            Ok(config) => Inner::parse(&config, port)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Inner::new(new_node_id(port), port),
+            Err(e) => return Err(e.to_string()),
+        };
+        let cluster = Cluster {
+            enabled: true,
+            path,
+            inner: Mutex::new(inner),
+        };
+        cluster
+            .save(&cluster.inner.lock().unwrap())
+            .map_err(|e| e.to_string())?;
+        Ok(cluster)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Where a command on `keys` must run: None if here, or the error that
+    /// redirects it. `exists` tells whether a key is here, for slots that
+    /// are migrating.
+    pub fn redirect(
+        &self,
+        keys: &[&[u8]],
+        asking: bool,
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Option<Reply<'static>> {
+        let first = keys.first().filter(|_| self.enabled)?;
+        let slot = key_slot(first);
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Some(Reply::ErrorCode(
+                "CROSSSLOT",
+                "Keys in request don't hash to the same slot".to_string(),
+            ));
+        }
+        let (ask, address) = {
+            let inner = self.inner.lock().unwrap();
+            if !inner.is_ok() {
+                return Some(Reply::ErrorCode(
+                    "CLUSTERDOWN",
+                    "The cluster is down".to_string(),
+                ));
+            }
+            match inner.slots[slot as usize] {
+                Some(0) => match inner.migrating.get(&slot) {
+                    Some(&to) => (true, inner.nodes[to].address()),
+                    None => return None,
+                },
+                _ if asking && inner.importing.contains_key(&slot) => {
+                    if keys.len() > 1 && !keys.iter().all(|key| exists(key)) {
+                        return Some(try_again_error());
+                    }
+                    return None;
+                }
+                Some(owner) => (false, inner.nodes[owner].address()),
+                None => unreachable!("the cluster is ok"),
+            }
+        };
+        // the keys that are still here are served here, and some of the
+        // keys of a command cannot be here and some there
+        if ask {
+            let present = keys.iter().filter(|key| exists(key)).count();
+            if present == keys.len() {
+                return None;
+            }
+            if present > 0 {
+                return Some(try_again_error());
+            }
+        }
+        let code = if ask { "ASK" } else { "MOVED" };
+        Some(Reply::ErrorCode(code, format!("{} {}", slot, address)))
+    }
+
+    /// Whether a script may call a command on `keys`: an error unless they
+    /// are all served here. `exists` is as for `redirect`.
+    pub fn check_script_keys(
+        &self,
+        keys: &[&[u8]],
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Option<Reply<'static>> {
+        match self.redirect(keys, false, exists)? {
+            Reply::ErrorCode("CROSSSLOT", _) => Some(Reply::Error(
+                "Script attempted to access keys that do not hash to the same slot",
+            )),
+            Reply::ErrorCode("CLUSTERDOWN", message) => {
+                Some(Reply::ErrorCode("CLUSTERDOWN", message))
+            }
+            _ => Some(Reply::Error(
+                "Script attempted to access a non local key in a cluster node",
+            )),
+        }
+    }
+
+    /// Runs a CLUSTER subcommand other than MEET, on the keys of database 0
+    /// for those that look at them.
+    pub fn command(
+        &self,
+        subcommand: Subcommand,
+        state: &HashMap<Vec<u8>, Value>,
+        durations: &HashMap<Vec<u8>, Instant>,
+    ) -> Reply<'static> {
+        if !self.enabled {
+            return disabled_error();
+        }
+        let now = Instant::now();
+        let keys_in_slot = |slot: u16| {
+            state.keys().filter(move |key| {
+                key_slot(key) == slot && durations.get(*key).is_none_or(|at| *at > now)
+            })
+        };
+        match subcommand {
+            Subcommand::KeySlot(key) => return Reply::Integer(key_slot(&key) as i64),
+            Subcommand::CountKeysInSlot(slot) => {
+                return Reply::Integer(keys_in_slot(slot).count() as i64)
+            }
+            Subcommand::GetKeysInSlot(slot, count) => {
+                let keys = keys_in_slot(slot).take(count);
+                return Reply::Nested(keys.map(|key| Reply::BulkBytes(key.clone())).collect());
+            }
+            _ => {}
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let reply = match subcommand {
+            Subcommand::Info => Reply::Bulk(inner.info()),
+            Subcommand::MyId => Reply::Bulk(inner.nodes[0].id.clone()),
+            Subcommand::Slots => inner.slots_reply(),
+            Subcommand::Shards => inner.shards_reply(),
+            Subcommand::Nodes => Reply::Bulk(inner.nodes_text()),
+            Subcommand::AddSlots(slots) => {
+                if let Some(slot) = slots.iter().find(|&&s| inner.slots[s as usize].is_some()) {
+                    return Reply::ErrorCode("ERR", format!("Slot {} is already busy", slot));
+                }
+                for slot in slots {
+                    inner.slots[slot as usize] = Some(0);
+                    inner.importing.remove(&slot);
+                }
+                Reply::Simple("OK".to_string())
+            }
+            Subcommand::DelSlots(slots) => {
+                if let Some(slot) = slots.iter().find(|&&s| inner.slots[s as usize].is_none()) {
+                    return Reply::ErrorCode("ERR", format!("Slot {} is already unassigned", slot));
+                }
+                for slot in slots {
+                    inner.slots[slot as usize] = None;
+                    inner.migrating.remove(&slot);
+                    inner.importing.remove(&slot);
+                }
+                Reply::Simple("OK".to_string())
+            }
+            Subcommand::SetSlot(slot, action) => {
+                let has_keys = keys_in_slot(slot).next().is_some();
+                match inner.set_slot(slot, action, has_keys) {
+                    Ok(()) => Reply::Simple("OK".to_string()),
+                    Err(msg) => return Reply::ErrorCode("ERR", msg),
+                }
+            }
+            Subcommand::Meet(..) => unreachable!("run without the keyspace"),
+            Subcommand::KeySlot(_)
+            | Subcommand::CountKeysInSlot(_)
+            | Subcommand::GetKeysInSlot(..) => unreachable!("handled above"),
+        };
+        if let Err(e) = self.save(&inner) {
+            return Reply::ErrorCode("ERR", format!("Can't save the cluster config: {}", e));
+        }
+        reply
+    }
+
+    /// CLUSTER MEET: adds the node at `host`:`other_port`, or updates it,
+    /// with the slots it reports for itself, then has it meet this node,
+    /// which listens on `port`, in turn if it does not know it yet. Not to
+    /// be run holding the keyspace, which the other node's requests may
+    /// need.
+    pub fn meet(&self, host: &str, other_port: u16, port: u16) -> Reply<'static> {
+        if !self.enabled {
+            return disabled_error();
+        }
+        let result = fetch_nodes(host, other_port).and_then(|nodes| {
+            let knows_us = {
+                let mut inner = self.inner.lock().unwrap();
+                let knows_us = inner.update(&nodes, host, other_port)?;
+                self.save(&inner)?;
+                knows_us
+            };
+            if !knows_us {
+                send_meet(host, other_port, port)?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Reply::Simple("OK".to_string()),
+            Err(e) => Reply::ErrorCode("ERR", format!("Can't meet {}:{}: {}", host, other_port, e)),
+        }
+    }
+
+    /// Has every other node meet this one, which listens on `port`, after
+    /// its slots changed. Not to be run holding the keyspace either.
+    pub fn announce(&self, port: u16) {
+        let peers: Vec<(String, u16)> = {
+            let inner = self.inner.lock().unwrap();
+            let peers = inner.nodes[1..].iter();
+            peers.map(|node| (node.host.clone(), node.port)).collect()
+        };
+        for (host, other_port) in peers {
+            if let Err(e) = send_meet(&host, other_port, port) {
+                eprintln!("Can't tell {}:{} about the slots: {}", host, other_port, e);
+            }
+        }
+    }
+
+    fn save(&self, inner: &Inner) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let config = format!(
+            "{}vars currentEpoch 0 lastVoteEpoch 0\n",
+            inner.nodes_text()
+        );
+        std::fs::write(&tmp, config)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+fn disabled_error() -> Reply<'static> {
+    Reply::Error("This instance has cluster support disabled")
+}
+
+fn try_again_error() -> Reply<'static> {
+    Reply::ErrorCode(
+        "TRYAGAIN",
+        "Multiple keys request during rehashing of slot".to_string(),
+    )
+}
+
+impl Subcommand {
+    /// Whether it may change which node serves a slot.
+    pub fn assigns_slots(&self) -> bool {
+        matches!(
+            self,
+            Subcommand::AddSlots(_)
+                | Subcommand::DelSlots(_)
+                | Subcommand::SetSlot(_, SetSlot::Node(_))
+        )
+    }
+}
+
+impl Node {
+    fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+impl Inner {
+    fn new(id: String, port: u16) -> Self {
+        Inner {
+            nodes: vec![Node {
+                id,
+                host: "127.0.0.1".to_string(),
+                port,
+            }],
+            slots: vec![None; SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        }
+    }
+
+    /// Reads a configuration saved by `Cluster::save`.
+    fn parse(config: &str, port: u16) -> Result<Self, String> {
+        let invalid = |line: &str| format!("Invalid cluster config line: {}", line);
+        let mut inner = Inner::new(String::new(), port);
+        // slot markers, resolved once every node is known
+        let mut moving = Vec::new();
+        for line in config.lines().filter(|line| !line.starts_with("vars")) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let Some((id, flags, slots)) = parse_node_line(line) else {
+                return Err(invalid(line));
+            };
+            let node = if flags.split(',').any(|flag| flag == "myself") {
+                inner.nodes[0].id = id.to_string();
+                0
+            } else {
+                let (host, port) = fields[1]
+                    .split('@')
+                    .next()
+                    .and_then(|address| address.rsplit_once(':'))
+                    .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+                    .ok_or_else(|| invalid(line))?;
+                inner.add_node(id.to_string(), host, port)
+            };
+            for slot in slots {
+                inner.slots[slot as usize] = Some(node);
+            }
+            for marker in fields[8..].iter().filter(|field| field.starts_with('[')) {
+                moving.push(marker.trim_matches(|c| c == '[' || c == ']').to_string());
+            }
+        }
+        if inner.nodes[0].id.is_empty() {
+            return Err("Invalid cluster config: no myself node".to_string());
+        }
+        for marker in moving {
+            let node = |id: &str| inner.nodes.iter().position(|node| node.id == id);
+            if let Some((slot, id)) = marker.split_once("->-") {
+                let slot = slot.parse().map_err(|_| invalid(&marker))?;
+                let to = node(id).ok_or_else(|| invalid(&marker))?;
+                inner.migrating.insert(slot, to);
+            } else if let Some((slot, id)) = marker.split_once("-<-") {
+                let slot = slot.parse().map_err(|_| invalid(&marker))?;
+                let from = node(id).ok_or_else(|| invalid(&marker))?;
+                inner.importing.insert(slot, from);
+            }
+        }
+        Ok(inner)
+    }
+
+    /// The index of the node with `id`, added if it is new.
+    fn add_node(&mut self, id: String, host: String, port: u16) -> usize {
+        match self.nodes.iter().position(|node| node.id == id) {
+            Some(i) => {
+                self.nodes[i].host = host;
+                self.nodes[i].port = port;
+                i
+            }
+            None => {
+                self.nodes.push(Node { id, host, port });
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Adds or updates the node at `host`:`port` from its CLUSTER NODES:
+    /// it serves the slots it reports for itself, unless this node serves
+    /// them, and those it no longer serves go to the node it knows serves
+    /// them now, if this node knows it too. Returns whether it knows this
+    /// node.
+    fn update(&mut self, nodes: &str, host: &str, port: u16) -> io::Result<bool> {
+        let mut peer = None;
+        let mut knows_us = false;
+        let mut owners: HashMap<u16, &str> = HashMap::new();
+        for line in nodes.lines() {
+            let Some((id, flags, slots)) = parse_node_line(line) else {
+                continue;
+            };
+            if id == self.nodes[0].id {
+                knows_us = true;
+            } else if flags.split(',').any(|flag| flag == "myself") {
+                peer = Some(id);
+            }
+            owners.extend(slots.into_iter().map(|slot| (slot, id)));
+        }
+        let Some(id) = peer else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the node did not describe itself",
+            ));
+        };
+        let node = self.add_node(id.to_string(), host.to_string(), port);
+        for slot in 0..SLOTS {
+            let owner = owners.get(&(slot as u16));
+            if self.slots[slot] == Some(0) {
+                continue;
+            } else if owner == Some(&id) {
+                self.slots[slot] = Some(node);
+            } else if self.slots[slot] == Some(node) {
+                let known = owner.and_then(|id| self.nodes.iter().position(|n| n.id == *id));
+                self.slots[slot] = known.filter(|&owner| owner != 0);
+            }
+        }
+        Ok(knows_us)
+    }
+
+    fn node(&self, id: &str) -> Result<usize, String> {
+        self.nodes
+            .iter()
+            .position(|node| node.id == id)
+            .ok_or_else(|| format!("I don't know about node {}", id))
+    }
+
+    /// Whether every slot is served.
+    fn is_ok(&self) -> bool {
+        self.slots.iter().all(Option::is_some)
+    }
+
+    fn set_slot(&mut self, slot: u16, action: SetSlot, has_keys: bool) -> Result<(), String> {
+        let owner = self.slots[slot as usize];
+        match action {
+            SetSlot::Migrating(id) => {
+                if owner != Some(0) {
+                    return Err(format!("I'm not the owner of hash slot {}", slot));
+                }
+                let to = self.node(&id)?;
+                if to == 0 {
+                    return Err("Target node is myself".to_string());
+                }
+                self.migrating.insert(slot, to);
+            }
+            SetSlot::Importing(id) => {
+                if owner == Some(0) {
+                    return Err(format!("I'm already the owner of hash slot {}", slot));
+                }
+                let from = self.node(&id)?;
+                if from == 0 {
+                    return Err("Source node is myself".to_string());
+                }
+                self.importing.insert(slot, from);
+            }
+            SetSlot::Stable => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                let node = self.node(&id)?;
+                if owner == Some(0) && node != 0 && has_keys {
+                    return Err(format!(
+                        "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                        slot
+                    ));
+                }
+                self.slots[slot as usize] = Some(node);
+                self.migrating.remove(&slot);
+                if node == 0 {
+                    self.importing.remove(&slot);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The ranges of consecutive slots served by the same node, with it.
+    fn ranges(&self) -> Vec<(u16, u16, usize)> {
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            let Some(owner) = *owner else { continue };
+            match ranges.last_mut() {
+                Some((_, end, node)) if *node == owner && *end as usize + 1 == slot => {
+                    *end = slot as u16;
+                }
+                _ => ranges.push((slot as u16, slot as u16, owner)),
+            }
+        }
+        ranges
+    }
+
+    fn info(&self) -> String {
+        let assigned = self.slots.iter().filter(|slot| slot.is_some()).count();
+        let size = (0..self.nodes.len())
+            .filter(|i| self.slots.contains(&Some(*i)))
+            .count();
+        let state = if self.is_ok() { "ok" } else { "fail" };
+        let fields = [
+            ("cluster_state", state.to_string()),
+            ("cluster_slots_assigned", assigned.to_string()),
+            ("cluster_slots_ok", assigned.to_string()),
+            ("cluster_slots_pfail", "0".to_string()),
+            ("cluster_slots_fail", "0".to_string()),
+            ("cluster_known_nodes", self.nodes.len().to_string()),
+            ("cluster_size", size.to_string()),
+            ("cluster_current_epoch", "0".to_string()),
+            ("cluster_my_epoch", "0".to_string()),
+            ("cluster_stats_messages_sent", "0".to_string()),
+            ("cluster_stats_messages_received", "0".to_string()),
+        ];
+        fields
+            .iter()
+            .map(|(name, value)| format!("{}:{}\r\n", name, value))
+            .collect()
+    }
+
+    fn node_reply(&self, node: usize) -> Reply<'static> {
+        let node = &self.nodes[node];
+        Reply::Nested(vec![
+            Reply::Bulk(node.host.clone()),
+            Reply::Integer(node.port as i64),
+            Reply::Bulk(node.id.clone()),
+            Reply::Nested(vec![]),
+        ])
+    }
+
+    fn slots_reply(&self) -> Reply<'static> {
+        Reply::Nested(
+            self.ranges()
+                .into_iter()
+                .map(|(start, end, node)| {
+                    Reply::Nested(vec![
+                        Reply::Integer(start as i64),
+                        Reply::Integer(end as i64),
+                        self.node_reply(node),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    fn shards_reply(&self) -> Reply<'static> {
+        let ranges = self.ranges();
+        let shards = self.nodes.iter().enumerate().map(|(i, node)| {
+            let slots = ranges
+                .iter()
+                .filter(|(_, _, owner)| *owner == i)
+                .flat_map(|(start, end, _)| {
+                    [Reply::Integer(*start as i64), Reply::Integer(*end as i64)]
+                })
+                .collect();
+            let node = Reply::Map(vec![
+                ("id".to_string(), Reply::Bulk(node.id.clone())),
+                ("port".to_string(), Reply::Integer(node.port as i64)),
+                ("ip".to_string(), Reply::Bulk(node.host.clone())),
+                ("endpoint".to_string(), Reply::Bulk(node.host.clone())),
+                ("role".to_string(), Reply::Bulk("master".to_string())),
+                ("replication-offset".to_string(), Reply::Integer(0)),
+                ("health".to_string(), Reply::Bulk("online".to_string())),
+            ]);
+            Reply::Map(vec![
+                ("slots".to_string(), Reply::Nested(slots)),
+                ("nodes".to_string(), Reply::Nested(vec![node])),
+            ])
+        });
+        Reply::Nested(shards.collect())
+    }
+
+    /// CLUSTER NODES: a line per node, with the slots it serves, and for
+    /// this node, the slots migrating and importing.
+    fn nodes_text(&self) -> String {
+        let ranges = self.ranges();
+        let mut text = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let flags = if i == 0 { "myself,master" } else { "master" };
+            text += &format!(
+                "{} {}@{} {} - 0 0 0 connected",
+                node.id,
+                node.address(),
+                node.port as u32 + BUS_PORT_OFFSET,
+                flags
+            );
+            for (start, end, _) in ranges.iter().filter(|(_, _, owner)| *owner == i) {
+                if start == end {
+                    text += &format!(" {}", start);
+                } else {
+                    text += &format!(" {}-{}", start, end);
+                }
+            }
+            if i == 0 {
+                let mut migrating: Vec<_> = self.migrating.iter().collect();
+                migrating.sort();
+                for (slot, to) in migrating {
+                    text += &format!(" [{}->-{}]", slot, self.nodes[*to].id);
+                }
+                let mut importing: Vec<_> = self.importing.iter().collect();
+                importing.sort();
+                for (slot, from) in importing {
+                    text += &format!(" [{}-<-{}]", slot, self.nodes[*from].id);
+                }
+            }
+            text += "\n";
+        }
+        text
+    }
+}
+
+/// The ID, flags and slots of a line of CLUSTER NODES.
+fn parse_node_line(line: &str) -> Option<(&str, &str, Vec<u16>)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 8 {
+        return None;
+    }
+    let mut slots = Vec::new();
+    for field in fields[8..].iter().filter(|field| !field.starts_with('[')) {
+        let (start, end) = field.split_once('-').unwrap_or((field, field));
+        let (start, end): (u16, u16) = (start.parse().ok()?, end.parse().ok()?);
+        if start > end || end as usize >= SLOTS {
+            return None;
+        }
+        slots.extend(start..=end);
+    }
+    Some((fields[0], fields[2], slots))
+}
+
+/// Connects to a node for CLUSTER MEET.
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown host"))?;
+    let stream = TcpStream::connect_timeout(&addr, MEET_TIMEOUT)?;
+    stream.set_read_timeout(Some(MEET_TIMEOUT))?;
+    stream.set_write_timeout(Some(MEET_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// The CLUSTER NODES of a node.
+fn fetch_nodes(host: &str, port: u16) -> io::Result<String> {
+    let mut stream = connect(host, port)?;
+    let mut command = Vec::new();
+    aof::encode_command(&[b"CLUSTER".to_vec(), b"NODES".to_vec()], &mut command);
+    stream.write_all(&command)?;
+    let mut reader = BufReader::new(stream);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let len: usize = header
+        .trim_end()
+        .strip_prefix('$')
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, header.trim_end().to_string()))?;
+    let mut nodes = vec![0; len + 2];
+    io::Read::read_exact(&mut reader, &mut nodes)?;
+    nodes.truncate(len);
+    Ok(String::from_utf8_lossy(&nodes).into_owned())
+}
+
+/// Asks a node to meet this one, at `port` and the address it has on the
+/// connection to that node, which it can be reached at.
+fn send_meet(host: &str, other_port: u16, port: u16) -> io::Result<()> {
+    let mut stream = connect(host, other_port)?;
+    let local_host = stream.local_addr()?.ip().to_string();
+    let mut command = Vec::new();
+    let argv = [
+        b"CLUSTER".to_vec(),
+        b"MEET".to_vec(),
+        local_host.as_bytes().to_vec(),
+        port.to_string().into_bytes(),
+    ];
+    aof::encode_command(&argv, &mut command);
+    stream.write_all(&command)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    if !reply.starts_with('+') {
+        return Err(io::Error::other(reply.trim_end().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_ne!(key_slot(b"foo{}{bar}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let mut inner = Inner::new("a".repeat(40), 7000);
+        let other = inner.add_node("b".repeat(40), "127.0.0.1".to_string(), 7001);
+        for slot in 0..SLOTS {
+            inner.slots[slot] = Some(if slot < 8192 { 0 } else { other });
+        }
+        let cluster = Cluster {
+            enabled: true,
+            path: PathBuf::new(),
+            inner: Mutex::new(inner),
+        };
+        let redirect = |keys: &[&[u8]], asking| {
+            cluster
+                .redirect(keys, asking, |key| key == b"a")
+                .map(|reply| String::from_utf8(reply.into_bytes()).unwrap())
+        };
+        // "a" is in slot 15495, "foo" in 12182, "b" in 3300
+        assert_eq!(redirect(&[b"b".as_slice()], false), None);
+        assert_eq!(
+            redirect(&[b"foo".as_slice()], false).as_deref(),
+            Some("-MOVED 12182 127.0.0.1:7001\r\n")
+        );
+        assert!(redirect(&[b"b".as_slice(), b"foo".as_slice()], false)
+            .unwrap()
+            .starts_with("-CROSSSLOT"));
+
+        let mut inner = cluster.inner.lock().unwrap();
+        inner
+            .set_slot(3300, SetSlot::Migrating("b".repeat(40)), true)
+            .unwrap();
+        inner
+            .set_slot(15495, SetSlot::Importing("b".repeat(40)), false)
+            .unwrap();
+        assert!(inner
+            .nodes_text()
+            .contains(&format!(" [3300->-{}]", "b".repeat(40))));
+        drop(inner);
+        assert_eq!(
+            redirect(&[b"b".as_slice()], false).as_deref(),
+            Some("-ASK 3300 127.0.0.1:7001\r\n")
+        );
+        assert_eq!(redirect(&[b"a".as_slice()], true), None);
+        assert!(redirect(&[b"a".as_slice()], false)
+            .unwrap()
+            .starts_with("-MOVED"));
+        // "{a}x" is in the slot of "a", and "{b}x" in the slot of "b"
+        assert!(redirect(&[b"a".as_slice(), b"{a}x".as_slice()], true)
+            .unwrap()
+            .starts_with("-TRYAGAIN"));
+        let exists = |key: &[u8]| key == b"b";
+        assert!(matches!(
+            cluster.redirect(&[b"b".as_slice(), b"{b}x".as_slice()], false, exists),
+            Some(Reply::ErrorCode("TRYAGAIN", _))
+        ));
+        assert!(matches!(
+            cluster.check_script_keys(&[b"foo".as_slice()], exists),
+            Some(Reply::Error(
+                "Script attempted to access a non local key in a cluster node"
+            ))
+        ));
+        assert!(cluster
+            .check_script_keys(&[b"b".as_slice()], exists)
+            .is_none());
+    }
+
+    #[test]
+    fn test_update() {
+        let (a, b, c) = ("a".repeat(40), "b".repeat(40), "c".repeat(40));
+        let mut inner = Inner::new(a.clone(), 7000);
+        inner.slots[0] = Some(0);
+        let nodes = |slots: &str, c_slots: &str| {
+            format!(
+                "{b} 127.0.0.1:7001@17001 myself,master - 0 0 0 connected {slots}\n\
+                 {c} 127.0.0.1:7002@17002 master - 0 0 0 connected {c_slots}\n"
+            )
+        };
+        // this node's slots stay its own
+        assert!(!inner.update(&nodes("0-2", ""), "127.0.0.1", 7001).unwrap());
+        assert_eq!(inner.slots[..4], [Some(0), Some(1), Some(1), None]);
+        // given up, to a node this one knows or not
+        inner.add_node(c.clone(), "127.0.0.1".to_string(), 7002);
+        inner.update(&nodes("1", "2"), "127.0.0.1", 7001).unwrap();
+        assert_eq!(inner.slots[..4], [Some(0), Some(1), Some(2), None]);
+        inner.update(&nodes("", ""), "127.0.0.1", 7001).unwrap();
+        assert_eq!(inner.slots[..4], [Some(0), None, Some(2), None]);
+    }
+
+    #[test]
+    fn test_config() {
+        let mut inner = Inner::new("a".repeat(40), 7000);
+        inner.add_node("b".repeat(40), "10.0.0.2".to_string(), 7001);
+        inner.slots[0] = Some(0);
+        inner.slots[5..=9].fill(Some(1));
+        inner.importing.insert(7, 1);
+        let text = inner.nodes_text();
+        assert!(text.starts_with(&format!(
+            "{} 127.0.0.1:7000@17000 myself,master - 0 0 0 connected 0 [7-<-{}]\n",
+            "a".repeat(40),
+            "b".repeat(40)
+        )));
+        let parsed = Inner::parse(&text, 7000).unwrap();
+        assert_eq!(parsed.nodes_text(), text);
+        assert!(Inner::parse("junk", 7000).is_err());
+    }
+}
@@ -1,15 +1,318 @@
+use crate::bitmap::{BitFieldOp, BitFieldType, BitOperation, BitRange, Overflow, MAX_BIT_OFFSET};
+use crate::cluster::{SetSlot, Subcommand as ClusterCommand, SLOTS};
+use crate::functions::RestorePolicy;
+use crate::geo::{GeoFrom, GeoSearch, GeoShape, GeoSort};
+use crate::migrate::{Migrate, Restore};
+use crate::pubsub::Kind;
+use crate::resp::{StrType, Type as RespType};
+use crate::scripting::Script;
+use crate::stream::{
+    ClaimOptions, PendingRange, StreamId, StreamTrim, TrimStrategy, XAddId, XReadId,
+};
 use std::borrow::Cow;
 
-use crate::resp::Type as RespType;
-
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Ping,
     Echo(String),
-    Set(String, String, Option<u64>),
-    Get(String),
+    Set(Vec<u8>, Vec<u8>, Option<u64>),
+    Get(Vec<u8>),
     ConfigGet(String),
     Keys(),
+    Type(Vec<u8>),
+    XAdd {
+        key: Vec<u8>,
+        id: XAddId,
+        fields: Vec<(Vec<u8>, Vec<u8>)>,
+        nomkstream: bool,
+        trim: Option<StreamTrim>,
+    },
+    XRange {
+        key: Vec<u8>,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    },
+    XLen(Vec<u8>),
+    XTrim(Vec<u8>, StreamTrim),
+    XDel(Vec<u8>, Vec<StreamId>),
+    XRead {
+        count: Option<usize>,
+        block: Option<u64>,
+        keys: Vec<Vec<u8>>,
+        ids: Vec<XReadId>,
+    },
+    XGroupCreate {
+        key: Vec<u8>,
+        group: String,
+        id: XReadId,
+        mkstream: bool,
+        entries_read: Option<u64>,
+    },
+    XGroupSetId {
+        key: Vec<u8>,
+        group: String,
+        id: XReadId,
+        entries_read: Option<u64>,
+    },
+    XGroupDestroy(Vec<u8>, String),
+    XGroupCreateConsumer(Vec<u8>, String, String),
+    XGroupDelConsumer(Vec<u8>, String, String),
+    XReadGroup {
+        group: String,
+        consumer: String,
+        count: Option<usize>,
+        block: Option<u64>,
+        noack: bool,
+        keys: Vec<Vec<u8>>,
+        ids: Vec<XReadId>,
+    },
+    XAck(Vec<u8>, String, Vec<StreamId>),
+    XPending(Vec<u8>, String, Option<PendingRange>),
+    XClaim {
+        key: Vec<u8>,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    XAutoClaim {
+        key: Vec<u8>,
+        group: String,
+        consumer: String,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    XInfoStream(Vec<u8>, Option<usize>),
+    XInfoGroups(Vec<u8>),
+    XInfoConsumers(Vec<u8>, String),
+    SetBit(Vec<u8>, u64, bool),
+    GetBit(Vec<u8>, u64),
+    BitCount(Vec<u8>, Option<BitRange>),
+    BitPos(Vec<u8>, bool, Option<BitRange>),
+    BitOp(BitOperation, Vec<u8>, Vec<Vec<u8>>),
+    BitField(Vec<u8>, Vec<BitFieldOp>),
+    PfAdd(Vec<u8>, Vec<Vec<u8>>),
+    PfCount(Vec<Vec<u8>>),
+    PfMerge(Vec<u8>, Vec<Vec<u8>>),
+    GeoAdd {
+        key: Vec<u8>,
+        nx: bool,
+        xx: bool,
+        ch: bool,
+        items: Vec<(f64, f64, Vec<u8>)>,
+    },
+    GeoDist(Vec<u8>, Vec<u8>, Vec<u8>, f64),
+    GeoPos(Vec<u8>, Vec<Vec<u8>>),
+    GeoHash(Vec<u8>, Vec<Vec<u8>>),
+    GeoSearch(Vec<u8>, GeoSearch),
+    GeoSearchStore {
+        dest: Vec<u8>,
+        key: Vec<u8>,
+        query: GeoSearch,
+        store_dist: bool,
+    },
+    Subscribe(Kind, Vec<String>),
+    Unsubscribe(Kind, Vec<String>),
+    Publish(String, Vec<u8>),
+    SPublish(String, Vec<u8>),
+    PubSubChannels(Kind, Option<String>),
+    PubSubNumSub(Kind, Vec<String>),
+    PubSubNumPat,
+    Hello(Option<u32>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+    Del(Vec<Vec<u8>>),
+    FlushDb,
+    FlushAll,
+    Select(i64),
+    Move(Vec<u8>, i64),
+    SwapDb(i64, i64),
+    Eval {
+        script: Script,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+    },
+    ScriptLoad(Vec<u8>),
+    ScriptExists(Vec<String>),
+    ScriptFlush,
+    ScriptKill,
+    FunctionLoad {
+        code: Vec<u8>,
+        replace: bool,
+    },
+    FunctionList {
+        pattern: Option<String>,
+        with_code: bool,
+    },
+    FunctionDelete(String),
+    FunctionDump,
+    FunctionRestore(Vec<u8>, RestorePolicy),
+    FunctionFlush,
+    FCall {
+        function: String,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+    },
+    Save,
+    BgSave,
+    LastSave,
+    /// Some(false) for NOSAVE, Some(true) for SAVE, None to save if save points are set
+    Shutdown(Option<bool>),
+    BgRewriteAof,
+    Dump(Vec<u8>),
+    Restore(Restore),
+    Migrate(Migrate),
+    // option-value pairs
+    ReplConf(Vec<(String, String)>),
+    Psync(String, i64),
+    // `None` for REPLICAOF NO ONE
+    ReplicaOf(Option<(String, u16)>),
+    Info(Vec<String>),
+    // numreplicas, and the timeout in ms, 0 to block forever
+    Wait(usize, u64),
+    // numlocal, numreplicas, timeout
+    WaitAof(usize, usize, u64),
+    Cluster(ClusterCommand),
+    Asking,
+}
+
+impl Command {
+    /// The keys a command reads or modifies, which must all be served by
+    /// this node in cluster mode.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Get(key)
+            | Command::Type(key)
+            | Command::XAdd { key, .. }
+            | Command::XRange { key, .. }
+            | Command::XLen(key)
+            | Command::XTrim(key, _)
+            | Command::XDel(key, _)
+            | Command::XGroupCreate { key, .. }
+            | Command::XGroupSetId { key, .. }
+            | Command::XGroupDestroy(key, _)
+            | Command::XGroupCreateConsumer(key, ..)
+            | Command::XGroupDelConsumer(key, ..)
+            | Command::XAck(key, ..)
+            | Command::XPending(key, ..)
+            | Command::XClaim { key, .. }
+            | Command::XAutoClaim { key, .. }
+            | Command::XInfoStream(key, _)
+            | Command::XInfoGroups(key)
+            | Command::XInfoConsumers(key, _)
+            | Command::SetBit(key, ..)
+            | Command::GetBit(key, _)
+            | Command::BitCount(key, _)
+            | Command::BitPos(key, ..)
+            | Command::BitField(key, _)
+            | Command::PfAdd(key, _)
+            | Command::GeoAdd { key, .. }
+            | Command::GeoDist(key, ..)
+            | Command::GeoPos(key, _)
+            | Command::GeoHash(key, _)
+            | Command::GeoSearch(key, _)
+            | Command::Move(key, _)
+            | Command::Dump(key)
+            | Command::Restore(Restore { key, .. }) => vec![key],
+            // shard channels belong to the slot of their name
+            Command::SPublish(channel, _) => vec![channel.as_bytes()],
+            Command::XRead { keys, .. }
+            | Command::XReadGroup { keys, .. }
+            | Command::PfCount(keys)
+            | Command::Watch(keys)
+            | Command::Del(keys)
+            | Command::Eval { keys, .. }
+            | Command::FCall { keys, .. }
+            | Command::Migrate(Migrate { keys, .. }) => keys.iter().map(Vec::as_slice).collect(),
+            Command::Subscribe(Kind::Shard, channels) => {
+                channels.iter().map(String::as_bytes).collect()
+            }
+            Command::BitOp(_, dest, keys) | Command::PfMerge(dest, keys) => std::iter::once(dest)
+                .chain(keys)
+                .map(Vec::as_slice)
+                .collect(),
+            Command::GeoSearchStore { dest, key, .. } => vec![dest, key],
+            _ => vec![],
+        }
+    }
+
+    /// The keys a command may modify.
+    pub fn written_keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Set(key, ..)
+            | Command::XAdd { key, .. }
+            | Command::XTrim(key, _)
+            | Command::XDel(key, _)
+            | Command::XGroupCreate { key, .. }
+            | Command::XGroupSetId { key, .. }
+            | Command::XGroupDestroy(key, _)
+            | Command::XGroupCreateConsumer(key, ..)
+            | Command::XGroupDelConsumer(key, ..)
+            | Command::XAck(key, ..)
+            | Command::XClaim { key, .. }
+            | Command::XAutoClaim { key, .. }
+            | Command::SetBit(key, ..)
+            | Command::PfAdd(key, _)
+            | Command::GeoAdd { key, .. }
+            | Command::Restore(Restore { key, .. })
+            | Command::Move(key, _) => vec![key],
+            Command::BitField(key, ops)
+                if ops
+                    .iter()
+                    .any(|op| matches!(op, BitFieldOp::Set(..) | BitFieldOp::IncrBy(..))) =>
+            {
+                vec![key]
+            }
+            Command::BitOp(_, dest, _)
+            | Command::PfMerge(dest, _)
+            | Command::GeoSearchStore { dest, .. } => vec![dest],
+            // consumers' pending entries and the group's last ID change
+            Command::Del(keys) | Command::XReadGroup { keys, .. } => {
+                keys.iter().map(Vec::as_slice).collect()
+            }
+            // the keys are deleted once moved
+            Command::Migrate(migrate) if !migrate.copy => {
+                migrate.keys.iter().map(Vec::as_slice).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Whether the command may modify the dataset.
+    pub fn is_write(&self) -> bool {
+        !self.written_keys().is_empty()
+            || matches!(
+                self,
+                Command::FlushDb
+                    | Command::FlushAll
+                    | Command::SwapDb(..)
+                    | Command::FunctionLoad { .. }
+                    | Command::FunctionDelete(_)
+                    | Command::FunctionRestore(..)
+                    | Command::FunctionFlush
+            )
+    }
+}
+
+impl Command {
+    /// Parses a command given as plain arguments, e.g. by a script.
+    pub fn from_args(args: &[Vec<u8>]) -> Result<Command, &'static str> {
+        let resp = args
+            .iter()
+            .map(|arg| RespType::String(Cow::Borrowed(arg.as_slice()), StrType::Bulk))
+            .collect::<Vec<_>>();
+        Command::try_from(resp)
+    }
 }
 
 impl<'a> TryFrom<Vec<RespType<'a>>> for Command {
@@ -21,40 +324,35 @@ impl<'a> TryFrom<Vec<RespType<'a>>> for Command {
         let cmd = iter.next();
 
         if let Some(RespType::String(a, _)) = cmd {
-            match a.as_ref() {
+            match text(&a).to_lowercase().as_str() {
                 "config" => {
                     let params = (iter.next(), iter.next());
                     match params {
-                        (
-                            Some(RespType::String(Cow::Borrowed("get"), _)),
-                            Some(RespType::String(key, _)),
-                        ) => {
-                            return Ok(Command::ConfigGet(key.to_string()));
+                        (Some(RespType::String(sub, _)), Some(RespType::String(key, _)))
+                            if sub.as_ref() == b"get" =>
+                        {
+                            Ok(Command::ConfigGet(text(&key)))
                         }
-                        _ => return Err("Invalid config command"),
+                        _ => Err("Invalid config command"),
                     }
                 }
-                "ping" => return Ok(Command::Ping),
+                "ping" => Ok(Command::Ping),
                 "keys" => {
                     let _ = iter.next();
-                    return Ok(Command::Keys());
+                    Ok(Command::Keys())
                 }
                 "echo" => {
                     let params = iter.next();
                     match params {
-                        Some(RespType::String(key, _)) => {
-                            return Ok(Command::Echo(key.to_string()));
-                        }
-                        _ => return Err("Invalid echo command format"),
+                        Some(RespType::String(key, _)) => Ok(Command::Echo(text(&key))),
+                        _ => Err("Invalid echo command format"),
                     }
                 }
                 "get" => {
                     let params = iter.next();
                     match params {
-                        Some(RespType::String(key, _)) => {
-                            return Ok(Command::Get(key.to_string()));
-                        }
-                        _ => return Err("Invalid get command format"),
+                        Some(RespType::String(key, _)) => Ok(Command::Get(key.into_owned())),
+                        _ => Err("Invalid get command format"),
                     }
                 }
                 "set" => {
@@ -65,40 +363,1555 @@ impl<'a> TryFrom<Vec<RespType<'a>>> for Command {
                             Some(RespType::String(val, _)),
                             None,
                             None,
-                        ) => {
-                            return Ok(Command::Set(key.to_string(), val.to_string(), None));
-                        }
+                        ) => Ok(Command::Set(key.into_owned(), val.into_owned(), None)),
                         (
                             Some(RespType::String(key, _)),
                             Some(RespType::String(val, _)),
-                            Some(RespType::String(Cow::Borrowed("px"), _)),
+                            Some(RespType::String(px, _)),
                             Some(RespType::String(i, _)),
-                        ) => {
-                            let px = u64::from_str_radix(i.as_ref(), 10).unwrap_or(0);
-                            return Ok(Command::Set(key.to_string(), val.to_string(), Some(px)));
+                        ) if px.eq_ignore_ascii_case(b"px") => {
+                            let px = text(&i).parse::<u64>().unwrap_or(0);
+                            Ok(Command::Set(key.into_owned(), val.into_owned(), Some(px)))
+                        }
+                        // how SET PX is written to the AOF: a Unix time in ms,
+                        // turned back into a TTL
+                        (
+                            Some(RespType::String(key, _)),
+                            Some(RespType::String(val, _)),
+                            Some(RespType::String(pxat, _)),
+                            Some(RespType::String(at, _)),
+                        ) if pxat.eq_ignore_ascii_case(b"pxat") => {
+                            let at = text(&at).parse::<u64>().unwrap_or(0);
+                            let px = at.saturating_sub(crate::rdb::unix_time_ms());
+                            Ok(Command::Set(key.into_owned(), val.into_owned(), Some(px)))
+                        }
+                        _ => Err("Invalid set command format"),
+                    }
+                }
+                "type" => {
+                    let params = iter.next();
+                    match params {
+                        Some(RespType::String(key, _)) => Ok(Command::Type(key.into_owned())),
+                        _ => Err("Invalid type command format"),
+                    }
+                }
+                "xadd" => parse_xadd(collect_raw_args(iter)?),
+                "xrange" => parse_xrange(collect_raw_args(iter)?, false),
+                "xrevrange" => parse_xrange(collect_raw_args(iter)?, true),
+                "xlen" => {
+                    let params = iter.next();
+                    match params {
+                        Some(RespType::String(key, _)) => Ok(Command::XLen(key.into_owned())),
+                        _ => Err("wrong number of arguments for 'xlen' command"),
+                    }
+                }
+                "xtrim" => parse_xtrim(collect_raw_args(iter)?),
+                "xdel" => parse_xdel(collect_raw_args(iter)?),
+                "xread" => parse_xread(collect_raw_args(iter)?, false),
+                "xreadgroup" => parse_xread(collect_raw_args(iter)?, true),
+                "xgroup" => parse_xgroup(collect_raw_args(iter)?),
+                "xack" => parse_xack(collect_raw_args(iter)?),
+                "xpending" => parse_xpending(collect_raw_args(iter)?),
+                "xclaim" => parse_xclaim(collect_raw_args(iter)?),
+                "xautoclaim" => parse_xautoclaim(collect_raw_args(iter)?),
+                "xinfo" => parse_xinfo(collect_raw_args(iter)?),
+                "setbit" => parse_setbit(collect_raw_args(iter)?),
+                "getbit" => parse_getbit(collect_raw_args(iter)?),
+                "bitcount" => parse_bitcount(collect_raw_args(iter)?),
+                "bitpos" => parse_bitpos(collect_raw_args(iter)?),
+                "bitop" => parse_bitop(collect_raw_args(iter)?),
+                "bitfield" => parse_bitfield(collect_raw_args(iter)?, false),
+                "bitfield_ro" => parse_bitfield(collect_raw_args(iter)?, true),
+                "geoadd" => parse_geoadd(collect_raw_args(iter)?),
+                "geodist" => parse_geodist(collect_raw_args(iter)?),
+                "geopos" | "geohash" => {
+                    let mut args = collect_raw_args(iter)?;
+                    if args.is_empty() {
+                        return Err(if a.eq_ignore_ascii_case(b"geopos") {
+                            "wrong number of arguments for 'geopos' command"
+                        } else {
+                            "wrong number of arguments for 'geohash' command"
+                        });
+                    }
+                    let key = args.remove(0);
+                    if a.eq_ignore_ascii_case(b"geopos") {
+                        Ok(Command::GeoPos(key, args))
+                    } else {
+                        Ok(Command::GeoHash(key, args))
+                    }
+                }
+                "geosearch" => {
+                    let args = collect_raw_args(iter)?;
+                    if args.len() < 2 {
+                        return Err("wrong number of arguments for 'geosearch' command");
+                    }
+                    let (query, _) = parse_geosearch(&args[1..], false)?;
+                    Ok(Command::GeoSearch(args[0].clone(), query))
+                }
+                "geosearchstore" => {
+                    let args = collect_raw_args(iter)?;
+                    if args.len() < 3 {
+                        return Err("wrong number of arguments for 'geosearchstore' command");
+                    }
+                    let (query, store_dist) = parse_geosearch(&args[2..], true)?;
+                    Ok(Command::GeoSearchStore {
+                        dest: args[0].clone(),
+                        key: args[1].clone(),
+                        query,
+                        store_dist,
+                    })
+                }
+                "pfadd" => {
+                    let mut args = collect_raw_args(iter)?.into_iter();
+                    match args.next() {
+                        Some(key) => Ok(Command::PfAdd(key, args.collect())),
+                        None => Err("wrong number of arguments for 'pfadd' command"),
+                    }
+                }
+                "pfcount" => {
+                    let keys = collect_raw_args(iter)?;
+                    if keys.is_empty() {
+                        return Err("wrong number of arguments for 'pfcount' command");
+                    }
+                    Ok(Command::PfCount(keys))
+                }
+                "pfmerge" => {
+                    let mut keys = collect_raw_args(iter)?;
+                    if keys.is_empty() {
+                        return Err("wrong number of arguments for 'pfmerge' command");
+                    }
+                    let dest = keys.remove(0);
+                    Ok(Command::PfMerge(dest, keys))
+                }
+                "subscribe" => {
+                    let channels = collect_args(iter)?;
+                    if channels.is_empty() {
+                        return Err("wrong number of arguments for 'subscribe' command");
+                    }
+                    Ok(Command::Subscribe(Kind::Channel, channels))
+                }
+                "psubscribe" => {
+                    let patterns = collect_args(iter)?;
+                    if patterns.is_empty() {
+                        return Err("wrong number of arguments for 'psubscribe' command");
+                    }
+                    Ok(Command::Subscribe(Kind::Pattern, patterns))
+                }
+                "ssubscribe" => {
+                    let channels = collect_args(iter)?;
+                    if channels.is_empty() {
+                        return Err("wrong number of arguments for 'ssubscribe' command");
+                    }
+                    Ok(Command::Subscribe(Kind::Shard, channels))
+                }
+                "unsubscribe" => Ok(Command::Unsubscribe(Kind::Channel, collect_args(iter)?)),
+                "punsubscribe" => Ok(Command::Unsubscribe(Kind::Pattern, collect_args(iter)?)),
+                "publish" => {
+                    let args = collect_raw_args(iter)?;
+                    match <[Vec<u8>; 2]>::try_from(args) {
+                        Ok([channel, message]) => Ok(Command::Publish(text(&channel), message)),
+                        Err(_) => Err("wrong number of arguments for 'publish' command"),
+                    }
+                }
+                "sunsubscribe" => Ok(Command::Unsubscribe(Kind::Shard, collect_args(iter)?)),
+                "spublish" => {
+                    let args = collect_raw_args(iter)?;
+                    match <[Vec<u8>; 2]>::try_from(args) {
+                        Ok([channel, message]) => Ok(Command::SPublish(text(&channel), message)),
+                        Err(_) => Err("wrong number of arguments for 'spublish' command"),
+                    }
+                }
+                "pubsub" => parse_pubsub(collect_args(iter)?),
+                "hello" => {
+                    let args = collect_args(iter)?;
+                    match args.as_slice() {
+                        [] => Ok(Command::Hello(None)),
+                        [version] => version
+                            .parse()
+                            .map(|v| Command::Hello(Some(v)))
+                            .map_err(|_| "Protocol version is not an integer or out of range"),
+                        _ => Err(SYNTAX_ERR),
+                    }
+                }
+                "multi" | "exec" | "discard" => {
+                    if iter.next().is_some() {
+                        return Err("wrong number of arguments for transaction command");
+                    }
+                    Ok(match text(&a).to_lowercase().as_str() {
+                        "multi" => Command::Multi,
+                        "exec" => Command::Exec,
+                        _ => Command::Discard,
+                    })
+                }
+                "watch" => {
+                    let keys = collect_raw_args(iter)?;
+                    if keys.is_empty() {
+                        return Err("wrong number of arguments for 'watch' command");
+                    }
+                    Ok(Command::Watch(keys))
+                }
+                "unwatch" => match iter.next() {
+                    None => Ok(Command::Unwatch),
+                    Some(_) => Err("wrong number of arguments for 'unwatch' command"),
+                },
+                "del" => {
+                    let keys = collect_raw_args(iter)?;
+                    if keys.is_empty() {
+                        return Err("wrong number of arguments for 'del' command");
+                    }
+                    Ok(Command::Del(keys))
+                }
+                "flushdb" | "flushall" => {
+                    let args = collect_args(iter)?;
+                    let flush = if a.eq_ignore_ascii_case(b"flushdb") {
+                        Command::FlushDb
+                    } else {
+                        Command::FlushAll
+                    };
+                    match args.as_slice() {
+                        [] => Ok(flush),
+                        [mode]
+                            if mode.eq_ignore_ascii_case("sync")
+                                || mode.eq_ignore_ascii_case("async") =>
+                        {
+                            Ok(flush)
                         }
-                        _ => return Err("Invalid set command format"),
+                        _ => Err(SYNTAX_ERR),
+                    }
+                }
+                "save" | "lastsave" => {
+                    if iter.next().is_some() {
+                        return Err(SYNTAX_ERR);
+                    }
+                    Ok(if a.eq_ignore_ascii_case(b"save") {
+                        Command::Save
+                    } else {
+                        Command::LastSave
+                    })
+                }
+                "shutdown" => match collect_args(iter)?.as_slice() {
+                    [] => Ok(Command::Shutdown(None)),
+                    [save] if save.eq_ignore_ascii_case("save") => {
+                        Ok(Command::Shutdown(Some(true)))
+                    }
+                    [save] if save.eq_ignore_ascii_case("nosave") => {
+                        Ok(Command::Shutdown(Some(false)))
                     }
+                    _ => Err(SYNTAX_ERR),
+                },
+                // SCHEDULE only matters while another kind of save is running
+                "bgsave" => match collect_args(iter)?.as_slice() {
+                    [] => Ok(Command::BgSave),
+                    [schedule] if schedule.eq_ignore_ascii_case("schedule") => Ok(Command::BgSave),
+                    _ => Err(SYNTAX_ERR),
+                },
+                "bgrewriteaof" => match iter.next() {
+                    None => Ok(Command::BgRewriteAof),
+                    Some(_) => Err("wrong number of arguments for 'bgrewriteaof' command"),
+                },
+                "select" => match collect_raw_args(iter)?.as_slice() {
+                    [index] => Ok(Command::Select(parse_int(index)?)),
+                    _ => Err("wrong number of arguments for 'select' command"),
+                },
+                "move" => match collect_raw_args(iter)?.as_slice() {
+                    [key, db] => Ok(Command::Move(key.clone(), parse_int(db)?)),
+                    _ => Err("wrong number of arguments for 'move' command"),
+                },
+                "swapdb" => match collect_args(iter)?.as_slice() {
+                    [first, second] => Ok(Command::SwapDb(
+                        first.parse().map_err(|_| "invalid first DB index")?,
+                        second.parse().map_err(|_| "invalid second DB index")?,
+                    )),
+                    _ => Err("wrong number of arguments for 'swapdb' command"),
+                },
+                "eval" | "evalsha" => {
+                    let mut args = collect_raw_args(iter)?.into_iter();
+                    let (Some(script), Some(numkeys)) = (args.next(), args.next()) else {
+                        return Err(if a.eq_ignore_ascii_case(b"eval") {
+                            "wrong number of arguments for 'eval' command"
+                        } else {
+                            "wrong number of arguments for 'evalsha' command"
+                        });
+                    };
+                    let script = if a.eq_ignore_ascii_case(b"eval") {
+                        Script::Source(script)
+                    } else {
+                        Script::Sha(text(&script))
+                    };
+                    let (keys, args) = split_keys(&numkeys, args.collect())?;
+                    Ok(Command::Eval { script, keys, args })
+                }
+                "fcall" | "fcall_ro" => {
+                    let mut args = collect_raw_args(iter)?.into_iter();
+                    let (Some(function), Some(numkeys)) = (args.next(), args.next()) else {
+                        return Err(if a.eq_ignore_ascii_case(b"fcall") {
+                            "wrong number of arguments for 'fcall' command"
+                        } else {
+                            "wrong number of arguments for 'fcall_ro' command"
+                        });
+                    };
+                    let (keys, args) = split_keys(&numkeys, args.collect())?;
+                    Ok(Command::FCall {
+                        function: text(&function),
+                        keys,
+                        args,
+                        read_only: a.eq_ignore_ascii_case(b"fcall_ro"),
+                    })
+                }
+                "dump" => match collect_raw_args(iter)?.as_slice() {
+                    [key] => Ok(Command::Dump(key.clone())),
+                    _ => Err("wrong number of arguments for 'dump' command"),
+                },
+                "restore" | "restore-asking" => parse_restore(collect_raw_args(iter)?),
+                "replconf" => {
+                    let args = collect_args(iter)?;
+                    if args.is_empty() {
+                        return Err("wrong number of arguments for 'replconf' command");
+                    }
+                    if args.len() % 2 != 0 {
+                        return Err(SYNTAX_ERR);
+                    }
+                    Ok(Command::ReplConf(
+                        args.chunks(2)
+                            .map(|pair| (pair[0].clone(), pair[1].clone()))
+                            .collect(),
+                    ))
+                }
+                "psync" => match collect_args(iter)?.as_slice() {
+                    [replid, offset] => Ok(Command::Psync(
+                        replid.clone(),
+                        parse_int(offset.as_bytes())?,
+                    )),
+                    _ => Err("wrong number of arguments for 'psync' command"),
+                },
+                "replicaof" | "slaveof" => match collect_args(iter)?.as_slice() {
+                    [no, one]
+                        if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") =>
+                    {
+                        Ok(Command::ReplicaOf(None))
+                    }
+                    [host, port] => Ok(Command::ReplicaOf(Some((
+                        host.clone(),
+                        parse_int(port.as_bytes()).map_err(|_| "Invalid master port")?,
+                    )))),
+                    _ => Err("wrong number of arguments for 'replicaof' command"),
+                },
+                "info" => Ok(Command::Info(
+                    collect_args(iter)?
+                        .iter()
+                        .map(|section| section.to_lowercase())
+                        .collect(),
+                )),
+                "wait" => match collect_raw_args(iter)?.as_slice() {
+                    [numreplicas, timeout] => Ok(Command::Wait(
+                        parse_int(numreplicas)?,
+                        parse_timeout(timeout)?,
+                    )),
+                    _ => Err("wrong number of arguments for 'wait' command"),
+                },
+                "waitaof" => match collect_raw_args(iter)?.as_slice() {
+                    [numlocal, numreplicas, timeout] => Ok(Command::WaitAof(
+                        parse_int(numlocal)?,
+                        parse_int(numreplicas)?,
+                        parse_timeout(timeout)?,
+                    )),
+                    _ => Err("wrong number of arguments for 'waitaof' command"),
+                },
+                "migrate" => parse_migrate(collect_raw_args(iter)?),
+                "function" => parse_function(collect_raw_args(iter)?),
+                "script" => parse_script(collect_raw_args(iter)?),
+                "cluster" => parse_cluster(collect_raw_args(iter)?).map(Command::Cluster),
+                "asking" => match collect_args(iter)?.as_slice() {
+                    [] => Ok(Command::Asking),
+                    _ => Err("wrong number of arguments for 'asking' command"),
+                },
+                _ => Err("Unrecognized command"),
+            }
+        } else {
+            Err("Invalid command")
+        }
+    }
+}
+
+const SYNTAX_ERR: &str = "syntax error";
+const NOT_AN_INTEGER: &str = "value is not an integer or out of range";
+const INVALID_STREAM_ID: &str = "Invalid stream ID specified as stream command argument";
+
+/// Arguments are binary-safe; commands that take them as text see invalid
+/// UTF-8 replaced.
+fn text(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+fn collect_args<'a>(iter: impl Iterator<Item = RespType<'a>>) -> Result<Vec<String>, &'static str> {
+    iter.map(|t| match t {
+        RespType::String(s, _) => Ok(text(&s)),
+        RespType::Integer(i) => Ok(i.to_string()),
+        RespType::Array(_) => Err("Invalid command"),
+    })
+    .collect()
+}
+
+/// Like `collect_args`, for commands whose arguments are binary data.
+fn collect_raw_args<'a>(
+    iter: impl Iterator<Item = RespType<'a>>,
+) -> Result<Vec<Vec<u8>>, &'static str> {
+    iter.map(|t| match t {
+        RespType::String(s, _) => Ok(s.into_owned()),
+        RespType::Integer(i) => Ok(i.to_string().into_bytes()),
+        RespType::Array(_) => Err("Invalid command"),
+    })
+    .collect()
+}
+
+/// A timeout in ms, as given to WAIT.
+fn parse_timeout(s: &[u8]) -> Result<u64, &'static str> {
+    let ms: i64 = parse_int(s).map_err(|_| "timeout is not an integer or out of range")?;
+    u64::try_from(ms).map_err(|_| "timeout is negative")
+}
+
+fn parse_int<T: std::str::FromStr>(s: &[u8]) -> Result<T, &'static str> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(NOT_AN_INTEGER)
+}
+
+fn parse_stream_id(s: &[u8], missing_seq: u64) -> Result<StreamId, &'static str> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| StreamId::parse(s, missing_seq))
+        .ok_or(INVALID_STREAM_ID)
+}
+
+/// Parses the MAXLEN/MINID/LIMIT options shared by XADD and XTRIM, starting
+/// at `args[*i]`. Stops at the first argument that is not an option.
+fn parse_trim_options(
+    args: &[Vec<u8>],
+    i: &mut usize,
+    nomkstream: Option<&mut bool>,
+) -> Result<Option<StreamTrim>, &'static str> {
+    let mut strategy = None;
+    let mut approx = false;
+    let mut limit = None;
+    let mut nomkstream = nomkstream;
+    while *i < args.len() {
+        let opt = text(&args[*i]).to_lowercase();
+        let has_next = *i + 1 < args.len();
+        match opt.as_str() {
+            "nomkstream" if nomkstream.is_some() => {
+                if let Some(flag) = nomkstream.as_mut() {
+                    **flag = true;
+                }
+            }
+            "maxlen" | "minid" if has_next => {
+                if strategy.is_some() {
+                    return Err("syntax error, MAXLEN and MINID options at the same time are not compatible");
+                }
+                *i += 1;
+                if args[*i] == b"~" || args[*i] == b"=" {
+                    approx = args[*i] == b"~";
+                    *i += 1;
+                }
+                let threshold = args.get(*i).ok_or(SYNTAX_ERR)?;
+                strategy = Some(if opt == "maxlen" {
+                    let maxlen: i64 = parse_int(threshold)?;
+                    if maxlen < 0 {
+                        return Err("The MAXLEN argument must be >= 0.");
+                    }
+                    TrimStrategy::MaxLen(maxlen as u64)
+                } else {
+                    TrimStrategy::MinId(parse_stream_id(threshold, 0)?)
+                });
+            }
+            "limit" if has_next => {
+                *i += 1;
+                let l: i64 = parse_int(&args[*i])?;
+                if l < 0 {
+                    return Err("The LIMIT argument must be >= 0.");
+                }
+                limit = Some(l as u64);
+            }
+            _ => break,
+        }
+        *i += 1;
+    }
+    if limit.is_some() && !approx {
+        return Err("syntax error, LIMIT cannot be used without the special ~ option");
+    }
+    Ok(strategy.map(|strategy| StreamTrim {
+        strategy,
+        approx,
+        limit,
+    }))
+}
+
+/// Where the ID is in XADD's arguments (the key being the first), so that
+/// an auto-generated one can be replaced with the ID it got.
+pub fn xadd_id_position(args: &[Vec<u8>]) -> Option<usize> {
+    let mut i = 1;
+    parse_trim_options(args, &mut i, Some(&mut false)).ok()?;
+    Some(i)
+}
+
+fn parse_xadd(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let key = args
+        .first()
+        .ok_or("wrong number of arguments for 'xadd' command")?
+        .clone();
+    let mut i = 1;
+    let mut nomkstream = false;
+    let trim = parse_trim_options(&args, &mut i, Some(&mut nomkstream))?;
+
+    let id = args
+        .get(i)
+        .ok_or("wrong number of arguments for 'xadd' command")?;
+    let id = if id == b"*" {
+        XAddId::Auto
+    } else if let Some(ms) = id.strip_suffix(b"-*") {
+        XAddId::AutoSeq(parse_int(ms).map_err(|_| INVALID_STREAM_ID)?)
+    } else {
+        let id = parse_stream_id(id, 0)?;
+        if id == StreamId::MIN {
+            return Err("The ID specified in XADD must be greater than 0-0");
+        }
+        XAddId::Explicit(id)
+    };
+
+    let rest = &args[i + 1..];
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err("wrong number of arguments for 'xadd' command");
+    }
+    let fields = rest
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::XAdd {
+        key,
+        id,
+        fields,
+        nomkstream,
+        trim,
+    })
+}
+
+fn parse_range_bound(s: &[u8], start: bool) -> Result<StreamId, &'static str> {
+    match s {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        _ => match s.strip_prefix(b"(") {
+            Some(id) if start => parse_stream_id(id, 0)?
+                .incr()
+                .ok_or("invalid start ID for the interval"),
+            Some(id) => parse_stream_id(id, u64::MAX)?
+                .decr()
+                .ok_or("invalid end ID for the interval"),
+            None => parse_stream_id(s, if start { 0 } else { u64::MAX }),
+        },
+    }
+}
+
+fn parse_xrange(args: Vec<Vec<u8>>, rev: bool) -> Result<Command, &'static str> {
+    if args.len() < 3 {
+        return Err(if rev {
+            "wrong number of arguments for 'xrevrange' command"
+        } else {
+            "wrong number of arguments for 'xrange' command"
+        });
+    }
+    let (start, end) = if rev {
+        (&args[2], &args[1])
+    } else {
+        (&args[1], &args[2])
+    };
+    let start = parse_range_bound(start, true)?;
+    let end = parse_range_bound(end, false)?;
+    let count = match &args[3..] {
+        [] => None,
+        [opt, n] if opt.eq_ignore_ascii_case(b"count") => {
+            let n: i64 = parse_int(n)?;
+            Some(n.max(0) as usize)
+        }
+        _ => return Err(SYNTAX_ERR),
+    };
+    Ok(Command::XRange {
+        key: args[0].clone(),
+        start,
+        end,
+        count,
+        rev,
+    })
+}
+
+fn parse_xtrim(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let key = args
+        .first()
+        .ok_or("wrong number of arguments for 'xtrim' command")?
+        .clone();
+    let mut i = 1;
+    let trim = parse_trim_options(&args, &mut i, None)?;
+    if i < args.len() {
+        return Err(SYNTAX_ERR);
+    }
+    let trim = trim.ok_or("syntax error, XTRIM must be called with a trimming strategy")?;
+    Ok(Command::XTrim(key, trim))
+}
+
+fn parse_xdel(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 2 {
+        return Err("wrong number of arguments for 'xdel' command");
+    }
+    let ids = args[1..]
+        .iter()
+        .map(|id| parse_stream_id(id, 0))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::XDel(args[0].clone(), ids))
+}
+
+/// Parses XREAD, or XREADGROUP when `group` is set.
+fn parse_xread(args: Vec<Vec<u8>>, group: bool) -> Result<Command, &'static str> {
+    let mut count = None;
+    let mut block = None;
+    let mut noack = false;
+    let mut streams = false;
+    let mut i = 0;
+    let mut group_consumer = None;
+    if group {
+        match &args[..] {
+            [opt, g, c, ..] if opt.eq_ignore_ascii_case(b"group") => {
+                group_consumer = Some((text(g), text(c)));
+                i = 3;
+            }
+            _ => return Err("Missing GROUP option for XREADGROUP"),
+        }
+    }
+    while i < args.len() && !streams {
+        let opt = text(&args[i]).to_lowercase();
+        match opt.as_str() {
+            "count" if i + 1 < args.len() => {
+                let n: i64 = parse_int(&args[i + 1])?;
+                count = if n > 0 { Some(n as usize) } else { None };
+                i += 2;
+            }
+            "block" if i + 1 < args.len() => {
+                let ms: i64 = parse_int(&args[i + 1])
+                    .map_err(|_| "timeout is not an integer or out of range")?;
+                if ms < 0 {
+                    return Err("timeout is negative");
+                }
+                block = Some(ms as u64);
+                i += 2;
+            }
+            "noack" if group => {
+                noack = true;
+                i += 1;
+            }
+            "streams" => {
+                streams = true;
+                i += 1;
+            }
+            _ => return Err(SYNTAX_ERR),
+        }
+    }
+    if !streams {
+        return Err(SYNTAX_ERR);
+    }
+    let rest = &args[i..];
+    if rest.is_empty() || rest.len() % 2 == 1 {
+        return Err(if group {
+            "Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified."
+        } else {
+            "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+        });
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    let ids = ids
+        .iter()
+        .map(|id| match id.as_slice() {
+            b"$" if group => Err("The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set."),
+            b"$" => Ok(XReadId::Last),
+            b">" if group => Ok(XReadId::New),
+            b">" => Err("The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option."),
+            _ => parse_stream_id(id, 0).map(XReadId::After),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(match group_consumer {
+        Some((group, consumer)) => Command::XReadGroup {
+            group,
+            consumer,
+            count,
+            block,
+            noack,
+            keys: keys.to_vec(),
+            ids,
+        },
+        None => Command::XRead {
+            count,
+            block,
+            keys: keys.to_vec(),
+            ids,
+        },
+    })
+}
+
+/// Parses the `[ENTRIESREAD n]` option of XGROUP CREATE and SETID.
+fn parse_entries_read(args: &[Vec<u8>]) -> Result<Option<u64>, &'static str> {
+    match args {
+        [] => Ok(None),
+        [opt, n] if opt.eq_ignore_ascii_case(b"entriesread") => {
+            let n: i64 = parse_int(n)?;
+            if n < -1 {
+                return Err("value for ENTRIESREAD must be positive or -1");
+            }
+            Ok(if n == -1 { None } else { Some(n as u64) })
+        }
+        _ => Err(SYNTAX_ERR),
+    }
+}
+
+fn parse_group_id(id: &[u8]) -> Result<XReadId, &'static str> {
+    match id {
+        b"$" => Ok(XReadId::Last),
+        _ => parse_stream_id(id, 0).map(XReadId::After),
+    }
+}
+
+fn parse_xgroup(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let subcommand = args
+        .first()
+        .map(|s| text(s).to_lowercase())
+        .unwrap_or_default();
+    match (subcommand.as_str(), &args[..]) {
+        ("create", [_, key, group, id, rest @ ..]) => {
+            let mut rest = rest;
+            let mut mkstream = false;
+            if let Some(opt) = rest.first() {
+                if opt.eq_ignore_ascii_case(b"mkstream") {
+                    mkstream = true;
+                    rest = &rest[1..];
+                }
+            }
+            Ok(Command::XGroupCreate {
+                key: key.clone(),
+                group: text(group),
+                id: parse_group_id(id)?,
+                mkstream,
+                entries_read: parse_entries_read(rest)?,
+            })
+        }
+        ("setid", [_, key, group, id, rest @ ..]) => Ok(Command::XGroupSetId {
+            key: key.clone(),
+            group: text(group),
+            id: parse_group_id(id)?,
+            entries_read: parse_entries_read(rest)?,
+        }),
+        ("destroy", [_, key, group]) => Ok(Command::XGroupDestroy(key.clone(), text(group))),
+        ("createconsumer", [_, key, group, consumer]) => Ok(Command::XGroupCreateConsumer(
+            key.clone(),
+            text(group),
+            text(consumer),
+        )),
+        ("delconsumer", [_, key, group, consumer]) => Ok(Command::XGroupDelConsumer(
+            key.clone(),
+            text(group),
+            text(consumer),
+        )),
+        ("create" | "setid" | "destroy" | "createconsumer" | "delconsumer", _) => {
+            Err("wrong number of arguments for 'xgroup' command")
+        }
+        _ => Err("Unknown XGROUP subcommand"),
+    }
+}
+
+fn parse_xack(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 3 {
+        return Err("wrong number of arguments for 'xack' command");
+    }
+    let ids = args[2..]
+        .iter()
+        .map(|id| parse_stream_id(id, 0))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::XAck(args[0].clone(), text(&args[1]), ids))
+}
+
+fn parse_xpending(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let (key, group, rest) = match &args[..] {
+        [key, group, rest @ ..] => (key.clone(), text(group), rest),
+        _ => return Err("wrong number of arguments for 'xpending' command"),
+    };
+    if rest.is_empty() {
+        return Ok(Command::XPending(key, group, None));
+    }
+    let (min_idle, rest) = match rest {
+        [opt, idle, rest @ ..] if opt.eq_ignore_ascii_case(b"idle") => {
+            let idle: i64 = parse_int(idle)?;
+            (Some(idle.max(0) as u64), rest)
+        }
+        _ => (None, rest),
+    };
+    let (start, end, count, consumer) = match rest {
+        [start, end, count] => (start, end, count, None),
+        [start, end, count, consumer] => (start, end, count, Some(text(consumer))),
+        _ => return Err(SYNTAX_ERR),
+    };
+    let count: i64 = parse_int(count)?;
+    Ok(Command::XPending(
+        key,
+        group,
+        Some(PendingRange {
+            min_idle,
+            start: parse_range_bound(start, true)?,
+            end: parse_range_bound(end, false)?,
+            count: count.max(0) as usize,
+            consumer,
+        }),
+    ))
+}
+
+fn parse_min_idle(s: &[u8], command: &'static str) -> Result<u64, &'static str> {
+    let min_idle: i64 = parse_int(s).map_err(|_| command)?;
+    Ok(min_idle.max(0) as u64)
+}
+
+fn parse_xclaim(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 5 {
+        return Err("wrong number of arguments for 'xclaim' command");
+    }
+    let min_idle = parse_min_idle(&args[3], "Invalid min-idle-time argument for XCLAIM")?;
+    let mut i = 4;
+    let mut ids = Vec::new();
+    while let Some(id) = args.get(i).and_then(|id| parse_stream_id(id, 0).ok()) {
+        ids.push(id);
+        i += 1;
+    }
+    let mut options = ClaimOptions::default();
+    while i < args.len() {
+        let opt = text(&args[i]).to_lowercase();
+        let next = args.get(i + 1);
+        match (opt.as_str(), next) {
+            ("force", _) => options.force = true,
+            ("justid", _) => options.just_id = true,
+            ("idle", Some(ms)) => {
+                let ms: i64 = parse_int(ms)?;
+                options.idle = Some(ms.max(0) as u64);
+                i += 1;
+            }
+            ("time", Some(ms)) => {
+                let ms: i64 = parse_int(ms)?;
+                options.time = Some(ms.max(0) as u64);
+                i += 1;
+            }
+            ("retrycount", Some(n)) => {
+                let n: i64 = parse_int(n)?;
+                options.retry_count = Some(n.max(0) as u64);
+                i += 1;
+            }
+            ("lastid", Some(id)) => {
+                options.last_id = Some(parse_stream_id(id, 0)?);
+                i += 1;
+            }
+            _ => return Err("Unrecognized XCLAIM option"),
+        }
+        i += 1;
+    }
+    Ok(Command::XClaim {
+        key: args[0].clone(),
+        group: text(&args[1]),
+        consumer: text(&args[2]),
+        min_idle,
+        ids,
+        options,
+    })
+}
+
+fn parse_xautoclaim(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 5 {
+        return Err("wrong number of arguments for 'xautoclaim' command");
+    }
+    let min_idle = parse_min_idle(&args[3], "Invalid min-idle-time argument for XAUTOCLAIM")?;
+    let start = parse_range_bound(&args[4], true)?;
+    let mut count = 100;
+    let mut just_id = false;
+    let mut i = 5;
+    while i < args.len() {
+        let opt = text(&args[i]).to_lowercase();
+        match (opt.as_str(), args.get(i + 1)) {
+            ("justid", _) => just_id = true,
+            ("count", Some(n)) => {
+                let n: i64 = parse_int(n)?;
+                if !(1..=i64::MAX / 10).contains(&n) {
+                    return Err("COUNT must be > 0");
                 }
-                _ => return Err("Unrecognized command"),
+                count = n as usize;
+                i += 1;
             }
+            _ => return Err(SYNTAX_ERR),
+        }
+        i += 1;
+    }
+    Ok(Command::XAutoClaim {
+        key: args[0].clone(),
+        group: text(&args[1]),
+        consumer: text(&args[2]),
+        min_idle,
+        start,
+        count,
+        just_id,
+    })
+}
+
+fn parse_xinfo(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let subcommand = args
+        .first()
+        .map(|s| text(s).to_lowercase())
+        .unwrap_or_default();
+    match (subcommand.as_str(), &args[..]) {
+        ("stream", [_, key]) => Ok(Command::XInfoStream(key.clone(), None)),
+        ("stream", [_, key, full, rest @ ..]) if full.eq_ignore_ascii_case(b"full") => {
+            let count = match rest {
+                [] => 10,
+                [opt, n] if opt.eq_ignore_ascii_case(b"count") => {
+                    let n: i64 = parse_int(n)?;
+                    n.max(0) as usize
+                }
+                _ => return Err(SYNTAX_ERR),
+            };
+            Ok(Command::XInfoStream(key.clone(), Some(count)))
+        }
+        ("groups", [_, key]) => Ok(Command::XInfoGroups(key.clone())),
+        ("consumers", [_, key, group]) => Ok(Command::XInfoConsumers(key.clone(), text(group))),
+        ("stream" | "groups" | "consumers", _) => {
+            Err("wrong number of arguments for 'xinfo' command")
+        }
+        _ => Err("Unknown XINFO subcommand"),
+    }
+}
+
+const BIT_OFFSET_ERR: &str = "bit offset is not an integer or out of range";
+
+fn parse_bit_offset(s: &[u8]) -> Result<u64, &'static str> {
+    parse_int::<u64>(s)
+        .ok()
+        .filter(|offset| *offset < MAX_BIT_OFFSET)
+        .ok_or(BIT_OFFSET_ERR)
+}
+
+fn parse_setbit(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() != 3 {
+        return Err("wrong number of arguments for 'setbit' command");
+    }
+    let offset = parse_bit_offset(&args[1])?;
+    let value = match args[2].as_slice() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Err("bit is not an integer or out of range"),
+    };
+    Ok(Command::SetBit(args[0].clone(), offset, value))
+}
+
+fn parse_getbit(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() != 2 {
+        return Err("wrong number of arguments for 'getbit' command");
+    }
+    Ok(Command::GetBit(
+        args[0].clone(),
+        parse_bit_offset(&args[1])?,
+    ))
+}
+
+/// Parses the optional BYTE|BIT argument of BITCOUNT and BITPOS.
+fn parse_bit_unit(arg: Option<&Vec<u8>>) -> Result<bool, &'static str> {
+    match arg.map(|s| text(s).to_lowercase()).as_deref() {
+        None | Some("byte") => Ok(false),
+        Some("bit") => Ok(true),
+        _ => Err(SYNTAX_ERR),
+    }
+}
+
+fn parse_bitcount(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let range = match args.len() {
+        0 => return Err("wrong number of arguments for 'bitcount' command"),
+        1 => None,
+        3 | 4 => Some(BitRange {
+            start: parse_int(&args[1])?,
+            end: Some(parse_int(&args[2])?),
+            bit: parse_bit_unit(args.get(3))?,
+        }),
+        _ => return Err(SYNTAX_ERR),
+    };
+    Ok(Command::BitCount(args[0].clone(), range))
+}
+
+fn parse_bitpos(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 2 {
+        return Err("wrong number of arguments for 'bitpos' command");
+    }
+    let bit = match parse_int::<i64>(&args[1])? {
+        0 => false,
+        1 => true,
+        _ => return Err("The bit argument must be 1 or 0."),
+    };
+    let range = match args.len() {
+        2 => None,
+        3..=5 => Some(BitRange {
+            start: parse_int(&args[2])?,
+            end: args.get(3).map(|end| parse_int(end)).transpose()?,
+            bit: parse_bit_unit(args.get(4))?,
+        }),
+        _ => return Err(SYNTAX_ERR),
+    };
+    Ok(Command::BitPos(args[0].clone(), bit, range))
+}
+
+fn parse_bitop(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 3 {
+        return Err("wrong number of arguments for 'bitop' command");
+    }
+    let op = match text(&args[0]).to_lowercase().as_str() {
+        "and" => BitOperation::And,
+        "or" => BitOperation::Or,
+        "xor" => BitOperation::Xor,
+        "not" => BitOperation::Not,
+        _ => return Err(SYNTAX_ERR),
+    };
+    if op == BitOperation::Not && args.len() != 3 {
+        return Err("BITOP NOT must be called with a single source key.");
+    }
+    Ok(Command::BitOp(op, args[1].clone(), args[2..].to_vec()))
+}
+
+/// Parses a BITFIELD offset, which is multiplied by the field width when
+/// prefixed with `#`.
+fn parse_bitfield_offset(s: &[u8], ty: BitFieldType) -> Result<u64, &'static str> {
+    let offset = match s.strip_prefix(b"#") {
+        Some(n) => parse_int::<u64>(n)
+            .ok()
+            .and_then(|n| n.checked_mul(ty.bits as u64)),
+        None => parse_int::<u64>(s).ok(),
+    };
+    offset
+        .filter(|offset| *offset < MAX_BIT_OFFSET)
+        .ok_or(BIT_OFFSET_ERR)
+}
+
+fn parse_bitfield(args: Vec<Vec<u8>>, read_only: bool) -> Result<Command, &'static str> {
+    let key = args.first().ok_or(if read_only {
+        "wrong number of arguments for 'bitfield_ro' command"
+    } else {
+        "wrong number of arguments for 'bitfield' command"
+    })?;
+    let mut ops = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let subcmd = text(&args[i]).to_lowercase();
+        let remaining = args.len() - i - 1;
+        match subcmd.as_str() {
+            "overflow" if remaining >= 1 => {
+                let overflow = match text(&args[i + 1]).to_lowercase().as_str() {
+                    "wrap" => Overflow::Wrap,
+                    "sat" => Overflow::Sat,
+                    "fail" => Overflow::Fail,
+                    _ => return Err("Invalid OVERFLOW type specified"),
+                };
+                ops.push(BitFieldOp::Overflow(overflow));
+                i += 2;
+                continue;
+            }
+            "get" if remaining >= 2 => {}
+            "set" | "incrby" if remaining >= 3 => {}
+            _ => return Err(SYNTAX_ERR),
+        }
+        let ty = BitFieldType::parse(&text(&args[i + 1])).ok_or(
+            "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.",
+        )?;
+        let offset = parse_bitfield_offset(&args[i + 2], ty)?;
+        if subcmd == "get" {
+            ops.push(BitFieldOp::Get(ty, offset));
+            i += 3;
+            continue;
+        }
+        if read_only {
+            return Err("BITFIELD_RO only supports the GET subcommand");
+        }
+        let value: i64 = parse_int(&args[i + 3])?;
+        ops.push(if subcmd == "set" {
+            BitFieldOp::Set(ty, offset, value)
         } else {
-            return Err("Invalid command");
+            BitFieldOp::IncrBy(ty, offset, value)
+        });
+        i += 4;
+    }
+    Ok(Command::BitField(key.clone(), ops))
+}
+
+fn parse_pubsub(args: Vec<String>) -> Result<Command, &'static str> {
+    let mut args = args.into_iter();
+    let subcommand = args.next().unwrap_or_default().to_lowercase();
+    let args: Vec<String> = args.collect();
+    match subcommand.as_str() {
+        "channels" if args.len() <= 1 => Ok(Command::PubSubChannels(
+            Kind::Channel,
+            args.into_iter().next(),
+        )),
+        "shardchannels" if args.len() <= 1 => Ok(Command::PubSubChannels(
+            Kind::Shard,
+            args.into_iter().next(),
+        )),
+        "numsub" => Ok(Command::PubSubNumSub(Kind::Channel, args)),
+        "shardnumsub" => Ok(Command::PubSubNumSub(Kind::Shard, args)),
+        "numpat" if args.is_empty() => Ok(Command::PubSubNumPat),
+        "channels" | "shardchannels" | "numpat" => {
+            Err("wrong number of arguments for 'pubsub' command")
+        }
+        _ => Err("unknown subcommand. Try PUBSUB HELP."),
+    }
+}
+
+fn parse_cluster(args: Vec<Vec<u8>>) -> Result<ClusterCommand, &'static str> {
+    let slot = |arg: &Vec<u8>| {
+        parse_int::<u16>(arg)
+            .ok()
+            .filter(|slot| (*slot as usize) < SLOTS)
+            .ok_or("Invalid or out of range slot")
+    };
+    let Some((subcommand, args)) = args.split_first() else {
+        return Err("wrong number of arguments for 'cluster' command");
+    };
+    let subcommand = text(subcommand).to_lowercase();
+    match (subcommand.as_str(), args) {
+        ("info", []) => Ok(ClusterCommand::Info),
+        ("myid", []) => Ok(ClusterCommand::MyId),
+        ("keyslot", [key]) => Ok(ClusterCommand::KeySlot(key.clone())),
+        ("countkeysinslot", [s]) => Ok(ClusterCommand::CountKeysInSlot(slot(s)?)),
+        ("getkeysinslot", [s, count]) => Ok(ClusterCommand::GetKeysInSlot(
+            slot(s)?,
+            parse_int(count).map_err(|_| "Invalid number of keys")?,
+        )),
+        ("slots", []) => Ok(ClusterCommand::Slots),
+        ("shards", []) => Ok(ClusterCommand::Shards),
+        ("nodes", []) => Ok(ClusterCommand::Nodes),
+        ("addslots" | "delslots", slots) if !slots.is_empty() => {
+            let slots = slots.iter().map(slot).collect::<Result<Vec<u16>, _>>()?;
+            parse_slots(subcommand.starts_with("add"), slots)
+        }
+        ("addslotsrange" | "delslotsrange", ranges)
+            if !ranges.is_empty() && ranges.len().is_multiple_of(2) =>
+        {
+            let mut slots = Vec::new();
+            for range in ranges.chunks(2) {
+                let (start, end) = (slot(&range[0])?, slot(&range[1])?);
+                if start > end {
+                    return Err("start slot number is greater than end slot number");
+                }
+                slots.extend(start..=end);
+            }
+            parse_slots(subcommand.starts_with("add"), slots)
+        }
+        ("setslot", [s, action, rest @ ..]) => {
+            let action =
+                match (text(action).to_lowercase().as_str(), rest) {
+                    ("importing", [id]) => SetSlot::Importing(text(id)),
+                    ("migrating", [id]) => SetSlot::Migrating(text(id)),
+                    ("node", [id]) => SetSlot::Node(text(id)),
+                    ("stable", []) => SetSlot::Stable,
+                    _ => return Err(
+                        "Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP",
+                    ),
+                };
+            Ok(ClusterCommand::SetSlot(slot(s)?, action))
+        }
+        ("meet", [host, port]) => Ok(ClusterCommand::Meet(
+            text(host),
+            parse_int(port).map_err(|_| "Invalid base port specified")?,
+        )),
+        (
+            "info" | "myid" | "keyslot" | "countkeysinslot" | "getkeysinslot" | "slots" | "shards"
+            | "nodes" | "addslots" | "delslots" | "addslotsrange" | "delslotsrange" | "setslot"
+            | "meet",
+            _,
+        ) => Err("wrong number of arguments for 'cluster' command"),
+        _ => Err("unknown subcommand. Try CLUSTER HELP."),
+    }
+}
+
+/// ADDSLOTS or DELSLOTS of `slots`, each given once.
+fn parse_slots(add: bool, mut slots: Vec<u16>) -> Result<ClusterCommand, &'static str> {
+    let count = slots.len();
+    slots.sort_unstable();
+    slots.dedup();
+    if slots.len() != count {
+        return Err("Slot specified multiple times");
+    }
+    Ok(if add {
+        ClusterCommand::AddSlots(slots)
+    } else {
+        ClusterCommand::DelSlots(slots)
+    })
+}
+
+fn parse_script(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let mut args = args.into_iter();
+    let subcommand = text(&args.next().unwrap_or_default()).to_lowercase();
+    let args: Vec<Vec<u8>> = args.collect();
+    match (subcommand.as_str(), args.as_slice()) {
+        ("load", [source]) => Ok(Command::ScriptLoad(source.clone())),
+        ("exists", shas) if !shas.is_empty() => Ok(Command::ScriptExists(
+            shas.iter().map(|sha| text(sha)).collect(),
+        )),
+        ("flush", []) => Ok(Command::ScriptFlush),
+        ("flush", [mode])
+            if mode.eq_ignore_ascii_case(b"sync") || mode.eq_ignore_ascii_case(b"async") =>
+        {
+            Ok(Command::ScriptFlush)
+        }
+        ("kill", []) => Ok(Command::ScriptKill),
+        ("load" | "exists" | "kill", _) => Err("wrong number of arguments for 'script' command"),
+        _ => Err("unknown subcommand. Try SCRIPT HELP."),
+    }
+}
+
+type Args = Vec<Vec<u8>>;
+
+/// Splits the arguments of EVAL and FCALL into keys and other arguments.
+fn split_keys(numkeys: &[u8], mut args: Args) -> Result<(Args, Args), &'static str> {
+    let numkeys: i64 = parse_int(numkeys)?;
+    if numkeys < 0 {
+        return Err("Number of keys can't be negative");
+    }
+    if numkeys as usize > args.len() {
+        return Err("Number of keys can't be greater than number of args");
+    }
+    let rest = args.split_off(numkeys as usize);
+    Ok((args, rest))
+}
+
+fn parse_function(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let mut args = args.into_iter();
+    let subcommand = text(&args.next().unwrap_or_default()).to_lowercase();
+    let args: Vec<Vec<u8>> = args.collect();
+    match (subcommand.as_str(), args.as_slice()) {
+        ("load", [code]) => Ok(Command::FunctionLoad {
+            code: code.clone(),
+            replace: false,
+        }),
+        ("load", [replace, code]) if replace.eq_ignore_ascii_case(b"replace") => {
+            Ok(Command::FunctionLoad {
+                code: code.clone(),
+                replace: true,
+            })
+        }
+        ("list", options) => {
+            let mut pattern = None;
+            let mut with_code = false;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match text(option).to_lowercase().as_str() {
+                    "withcode" => with_code = true,
+                    "libraryname" => match options.next() {
+                        Some(p) => pattern = Some(text(p)),
+                        None => return Err("library name argument was not given"),
+                    },
+                    _ => return Err("Unknown argument"),
+                }
+            }
+            Ok(Command::FunctionList { pattern, with_code })
+        }
+        ("delete", [name]) => Ok(Command::FunctionDelete(text(name))),
+        ("dump", []) => Ok(Command::FunctionDump),
+        ("restore", [payload, policy @ ..]) if policy.len() <= 1 => {
+            let policy = match policy.first().map(|p| text(p).to_lowercase()).as_deref() {
+                None | Some("append") => RestorePolicy::Append,
+                Some("replace") => RestorePolicy::Replace,
+                Some("flush") => RestorePolicy::Flush,
+                Some(_) => return Err(
+                    "Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.",
+                ),
+            };
+            Ok(Command::FunctionRestore(payload.clone(), policy))
+        }
+        ("flush", []) => Ok(Command::FunctionFlush),
+        ("flush", [mode])
+            if mode.eq_ignore_ascii_case(b"sync") || mode.eq_ignore_ascii_case(b"async") =>
+        {
+            Ok(Command::FunctionFlush)
+        }
+        ("load" | "delete" | "dump" | "restore" | "flush", _) => {
+            Err("wrong number of arguments for 'function' command")
+        }
+        _ => Err("unknown subcommand. Try FUNCTION HELP."),
+    }
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]. Keys have no LRU or LFU data to restore, so IDLETIME
+/// and FREQ are only validated.
+fn parse_restore(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let [key, ttl, payload, options @ ..] = args.as_slice() else {
+        return Err("wrong number of arguments for 'restore' command");
+    };
+    let ttl: i64 = parse_int(ttl)?;
+    if ttl < 0 {
+        return Err("Invalid TTL value, must be >= 0");
+    }
+    let mut restore = Restore {
+        key: key.clone(),
+        ttl: ttl as u64,
+        payload: payload.clone(),
+        replace: false,
+        absttl: false,
+    };
+    let (mut idletime, mut freq) = (false, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match text(option).to_lowercase().as_str() {
+            "replace" => restore.replace = true,
+            "absttl" => restore.absttl = true,
+            "idletime" if !freq => {
+                let idle: i64 = parse_int(options.next().ok_or(SYNTAX_ERR)?)?;
+                if idle < 0 {
+                    return Err("Invalid IDLETIME value, must be >= 0");
+                }
+                idletime = true;
+            }
+            "freq" if !idletime => {
+                let frequency: i64 = parse_int(options.next().ok_or(SYNTAX_ERR)?)?;
+                if !(0..=255).contains(&frequency) {
+                    return Err("Invalid FREQ value, must be >= 0 and <= 255");
+                }
+                freq = true;
+            }
+            _ => return Err(SYNTAX_ERR),
+        }
+    }
+    Ok(Command::Restore(restore))
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password | AUTH2 username password] [KEYS key [key ...]]
+fn parse_migrate(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let [host, port, key, db, timeout, options @ ..] = args.as_slice() else {
+        return Err("wrong number of arguments for 'migrate' command");
+    };
+    let timeout: i64 = parse_int(timeout)?;
+    let mut migrate = Migrate {
+        host: text(host),
+        port: parse_int(port)?,
+        db: parse_int(db)?,
+        timeout: timeout.max(0) as u64,
+        copy: false,
+        replace: false,
+        auth: vec![],
+        keys: vec![key.clone()],
+    };
+    let mut i = 0;
+    while i < options.len() {
+        match text(&options[i]).to_lowercase().as_str() {
+            "copy" => migrate.copy = true,
+            "replace" => migrate.replace = true,
+            "auth" => {
+                let auth = options.get(i + 1..i + 2).ok_or(SYNTAX_ERR)?;
+                migrate.auth = auth.iter().map(|arg| text(arg)).collect();
+                i += 1;
+            }
+            "auth2" => {
+                let auth = options.get(i + 1..i + 3).ok_or(SYNTAX_ERR)?;
+                migrate.auth = auth.iter().map(|arg| text(arg)).collect();
+                i += 2;
+            }
+            "keys" => {
+                if !key.is_empty() {
+                    return Err(
+                        "When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                    );
+                }
+                migrate.keys = options[i + 1..].to_vec();
+                break;
+            }
+            _ => return Err(SYNTAX_ERR),
+        }
+        i += 1;
+    }
+    Ok(Command::Migrate(migrate))
+}
+
+const NOT_A_FLOAT: &str = "value is not a valid float";
+
+fn parse_float(s: &[u8]) -> Result<f64, &'static str> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|f| !f.is_nan())
+        .ok_or(NOT_A_FLOAT)
+}
+
+/// Meters per unit.
+fn parse_geo_unit(s: &[u8]) -> Result<f64, &'static str> {
+    match text(s).to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err("unsupported unit provided. please use M, KM, FT, MI"),
+    }
+}
+
+fn parse_geoadd(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    if args.len() < 4 {
+        return Err("wrong number of arguments for 'geoadd' command");
+    }
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut i = 1;
+    while i < args.len() {
+        match text(&args[i]).to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "ch" => ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let rest = &args[i..];
+    if rest.is_empty() || !rest.chunks_exact(3).remainder().is_empty() {
+        return Err("syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ");
+    }
+    if nx && xx {
+        return Err("XX and NX options at the same time are not compatible");
+    }
+    let items = rest
+        .chunks(3)
+        .map(|c| Ok((parse_float(&c[0])?, parse_float(&c[1])?, c[2].clone())))
+        .collect::<Result<_, &'static str>>()?;
+    Ok(Command::GeoAdd {
+        key: args[0].clone(),
+        nx,
+        xx,
+        ch,
+        items,
+    })
+}
+
+fn parse_geodist(args: Vec<Vec<u8>>) -> Result<Command, &'static str> {
+    let unit = match args.len() {
+        0..=2 => return Err("wrong number of arguments for 'geodist' command"),
+        3 => 1.0,
+        4 => parse_geo_unit(&args[3])?,
+        _ => return Err(SYNTAX_ERR),
+    };
+    Ok(Command::GeoDist(
+        args[0].clone(),
+        args[1].clone(),
+        args[2].clone(),
+        unit,
+    ))
+}
+
+/// Parses the options of GEOSEARCH and GEOSEARCHSTORE (after the keys).
+/// Returns the query and whether STOREDIST was given.
+fn parse_geosearch(args: &[Vec<u8>], store: bool) -> Result<(GeoSearch, bool), &'static str> {
+    const ONE_FROM: &str = "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH";
+    const ONE_BY: &str = "exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH";
+    let mut from = None;
+    let mut shape = None;
+    let mut unit = 1.0;
+    let mut sort = GeoSort::None;
+    let mut count = None;
+    let (mut any, mut with_coord, mut with_dist, mut with_hash) = (false, false, false, false);
+    let mut store_dist = false;
+    let mut i = 0;
+    while i < args.len() {
+        let remaining = args.len() - i - 1;
+        match text(&args[i]).to_lowercase().as_str() {
+            "withcoord" => with_coord = true,
+            "withdist" => with_dist = true,
+            "withhash" => with_hash = true,
+            "any" => any = true,
+            "asc" => sort = GeoSort::Asc,
+            "desc" => sort = GeoSort::Desc,
+            "storedist" if store => store_dist = true,
+            "count" if remaining >= 1 => {
+                let n: i64 = parse_int(&args[i + 1])?;
+                if n <= 0 {
+                    return Err("COUNT must be > 0");
+                }
+                count = Some(n as usize);
+                i += 1;
+            }
+            "frommember" if remaining >= 1 => {
+                if from.is_some() {
+                    return Err(ONE_FROM);
+                }
+                from = Some(GeoFrom::Member(args[i + 1].clone()));
+                i += 1;
+            }
+            "fromlonlat" if remaining >= 2 => {
+                if from.is_some() {
+                    return Err(ONE_FROM);
+                }
+                let (lon, lat) = (parse_float(&args[i + 1])?, parse_float(&args[i + 2])?);
+                from = Some(GeoFrom::LonLat(lon, lat));
+                i += 2;
+            }
+            "byradius" if remaining >= 2 => {
+                if shape.is_some() {
+                    return Err(ONE_BY);
+                }
+                let radius = parse_float(&args[i + 1])?;
+                if radius < 0.0 {
+                    return Err("radius cannot be negative");
+                }
+                unit = parse_geo_unit(&args[i + 2])?;
+                shape = Some(GeoShape::Radius(radius));
+                i += 2;
+            }
+            "bybox" if remaining >= 3 => {
+                if shape.is_some() {
+                    return Err(ONE_BY);
+                }
+                let (width, height) = (parse_float(&args[i + 1])?, parse_float(&args[i + 2])?);
+                if width < 0.0 || height < 0.0 {
+                    return Err("height or width cannot be negative");
+                }
+                unit = parse_geo_unit(&args[i + 3])?;
+                shape = Some(GeoShape::Box(width, height));
+                i += 3;
+            }
+            _ => return Err(SYNTAX_ERR),
         }
+        i += 1;
+    }
+    let from = from.ok_or(ONE_FROM)?;
+    let shape = shape.ok_or(ONE_BY)?;
+    if any && count.is_none() {
+        return Err("the ANY argument requires COUNT argument");
     }
+    if store && (with_coord || with_dist || with_hash) {
+        return Err(
+            "GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+        );
+    }
+    let query = GeoSearch {
+        from,
+        shape,
+        unit,
+        sort,
+        count,
+        any,
+        with_coord,
+        with_dist,
+        with_hash,
+    };
+    Ok((query, store_dist))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::resp::StrType;
-    use std::borrow::Cow;
 
     #[test]
     fn test_set_invalid() {
         let resp = vec![
-            RespType::String(Cow::from("set"), StrType::Bulk),
-            RespType::String(Cow::from("test_string"), StrType::Bulk),
+            RespType::String(Cow::from("set".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("test_string".as_bytes()), StrType::Bulk),
         ];
         let command = Command::try_from(resp);
         assert!(command.is_err());
@@ -107,36 +1920,520 @@ mod tests {
     #[test]
     fn test_set() {
         let resp = vec![
-            RespType::String(Cow::from("set"), StrType::Bulk),
-            RespType::String(Cow::from("test_string"), StrType::Bulk),
-            RespType::String(Cow::from("test_value"), StrType::Bulk),
+            RespType::String(Cow::from("set".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("test_string".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("test_value".as_bytes()), StrType::Bulk),
         ];
         let command = Command::try_from(resp);
         assert!(command.is_ok());
         assert_eq!(
             command.unwrap(),
-            Command::Set("test_string".to_string(), "test_value".to_string(), None)
+            Command::Set(b"test_string".to_vec(), b"test_value".to_vec(), None)
         );
     }
 
     #[test]
     fn test_set_with_px() {
         let resp = vec![
-            RespType::String(Cow::from("set"), StrType::Bulk),
-            RespType::String(Cow::from("test_string"), StrType::Bulk),
-            RespType::String(Cow::from("test_value"), StrType::Bulk),
-            RespType::String(Cow::from("px"), StrType::Bulk),
-            RespType::String(Cow::from("142"), StrType::Bulk),
+            RespType::String(Cow::from("set".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("test_string".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("test_value".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("px".as_bytes()), StrType::Bulk),
+            RespType::String(Cow::from("142".as_bytes()), StrType::Bulk),
         ];
         let command = Command::try_from(resp);
         assert!(command.is_ok());
         assert_eq!(
             command.unwrap(),
             Command::Set(
-                "test_string".to_string(),
-                "test_value".to_string(),
+                b"test_string".to_vec(),
+                b"test_value".to_vec(),
                 Some(142u64)
             )
         );
     }
+
+    fn bulk_strings(args: &[&'static str]) -> Vec<RespType<'static>> {
+        args.iter()
+            .map(|a| RespType::String(Cow::from(a.as_bytes()), StrType::Bulk))
+            .collect()
+    }
+
+    #[test]
+    fn test_xadd_with_trim() {
+        let command = Command::try_from(bulk_strings(&[
+            "XADD", "s", "MAXLEN", "~", "1000", "LIMIT", "10", "5-*", "f", "v",
+        ]));
+        assert_eq!(
+            command.unwrap(),
+            Command::XAdd {
+                key: b"s".to_vec(),
+                id: XAddId::AutoSeq(5),
+                fields: vec![(b"f".to_vec(), b"v".to_vec())],
+                nomkstream: false,
+                trim: Some(StreamTrim {
+                    strategy: TrimStrategy::MaxLen(1000),
+                    approx: true,
+                    limit: Some(10),
+                }),
+            }
+        );
+        let command = Command::try_from(bulk_strings(&["xadd", "s", "0-0", "f", "v"]));
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn test_xrange_exclusive() {
+        let command = Command::try_from(bulk_strings(&["xrange", "s", "(1-5", "7", "COUNT", "2"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::XRange {
+                key: b"s".to_vec(),
+                start: StreamId::new(1, 6),
+                end: StreamId::new(7, u64::MAX),
+                count: Some(2),
+                rev: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_xread_block() {
+        let command = Command::try_from(bulk_strings(&[
+            "xread", "block", "0", "streams", "a", "b", "$", "0-1",
+        ]));
+        assert_eq!(
+            command.unwrap(),
+            Command::XRead {
+                count: None,
+                block: Some(0),
+                keys: vec![b"a".to_vec(), b"b".to_vec()],
+                ids: vec![XReadId::Last, XReadId::After(StreamId::new(0, 1))],
+            }
+        );
+    }
+
+    #[test]
+    fn test_bitfield_parsing() {
+        let command = Command::try_from(bulk_strings(&[
+            "BITFIELD", "k", "OVERFLOW", "SAT", "INCRBY", "u8", "#2", "5", "GET", "i64", "0",
+        ]));
+        let u8 = BitFieldType::parse("u8").unwrap();
+        let i64 = BitFieldType::parse("i64").unwrap();
+        assert_eq!(
+            command.unwrap(),
+            Command::BitField(
+                b"k".to_vec(),
+                vec![
+                    BitFieldOp::Overflow(Overflow::Sat),
+                    BitFieldOp::IncrBy(u8, 16, 5),
+                    BitFieldOp::Get(i64, 0),
+                ]
+            )
+        );
+        let command = Command::try_from(bulk_strings(&["bitfield_ro", "k", "set", "u8", "0", "1"]));
+        assert_eq!(command, Err("BITFIELD_RO only supports the GET subcommand"));
+        let command = Command::try_from(bulk_strings(&["bitfield", "k", "get", "u64", "0"]));
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn test_geosearch_parsing() {
+        let command = Command::try_from(bulk_strings(&[
+            "GEOSEARCH",
+            "Sicily",
+            "FROMLONLAT",
+            "15",
+            "37",
+            "BYBOX",
+            "400",
+            "400",
+            "km",
+            "COUNT",
+            "2",
+            "ANY",
+            "WITHDIST",
+        ]));
+        assert_eq!(
+            command.unwrap(),
+            Command::GeoSearch(
+                b"Sicily".to_vec(),
+                GeoSearch {
+                    from: GeoFrom::LonLat(15.0, 37.0),
+                    shape: GeoShape::Box(400.0, 400.0),
+                    unit: 1000.0,
+                    sort: GeoSort::None,
+                    count: Some(2),
+                    any: true,
+                    with_coord: false,
+                    with_dist: true,
+                    with_hash: false,
+                }
+            )
+        );
+        let command = Command::try_from(bulk_strings(&["geosearch", "s", "byradius", "1", "m"]));
+        assert_eq!(
+            command,
+            Err("exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH")
+        );
+        let command = Command::try_from(bulk_strings(&[
+            "geosearchstore",
+            "d",
+            "s",
+            "frommember",
+            "a",
+            "byradius",
+            "1",
+            "m",
+            "withdist",
+        ]));
+        assert!(command.is_err());
+    }
+
+    #[test]
+    fn test_pubsub_parsing() {
+        let command = Command::try_from(bulk_strings(&["PUBLISH", "news", "hello"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::Publish("news".to_string(), b"hello".to_vec())
+        );
+        let command = Command::try_from(bulk_strings(&["psubscribe", "a*", "b?"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::Subscribe(Kind::Pattern, vec!["a*".to_string(), "b?".to_string()])
+        );
+        let command = Command::try_from(bulk_strings(&["unsubscribe"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::Unsubscribe(Kind::Channel, vec![])
+        );
+        let command = Command::try_from(bulk_strings(&["pubsub", "CHANNELS", "n*"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::PubSubChannels(Kind::Channel, Some("n*".to_string()))
+        );
+        let command = Command::try_from(bulk_strings(&["pubsub", "shardnumsub", "a"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::PubSubNumSub(Kind::Shard, vec!["a".to_string()])
+        );
+        let command = Command::try_from(bulk_strings(&["ssubscribe", "a", "b"]));
+        assert_eq!(
+            command.unwrap(),
+            Command::Subscribe(Kind::Shard, vec!["a".to_string(), "b".to_string()])
+        );
+        assert!(Command::try_from(bulk_strings(&["subscribe"])).is_err());
+        assert!(Command::try_from(bulk_strings(&["pubsub", "numpat", "x"])).is_err());
+        assert_eq!(
+            Command::try_from(bulk_strings(&["hello", "3"])).unwrap(),
+            Command::Hello(Some(3))
+        );
+    }
+
+    #[test]
+    fn test_transaction_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&["MULTI"])),
+            Ok(Command::Multi)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["exec"])),
+            Ok(Command::Exec)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["Discard"])),
+            Ok(Command::Discard)
+        );
+        assert!(Command::try_from(bulk_strings(&["multi", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_save_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&["SAVE"])),
+            Ok(Command::Save)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["bgsave", "SCHEDULE"])),
+            Ok(Command::BgSave)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["lastsave"])),
+            Ok(Command::LastSave)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["bgsave", "now"])),
+            Err(SYNTAX_ERR)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["BGREWRITEAOF"])),
+            Ok(Command::BgRewriteAof)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["shutdown"])),
+            Ok(Command::Shutdown(None))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["SHUTDOWN", "NOSAVE"])),
+            Ok(Command::Shutdown(Some(false)))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["shutdown", "save", "nosave"])),
+            Err(SYNTAX_ERR)
+        );
+    }
+
+    #[test]
+    fn test_replication_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "REPLCONF",
+                "listening-port",
+                "6380",
+                "capa",
+                "psync2"
+            ])),
+            Ok(Command::ReplConf(vec![
+                ("listening-port".to_string(), "6380".to_string()),
+                ("capa".to_string(), "psync2".to_string()),
+            ]))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["replconf", "capa"])),
+            Err(SYNTAX_ERR)
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["PSYNC", "?", "-1"])),
+            Ok(Command::Psync("?".to_string(), -1))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["slaveof", "NO", "one"])),
+            Ok(Command::ReplicaOf(None))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["replicaof", "localhost", "6379"])),
+            Ok(Command::ReplicaOf(Some(("localhost".to_string(), 6379))))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["INFO", "Replication"])),
+            Ok(Command::Info(vec!["replication".to_string()]))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["WAIT", "2", "100"])),
+            Ok(Command::Wait(2, 100))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["wait", "1", "-1"])),
+            Err("timeout is negative")
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["waitaof", "1", "0", "0"])),
+            Ok(Command::WaitAof(1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_restore_and_migrate_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "RESTORE", "k", "100", "p", "replace", "absttl"
+            ])),
+            Ok(Command::Restore(Restore {
+                key: b"k".to_vec(),
+                ttl: 100,
+                payload: b"p".to_vec(),
+                replace: true,
+                absttl: true,
+            }))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["restore", "k", "-1", "p"])),
+            Err("Invalid TTL value, must be >= 0")
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["restore", "k", "0", "p", "FREQ", "256"])),
+            Err("Invalid FREQ value, must be >= 0 and <= 255")
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "restore", "k", "0", "p", "idletime", "1", "freq", "1"
+            ])),
+            Err(SYNTAX_ERR)
+        );
+
+        let Ok(Command::Migrate(migrate)) = Command::try_from(bulk_strings(&[
+            "MIGRATE",
+            "localhost",
+            "6380",
+            "",
+            "2",
+            "500",
+            "COPY",
+            "AUTH2",
+            "u",
+            "p",
+            "KEYS",
+            "a",
+            "b",
+        ])) else {
+            panic!("not a MIGRATE")
+        };
+        assert_eq!((migrate.port, migrate.db, migrate.timeout), (6380, 2, 500));
+        assert!(migrate.copy && !migrate.replace);
+        assert_eq!(migrate.auth, ["u", "p"]);
+        assert_eq!(migrate.keys, [b"a".to_vec(), b"b".to_vec()]);
+        assert!(Command::try_from(bulk_strings(&[
+            "migrate", "h", "1", "k", "0", "0", "keys", "a"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_written_keys() {
+        let command = Command::try_from(bulk_strings(&["bitop", "and", "d", "a", "b"])).unwrap();
+        assert_eq!(command.written_keys(), [b"d".as_slice()]);
+        let command =
+            Command::try_from(bulk_strings(&["bitfield", "k", "get", "u8", "0"])).unwrap();
+        assert!(command.written_keys().is_empty());
+        let command = Command::try_from(bulk_strings(&["del", "a", "b"])).unwrap();
+        assert_eq!(command.written_keys(), [b"a".as_slice(), b"b".as_slice()]);
+        let command = Command::try_from(bulk_strings(&["watch", "a"])).unwrap();
+        assert!(command.written_keys().is_empty());
+        let command = Command::try_from(bulk_strings(&["move", "a", "1"])).unwrap();
+        assert_eq!(command.written_keys(), [b"a".as_slice()]);
+        let command = Command::try_from(bulk_strings(&[
+            "xreadgroup",
+            "group",
+            "g",
+            "c",
+            "streams",
+            "a",
+            "b",
+            ">",
+            ">",
+        ]))
+        .unwrap();
+        assert_eq!(command.written_keys(), [b"a".as_slice(), b"b".as_slice()]);
+        assert!(command.is_write());
+        assert_eq!(
+            Command::try_from(bulk_strings(&["swapdb", "x", "1"])),
+            Err("invalid first DB index")
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["FLUSHALL", "async"])),
+            Ok(Command::FlushAll)
+        );
+    }
+
+    #[test]
+    fn test_script_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&["EVAL", "return 1", "1", "k", "a"])),
+            Ok(Command::Eval {
+                script: Script::Source(b"return 1".to_vec()),
+                keys: vec![b"k".to_vec()],
+                args: vec![b"a".to_vec()],
+            })
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["evalsha", "abc", "0"])),
+            Ok(Command::Eval {
+                script: Script::Sha("abc".to_string()),
+                keys: vec![],
+                args: vec![],
+            })
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["eval", "return 1", "2", "k"])),
+            Err("Number of keys can't be greater than number of args")
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["script", "flush", "async"])),
+            Ok(Command::ScriptFlush)
+        );
+        assert!(Command::try_from(bulk_strings(&["script", "kill", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_function_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&["FCALL_RO", "f", "1", "k", "a"])),
+            Ok(Command::FCall {
+                function: "f".to_string(),
+                keys: vec![b"k".to_vec()],
+                args: vec![b"a".to_vec()],
+                read_only: true,
+            })
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["function", "load", "REPLACE", "code"])),
+            Ok(Command::FunctionLoad {
+                code: b"code".to_vec(),
+                replace: true,
+            })
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "function",
+                "list",
+                "withcode",
+                "libraryname",
+                "a*"
+            ])),
+            Ok(Command::FunctionList {
+                pattern: Some("a*".to_string()),
+                with_code: true,
+            })
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&["function", "restore", "x", "flush"])),
+            Ok(Command::FunctionRestore(
+                b"x".to_vec(),
+                RestorePolicy::Flush
+            ))
+        );
+        assert!(Command::try_from(bulk_strings(&["function", "restore", "x", "y"])).is_err());
+        assert!(Command::try_from(bulk_strings(&["fcall", "f"])).is_err());
+    }
+
+    #[test]
+    fn test_cluster_parsing() {
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "cluster",
+                "addslotsrange",
+                "0",
+                "2",
+                "5",
+                "5"
+            ])),
+            Ok(Command::Cluster(ClusterCommand::AddSlots(vec![0, 1, 2, 5])))
+        );
+        assert_eq!(
+            Command::try_from(bulk_strings(&[
+                "CLUSTER",
+                "SETSLOT",
+                "7",
+                "MIGRATING",
+                "id"
+            ])),
+            Ok(Command::Cluster(ClusterCommand::SetSlot(
+                7,
+                SetSlot::Migrating("id".to_string())
+            )))
+        );
+        assert!(Command::try_from(bulk_strings(&["cluster", "addslots", "1", "1"])).is_err());
+        assert!(Command::try_from(bulk_strings(&["cluster", "delslots", "16384"])).is_err());
+        assert!(
+            Command::try_from(bulk_strings(&["cluster", "setslot", "1", "stable", "x"])).is_err()
+        );
+
+        let bitop = Command::try_from(bulk_strings(&["bitop", "and", "d", "a", "b"])).unwrap();
+        assert_eq!(
+            bitop.keys(),
+            [b"d".as_slice(), b"a".as_slice(), b"b".as_slice()]
+        );
+        let eval = Command::try_from(bulk_strings(&["eval", "return 1", "1", "k", "v"])).unwrap();
+        assert_eq!(eval.keys(), [b"k".as_slice()]);
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::multi::Watches;
+use crate::reply::Reply;
+use crate::value::Value;
+
+/// The number of databases when `--databases` is not given.
+pub const DEFAULT_DATABASES: usize = 16;
+
+const OUT_OF_RANGE: &str = "DB index is out of range";
+
+/// Validates a database index given by a client.
+pub fn index(index: i64, count: usize) -> Option<usize> {
+    usize::try_from(index).ok().filter(|index| *index < count)
+}
+
+/// MOVE: moves `key`, along with its TTL, unless the destination already
+/// has it. Expired keys must have been removed by the caller.
+pub fn move_key(
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, Instant>],
+    watches: &mut Watches,
+    src: usize,
+    dest: i64,
+    key: &[u8],
+) -> Reply<'static> {
+    let Some(dest) = index(dest, state.len()) else {
+        return Reply::Error(OUT_OF_RANGE);
+    };
+    if src == dest {
+        return Reply::Error("source and destination objects are the same");
+    }
+    if state[dest].contains_key(key) {
+        return Reply::Integer(0);
+    }
+    let Some(value) = state[src].remove(key) else {
+        return Reply::Integer(0);
+    };
+    state[dest].insert(key.to_vec(), value);
+    if let Some(at) = durations[src].remove(key) {
+        durations[dest].insert(key.to_vec(), at);
+    }
+    watches.touch(src, key);
+    watches.touch(dest, key);
+    Reply::Integer(1)
+}
+
+/// SWAPDB: clients connected to either database see the other's keys.
+pub fn swapdb(
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, Instant>],
+    watches: &mut Watches,
+    first: i64,
+    second: i64,
+) -> Reply<'static> {
+    let (Some(first), Some(second)) = (index(first, state.len()), index(second, state.len()))
+    else {
+        return Reply::Error(OUT_OF_RANGE);
+    };
+    if first != second {
+        // keys that exist in either database change
+        let exists = |key: &[u8]| state[first].contains_key(key) || state[second].contains_key(key);
+        watches.touch_db(first, exists);
+        watches.touch_db(second, exists);
+        state.swap(first, second);
+        durations.swap(first, second);
+    }
+    Reply::Simple("OK".to_string())
+}
+
+pub fn flushall(
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, Instant>],
+    watches: &mut Watches,
+) -> Reply<'static> {
+    for (db, keys) in state.iter().enumerate() {
+        watches.touch_db(db, |key| keys.contains_key(key));
+    }
+    state.iter_mut().for_each(HashMap::clear);
+    durations.iter_mut().for_each(HashMap::clear);
+    Reply::Simple("OK".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn databases() -> Vec<HashMap<Vec<u8>, Value>> {
+        let mut state = vec![HashMap::new(); 3];
+        state[0].insert(b"a".to_vec(), Value::String(b"0".to_vec().into()));
+        state[1].insert(b"b".to_vec(), Value::String(b"1".to_vec().into()));
+        state
+    }
+
+    #[test]
+    fn test_move_key() {
+        let mut state = databases();
+        let mut durations = vec![HashMap::new(); 3];
+        let mut watches = Watches::default();
+        let at = Instant::now();
+        durations[0].insert(b"a".to_vec(), at);
+        watches.watch(1, 2, b"a");
+
+        let mut move_key = |src, dest, key: &[u8]| {
+            move_key(&mut state, &mut durations, &mut watches, src, dest, key).into_bytes()
+        };
+        assert_eq!(move_key(0, 2, b"a"), b":1\r\n");
+        assert_eq!(move_key(0, 2, b"a"), b":0\r\n");
+        assert_eq!(
+            move_key(1, 1, b"b"),
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(move_key(1, 3, b"b"), b"-ERR DB index is out of range\r\n");
+        assert!(state[0].is_empty());
+        assert_eq!(durations[2].get(b"a".as_slice()), Some(&at));
+        assert!(watches.is_dirty(1));
+    }
+
+    #[test]
+    fn test_swapdb() {
+        let mut state = databases();
+        let mut durations = vec![HashMap::new(); 3];
+        let mut watches = Watches::default();
+        let reply = swapdb(&mut state, &mut durations, &mut watches, 0, 1);
+        assert_eq!(reply.into_bytes(), b"+OK\r\n");
+        assert!(state[0].contains_key(b"b".as_slice()) && state[1].contains_key(b"a".as_slice()));
+        let reply = swapdb(&mut state, &mut durations, &mut watches, 0, -1);
+        assert_eq!(reply.into_bytes(), b"-ERR DB index is out of range\r\n");
+
+        // only keys in either database are modified
+        watches.watch(1, 0, b"b");
+        watches.watch(2, 0, b"c");
+        swapdb(&mut state, &mut durations, &mut watches, 0, 2);
+        assert!(watches.is_dirty(1));
+        assert!(!watches.is_dirty(2));
+    }
+
+    #[test]
+    fn test_flushall() {
+        let mut state = databases();
+        let mut durations = vec![HashMap::new(); 3];
+        let mut watches = Watches::default();
+        watches.watch(1, 1, b"b");
+        watches.watch(2, 0, b"missing");
+        flushall(&mut state, &mut durations, &mut watches);
+        assert!(state.iter().all(HashMap::is_empty));
+        assert!(watches.is_dirty(1));
+        // a transaction watching a missing key still runs
+        assert!(!watches.is_dirty(2));
+    }
+}
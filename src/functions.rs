@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::glob;
+use crate::lua::Proto;
+use crate::rdb;
+use crate::reply::Reply;
+
+pub const FUNCTION_FLAGS: [&str; 5] = [
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// What FUNCTION RESTORE does with the libraries already loaded.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RestorePolicy {
+    /// Fail if a restored library already exists.
+    Append,
+    /// Restored libraries replace existing ones with the same name.
+    Replace,
+    /// Delete all libraries first.
+    Flush,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+}
+
+/// A library loaded with FUNCTION LOAD: its code, and the functions that
+/// running it registered.
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub proto: Arc<Proto>,
+    pub functions: Vec<FunctionInfo>,
+}
+
+/// The loaded libraries, by name, and which library each function is in.
+#[derive(Default, Clone)]
+pub struct Functions {
+    libraries: BTreeMap<String, Library>,
+    owners: HashMap<String, String>,
+}
+
+/// Splits a library's `#!lua name=<name>` header from its code. The header
+/// line is kept empty, so that line numbers in errors match the source.
+pub fn parse_metadata(code: &[u8]) -> Result<(String, Vec<u8>), String> {
+    if !code.starts_with(b"#!") {
+        return Err("Missing library metadata".to_string());
+    }
+    let end = code.iter().position(|&b| b == b'\n').unwrap_or(code.len());
+    let header = String::from_utf8_lossy(&code[2..end]).into_owned();
+    let mut parts = header.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(format!("Invalid metadata value given: {}", part)),
+        }
+    }
+    let name = name.ok_or("Library name was not given")?;
+    if !valid_name(&name) {
+        return Err("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok((name, code[end..].to_vec()))
+}
+
+/// Library and function names: letters, digits and underscores.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+impl Functions {
+    /// Adds a library, replacing the one with the same name if `replace`.
+    pub fn add(&mut self, library: Library, replace: bool) -> Result<String, String> {
+        if self.libraries.contains_key(&library.name) && !replace {
+            return Err(format!("Library '{}' already exists", library.name));
+        }
+        for function in &library.functions {
+            match self.owners.get(&function.name) {
+                Some(owner) if *owner != library.name => {
+                    return Err(format!("Function {} already exists", function.name));
+                }
+                _ => {}
+            }
+        }
+        self.delete(&library.name);
+        for function in &library.functions {
+            self.owners
+                .insert(function.name.clone(), library.name.clone());
+        }
+        let name = library.name.clone();
+        self.libraries.insert(name.clone(), library);
+        Ok(name)
+    }
+
+    /// Removes a library, returning whether it existed.
+    pub fn delete(&mut self, name: &str) -> bool {
+        match self.libraries.remove(name) {
+            Some(library) => {
+                for function in &library.functions {
+                    self.owners.remove(&function.name);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+        self.owners.clear();
+    }
+
+    /// A function and the library it belongs to.
+    pub fn find(&self, function: &str) -> Option<(&Library, &FunctionInfo)> {
+        let library = self.libraries.get(self.owners.get(function)?)?;
+        let info = library.functions.iter().find(|f| f.name == function)?;
+        Some((library, info))
+    }
+
+    /// FUNCTION LIST, optionally filtered by a library name pattern.
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> Reply<'static> {
+        let libraries = self
+            .libraries
+            .values()
+            .filter(|lib| pattern.is_none_or(|p| glob::matches(p.as_bytes(), lib.name.as_bytes())))
+            .map(|lib| {
+                let functions = lib
+                    .functions
+                    .iter()
+                    .map(|f| {
+                        Reply::Map(vec![
+                            ("name".to_string(), Reply::Bulk(f.name.clone())),
+                            (
+                                "description".to_string(),
+                                f.description.clone().map_or(Reply::NullBulk, Reply::Bulk),
+                            ),
+                            ("flags".to_string(), Reply::Array(f.flags.clone())),
+                        ])
+                    })
+                    .collect();
+                let mut fields = vec![
+                    ("library_name".to_string(), Reply::Bulk(lib.name.clone())),
+                    ("engine".to_string(), Reply::Bulk("LUA".to_string())),
+                    ("functions".to_string(), Reply::Nested(functions)),
+                ];
+                if with_code {
+                    fields.push((
+                        "library_code".to_string(),
+                        Reply::BulkBytes(lib.code.clone()),
+                    ));
+                }
+                Reply::Map(fields)
+            })
+            .collect();
+        Reply::Nested(libraries)
+    }
+
+    /// The code of every library, as saved in RDB files.
+    pub fn codes(&self) -> Vec<Vec<u8>> {
+        self.libraries
+            .values()
+            .map(|lib| lib.code.clone())
+            .collect()
+    }
+
+    /// FUNCTION DUMP: the libraries' code as RDB function records, followed
+    /// by the RDB version and a checksum, like a DUMP payload.
+    pub fn dump(&self, compress: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for library in self.libraries.values() {
+            rdb::encode_function(&library.code, compress, &mut out);
+        }
+        rdb::add_dump_footer(&mut out);
+        out
+    }
+}
+
+/// The library codes in a FUNCTION DUMP payload.
+pub fn parse_dump(payload: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+    let mut body =
+        rdb::check_dump_footer(payload).map_err(|_| "payload version or checksum are wrong")?;
+    let mut codes = Vec::new();
+    while !body.is_empty() {
+        let (rest, code) =
+            rdb::parse_function(body).map_err(|_| "given payload is not a valid function dump")?;
+        codes.push(code);
+        body = rest;
+    }
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(name: &str, functions: &[&str]) -> Library {
+        Library {
+            name: name.to_string(),
+            code: format!("#!lua name={}\n", name).into_bytes(),
+            proto: crate::lua::parse(b"").unwrap(),
+            functions: functions
+                .iter()
+                .map(|f| FunctionInfo {
+                    name: f.to_string(),
+                    description: None,
+                    flags: vec![],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_metadata() {
+        assert_eq!(
+            parse_metadata(b"#!lua name=mylib\nreturn 1"),
+            Ok(("mylib".to_string(), b"\nreturn 1".to_vec()))
+        );
+        assert_eq!(
+            parse_metadata(b"return 1"),
+            Err("Missing library metadata".to_string())
+        );
+        assert_eq!(
+            parse_metadata(b"#!js name=x\n"),
+            Err("Engine 'js' not found".to_string())
+        );
+        assert_eq!(
+            parse_metadata(b"#!lua foo=bar\n"),
+            Err("Invalid metadata value given: foo=bar".to_string())
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut functions = Functions::default();
+        functions.add(library("a", &["f", "g"]), false).unwrap();
+        assert_eq!(
+            functions.add(library("a", &["h"]), false),
+            Err("Library 'a' already exists".to_string())
+        );
+        assert_eq!(
+            functions.add(library("b", &["f"]), false),
+            Err("Function f already exists".to_string())
+        );
+        // replacing drops the functions the old version had
+        functions.add(library("a", &["h"]), true).unwrap();
+        assert!(functions.find("f").is_none());
+        functions.add(library("b", &["f"]), false).unwrap();
+        assert_eq!(functions.find("f").unwrap().0.name, "b");
+
+        let codes = parse_dump(&functions.dump(true)).unwrap();
+        assert_eq!(
+            codes,
+            vec![b"#!lua name=a\n".to_vec(), b"#!lua name=b\n".to_vec()]
+        );
+        assert!(parse_dump(b"short").is_err());
+        let mut corrupted = functions.dump(false);
+        corrupted[3] ^= 1;
+        assert!(parse_dump(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+
+use crate::reply::{Reply, WRONGTYPE};
+use crate::value::Value;
+use crate::zset::SortedSet;
+
+// Locations are stored in sorted sets, scored by a 52-bit geohash that
+// interleaves 26 bits of latitude (even bits) and 26 bits of longitude (odd
+// bits). Latitudes are limited to the range supported by Web Mercator.
+const GEO_STEP_MAX: u32 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoFrom {
+    Member(Vec<u8>),
+    LonLat(f64, f64),
+}
+
+/// Search area, in the unit of the query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoSort {
+    None,
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoSearch {
+    pub from: GeoFrom,
+    pub shape: GeoShape,
+    // meters per unit
+    pub unit: f64,
+    pub sort: GeoSort,
+    pub count: Option<usize>,
+    pub any: bool,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GeoHash {
+    bits: u64,
+    step: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    lon: (f64, f64),
+    lat: (f64, f64),
+}
+
+fn interleave(x: u32, y: u32) -> u64 {
+    (0..32).fold(0, |acc, i| {
+        acc | ((x as u64 >> i) & 1) << (2 * i) | ((y as u64 >> i) & 1) << (2 * i + 1)
+    })
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    (0..32).fold((0, 0), |(x, y), i| {
+        (
+            x | (((bits >> (2 * i)) & 1) as u32) << i,
+            y | (((bits >> (2 * i + 1)) & 1) as u32) << i,
+        )
+    })
+}
+
+fn encode(lon_range: (f64, f64), lat_range: (f64, f64), lon: f64, lat: f64, step: u32) -> GeoHash {
+    let lat_offset = (lat - lat_range.0) / (lat_range.1 - lat_range.0);
+    let lon_offset = (lon - lon_range.0) / (lon_range.1 - lon_range.0);
+    let cells = (1u64 << step) as f64;
+    GeoHash {
+        bits: interleave((lat_offset * cells) as u32, (lon_offset * cells) as u32),
+        step,
+    }
+}
+
+fn encode_wgs84(lon: f64, lat: f64, step: u32) -> GeoHash {
+    encode(
+        (GEO_LONG_MIN, GEO_LONG_MAX),
+        (GEO_LAT_MIN, GEO_LAT_MAX),
+        lon,
+        lat,
+        step,
+    )
+}
+
+fn decode_wgs84(hash: GeoHash) -> Area {
+    let (ilat, ilon) = deinterleave(hash.bits);
+    let cells = (1u64 << hash.step) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let lon_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+    Area {
+        lat: (
+            GEO_LAT_MIN + (ilat as f64 / cells) * lat_scale,
+            GEO_LAT_MIN + ((ilat as f64 + 1.0) / cells) * lat_scale,
+        ),
+        lon: (
+            GEO_LONG_MIN + (ilon as f64 / cells) * lon_scale,
+            GEO_LONG_MIN + ((ilon as f64 + 1.0) / cells) * lon_scale,
+        ),
+    }
+}
+
+/// Center of the area encoded by a sorted set score.
+fn score_to_lonlat(score: f64) -> (f64, f64) {
+    let area = decode_wgs84(GeoHash {
+        bits: score as u64,
+        step: GEO_STEP_MAX,
+    });
+    let lon = ((area.lon.0 + area.lon.1) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let lat = ((area.lat.0 + area.lat.1) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (lon, lat)
+}
+
+fn valid_lonlat(lon: f64, lat: f64) -> Result<(), Reply<'static>> {
+    if (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat) {
+        Ok(())
+    } else {
+        Err(Reply::ErrorCode(
+            "ERR",
+            format!("invalid longitude,latitude pair {:.6},{:.6}", lon, lat),
+        ))
+    }
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+/// Haversine distance in meters.
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lon1r) = (lat1.to_radians(), lon1.to_radians());
+    let (lat2r, lon2r) = (lat2.to_radians(), lon2.to_radians());
+    let v = ((lon2r - lon1r) / 2.0).sin();
+    // on the same meridian the distance is just the latitude difference
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn move_x(hash: GeoHash, d: i8) -> GeoHash {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step * 2);
+    let x = if d > 0 {
+        x.wrapping_add(zz + 1)
+    } else {
+        (x | zz).wrapping_sub(zz + 1)
+    };
+    let x = x & (0xaaaaaaaaaaaaaaaa >> (64 - hash.step * 2));
+    GeoHash {
+        bits: x | y,
+        step: hash.step,
+    }
+}
+
+fn move_y(hash: GeoHash, d: i8) -> GeoHash {
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step * 2);
+    let y = if d > 0 {
+        y.wrapping_add(zz + 1)
+    } else {
+        (y | zz).wrapping_sub(zz + 1)
+    };
+    let y = y & (0x5555555555555555 >> (64 - hash.step * 2));
+    GeoHash {
+        bits: x | y,
+        step: hash.step,
+    }
+}
+
+/// Coarsest geohash step whose cells are still small enough for a search
+/// of the given radius.
+fn estimate_steps_by_radius(mut range_meters: f64, lat: f64) -> u32 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    // make sure range is included in most of the base cases
+    step -= 2;
+    // cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+/// Geohash boxes that together cover the search area: the box of the
+/// center and its 8 neighbors, minus the ones that are known to be outside.
+fn covering_boxes(lon: f64, lat: f64, shape: GeoShape, unit: f64) -> Vec<GeoHash> {
+    let (width, height, radius_meters) = match shape {
+        GeoShape::Radius(r) => (r * unit, r * unit, r * unit),
+        GeoShape::Box(w, h) => {
+            let (w, h) = (w * unit / 2.0, h * unit / 2.0);
+            (w, h, (w * w + h * h).sqrt())
+        }
+    };
+    // bounding box of the search area
+    let lat_delta = (height / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top =
+        (width / EARTH_RADIUS_IN_METERS / (lat + lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta_bottom =
+        (width / EARTH_RADIUS_IN_METERS / (lat - lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta = if lat < 0.0 {
+        lon_delta_bottom
+    } else {
+        lon_delta_top
+    };
+    let (min_lon, max_lon) = (lon - lon_delta, lon + lon_delta);
+    let (min_lat, max_lat) = (lat - lat_delta, lat + lat_delta);
+
+    let neighbors = |hash: GeoHash| {
+        [
+            hash,
+            move_y(hash, 1),
+            move_y(hash, -1),
+            move_x(hash, 1),
+            move_x(hash, -1),
+            move_y(move_x(hash, 1), 1),
+            move_y(move_x(hash, -1), 1),
+            move_y(move_x(hash, 1), -1),
+            move_y(move_x(hash, -1), -1),
+        ]
+    };
+    let mut steps = estimate_steps_by_radius(radius_meters, lat);
+    let mut boxes = neighbors(encode_wgs84(lon, lat, steps));
+
+    // the neighbors may still not cover the whole area, e.g. close to the
+    // edge of a cell; retry with larger cells
+    let [north, south, east, west] = [1, 2, 3, 4].map(|i| decode_wgs84(boxes[i]));
+    let too_small = north.lat.1 < max_lat
+        || south.lat.0 > min_lat
+        || east.lon.1 < max_lon
+        || west.lon.0 > min_lon;
+    if steps > 1 && too_small {
+        steps -= 1;
+        boxes = neighbors(encode_wgs84(lon, lat, steps));
+    }
+
+    let area = decode_wgs84(boxes[0]);
+    let mut skip = [false; 9];
+    if steps >= 2 {
+        if area.lat.0 < min_lat {
+            // south, south east, south west
+            for i in [2, 7, 8] {
+                skip[i] = true;
+            }
+        }
+        if area.lat.1 > max_lat {
+            for i in [1, 5, 6] {
+                skip[i] = true;
+            }
+        }
+        if area.lon.0 < min_lon {
+            for i in [4, 6, 8] {
+                skip[i] = true;
+            }
+        }
+        if area.lon.1 > max_lon {
+            for i in [3, 5, 7] {
+                skip[i] = true;
+            }
+        }
+    }
+    let mut result: Vec<GeoHash> = Vec::new();
+    for (hash, skip) in boxes.into_iter().zip(skip) {
+        // large areas can make neighbors wrap onto each other
+        if !skip && !result.contains(&hash) {
+            result.push(hash);
+        }
+    }
+    result
+}
+
+struct GeoPoint<'a> {
+    member: &'a [u8],
+    score: f64,
+    // meters
+    dist: f64,
+    lon: f64,
+    lat: f64,
+}
+
+fn search<'a>(zset: &'a SortedSet, lon: f64, lat: f64, query: &GeoSearch) -> Vec<GeoPoint<'a>> {
+    let limit = query.count.filter(|_| query.any);
+    let mut points = Vec::new();
+    'boxes: for hash in covering_boxes(lon, lat, query.shape, query.unit) {
+        let shift = (GEO_STEP_MAX - hash.step) * 2;
+        let min = (hash.bits << shift) as f64;
+        let max = ((hash.bits + 1) << shift) as f64;
+        for (member, score) in zset.range_by_score(min, max) {
+            let (plon, plat) = score_to_lonlat(score);
+            let dist = match query.shape {
+                GeoShape::Radius(r) => {
+                    let dist = distance(lon, lat, plon, plat);
+                    if dist > r * query.unit {
+                        continue;
+                    }
+                    dist
+                }
+                GeoShape::Box(w, h) => {
+                    if lat_distance(plat, lat) > h * query.unit / 2.0
+                        || distance(plon, plat, lon, plat) > w * query.unit / 2.0
+                    {
+                        continue;
+                    }
+                    distance(lon, lat, plon, plat)
+                }
+            };
+            points.push(GeoPoint {
+                member,
+                score,
+                dist,
+                lon: plon,
+                lat: plat,
+            });
+            if limit.is_some_and(|n| points.len() >= n) {
+                break 'boxes;
+            }
+        }
+    }
+
+    // asking for the N closest results only makes sense sorted
+    let sort = match query.sort {
+        GeoSort::None if query.count.is_some() && !query.any => GeoSort::Asc,
+        sort => sort,
+    };
+    match sort {
+        GeoSort::None => {}
+        GeoSort::Asc => points.sort_by(|a, b| a.dist.total_cmp(&b.dist)),
+        GeoSort::Desc => points.sort_by(|a, b| b.dist.total_cmp(&a.dist)),
+    }
+    if let Some(count) = query.count {
+        points.truncate(count);
+    }
+    points
+}
+
+/// Formats a coordinate like Redis does: 17 decimals, trailing zeros removed.
+fn human_float(x: f64) -> String {
+    let s = format!("{:.17}", x);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn get_zset<'a>(
+    state: &'a HashMap<Vec<u8>, Value>,
+    key: &[u8],
+) -> Result<Option<&'a SortedSet>, Reply<'static>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(Value::ZSet(z)) => Ok(Some(z)),
+        Some(_) => Err(Reply::ErrorCode("WRONGTYPE", WRONGTYPE.to_string())),
+    }
+}
+
+pub fn geoadd(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    items: &[(f64, f64, Vec<u8>)],
+    nx: bool,
+    xx: bool,
+    ch: bool,
+) -> Reply<'static> {
+    for (lon, lat, _) in items {
+        if let Err(reply) = valid_lonlat(*lon, *lat) {
+            return reply;
+        }
+    }
+    if let Err(reply) = get_zset(state, key) {
+        return reply;
+    }
+    if xx && !state.contains_key(key) {
+        return Reply::Integer(0);
+    }
+    let zset = match state
+        .entry(key.to_vec())
+        .or_insert_with(|| Value::ZSet(SortedSet::new().into()))
+    {
+        Value::ZSet(z) => z,
+        _ => unreachable!(),
+    };
+    let (mut added, mut updated) = (0, 0);
+    for (lon, lat, member) in items {
+        let score = encode_wgs84(*lon, *lat, GEO_STEP_MAX).bits as f64;
+        match zset.score(member) {
+            None if !xx => {
+                zset.insert(member, score);
+                added += 1;
+            }
+            Some(old) if !nx && old != score => {
+                zset.insert(member, score);
+                updated += 1;
+            }
+            _ => {}
+        }
+    }
+    Reply::Integer(if ch { added + updated } else { added })
+}
+
+pub fn geodist(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    member1: &[u8],
+    member2: &[u8],
+    unit: f64,
+) -> Reply<'static> {
+    let zset = match get_zset(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::NullBulk,
+        Ok(Some(zset)) => zset,
+    };
+    match (zset.score(member1), zset.score(member2)) {
+        (Some(a), Some(b)) => {
+            let (lon1, lat1) = score_to_lonlat(a);
+            let (lon2, lat2) = score_to_lonlat(b);
+            Reply::Bulk(format!("{:.4}", distance(lon1, lat1, lon2, lat2) / unit))
+        }
+        _ => Reply::NullBulk,
+    }
+}
+
+pub fn geopos(state: &HashMap<Vec<u8>, Value>, key: &[u8], members: &[Vec<u8>]) -> Reply<'static> {
+    let zset = match get_zset(state, key) {
+        Err(reply) => return reply,
+        Ok(zset) => zset,
+    };
+    let replies = members
+        .iter()
+        .map(|m| match zset.and_then(|z| z.score(m)) {
+            None => Reply::NullArray,
+            Some(score) => {
+                let (lon, lat) = score_to_lonlat(score);
+                Reply::Array(vec![human_float(lon), human_float(lat)])
+            }
+        })
+        .collect();
+    Reply::Nested(replies)
+}
+
+pub fn geohash(state: &HashMap<Vec<u8>, Value>, key: &[u8], members: &[Vec<u8>]) -> Reply<'static> {
+    let zset = match get_zset(state, key) {
+        Err(reply) => return reply,
+        Ok(zset) => zset,
+    };
+    let replies = members
+        .iter()
+        .map(|m| match zset.and_then(|z| z.score(m)) {
+            None => Reply::NullBulk,
+            Some(score) => {
+                // standard geohashes use the full [-90, 90] latitude range
+                let (lon, lat) = score_to_lonlat(score);
+                let hash = encode((-180.0, 180.0), (-90.0, 90.0), lon, lat, GEO_STEP_MAX);
+                let s = (0..11)
+                    .map(|i| {
+                        let idx = if i == 10 {
+                            0
+                        } else {
+                            (hash.bits >> (52 - (i + 1) * 5)) & 0x1f
+                        };
+                        GEOHASH_ALPHABET[idx as usize] as char
+                    })
+                    .collect();
+                Reply::Bulk(s)
+            }
+        })
+        .collect();
+    Reply::Nested(replies)
+}
+
+/// Resolves the center of a search, or None if the source key is missing.
+fn search_center(
+    zset: Option<&SortedSet>,
+    from: &GeoFrom,
+) -> Result<Option<(f64, f64)>, Reply<'static>> {
+    if let GeoFrom::LonLat(lon, lat) = from {
+        valid_lonlat(*lon, *lat)?;
+    }
+    let Some(zset) = zset else {
+        return Ok(None);
+    };
+    match from {
+        GeoFrom::LonLat(lon, lat) => Ok(Some((*lon, *lat))),
+        GeoFrom::Member(member) => match zset.score(member) {
+            Some(score) => Ok(Some(score_to_lonlat(score))),
+            None => Err(Reply::Error("could not decode requested zset member")),
+        },
+    }
+}
+
+pub fn geosearch(state: &HashMap<Vec<u8>, Value>, key: &[u8], query: &GeoSearch) -> Reply<'static> {
+    let zset = match get_zset(state, key) {
+        Err(reply) => return reply,
+        Ok(zset) => zset,
+    };
+    let (zset, (lon, lat)) = match search_center(zset, &query.from) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Nested(vec![]),
+        Ok(Some(center)) => (zset.unwrap(), center),
+    };
+    let points = search(zset, lon, lat, query);
+
+    if !(query.with_dist || query.with_hash || query.with_coord) {
+        return Reply::Nested(
+            points
+                .iter()
+                .map(|p| Reply::BulkBytes(p.member.to_vec()))
+                .collect(),
+        );
+    }
+    let replies = points
+        .iter()
+        .map(|p| {
+            let mut fields = vec![Reply::BulkBytes(p.member.to_vec())];
+            if query.with_dist {
+                fields.push(Reply::Bulk(format!("{:.4}", p.dist / query.unit)));
+            }
+            if query.with_hash {
+                fields.push(Reply::Integer(p.score as i64));
+            }
+            if query.with_coord {
+                fields.push(Reply::Array(vec![human_float(p.lon), human_float(p.lat)]));
+            }
+            Reply::Nested(fields)
+        })
+        .collect();
+    Reply::Nested(replies)
+}
+
+pub fn geosearchstore(
+    state: &mut HashMap<Vec<u8>, Value>,
+    dest: &[u8],
+    key: &[u8],
+    query: &GeoSearch,
+    store_dist: bool,
+) -> Reply<'static> {
+    let zset = match get_zset(state, key) {
+        Err(reply) => return reply,
+        Ok(zset) => zset,
+    };
+    let mut result = SortedSet::new();
+    match search_center(zset, &query.from) {
+        Err(reply) => return reply,
+        Ok(None) => {}
+        Ok(Some((lon, lat))) => {
+            for p in search(zset.unwrap(), lon, lat, query) {
+                let score = if store_dist {
+                    p.dist / query.unit
+                } else {
+                    p.score
+                };
+                result.insert(p.member, score);
+            }
+        }
+    }
+    let len = result.len();
+    if result.is_empty() {
+        state.remove(dest);
+    } else {
+        state.insert(dest.to_vec(), Value::ZSet(result.into()));
+    }
+    Reply::Integer(len as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sicily() -> HashMap<Vec<u8>, Value> {
+        let mut state = HashMap::new();
+        let items = [
+            (13.361389, 38.115556, b"Palermo".to_vec()),
+            (15.087269, 37.502669, b"Catania".to_vec()),
+            (12.758489, 38.788135, b"edge1".to_vec()),
+            (17.241510, 38.788135, b"edge2".to_vec()),
+        ];
+        geoadd(&mut state, b"Sicily", &items, false, false, false);
+        state
+    }
+
+    fn query(from: GeoFrom, shape: GeoShape, unit: f64) -> GeoSearch {
+        GeoSearch {
+            from,
+            shape,
+            unit,
+            sort: GeoSort::Asc,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        }
+    }
+
+    fn strings(reply: Reply) -> Vec<String> {
+        match reply {
+            Reply::Array(v) => v,
+            Reply::Bulk(s) => vec![s],
+            Reply::BulkBytes(s) => vec![String::from_utf8(s).unwrap()],
+            Reply::Nested(v) => v.into_iter().flat_map(strings).collect(),
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn test_encode_and_positions() {
+        let state = sicily();
+        match state.get(b"Sicily".as_slice()) {
+            Some(Value::ZSet(z)) => assert_eq!(z.score(b"Palermo"), Some(3479099956230698.0)),
+            _ => panic!("expected a sorted set"),
+        }
+        let dist = geodist(&state, b"Sicily", b"Palermo", b"Catania", 1000.0);
+        assert_eq!(strings(dist), vec!["166.2742"]);
+        let pos = geopos(&state, b"Sicily", &[b"Palermo".to_vec()]);
+        assert_eq!(
+            strings(pos),
+            vec!["13.36138933897018433", "38.11555639549629859"]
+        );
+        let hash = geohash(&state, b"Sicily", &[b"Palermo".to_vec()]);
+        assert_eq!(strings(hash), vec!["sqc8b49rny0"]);
+    }
+
+    #[test]
+    fn test_search() {
+        let state = sicily();
+        let q = query(GeoFrom::LonLat(15.0, 37.0), GeoShape::Radius(200.0), 1000.0);
+        assert_eq!(
+            strings(geosearch(&state, b"Sicily", &q)),
+            vec!["Catania", "Palermo"]
+        );
+        let q = query(
+            GeoFrom::LonLat(15.0, 37.0),
+            GeoShape::Box(400.0, 400.0),
+            1000.0,
+        );
+        assert_eq!(
+            strings(geosearch(&state, b"Sicily", &q)),
+            vec!["Catania", "Palermo", "edge2", "edge1"]
+        );
+        let q = query(
+            GeoFrom::Member(b"Palermo".to_vec()),
+            GeoShape::Radius(0.0),
+            1.0,
+        );
+        assert_eq!(strings(geosearch(&state, b"Sicily", &q)), vec!["Palermo"]);
+    }
+}
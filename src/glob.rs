@@ -0,0 +1,105 @@
+// Glob-style matching with the same rules as Redis: `*`, `?`, `[abc]`,
+// `[^abc]`, `[a-z]` and `\` to escape special characters.
+
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let mut skip_longer = false;
+    match_impl(pattern, string, &mut skip_longer)
+}
+
+/// `skip_longer` is set once a `*` failed to match the rest of the string,
+/// in which case trying with shorter remainders can't succeed either.
+fn match_impl(pat: &[u8], s: &[u8], skip_longer: &mut bool) -> bool {
+    let (mut p, mut i) = (0, 0);
+    while p < pat.len() && i < s.len() {
+        match pat[p] {
+            b'*' => {
+                while p + 1 < pat.len() && pat[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pat.len() {
+                    return true;
+                }
+                while i < s.len() {
+                    if match_impl(&pat[p + 1..], &s[i..], skip_longer) {
+                        return true;
+                    }
+                    if *skip_longer {
+                        return false;
+                    }
+                    i += 1;
+                }
+                *skip_longer = true;
+                return false;
+            }
+            b'?' => i += 1,
+            b'[' => {
+                p += 1;
+                let not = pat.get(p) == Some(&b'^');
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    if p >= pat.len() {
+                        // unterminated class: stop at the end of the pattern
+                        p -= 1;
+                        break;
+                    } else if pat[p] == b'\\' && pat.len() - p >= 2 {
+                        p += 1;
+                        matched |= pat[p] == s[i];
+                    } else if pat[p] == b']' {
+                        break;
+                    } else if pat.len() - p >= 3 && pat[p + 1] == b'-' {
+                        let (start, end) = (pat[p].min(pat[p + 2]), pat[p].max(pat[p + 2]));
+                        p += 2;
+                        matched |= (start..=end).contains(&s[i]);
+                    } else {
+                        matched |= pat[p] == s[i];
+                    }
+                    p += 1;
+                }
+                if matched == not {
+                    return false;
+                }
+                i += 1;
+            }
+            c => {
+                if c == b'\\' && pat.len() - p >= 2 {
+                    p += 1;
+                }
+                if pat[p] != s[i] {
+                    return false;
+                }
+                i += 1;
+            }
+        }
+        p += 1;
+    }
+    if i == s.len() {
+        while p < pat.len() && pat[p] == b'*' {
+            p += 1;
+        }
+    }
+    p == pat.len() && i == s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let m = |p: &str, s: &str| matches(p.as_bytes(), s.as_bytes());
+        assert!(m("news.*", "news.tech"));
+        assert!(m("*", ""));
+        assert!(!m("news.*", "new"));
+        assert!(m("h?llo", "hallo"));
+        assert!(m("h[ae]llo", "hello"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-b]llo", "hbllo"));
+        assert!(m("h\\*llo", "h*llo"));
+        assert!(!m("h\\*llo", "hello"));
+        assert!(m("a*b*c", "aXXbYYc"));
+        assert!(!m("a*b*c", "aXXbYY"));
+    }
+}
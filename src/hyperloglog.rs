@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+
+use crate::reply::Reply;
+use crate::value::Value;
+
+// HyperLogLogs are string values laid out exactly like Redis does:
+//
+// "HYLL" | encoding | 3 unused bytes | cached cardinality (8 bytes, LE)
+//
+// followed by the registers. The dense encoding packs 16384 6-bit registers
+// (LSB first), while the sparse encoding is a run-length sequence of opcodes:
+//
+// ZERO:  00xxxxxx          -> xxxxxx+1 zero registers
+// XZERO: 01xxxxxx yyyyyyyy -> xxxxxxyyyyyyyy+1 zero registers
+// VAL:   1vvvvvxx          -> xx+1 registers set to vvvvv+1
+//
+// The most significant bit of the cached cardinality flags it as stale.
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + HLL_REGISTERS * HLL_BITS / 8;
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+// larger sparse representations are promoted to dense
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+const INVALID_HLL: &str = "Key is not a valid HyperLogLog string value.";
+const CORRUPTED_HLL: &str = "Corrupted HLL object detected";
+
+/// MurmurHash2, 64-bit version, by Austin Appleby (as used by Redis).
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Returns the register an element hashes to and the length of the
+/// 000..1 pattern it sets it to.
+fn pat_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, 0xadc83b19);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    // the extra bit makes sure the loop terminates
+    let hash = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(regs: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = regs[byte] as u16;
+    let b1 = regs.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> fb) | (b1 << (8 - fb))) as u8) & HLL_REGISTER_MAX
+}
+
+fn dense_set(regs: &mut [u8], index: usize, value: u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let (mask, value) = ((HLL_REGISTER_MAX as u16) << fb, (value as u16) << fb);
+    regs[byte] = (regs[byte] & !(mask as u8)) | value as u8;
+    if let Some(next) = regs.get_mut(byte + 1) {
+        *next = (*next & !((mask >> 8) as u8)) | (value >> 8) as u8;
+    }
+}
+
+/// An HLL decoded into one byte per register.
+struct Hll {
+    registers: Vec<u8>,
+    dense: bool,
+}
+
+impl Hll {
+    fn new() -> Self {
+        Hll {
+            registers: vec![0; HLL_REGISTERS],
+            dense: false,
+        }
+    }
+
+    /// Decodes and validates a string value.
+    fn decode(bytes: &[u8]) -> Result<Self, Reply<'static>> {
+        let invalid = || Reply::ErrorCode("WRONGTYPE", INVALID_HLL.to_string());
+        if bytes.len() < HLL_HDR_SIZE || &bytes[..4] != b"HYLL" {
+            return Err(invalid());
+        }
+        let body = &bytes[HLL_HDR_SIZE..];
+        match bytes[4] {
+            HLL_DENSE if bytes.len() == HLL_DENSE_SIZE => Ok(Hll {
+                registers: (0..HLL_REGISTERS).map(|i| dense_get(body, i)).collect(),
+                dense: true,
+            }),
+            HLL_SPARSE => {
+                let corrupted = || Reply::ErrorCode("INVALIDOBJ", CORRUPTED_HLL.to_string());
+                let mut registers = Vec::with_capacity(HLL_REGISTERS);
+                let mut i = 0;
+                while i < body.len() {
+                    let op = body[i];
+                    let (value, len) = if op & 0x80 != 0 {
+                        (((op >> 2) & 0x1f) + 1, (op & 0x3) as usize + 1)
+                    } else if op & 0x40 != 0 {
+                        i += 1;
+                        let low = *body.get(i).ok_or_else(corrupted)? as usize;
+                        (0, ((op as usize & 0x3f) << 8 | low) + 1)
+                    } else {
+                        (0, op as usize + 1)
+                    };
+                    if registers.len() + len > HLL_REGISTERS {
+                        return Err(corrupted());
+                    }
+                    registers.resize(registers.len() + len, value);
+                    i += 1;
+                }
+                if registers.len() != HLL_REGISTERS {
+                    return Err(corrupted());
+                }
+                Ok(Hll {
+                    registers,
+                    dense: false,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Sparse representation of the registers, or None if they can't be
+    /// represented sparsely (or not compactly enough).
+    fn encode_sparse(&self) -> Option<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut i = 0;
+        while i < HLL_REGISTERS {
+            let value = self.registers[i];
+            let run = self.registers[i..]
+                .iter()
+                .take_while(|v| **v == value)
+                .count();
+            if value > HLL_SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            let mut left = run;
+            while left > 0 {
+                let len = if value != 0 {
+                    let len = left.min(HLL_SPARSE_VAL_MAX_LEN);
+                    body.push(0x80 | (value - 1) << 2 | (len - 1) as u8);
+                    len
+                } else if left > HLL_SPARSE_ZERO_MAX_LEN {
+                    let len = left.min(HLL_SPARSE_XZERO_MAX_LEN) - 1;
+                    body.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+                    len + 1
+                } else {
+                    body.push((left - 1) as u8);
+                    left
+                };
+                left -= len;
+            }
+            if HLL_HDR_SIZE + body.len() > HLL_SPARSE_MAX_BYTES {
+                return None;
+            }
+            i += run;
+        }
+        Some(body)
+    }
+
+    /// Encodes the registers, sparsely if possible unless `dense` is set,
+    /// with a stale cached cardinality.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = b"HYLL".to_vec();
+        let sparse = if self.dense {
+            None
+        } else {
+            self.encode_sparse()
+        };
+        bytes.push(if sparse.is_some() {
+            HLL_SPARSE
+        } else {
+            HLL_DENSE
+        });
+        bytes.extend_from_slice(&[0; 3]);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x80]);
+        match sparse {
+            Some(body) => bytes.extend(body),
+            None => {
+                bytes.resize(HLL_DENSE_SIZE, 0);
+                for (i, value) in self.registers.iter().enumerate() {
+                    dense_set(&mut bytes[HLL_HDR_SIZE..], i, *value);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Returns whether the element changed a register.
+    fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = pat_len(element);
+        if self.registers[index] < count {
+            self.registers[index] = count;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn merge(&mut self, other: &Hll) {
+        for (a, b) in self.registers.iter_mut().zip(&other.registers) {
+            *a = (*a).max(*b);
+        }
+        self.dense |= other.dense;
+    }
+
+    /// Cardinality estimation from "New cardinality estimation algorithms
+    /// for HyperLogLog sketches" (Otmar Ertl), as implemented by Redis.
+    fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; 64];
+        for value in &self.registers {
+            histogram[*value as usize] += 1;
+        }
+        let mut z = m * tau((m - histogram[HLL_Q as usize + 1] as f64) / m);
+        for j in (1..=HLL_Q as usize).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (HLL_ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if prev == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Decodes the HLL stored at `key`, if any.
+fn get_hll(state: &HashMap<Vec<u8>, Value>, key: &[u8]) -> Result<Option<Hll>, Reply<'static>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(Value::String(bytes)) => Hll::decode(bytes).map(Some),
+        Some(_) => Err(Reply::ErrorCode("WRONGTYPE", INVALID_HLL.to_string())),
+    }
+}
+
+pub fn pfadd(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    elements: &[Vec<u8>],
+) -> Reply<'static> {
+    let (mut hll, mut changed) = match get_hll(state, key) {
+        Err(reply) => return reply,
+        Ok(Some(hll)) => (hll, false),
+        Ok(None) => (Hll::new(), true),
+    };
+    for element in elements {
+        changed |= hll.add(element);
+    }
+    if changed {
+        state.insert(key.to_vec(), Value::String(hll.encode().into()));
+    }
+    Reply::Integer(changed as i64)
+}
+
+pub fn pfcount(state: &mut HashMap<Vec<u8>, Value>, keys: &[Vec<u8>]) -> Reply<'static> {
+    if let [key] = keys {
+        let bytes = match state.get_mut(key) {
+            None => return Reply::Integer(0),
+            Some(Value::String(bytes)) => bytes,
+            Some(_) => return Reply::ErrorCode("WRONGTYPE", INVALID_HLL.to_string()),
+        };
+        let hll = match Hll::decode(bytes) {
+            Err(reply) => return reply,
+            Ok(hll) => hll,
+        };
+        if bytes[15] & 0x80 == 0 {
+            return Reply::Integer(u64::from_le_bytes(bytes[8..16].try_into().unwrap()) as i64);
+        }
+        let count = hll.count();
+        bytes[8..16].copy_from_slice(&count.to_le_bytes());
+        return Reply::Integer(count as i64);
+    }
+
+    let mut merged = Hll::new();
+    for key in keys {
+        match get_hll(state, key) {
+            Err(reply) => return reply,
+            Ok(Some(hll)) => merged.merge(&hll),
+            Ok(None) => {}
+        }
+    }
+    Reply::Integer(merged.count() as i64)
+}
+
+pub fn pfmerge(
+    state: &mut HashMap<Vec<u8>, Value>,
+    dest: &[u8],
+    sources: &[Vec<u8>],
+) -> Reply<'static> {
+    let mut merged = Hll::new();
+    for key in std::iter::once(dest).chain(sources.iter().map(Vec::as_slice)) {
+        match get_hll(state, key) {
+            Err(reply) => return reply,
+            Ok(Some(hll)) => merged.merge(&hll),
+            Ok(None) => {}
+        }
+    }
+    state.insert(dest.to_vec(), Value::String(merged.encode().into()));
+    Reply::Simple("OK".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmurhash64a() {
+        let hashes: Vec<u64> = ["", "a", "hello", "hello world!", "0123456789abcdefXYZ"]
+            .iter()
+            .map(|s| murmurhash64a(s.as_bytes(), 0xadc83b19))
+            .collect();
+        assert_eq!(
+            hashes,
+            vec![
+                15627466953755236146,
+                6039968161137406375,
+                1109414937308947456,
+                1136107777615667724,
+                216464458254671902
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sparse_encoding() {
+        let mut hll = Hll::new();
+        assert_eq!(hll.encode_sparse(), Some(vec![0x7f, 0xff]));
+        hll.registers[1] = 3;
+        hll.registers[2] = 3;
+        let body = hll.encode_sparse().unwrap();
+        assert_eq!(body, vec![0x00, 0x89, 0x7f, 0xfc]);
+        let decoded = Hll::decode(&hll.encode()).unwrap();
+        assert_eq!(decoded.registers, hll.registers);
+        hll.registers[100] = 33;
+        assert_eq!(hll.encode_sparse(), None);
+    }
+
+    #[test]
+    fn test_dense_registers() {
+        let mut regs = vec![0; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        for i in 0..HLL_REGISTERS {
+            dense_set(&mut regs, i, (i % 64) as u8);
+        }
+        for i in 0..HLL_REGISTERS {
+            assert_eq!(dense_get(&regs, i), (i % 64) as u8);
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        let mut state = HashMap::new();
+        let elements: Vec<Vec<u8>> = (0..1000).map(|i| format!("el{}", i).into_bytes()).collect();
+        assert_eq!(integer(pfadd(&mut state, b"a", &elements)), 1);
+        assert_eq!(integer(pfadd(&mut state, b"a", &elements[..10])), 0);
+        let count = integer(pfcount(&mut state, &[b"a".to_vec()]));
+        assert!((980..=1020).contains(&count), "{}", count);
+        // the cached value is used from now on
+        assert_eq!(integer(pfcount(&mut state, &[b"a".to_vec()])), count);
+
+        let more: Vec<Vec<u8>> = (0..20000).map(|i| format!("x{}", i).into_bytes()).collect();
+        pfadd(&mut state, b"b", &more);
+        assert!(matches!(state.get(b"b".as_slice()), Some(Value::String(b)) if b[4] == HLL_DENSE));
+        pfmerge(&mut state, b"c", &[b"a".to_vec(), b"b".to_vec()]);
+        let count = integer(pfcount(&mut state, &[b"c".to_vec()]));
+        assert!((20500..=21500).contains(&count), "{}", count);
+    }
+
+    fn integer(reply: Reply) -> i64 {
+        match reply {
+            Reply::Integer(i) => i,
+            _ => panic!("expected an integer reply"),
+        }
+    }
+}
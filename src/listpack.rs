@@ -0,0 +1,452 @@
+// Listpack: a compact, serialized list of strings and integers, byte-compatible
+// with the format Redis uses for stream nodes (and, in RDB files, for small
+// hashes, sets and sorted sets).
+//
+// <total-bytes:u32le> <num-elements:u16le> <entry> ... <entry> <0xFF>
+//
+// Every entry is <encoding+data> <backlen>, where backlen is the length of the
+// encoding+data part, stored so it can be read right to left.
+
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xFF;
+const UNKNOWN_COUNT: u16 = u16::MAX;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Elem<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+impl<'a> Elem<'a> {
+    pub fn to_vec(self) -> Vec<u8> {
+        match self {
+            Elem::Int(i) => i.to_string().into_bytes(),
+            Elem::Str(s) => s.to_vec(),
+        }
+    }
+
+    pub fn as_int(self) -> Option<i64> {
+        match self {
+            Elem::Int(i) => Some(i),
+            Elem::Str(s) => string_to_i64(s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Listpack {
+    buf: Vec<u8>,
+}
+
+impl Default for Listpack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(unused)]
+impl Listpack {
+    pub fn new() -> Self {
+        let mut buf = vec![0; HEADER_SIZE];
+        buf.push(EOF);
+        let mut lp = Listpack { buf };
+        lp.set_header(0);
+        lp
+    }
+
+    /// Wraps a serialized listpack, checking that every entry can be walked
+    /// without running past the buffer.
+    pub fn from_bytes(buf: Vec<u8>) -> Option<Self> {
+        if buf.len() < HEADER_SIZE + 1 {
+            return None;
+        }
+        let total = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        if total != buf.len() || buf[buf.len() - 1] != EOF {
+            return None;
+        }
+        let lp = Listpack { buf };
+        let mut count = 0usize;
+        let mut off = HEADER_SIZE;
+        while lp.buf[off] != EOF {
+            let (_, len) = lp.decode_checked(off)?;
+            let backlen = backlen_size(len);
+            if off + len + backlen >= lp.buf.len()
+                || decode_backlen(&lp.buf[..off + len + backlen]) != len
+            {
+                return None;
+            }
+            off += len + backlen;
+            count += 1;
+        }
+        let header = u16::from_le_bytes([lp.buf[4], lp.buf[5]]);
+        if header != UNKNOWN_COUNT && header as usize != count {
+            return None;
+        }
+        Some(lp)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn len(&self) -> usize {
+        let header = u16::from_le_bytes([self.buf[4], self.buf[5]]);
+        if header != UNKNOWN_COUNT {
+            return header as usize;
+        }
+        let mut count = 0;
+        let mut cur = self.first();
+        while let Some(off) = cur {
+            count += 1;
+            cur = self.next(off);
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf[HEADER_SIZE] == EOF
+    }
+
+    /// Appends a string, storing it as an integer when it is the canonical
+    /// representation of one (as Redis does).
+    pub fn append(&mut self, s: &[u8]) {
+        match string_to_i64(s) {
+            Some(i) => self.append_int(i),
+            None => self.insert_raw(self.buf.len() - 1, encode_str(s)),
+        }
+    }
+
+    pub fn append_int(&mut self, i: i64) {
+        self.insert_raw(self.buf.len() - 1, encode_int(i));
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        if self.buf[HEADER_SIZE] == EOF {
+            None
+        } else {
+            Some(HEADER_SIZE)
+        }
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.prev(self.buf.len() - 1)
+    }
+
+    pub fn next(&self, off: usize) -> Option<usize> {
+        let len = self.entry_len(off);
+        let next = off + len + backlen_size(len);
+        if self.buf[next] == EOF {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    pub fn prev(&self, off: usize) -> Option<usize> {
+        if off <= HEADER_SIZE {
+            return None;
+        }
+        let len = decode_backlen(&self.buf[..off]);
+        Some(off - backlen_size(len) - len)
+    }
+
+    pub fn get(&self, off: usize) -> Elem<'_> {
+        self.decode_checked(off).expect("corrupted listpack").0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Elem<'_>> {
+        let mut cur = self.first();
+        std::iter::from_fn(move || {
+            let off = cur?;
+            cur = self.next(off);
+            Some(self.get(off))
+        })
+    }
+
+    /// Replaces the element at `off`; returns the offset of the element that
+    /// follows the replaced one (if any).
+    pub fn replace(&mut self, off: usize, elem: Elem) -> Option<usize> {
+        let old_len = self.entry_len(off);
+        let old_total = old_len + backlen_size(old_len);
+        let encoded = match elem {
+            Elem::Int(i) => encode_int(i),
+            Elem::Str(s) => match string_to_i64(s) {
+                Some(i) => encode_int(i),
+                None => encode_str(s),
+            },
+        };
+        let entry = with_backlen(encoded);
+        let new_total = entry.len();
+        self.buf.splice(off..off + old_total, entry);
+        self.set_header(self.header_count());
+        if self.buf[off + new_total] == EOF {
+            None
+        } else {
+            Some(off + new_total)
+        }
+    }
+
+    /// Removes `n` elements starting at `off`.
+    pub fn delete_range(&mut self, off: usize, n: usize) {
+        let mut end = off;
+        let mut removed = 0;
+        while removed < n && self.buf[end] != EOF {
+            let len = self.entry_len(end);
+            end += len + backlen_size(len);
+            removed += 1;
+        }
+        self.buf.drain(off..end);
+        let count = self.header_count().map(|c| c - removed);
+        self.set_header(count);
+    }
+
+    fn insert_raw(&mut self, off: usize, encoded: Vec<u8>) {
+        let entry = with_backlen(encoded);
+        self.buf.splice(off..off, entry);
+        let count = self.header_count().map(|c| c + 1);
+        self.set_header(count);
+    }
+
+    fn header_count(&self) -> Option<usize> {
+        let header = u16::from_le_bytes([self.buf[4], self.buf[5]]);
+        if header == UNKNOWN_COUNT {
+            None
+        } else {
+            Some(header as usize)
+        }
+    }
+
+    fn set_header(&mut self, count: impl Into<Option<usize>>) {
+        let total = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&total.to_le_bytes());
+        let count = match count.into() {
+            Some(c) if c < UNKNOWN_COUNT as usize => c as u16,
+            _ => UNKNOWN_COUNT,
+        };
+        self.buf[4..6].copy_from_slice(&count.to_le_bytes());
+    }
+
+    fn entry_len(&self, off: usize) -> usize {
+        self.decode_checked(off).expect("corrupted listpack").1
+    }
+
+    /// Decodes the element at `off`, returning it together with the size of
+    /// its encoding+data part, or `None` if it does not fit in the buffer.
+    fn decode_checked(&self, off: usize) -> Option<(Elem<'_>, usize)> {
+        let buf = &self.buf;
+        let b = *buf.get(off)?;
+        let int = |n: usize| -> Option<u64> {
+            let bytes = buf.get(off + 1..off + 1 + n)?;
+            let mut v = 0u64;
+            for (i, byte) in bytes.iter().enumerate() {
+                v |= (*byte as u64) << (8 * i);
+            }
+            Some(v)
+        };
+        let s =
+            |start: usize, len: usize| -> Option<&[u8]> { buf.get(off + start..off + start + len) };
+
+        if b & 0x80 == 0 {
+            Some((Elem::Int((b & 0x7F) as i64), 1))
+        } else if b & 0xC0 == 0x80 {
+            let len = (b & 0x3F) as usize;
+            Some((Elem::Str(s(1, len)?), 1 + len))
+        } else if b & 0xE0 == 0xC0 {
+            let v = (((b & 0x1F) as u64) << 8) | *buf.get(off + 1)? as u64;
+            Some((Elem::Int(sign_extend(v, 13)), 2))
+        } else if b & 0xF0 == 0xE0 {
+            let len = (((b & 0x0F) as usize) << 8) | *buf.get(off + 1)? as usize;
+            Some((Elem::Str(s(2, len)?), 2 + len))
+        } else {
+            match b {
+                0xF0 => {
+                    let len = int(4)? as usize;
+                    Some((Elem::Str(s(5, len)?), 5 + len))
+                }
+                0xF1 => Some((Elem::Int(sign_extend(int(2)?, 16)), 3)),
+                0xF2 => Some((Elem::Int(sign_extend(int(3)?, 24)), 4)),
+                0xF3 => Some((Elem::Int(sign_extend(int(4)?, 32)), 5)),
+                0xF4 => Some((Elem::Int(int(8)? as i64), 9)),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn sign_extend(v: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((v << shift) as i64) >> shift
+}
+
+fn encode_int(v: i64) -> Vec<u8> {
+    if (0..=127).contains(&v) {
+        vec![v as u8]
+    } else if (-4096..=4095).contains(&v) {
+        let u = (v as u64) & 0x1FFF;
+        vec![((u >> 8) as u8) | 0xC0, (u & 0xFF) as u8]
+    } else if (-32768..=32767).contains(&v) {
+        let mut out = vec![0xF1];
+        out.extend_from_slice(&(v as i16).to_le_bytes());
+        out
+    } else if (-8388608..=8388607).contains(&v) {
+        let mut out = vec![0xF2];
+        out.extend_from_slice(&(v as i32).to_le_bytes()[..3]);
+        out
+    } else if (i32::MIN as i64..=i32::MAX as i64).contains(&v) {
+        let mut out = vec![0xF3];
+        out.extend_from_slice(&(v as i32).to_le_bytes());
+        out
+    } else {
+        let mut out = vec![0xF4];
+        out.extend_from_slice(&v.to_le_bytes());
+        out
+    }
+}
+
+fn encode_str(s: &[u8]) -> Vec<u8> {
+    let len = s.len();
+    let mut out = if len < 64 {
+        vec![0x80 | len as u8]
+    } else if len < 4096 {
+        vec![((len >> 8) as u8) | 0xE0, (len & 0xFF) as u8]
+    } else {
+        let mut out = vec![0xF0];
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        out
+    };
+    out.extend_from_slice(s);
+    out
+}
+
+fn with_backlen(mut encoded: Vec<u8>) -> Vec<u8> {
+    let l = encoded.len();
+    let backlen: Vec<u8> = if l <= 127 {
+        vec![l as u8]
+    } else if l < 16383 {
+        vec![(l >> 7) as u8, (l & 127) as u8 | 128]
+    } else if l < 2097151 {
+        vec![
+            (l >> 14) as u8,
+            ((l >> 7) & 127) as u8 | 128,
+            (l & 127) as u8 | 128,
+        ]
+    } else if l < 268435455 {
+        vec![
+            (l >> 21) as u8,
+            ((l >> 14) & 127) as u8 | 128,
+            ((l >> 7) & 127) as u8 | 128,
+            (l & 127) as u8 | 128,
+        ]
+    } else {
+        vec![
+            (l >> 28) as u8,
+            ((l >> 21) & 127) as u8 | 128,
+            ((l >> 14) & 127) as u8 | 128,
+            ((l >> 7) & 127) as u8 | 128,
+            (l & 127) as u8 | 128,
+        ]
+    };
+    encoded.extend(backlen);
+    encoded
+}
+
+fn backlen_size(l: usize) -> usize {
+    match l {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// Decodes the backlen that ends right before `buf.len()`.
+fn decode_backlen(buf: &[u8]) -> usize {
+    let mut val = 0usize;
+    let mut shift = 0;
+    for &b in buf.iter().rev().take(5) {
+        val |= ((b & 127) as usize) << shift;
+        if b & 128 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    val
+}
+
+/// Strict string to integer conversion: only canonical representations
+/// ("-12", not "+12", "012" or " 12") are accepted.
+pub fn string_to_i64(s: &[u8]) -> Option<i64> {
+    if s.is_empty() || s.len() > 20 {
+        return None;
+    }
+    if s == b"0" {
+        return Some(0);
+    }
+    let digits = s.strip_prefix(b"-").unwrap_or(s);
+    if digits.is_empty() || digits[0] == b'0' || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_iterate() {
+        let mut lp = Listpack::new();
+        lp.append(b"field");
+        lp.append(b"12");
+        lp.append(b"-5000");
+        lp.append(b"012");
+        lp.append_int(i64::MAX);
+        assert_eq!(lp.len(), 5);
+        let elems: Vec<Elem> = lp.iter().collect();
+        assert_eq!(
+            elems,
+            vec![
+                Elem::Str(b"field"),
+                Elem::Int(12),
+                Elem::Int(-5000),
+                Elem::Str(b"012"),
+                Elem::Int(i64::MAX)
+            ]
+        );
+        assert_eq!(lp.get(lp.last().unwrap()), Elem::Int(i64::MAX));
+        assert!(Listpack::from_bytes(lp.as_bytes().to_vec()).is_some());
+    }
+
+    #[test]
+    fn test_backward_walk_with_long_string() {
+        let mut lp = Listpack::new();
+        let long = vec![b'x'; 5000];
+        lp.append(b"a");
+        lp.append(&long);
+        lp.append(b"b");
+        let mut off = lp.last().unwrap();
+        assert_eq!(lp.get(off), Elem::Str(b"b"));
+        off = lp.prev(off).unwrap();
+        assert_eq!(lp.get(off), Elem::Str(&long));
+        off = lp.prev(off).unwrap();
+        assert_eq!(lp.get(off), Elem::Str(b"a"));
+        assert_eq!(lp.prev(off), None);
+    }
+
+    #[test]
+    fn test_replace_grows_entry() {
+        let mut lp = Listpack::new();
+        lp.append_int(127);
+        lp.append(b"tail");
+        let first = lp.first().unwrap();
+        let next = lp.replace(first, Elem::Int(128)).unwrap();
+        assert_eq!(lp.get(first), Elem::Int(128));
+        assert_eq!(lp.get(next), Elem::Str(b"tail"));
+        assert_eq!(lp.bytes(), lp.as_bytes().len());
+    }
+}
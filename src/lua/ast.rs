@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+/// A compiled function. Variables are resolved while parsing: locals live in
+/// numbered slots of the function's frame, captured variables in upvalues,
+/// and anything else is a global.
+#[derive(Debug)]
+pub struct Proto {
+    pub params: Vec<usize>,
+    pub is_vararg: bool,
+    pub slots: usize,
+    pub upvalues: Vec<Upvalue>,
+    pub body: Block,
+}
+
+/// Where a closure finds a captured variable when it is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upvalue {
+    /// A local slot of the enclosing function.
+    Local(usize),
+    /// An upvalue of the enclosing function.
+    Outer(usize),
+}
+
+pub type Block = Vec<Stat>;
+
+#[derive(Debug)]
+pub struct Stat {
+    pub line: u32,
+    pub kind: StatKind,
+}
+
+#[derive(Debug)]
+pub enum StatKind {
+    Expr(Expr),
+    Local(Vec<usize>, Vec<Expr>),
+    /// `local function`: the variable exists before the closure captures it.
+    LocalFunction(usize, Expr),
+    Assign(Vec<Expr>, Vec<Expr>),
+    Do(Block),
+    While(Expr, Block),
+    Repeat(Block, Expr),
+    If(Vec<(Expr, Block)>, Option<Block>),
+    NumericFor {
+        var: usize,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    GenericFor {
+        vars: Vec<usize>,
+        exprs: Vec<Expr>,
+        body: Block,
+    },
+    Return(Vec<Expr>),
+    Break,
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    Nil,
+    True,
+    False,
+    Number(f64),
+    Str(Vec<u8>),
+    Vararg,
+    Function(Arc<Proto>),
+    Local(usize),
+    Upvalue(usize),
+    Global(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Table(Vec<Field>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    /// A parenthesized expression, truncated to a single value.
+    Paren(Box<Expr>),
+}
+
+impl Expr {
+    /// Whether the expression can produce several values.
+    pub fn is_multi(&self) -> bool {
+        matches!(self, Expr::Call(..) | Expr::Method(..) | Expr::Vararg)
+    }
+}
+
+#[derive(Debug)]
+pub enum Field {
+    Positional(Expr),
+    Named(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+}
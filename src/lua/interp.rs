@@ -0,0 +1,679 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::ast::{BinOp, Block, Expr, Field, Proto, StatKind, UnOp, Upvalue};
+use super::value::{Closure, Function, LuaError, LuaResult, Table, TableRef, Value};
+
+/// Nested calls beyond this fail with "stack overflow".
+const MAX_DEPTH: usize = 200;
+
+/// The tree is walked recursively, a native call for each block and
+/// expression evaluated within another. Nesting beyond this, across all
+/// calls, fails with "stack overflow" too, well before running out of the
+/// `STACK_SIZE` scripts run with.
+const MAX_LEVELS: usize = 2000;
+
+/// Lets Lua code run commands against the server, see `redis.call`.
+pub type Host<'h> = dyn FnMut(Vec<Vec<u8>>) -> Value + 'h;
+
+pub struct Interp<'h> {
+    pub globals: TableRef,
+    /// Indexing a string looks up this table, enabling `s:upper()`.
+    pub string_lib: Option<TableRef>,
+    /// Prefixed to error positions, e.g. "user_script:1: ...".
+    pub chunkname: String,
+    pub host: Option<&'h mut Host<'h>>,
+    /// Checked periodically; once set the script is aborted.
+    pub kill: Option<&'h AtomicBool>,
+    pub line: u32,
+    depth: usize,
+    levels: usize,
+    steps: u64,
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+struct Frame<'c> {
+    slots: Vec<Rc<RefCell<Value>>>,
+    varargs: Vec<Value>,
+    upvalues: &'c [Rc<RefCell<Value>>],
+}
+
+impl Frame<'_> {
+    fn get(&self, slot: usize) -> Value {
+        self.slots[slot].borrow().clone()
+    }
+
+    fn set(&self, slot: usize, value: Value) {
+        *self.slots[slot].borrow_mut() = value;
+    }
+
+    /// Starts a new variable in `slot`. Closures that captured the previous
+    /// one (e.g. in an earlier loop iteration) keep it.
+    fn declare(&mut self, slot: usize, value: Value) {
+        if Rc::strong_count(&self.slots[slot]) == 1 {
+            self.set(slot, value);
+        } else {
+            self.slots[slot] = Rc::new(RefCell::new(value));
+        }
+    }
+}
+
+/// How an expression is named in error messages, e.g. "global 'x'".
+fn describe(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Global(name) => Some(format!("global '{}'", name)),
+        Expr::Index(_, key) => match &**key {
+            Expr::Str(s) => Some(format!("field '{}'", String::from_utf8_lossy(s))),
+            _ => None,
+        },
+        Expr::Method(_, name, _) => Some(format!("method '{}'", name)),
+        _ => None,
+    }
+}
+
+impl<'h> Interp<'h> {
+    pub fn new(chunkname: &str) -> Self {
+        Interp {
+            globals: Rc::new(RefCell::new(Table::default())),
+            string_lib: None,
+            chunkname: chunkname.to_string(),
+            host: None,
+            kill: None,
+            line: 0,
+            depth: 0,
+            levels: 0,
+            steps: 0,
+        }
+    }
+
+    /// An error at the current position.
+    pub fn error(&self, msg: impl AsRef<str>) -> LuaError {
+        LuaError::Error(Value::str(format!(
+            "{}:{}: {}",
+            self.chunkname,
+            self.line,
+            msg.as_ref()
+        )))
+    }
+
+    /// Wraps the main function of a chunk.
+    pub fn load(&self, proto: Arc<Proto>) -> Value {
+        Value::Function(Rc::new(Function::Lua(Closure {
+            proto,
+            upvalues: vec![],
+        })))
+    }
+
+    pub fn call(&mut self, func: &Value, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        match func {
+            Value::Function(f) => match &**f {
+                Function::Lua(closure) => self.call_lua(closure, args),
+                Function::Native(native) => (native.f)(self, native, args),
+            },
+            Value::Table(t) => {
+                let handler = t
+                    .borrow()
+                    .metatable
+                    .as_ref()
+                    .map(|m| m.borrow().get_str("__call"));
+                match handler {
+                    Some(handler @ Value::Function(_)) => {
+                        let mut full = vec![func.clone()];
+                        full.extend(args);
+                        self.call(&handler, full)
+                    }
+                    _ => Err(self.error("attempt to call a table value")),
+                }
+            }
+            other => Err(self.error(format!("attempt to call a {} value", other.type_name()))),
+        }
+    }
+
+    fn call_lua(&mut self, closure: &Closure, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("stack overflow"));
+        }
+        let proto = &closure.proto;
+        let mut frame = Frame {
+            slots: (0..proto.slots)
+                .map(|_| Rc::new(RefCell::new(Value::Nil)))
+                .collect(),
+            varargs: vec![],
+            upvalues: &closure.upvalues,
+        };
+        if proto.is_vararg && args.len() > proto.params.len() {
+            frame.varargs = args.split_off(proto.params.len());
+        }
+        let mut args = args.into_iter();
+        for &slot in &proto.params {
+            frame.set(slot, args.next().unwrap_or_default());
+        }
+        let line = self.line;
+        self.depth += 1;
+        let result = self.exec_block(&mut frame, &proto.body);
+        self.depth -= 1;
+        // on errors `line` keeps pointing at where they were raised
+        let flow = result?;
+        self.line = line;
+        match flow {
+            Flow::Return(values) => Ok(values),
+            Flow::Normal | Flow::Break => Ok(vec![]),
+        }
+    }
+
+    fn tick(&mut self) -> LuaResult<()> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(1024) && self.kill.is_some_and(|k| k.load(Ordering::Relaxed)) {
+            return Err(LuaError::Abort(
+                "Script killed by user with SCRIPT KILL...".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs `f` one level down.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> LuaResult<T>) -> LuaResult<T> {
+        if self.levels >= MAX_LEVELS {
+            return Err(self.error("stack overflow"));
+        }
+        self.levels += 1;
+        let result = f(self);
+        self.levels -= 1;
+        result
+    }
+
+    fn exec_block(&mut self, frame: &mut Frame, block: &Block) -> LuaResult<Flow> {
+        self.nested(|interp| interp.exec_statements(frame, block))
+    }
+
+    fn exec_statements(&mut self, frame: &mut Frame, block: &Block) -> LuaResult<Flow> {
+        for stat in block {
+            self.line = stat.line;
+            self.tick()?;
+            match &stat.kind {
+                StatKind::Expr(expr) => {
+                    self.eval_multi(frame, expr)?;
+                }
+                StatKind::Local(slots, exprs) => {
+                    let mut values = self.eval_list(frame, exprs)?.into_iter();
+                    for &slot in slots {
+                        frame.declare(slot, values.next().unwrap_or_default());
+                    }
+                }
+                StatKind::LocalFunction(slot, function) => {
+                    frame.declare(*slot, Value::Nil);
+                    let function = self.eval(frame, function)?;
+                    frame.set(*slot, function);
+                }
+                StatKind::Assign(targets, exprs) => self.assign(frame, targets, exprs)?,
+                StatKind::Do(body) => match self.exec_block(frame, body)? {
+                    Flow::Normal => {}
+                    flow => return Ok(flow),
+                },
+                StatKind::While(cond, body) => {
+                    while self.eval(frame, cond)?.truthy() {
+                        self.tick()?;
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            Flow::Break => break,
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                StatKind::Repeat(body, cond) => loop {
+                    self.tick()?;
+                    match self.exec_block(frame, body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                    if self.eval(frame, cond)?.truthy() {
+                        break;
+                    }
+                },
+                StatKind::If(branches, otherwise) => {
+                    let mut taken = None;
+                    for (cond, body) in branches {
+                        if self.eval(frame, cond)?.truthy() {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    if let Some(body) = taken.or(otherwise.as_ref()) {
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                StatKind::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    body,
+                } => {
+                    let number = |interp: &mut Self, v: Value, what: &str| {
+                        v.to_number()
+                            .ok_or_else(|| interp.error(format!("'for' {} must be a number", what)))
+                    };
+                    let value = self.eval(frame, start)?;
+                    let mut i = number(self, value, "initial value")?;
+                    let value = self.eval(frame, limit)?;
+                    let limit = number(self, value, "limit")?;
+                    let step = match step {
+                        Some(step) => {
+                            let value = self.eval(frame, step)?;
+                            number(self, value, "step")?
+                        }
+                        None => 1.0,
+                    };
+                    while (step > 0.0 && i <= limit) || (step <= 0.0 && i >= limit) {
+                        frame.declare(*var, Value::Number(i));
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            Flow::Break => break,
+                            flow => return Ok(flow),
+                        }
+                        self.tick()?;
+                        i += step;
+                    }
+                }
+                StatKind::GenericFor { vars, exprs, body } => {
+                    let mut values = self.eval_list(frame, exprs)?.into_iter();
+                    let func = values.next().unwrap_or_default();
+                    let state = values.next().unwrap_or_default();
+                    let mut control = values.next().unwrap_or_default();
+                    loop {
+                        self.tick()?;
+                        let mut results = self
+                            .call(&func, vec![state.clone(), control.clone()])?
+                            .into_iter();
+                        let first = results.next().unwrap_or_default();
+                        if first.is_nil() {
+                            break;
+                        }
+                        control = first.clone();
+                        frame.declare(vars[0], first);
+                        for &slot in &vars[1..] {
+                            frame.declare(slot, results.next().unwrap_or_default());
+                        }
+                        match self.exec_block(frame, body)? {
+                            Flow::Normal => {}
+                            Flow::Break => break,
+                            flow => return Ok(flow),
+                        }
+                    }
+                }
+                StatKind::Return(exprs) => {
+                    let values = self.eval_list(frame, exprs)?;
+                    return Ok(Flow::Return(values));
+                }
+                StatKind::Break => return Ok(Flow::Break),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn assign(&mut self, frame: &mut Frame, targets: &[Expr], exprs: &[Expr]) -> LuaResult<()> {
+        if let ([target], [expr]) = (targets, exprs) {
+            let value = self.eval(frame, expr)?;
+            return self.assign_to(frame, target, value);
+        }
+        let mut values = self.eval_list(frame, exprs)?.into_iter();
+        for target in targets {
+            let value = values.next().unwrap_or_default();
+            self.assign_to(frame, target, value)?;
+        }
+        Ok(())
+    }
+
+    fn assign_to(&mut self, frame: &mut Frame, target: &Expr, value: Value) -> LuaResult<()> {
+        match target {
+            Expr::Local(slot) => frame.set(*slot, value),
+            Expr::Upvalue(i) => *frame.upvalues[*i].borrow_mut() = value,
+            Expr::Global(name) => {
+                let globals = Value::Table(Rc::clone(&self.globals));
+                self.set_index(&globals, Value::str(name), value)?;
+            }
+            Expr::Index(obj, key) => {
+                let obj_value = self.eval(frame, obj)?;
+                let key = self.eval(frame, key)?;
+                if !matches!(obj_value, Value::Table(_)) {
+                    return Err(self.index_error(obj, &obj_value));
+                }
+                self.set_index(&obj_value, key, value)?;
+            }
+            _ => unreachable!("the parser only accepts assignable targets"),
+        }
+        Ok(())
+    }
+
+    fn index_error(&self, expr: &Expr, value: &Value) -> LuaError {
+        match describe(expr) {
+            Some(name) => self.error(format!(
+                "attempt to index {} (a {} value)",
+                name,
+                value.type_name()
+            )),
+            None => self.error(format!("attempt to index a {} value", value.type_name())),
+        }
+    }
+
+    /// `obj[key]`, honoring `__index`.
+    pub fn index(&mut self, obj: &Value, key: &Value) -> LuaResult<Value> {
+        let mut obj = obj.clone();
+        for _ in 0..100 {
+            let handler = match &obj {
+                Value::Table(t) => {
+                    let t = t.borrow();
+                    let value = t.get(key);
+                    if !value.is_nil() {
+                        return Ok(value);
+                    }
+                    match &t.metatable {
+                        Some(meta) => meta.borrow().get_str("__index"),
+                        None => return Ok(Value::Nil),
+                    }
+                }
+                Value::Str(_) => {
+                    return Ok(match &self.string_lib {
+                        Some(lib) => lib.borrow().get(key),
+                        None => Value::Nil,
+                    })
+                }
+                other => {
+                    return Err(
+                        self.error(format!("attempt to index a {} value", other.type_name()))
+                    )
+                }
+            };
+            match handler {
+                Value::Nil => return Ok(Value::Nil),
+                Value::Function(_) => {
+                    let results = self.call(&handler, vec![obj.clone(), key.clone()])?;
+                    return Ok(results.into_iter().next().unwrap_or_default());
+                }
+                next => obj = next,
+            }
+        }
+        Err(self.error("loop in gettable"))
+    }
+
+    /// `obj[key] = value`, honoring `__newindex` and read-only tables.
+    pub fn set_index(&mut self, obj: &Value, key: Value, value: Value) -> LuaResult<()> {
+        let Value::Table(t) = obj else {
+            return Err(self.error(format!("attempt to index a {} value", obj.type_name())));
+        };
+        let handler = {
+            let table = t.borrow();
+            match &table.metatable {
+                Some(meta) if table.get(&key).is_nil() => meta.borrow().get_str("__newindex"),
+                _ => Value::Nil,
+            }
+        };
+        match handler {
+            Value::Nil => self.raw_set(t, key, value),
+            Value::Function(_) => {
+                self.call(&handler, vec![obj.clone(), key, value])?;
+                Ok(())
+            }
+            next => self.set_index(&next, key, value),
+        }
+    }
+
+    pub fn raw_set(&self, t: &TableRef, key: Value, value: Value) -> LuaResult<()> {
+        match &key {
+            Value::Nil => return Err(self.error("table index is nil")),
+            Value::Number(n) if n.is_nan() => return Err(self.error("table index is NaN")),
+            _ => {}
+        }
+        let mut table = t.borrow_mut();
+        if table.readonly {
+            return Err(self.error("Attempt to modify a readonly table"));
+        }
+        table.set(key, value);
+        Ok(())
+    }
+
+    fn eval(&mut self, frame: &mut Frame, expr: &Expr) -> LuaResult<Value> {
+        self.nested(|interp| interp.eval_expr(frame, expr))
+    }
+
+    fn eval_expr(&mut self, frame: &mut Frame, expr: &Expr) -> LuaResult<Value> {
+        Ok(match expr {
+            Expr::Nil => Value::Nil,
+            Expr::True => Value::Boolean(true),
+            Expr::False => Value::Boolean(false),
+            Expr::Number(n) => Value::Number(*n),
+            Expr::Str(s) => Value::str(s),
+            Expr::Vararg => frame.varargs.first().cloned().unwrap_or_default(),
+            Expr::Function(proto) => {
+                let upvalues = proto
+                    .upvalues
+                    .iter()
+                    .map(|up| match up {
+                        Upvalue::Local(slot) => Rc::clone(&frame.slots[*slot]),
+                        Upvalue::Outer(i) => Rc::clone(&frame.upvalues[*i]),
+                    })
+                    .collect();
+                Value::Function(Rc::new(Function::Lua(Closure {
+                    proto: Arc::clone(proto),
+                    upvalues,
+                })))
+            }
+            Expr::Local(slot) => frame.get(*slot),
+            Expr::Upvalue(i) => frame.upvalues[*i].borrow().clone(),
+            Expr::Global(name) => {
+                let globals = Value::Table(Rc::clone(&self.globals));
+                self.index(&globals, &Value::str(name))?
+            }
+            Expr::Index(obj, key) => {
+                let obj_value = self.eval(frame, obj)?;
+                let key = self.eval(frame, key)?;
+                if !matches!(obj_value, Value::Table(_) | Value::Str(_)) {
+                    return Err(self.index_error(obj, &obj_value));
+                }
+                self.index(&obj_value, &key)?
+            }
+            Expr::Call(..) | Expr::Method(..) => self
+                .eval_multi(frame, expr)?
+                .into_iter()
+                .next()
+                .unwrap_or_default(),
+            Expr::Paren(inner) => self.eval(frame, inner)?,
+            Expr::Table(fields) => self.table(frame, fields)?,
+            Expr::And(a, b) => {
+                let a = self.eval(frame, a)?;
+                if a.truthy() {
+                    self.eval(frame, b)?
+                } else {
+                    a
+                }
+            }
+            Expr::Or(a, b) => {
+                let a = self.eval(frame, a)?;
+                if a.truthy() {
+                    a
+                } else {
+                    self.eval(frame, b)?
+                }
+            }
+            Expr::Unary(op, operand) => {
+                let value = self.eval(frame, operand)?;
+                self.unary(*op, value)?
+            }
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(frame, a)?;
+                let b = self.eval(frame, b)?;
+                self.binary(*op, a, b)?
+            }
+        })
+    }
+
+    /// All the values of an expression: calls and `...` may produce any
+    /// number of them.
+    fn eval_multi(&mut self, frame: &mut Frame, expr: &Expr) -> LuaResult<Vec<Value>> {
+        match expr {
+            Expr::Call(callee, args) => {
+                let func = self.eval(frame, callee)?;
+                let args = self.eval_list(frame, args)?;
+                if !matches!(func, Value::Function(_) | Value::Table(_)) {
+                    if let Some(name) = describe(callee) {
+                        return Err(self.error(format!(
+                            "attempt to call {} (a {} value)",
+                            name,
+                            func.type_name()
+                        )));
+                    }
+                }
+                self.call(&func, args)
+            }
+            Expr::Method(obj, name, args) => {
+                let obj_value = self.eval(frame, obj)?;
+                if !matches!(obj_value, Value::Table(_) | Value::Str(_)) {
+                    return Err(self.index_error(obj, &obj_value));
+                }
+                let func = self.index(&obj_value, &Value::str(name))?;
+                let mut full = vec![obj_value];
+                full.extend(self.eval_list(frame, args)?);
+                if !matches!(func, Value::Function(_) | Value::Table(_)) {
+                    return Err(self.error(format!(
+                        "attempt to call method '{}' (a {} value)",
+                        name,
+                        func.type_name()
+                    )));
+                }
+                self.call(&func, full)
+            }
+            Expr::Vararg => Ok(frame.varargs.clone()),
+            _ => Ok(vec![self.eval(frame, expr)?]),
+        }
+    }
+
+    fn eval_list(&mut self, frame: &mut Frame, exprs: &[Expr]) -> LuaResult<Vec<Value>> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for (i, expr) in exprs.iter().enumerate() {
+            if i == exprs.len() - 1 && expr.is_multi() {
+                values.extend(self.eval_multi(frame, expr)?);
+            } else {
+                values.push(self.eval(frame, expr)?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn table(&mut self, frame: &mut Frame, fields: &[Field]) -> LuaResult<Value> {
+        let table = Rc::new(RefCell::new(Table::default()));
+        let mut n = 0;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::Positional(expr) if i == fields.len() - 1 && expr.is_multi() => {
+                    for value in self.eval_multi(frame, expr)? {
+                        n += 1;
+                        table.borrow_mut().set(Value::Number(n as f64), value);
+                    }
+                }
+                Field::Positional(expr) => {
+                    let value = self.eval(frame, expr)?;
+                    n += 1;
+                    table.borrow_mut().set(Value::Number(n as f64), value);
+                }
+                Field::Named(key, value) => {
+                    let key = self.eval(frame, key)?;
+                    let value = self.eval(frame, value)?;
+                    self.raw_set(&table, key, value)?;
+                }
+            }
+        }
+        Ok(Value::Table(table))
+    }
+
+    fn unary(&mut self, op: UnOp, value: Value) -> LuaResult<Value> {
+        Ok(match op {
+            UnOp::Not => Value::Boolean(!value.truthy()),
+            UnOp::Neg => match value.to_number() {
+                Some(n) => Value::Number(-n),
+                None => return Err(self.arith_error(&value)),
+            },
+            UnOp::Len => match &value {
+                Value::Str(s) => Value::Number(s.len() as f64),
+                Value::Table(t) => Value::Number(t.borrow().len() as f64),
+                other => {
+                    return Err(self.error(format!(
+                        "attempt to get length of a {} value",
+                        other.type_name()
+                    )))
+                }
+            },
+        })
+    }
+
+    fn arith_error(&self, value: &Value) -> LuaError {
+        self.error(format!(
+            "attempt to perform arithmetic on a {} value",
+            value.type_name()
+        ))
+    }
+
+    pub fn binary(&mut self, op: BinOp, a: Value, b: Value) -> LuaResult<Value> {
+        let arith = |interp: &Self, f: fn(f64, f64) -> f64| match (a.to_number(), b.to_number()) {
+            (Some(x), Some(y)) => Ok(Value::Number(f(x, y))),
+            (None, _) => Err(interp.arith_error(&a)),
+            _ => Err(interp.arith_error(&b)),
+        };
+        match op {
+            BinOp::Add => arith(self, |x, y| x + y),
+            BinOp::Sub => arith(self, |x, y| x - y),
+            BinOp::Mul => arith(self, |x, y| x * y),
+            BinOp::Div => arith(self, |x, y| x / y),
+            BinOp::Mod => arith(self, |x, y| x - (x / y).floor() * y),
+            BinOp::Pow => arith(self, f64::powf),
+            BinOp::Concat => match (a.to_bytes(), b.to_bytes()) {
+                (Some(x), Some(y)) => {
+                    let mut s = Vec::with_capacity(x.len() + y.len());
+                    s.extend_from_slice(&x);
+                    s.extend_from_slice(&y);
+                    Ok(Value::str(s))
+                }
+                (None, _) => Err(self.concat_error(&a)),
+                _ => Err(self.concat_error(&b)),
+            },
+            BinOp::Eq => Ok(Value::Boolean(a == b)),
+            BinOp::Ne => Ok(Value::Boolean(a != b)),
+            BinOp::Lt => Ok(Value::Boolean(self.less_than(&a, &b)?)),
+            BinOp::Le => Ok(Value::Boolean(!self.less_than(&b, &a)?)),
+            BinOp::Gt => Ok(Value::Boolean(self.less_than(&b, &a)?)),
+            BinOp::Ge => Ok(Value::Boolean(!self.less_than(&a, &b)?)),
+        }
+    }
+
+    fn concat_error(&self, value: &Value) -> LuaError {
+        self.error(format!(
+            "attempt to concatenate a {} value",
+            value.type_name()
+        ))
+    }
+
+    pub fn less_than(&self, a: &Value, b: &Value) -> LuaResult<bool> {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) => Ok(x < y),
+            (Value::Str(x), Value::Str(y)) => Ok(x < y),
+            _ if a.type_name() == b.type_name() => {
+                Err(self.error(format!("attempt to compare two {} values", a.type_name())))
+            }
+            _ => Err(self.error(format!(
+                "attempt to compare {} with {}",
+                a.type_name(),
+                b.type_name()
+            ))),
+        }
+    }
+}
@@ -0,0 +1,423 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    Str(Vec<u8>),
+    Number(f64),
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Hash,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Assign,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Semi,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Dots,
+    Eof,
+}
+
+impl Token {
+    /// How the token is shown in syntax errors.
+    pub fn describe(&self) -> String {
+        let s = match self {
+            Token::Name(name) => name,
+            Token::Str(s) => return String::from_utf8_lossy(s).into_owned(),
+            Token::Number(n) => return super::value::format_number(*n),
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::Semi => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eof => "<eof>",
+        };
+        s.to_string()
+    }
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    Some(match name {
+        "and" => Token::And,
+        "break" => Token::Break,
+        "do" => Token::Do,
+        "else" => Token::Else,
+        "elseif" => Token::Elseif,
+        "end" => Token::End,
+        "false" => Token::False,
+        "for" => Token::For,
+        "function" => Token::Function,
+        "if" => Token::If,
+        "in" => Token::In,
+        "local" => Token::Local,
+        "nil" => Token::Nil,
+        "not" => Token::Not,
+        "or" => Token::Or,
+        "repeat" => Token::Repeat,
+        "return" => Token::Return,
+        "then" => Token::Then,
+        "true" => Token::True,
+        "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None,
+    })
+}
+
+/// A syntax error: the line and a message naming the offending text.
+pub type SyntaxError = (u32, String);
+
+/// Splits a chunk into tokens, each with its line number.
+pub fn tokenize(src: &[u8]) -> Result<Vec<(Token, u32)>, SyntaxError> {
+    let mut lexer = Lexer {
+        src,
+        pos: 0,
+        line: 1,
+    };
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let done = token == Token::Eof;
+        tokens.push((token, lexer.line));
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
+
+struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: u32,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, offset: usize) -> u8 {
+        self.src.get(self.pos + offset).copied().unwrap_or(0)
+    }
+
+    fn error<T>(&self, msg: &str, near: &[u8]) -> Result<T, SyntaxError> {
+        Err((
+            self.line,
+            format!("{} near '{}'", msg, String::from_utf8_lossy(near)),
+        ))
+    }
+
+    fn newline(&mut self) {
+        // \r\n and \n\r count as one line break
+        let c = self.peek(0);
+        self.pos += 1;
+        let next = self.peek(0);
+        if (next == b'\n' || next == b'\r') && next != c {
+            self.pos += 1;
+        }
+        self.line += 1;
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), SyntaxError> {
+        loop {
+            match self.peek(0) {
+                b'\n' | b'\r' => self.newline(),
+                b' ' | b'\t' | 0x0b | 0x0c => self.pos += 1,
+                b'-' if self.peek(1) == b'-' => {
+                    self.pos += 2;
+                    if let Some(level) = self.long_bracket_level() {
+                        self.long_string(level)?;
+                    } else {
+                        while self.pos < self.src.len() && !matches!(self.peek(0), b'\n' | b'\r') {
+                            self.pos += 1;
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// The level of a `[==[` opening at the current position, if any.
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek(0) != b'[' {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek(1 + level) == b'=' {
+            level += 1;
+        }
+        (self.peek(1 + level) == b'[').then_some(level)
+    }
+
+    fn long_string(&mut self, level: usize) -> Result<Vec<u8>, SyntaxError> {
+        let start = self.pos;
+        self.pos += level + 2;
+        // a newline right after the opening bracket is skipped
+        if matches!(self.peek(0), b'\n' | b'\r') {
+            self.newline();
+        }
+        let mut out = Vec::new();
+        loop {
+            match self.peek(0) {
+                0 if self.pos >= self.src.len() => {
+                    return self.error("unfinished long string", &self.src[start..]);
+                }
+                b']' if (1..=level).all(|i| self.peek(i) == b'=')
+                    && self.peek(level + 1) == b']' =>
+                {
+                    self.pos += level + 2;
+                    return Ok(out);
+                }
+                b'\n' | b'\r' => {
+                    self.newline();
+                    out.push(b'\n');
+                }
+                c => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn quoted_string(&mut self, quote: u8) -> Result<Vec<u8>, SyntaxError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = self.peek(0);
+            if self.pos >= self.src.len() || c == b'\n' || c == b'\r' {
+                return self.error("unfinished string", &self.src[start..self.pos]);
+            }
+            self.pos += 1;
+            if c == quote {
+                return Ok(out);
+            }
+            if c != b'\\' {
+                out.push(c);
+                continue;
+            }
+            let escaped = self.peek(0);
+            match escaped {
+                b'n' => out.push(b'\n'),
+                b't' => out.push(b'\t'),
+                b'r' => out.push(b'\r'),
+                b'a' => out.push(0x07),
+                b'b' => out.push(0x08),
+                b'f' => out.push(0x0c),
+                b'v' => out.push(0x0b),
+                b'\n' | b'\r' => {
+                    self.newline();
+                    out.push(b'\n');
+                    continue;
+                }
+                b'0'..=b'9' => {
+                    let mut n = 0u32;
+                    let mut digits = 0;
+                    while digits < 3 && self.peek(0).is_ascii_digit() {
+                        n = n * 10 + (self.peek(0) - b'0') as u32;
+                        self.pos += 1;
+                        digits += 1;
+                    }
+                    if n > 255 {
+                        return self.error("escape sequence too large", &self.src[start..self.pos]);
+                    }
+                    out.push(n as u8);
+                    continue;
+                }
+                0 if self.pos >= self.src.len() => {
+                    return self.error("unfinished string", &self.src[start..self.pos]);
+                }
+                // \\, \", \' and any other character stand for themselves
+                c => out.push(c),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn number(&mut self) -> Result<Token, SyntaxError> {
+        let start = self.pos;
+        if self.peek(0) == b'0' && matches!(self.peek(1), b'x' | b'X') {
+            self.pos += 2;
+        }
+        loop {
+            let c = self.peek(0);
+            if matches!(c, b'e' | b'E') && matches!(self.peek(1), b'+' | b'-') {
+                self.pos += 2;
+            } else if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = &self.src[start..self.pos];
+        match super::value::parse_number(text) {
+            Some(n) => Ok(Token::Number(n)),
+            None => self.error("malformed number", text),
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        self.skip_whitespace_and_comments()?;
+        if self.pos >= self.src.len() {
+            return Ok(Token::Eof);
+        }
+        let c = self.peek(0);
+        if c.is_ascii_alphabetic() || c == b'_' {
+            let start = self.pos;
+            while self.peek(0).is_ascii_alphanumeric() || self.peek(0) == b'_' {
+                self.pos += 1;
+            }
+            let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+            return Ok(keyword(name).unwrap_or_else(|| Token::Name(name.to_string())));
+        }
+        if c.is_ascii_digit() || (c == b'.' && self.peek(1).is_ascii_digit()) {
+            return self.number();
+        }
+        if c == b'"' || c == b'\'' {
+            return Ok(Token::Str(self.quoted_string(c)?));
+        }
+        if let Some(level) = self.long_bracket_level() {
+            return Ok(Token::Str(self.long_string(level)?));
+        }
+        let (token, len) = match (c, self.peek(1), self.peek(2)) {
+            (b'.', b'.', b'.') => (Token::Dots, 3),
+            (b'.', b'.', _) => (Token::Concat, 2),
+            (b'=', b'=', _) => (Token::Eq, 2),
+            (b'~', b'=', _) => (Token::Ne, 2),
+            (b'<', b'=', _) => (Token::Le, 2),
+            (b'>', b'=', _) => (Token::Ge, 2),
+            (b'.', ..) => (Token::Dot, 1),
+            (b'=', ..) => (Token::Assign, 1),
+            (b'<', ..) => (Token::Lt, 1),
+            (b'>', ..) => (Token::Gt, 1),
+            (b'+', ..) => (Token::Plus, 1),
+            (b'-', ..) => (Token::Minus, 1),
+            (b'*', ..) => (Token::Star, 1),
+            (b'/', ..) => (Token::Slash, 1),
+            (b'%', ..) => (Token::Percent, 1),
+            (b'^', ..) => (Token::Caret, 1),
+            (b'#', ..) => (Token::Hash, 1),
+            (b'(', ..) => (Token::LParen, 1),
+            (b')', ..) => (Token::RParen, 1),
+            (b'{', ..) => (Token::LBrace, 1),
+            (b'}', ..) => (Token::RBrace, 1),
+            (b'[', ..) => (Token::LBracket, 1),
+            (b']', ..) => (Token::RBracket, 1),
+            (b';', ..) => (Token::Semi, 1),
+            (b':', ..) => (Token::Colon, 1),
+            (b',', ..) => (Token::Comma, 1),
+            _ => return self.error("unexpected symbol", &[c]),
+        };
+        self.pos += len;
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens: Vec<Token> =
+            tokenize(b"local x = 0x1F .. [[\nlong]] -- comment\nreturn 'a\\65\\n' ~= 1e2")
+                .unwrap()
+                .into_iter()
+                .map(|(t, _)| t)
+                .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Local,
+                Token::Name("x".to_string()),
+                Token::Assign,
+                Token::Number(31.0),
+                Token::Concat,
+                Token::Str(b"long".to_vec()),
+                Token::Return,
+                Token::Str(b"aA\n".to_vec()),
+                Token::Ne,
+                Token::Number(100.0),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(
+            tokenize(b"x = 'abc").unwrap_err(),
+            (1, "unfinished string near ''abc'".to_string())
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! A sandboxed interpreter for the dialect of Lua 5.1 that scripts are
+//! written in. Scripts are parsed into a tree with variables resolved ahead
+//! of time, and evaluated by walking it.
+
+mod ast;
+mod interp;
+mod lexer;
+mod parser;
+mod pattern;
+mod stdlib;
+mod value;
+
+pub use ast::Proto;
+pub use interp::Interp;
+pub use parser::parse;
+pub use stdlib::{arg, check_str, open, to_display};
+pub use value::{LuaError, LuaResult, Native, NativeFn, Table, Value};
+
+/// The native stack scripts are parsed and run with.
+pub const STACK_SIZE: usize = 64 << 20;
+
+/// Runs `f` on a thread of its own with `STACK_SIZE` of stack: enough for
+/// the deepest nesting the parser and interpreter allow, even in a debug
+/// build, whichever thread the script comes from.
+pub fn on_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .expect("can't start a thread for the script")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
@@ -0,0 +1,742 @@
+use std::sync::Arc;
+
+use super::ast::{BinOp, Block, Expr, Field, Proto, Stat, StatKind, UnOp, Upvalue};
+use super::lexer::{tokenize, SyntaxError, Token};
+
+/// Parses a chunk into the prototype of its main function.
+pub fn parse(src: &[u8]) -> Result<Arc<Proto>, SyntaxError> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+        funcs: vec![FuncState::new(true)],
+        levels: 0,
+    };
+    let body = parser.block()?;
+    if *parser.peek() != Token::Eof {
+        return parser.error_near("'<eof>' expected");
+    }
+    let main = parser.funcs.pop().unwrap();
+    Ok(Arc::new(Proto {
+        params: vec![],
+        is_vararg: true,
+        slots: main.slots,
+        upvalues: vec![],
+        body,
+    }))
+}
+
+struct FuncState {
+    // locals in scope, innermost last
+    actives: Vec<(String, usize)>,
+    slots: usize,
+    upvalues: Vec<(String, Upvalue)>,
+    is_vararg: bool,
+}
+
+impl FuncState {
+    fn new(is_vararg: bool) -> Self {
+        FuncState {
+            actives: vec![],
+            slots: 0,
+            upvalues: vec![],
+            is_vararg,
+        }
+    }
+}
+
+enum Var {
+    Local(usize),
+    Upvalue(usize),
+}
+
+/// How deeply statements and expressions may nest, as LUAI_MAXCCALLS in
+/// Lua. Parsing, evaluating and dropping the tree recurse once per level,
+/// so this bounds the native stack they take.
+const MAX_LEVELS: usize = 200;
+
+struct Parser {
+    tokens: Vec<(Token, u32)>,
+    pos: usize,
+    funcs: Vec<FuncState>,
+    // syntax levels entered
+    levels: usize,
+}
+
+/// Left and right priorities of binary operators, as in Lua 5.1; the right
+/// one being lower makes `..` and `^` right associative.
+fn binary_op(token: &Token) -> Option<(Option<BinOp>, u8, u8)> {
+    Some(match token {
+        Token::Or => (None, 1, 1),
+        Token::And => (None, 2, 2),
+        Token::Lt => (Some(BinOp::Lt), 3, 3),
+        Token::Gt => (Some(BinOp::Gt), 3, 3),
+        Token::Le => (Some(BinOp::Le), 3, 3),
+        Token::Ge => (Some(BinOp::Ge), 3, 3),
+        Token::Ne => (Some(BinOp::Ne), 3, 3),
+        Token::Eq => (Some(BinOp::Eq), 3, 3),
+        Token::Concat => (Some(BinOp::Concat), 5, 4),
+        Token::Plus => (Some(BinOp::Add), 6, 6),
+        Token::Minus => (Some(BinOp::Sub), 6, 6),
+        Token::Star => (Some(BinOp::Mul), 7, 7),
+        Token::Slash => (Some(BinOp::Div), 7, 7),
+        Token::Percent => (Some(BinOp::Mod), 7, 7),
+        Token::Caret => (Some(BinOp::Pow), 10, 9),
+        _ => return None,
+    })
+}
+
+const UNARY_PRIORITY: u8 = 8;
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
+        let i = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[i].0
+    }
+
+    fn line(&self) -> u32 {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn check(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn enter_level(&mut self) -> Result<(), SyntaxError> {
+        self.levels += 1;
+        if self.levels > MAX_LEVELS {
+            return Err((self.line(), "chunk has too many syntax levels".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Parses a construct one syntax level down.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, SyntaxError>,
+    ) -> Result<T, SyntaxError> {
+        self.enter_level()?;
+        let result = parse(self);
+        self.levels -= 1;
+        result
+    }
+
+    fn error_near<T>(&self, msg: &str) -> Result<T, SyntaxError> {
+        Err((
+            self.line(),
+            format!("{} near '{}'", msg, self.peek().describe()),
+        ))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), SyntaxError> {
+        if self.check(&token) {
+            Ok(())
+        } else {
+            self.error_near(&format!("'{}' expected", token.describe()))
+        }
+    }
+
+    /// Expects the token closing a construct opened at `line`.
+    fn expect_match(&mut self, token: Token, open: Token, line: u32) -> Result<(), SyntaxError> {
+        if self.check(&token) {
+            return Ok(());
+        }
+        if line == self.line() {
+            self.error_near(&format!("'{}' expected", token.describe()))
+        } else {
+            self.error_near(&format!(
+                "'{}' expected (to close '{}' at line {})",
+                token.describe(),
+                open.describe(),
+                line
+            ))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, SyntaxError> {
+        match self.peek() {
+            Token::Name(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => self.error_near("'<name>' expected"),
+        }
+    }
+
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn declare(&mut self, name: String) -> usize {
+        let func = self.func();
+        let slot = func.slots;
+        func.slots += 1;
+        func.actives.push((name, slot));
+        slot
+    }
+
+    fn resolve(&mut self, name: &str) -> Expr {
+        match self.resolve_at(self.funcs.len() - 1, name) {
+            Some(Var::Local(slot)) => Expr::Local(slot),
+            Some(Var::Upvalue(i)) => Expr::Upvalue(i),
+            None => Expr::Global(name.to_string()),
+        }
+    }
+
+    fn resolve_at(&mut self, level: usize, name: &str) -> Option<Var> {
+        let func = &self.funcs[level];
+        if let Some((_, slot)) = func.actives.iter().rev().find(|(n, _)| n == name) {
+            return Some(Var::Local(*slot));
+        }
+        if let Some(i) = func.upvalues.iter().position(|(n, _)| n == name) {
+            return Some(Var::Upvalue(i));
+        }
+        if level == 0 {
+            return None;
+        }
+        let upvalue = match self.resolve_at(level - 1, name)? {
+            Var::Local(slot) => Upvalue::Local(slot),
+            Var::Upvalue(i) => Upvalue::Outer(i),
+        };
+        let upvalues = &mut self.funcs[level].upvalues;
+        upvalues.push((name.to_string(), upvalue));
+        Some(Var::Upvalue(upvalues.len() - 1))
+    }
+
+    fn block_follows(&self) -> bool {
+        matches!(
+            self.peek(),
+            Token::Else | Token::Elseif | Token::End | Token::Until | Token::Eof
+        )
+    }
+
+    /// A block in its own scope.
+    fn block(&mut self) -> Result<Block, SyntaxError> {
+        let scope = self.func().actives.len();
+        let block = self.statements();
+        self.func().actives.truncate(scope);
+        block
+    }
+
+    fn statements(&mut self) -> Result<Block, SyntaxError> {
+        let mut block = Vec::new();
+        while !self.block_follows() {
+            if *self.peek() == Token::Return {
+                let line = self.line();
+                self.advance();
+                let exprs = if self.block_follows() || *self.peek() == Token::Semi {
+                    vec![]
+                } else {
+                    self.expr_list()?
+                };
+                self.check(&Token::Semi);
+                block.push(Stat {
+                    line,
+                    kind: StatKind::Return(exprs),
+                });
+                if !self.block_follows() {
+                    return self.error_near("'<eof>' expected");
+                }
+                break;
+            }
+            if let Some(stat) = self.nested(Self::statement)? {
+                block.push(stat);
+            }
+        }
+        Ok(block)
+    }
+
+    fn statement(&mut self) -> Result<Option<Stat>, SyntaxError> {
+        let line = self.line();
+        let kind = match self.peek() {
+            Token::Semi => {
+                self.advance();
+                return Ok(None);
+            }
+            Token::If => self.if_stat(line)?,
+            Token::While => {
+                self.advance();
+                let cond = self.expr()?;
+                self.expect(Token::Do)?;
+                let body = self.block()?;
+                self.expect_match(Token::End, Token::While, line)?;
+                StatKind::While(cond, body)
+            }
+            Token::Do => {
+                self.advance();
+                let body = self.block()?;
+                self.expect_match(Token::End, Token::Do, line)?;
+                StatKind::Do(body)
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => {
+                self.advance();
+                // the condition can see the body's locals
+                let scope = self.func().actives.len();
+                let body = self.statements()?;
+                self.expect_match(Token::Until, Token::Repeat, line)?;
+                let cond = self.expr()?;
+                self.func().actives.truncate(scope);
+                StatKind::Repeat(body, cond)
+            }
+            Token::Function => {
+                self.advance();
+                let mut target = {
+                    let name = self.name()?;
+                    self.resolve(&name)
+                };
+                let mut method = false;
+                loop {
+                    match self.peek() {
+                        Token::Dot => {
+                            self.advance();
+                            let key = self.name()?;
+                            target = Expr::Index(
+                                Box::new(target),
+                                Box::new(Expr::Str(key.into_bytes())),
+                            );
+                        }
+                        Token::Colon => {
+                            self.advance();
+                            let key = self.name()?;
+                            target = Expr::Index(
+                                Box::new(target),
+                                Box::new(Expr::Str(key.into_bytes())),
+                            );
+                            method = true;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                let function = self.function_body(method, line)?;
+                StatKind::Assign(vec![target], vec![function])
+            }
+            Token::Local => {
+                self.advance();
+                if self.check(&Token::Function) {
+                    let name = self.name()?;
+                    // declared first, so the function can call itself
+                    let slot = self.declare(name);
+                    let function = self.function_body(false, line)?;
+                    StatKind::LocalFunction(slot, function)
+                } else {
+                    let mut names = vec![self.name()?];
+                    while self.check(&Token::Comma) {
+                        names.push(self.name()?);
+                    }
+                    let exprs = if self.check(&Token::Assign) {
+                        self.expr_list()?
+                    } else {
+                        vec![]
+                    };
+                    let slots = names.into_iter().map(|name| self.declare(name)).collect();
+                    StatKind::Local(slots, exprs)
+                }
+            }
+            Token::Break => {
+                self.advance();
+                StatKind::Break
+            }
+            _ => self.expr_stat()?,
+        };
+        Ok(Some(Stat { line, kind }))
+    }
+
+    fn if_stat(&mut self, line: u32) -> Result<StatKind, SyntaxError> {
+        self.advance();
+        let mut branches = Vec::new();
+        let cond = self.expr()?;
+        self.expect(Token::Then)?;
+        branches.push((cond, self.block()?));
+        let mut otherwise = None;
+        loop {
+            match self.peek() {
+                Token::Elseif => {
+                    self.advance();
+                    let cond = self.expr()?;
+                    self.expect(Token::Then)?;
+                    branches.push((cond, self.block()?));
+                }
+                Token::Else => {
+                    self.advance();
+                    otherwise = Some(self.block()?);
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+                _ => {
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+            }
+        }
+        Ok(StatKind::If(branches, otherwise))
+    }
+
+    fn for_stat(&mut self, line: u32) -> Result<StatKind, SyntaxError> {
+        self.advance();
+        let first = self.name()?;
+        match self.peek() {
+            Token::Assign => {
+                self.advance();
+                let start = self.expr()?;
+                self.expect(Token::Comma)?;
+                let limit = self.expr()?;
+                let step = if self.check(&Token::Comma) {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Do)?;
+                let scope = self.func().actives.len();
+                let var = self.declare(first);
+                let body = self.block()?;
+                self.func().actives.truncate(scope);
+                self.expect_match(Token::End, Token::For, line)?;
+                Ok(StatKind::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    body,
+                })
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.check(&Token::Comma) {
+                    names.push(self.name()?);
+                }
+                self.expect(Token::In)?;
+                let exprs = self.expr_list()?;
+                self.expect(Token::Do)?;
+                let scope = self.func().actives.len();
+                let vars = names.into_iter().map(|name| self.declare(name)).collect();
+                let body = self.block()?;
+                self.func().actives.truncate(scope);
+                self.expect_match(Token::End, Token::For, line)?;
+                Ok(StatKind::GenericFor { vars, exprs, body })
+            }
+            _ => self.error_near("'=' or 'in' expected"),
+        }
+    }
+
+    fn expr_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let expr = self.suffixed_expr()?;
+        if matches!(self.peek(), Token::Assign | Token::Comma) {
+            let mut targets = vec![expr];
+            while self.check(&Token::Comma) {
+                targets.push(self.suffixed_expr()?);
+            }
+            for target in &targets {
+                if !matches!(
+                    target,
+                    Expr::Local(_) | Expr::Upvalue(_) | Expr::Global(_) | Expr::Index(..)
+                ) {
+                    return self.error_near("syntax error");
+                }
+            }
+            self.expect(Token::Assign)?;
+            let exprs = self.expr_list()?;
+            return Ok(StatKind::Assign(targets, exprs));
+        }
+        if !matches!(expr, Expr::Call(..) | Expr::Method(..)) {
+            return self.error_near("syntax error");
+        }
+        Ok(StatKind::Expr(expr))
+    }
+
+    fn function_body(&mut self, method: bool, line: u32) -> Result<Expr, SyntaxError> {
+        self.funcs.push(FuncState::new(false));
+        let mut params = Vec::new();
+        if method {
+            params.push(self.declare("self".to_string()));
+        }
+        self.expect(Token::LParen)?;
+        if *self.peek() != Token::RParen {
+            loop {
+                match self.peek() {
+                    Token::Dots => {
+                        self.advance();
+                        self.func().is_vararg = true;
+                        break;
+                    }
+                    _ => {
+                        let name = self.name()?;
+                        params.push(self.declare(name));
+                    }
+                }
+                if !self.check(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RParen)?;
+        let body = self.block()?;
+        self.expect_match(Token::End, Token::Function, line)?;
+        let func = self.funcs.pop().unwrap();
+        Ok(Expr::Function(Arc::new(Proto {
+            params,
+            is_vararg: func.is_vararg,
+            slots: func.slots,
+            upvalues: func.upvalues.into_iter().map(|(_, up)| up).collect(),
+            body,
+        })))
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>, SyntaxError> {
+        let mut exprs = vec![self.expr()?];
+        while self.check(&Token::Comma) {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> Result<Expr, SyntaxError> {
+        self.nested(|parser| parser.sub_expr(0))
+    }
+
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr, SyntaxError> {
+        let unary = match self.peek() {
+            Token::Not => Some(UnOp::Not),
+            Token::Minus => Some(UnOp::Neg),
+            Token::Hash => Some(UnOp::Len),
+            _ => None,
+        };
+        let mut left = match unary {
+            Some(op) => {
+                self.advance();
+                let operand = self.nested(|parser| parser.sub_expr(UNARY_PRIORITY))?;
+                match (op, operand) {
+                    (UnOp::Neg, Expr::Number(n)) => Expr::Number(-n),
+                    (op, operand) => Expr::Unary(op, Box::new(operand)),
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        // each operator nests what came before it one level deeper
+        let levels = self.levels;
+        while let Some((op, left_priority, right_priority)) = binary_op(self.peek()) {
+            if left_priority <= limit {
+                break;
+            }
+            let token = self.advance();
+            let right = self.nested(|parser| parser.sub_expr(right_priority))?;
+            left = match op {
+                Some(op) => Expr::Binary(op, Box::new(left), Box::new(right)),
+                None if token == Token::And => Expr::And(Box::new(left), Box::new(right)),
+                None => Expr::Or(Box::new(left), Box::new(right)),
+            };
+            self.enter_level()?;
+        }
+        self.levels = levels;
+        Ok(left)
+    }
+
+    fn simple_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let expr = match self.peek() {
+            Token::Number(n) => Expr::Number(*n),
+            Token::Str(s) => Expr::Str(s.clone()),
+            Token::Nil => Expr::Nil,
+            Token::True => Expr::True,
+            Token::False => Expr::False,
+            Token::Dots => {
+                if !self.func().is_vararg {
+                    return self.error_near("cannot use '...' outside a vararg function");
+                }
+                Expr::Vararg
+            }
+            Token::LBrace => return self.table(),
+            Token::Function => {
+                let line = self.line();
+                self.advance();
+                return self.function_body(false, line);
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    fn primary_expr(&mut self) -> Result<Expr, SyntaxError> {
+        match self.peek() {
+            Token::Name(_) => {
+                let name = self.name()?;
+                Ok(self.resolve(&name))
+            }
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                let expr = self.expr()?;
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            _ => self.error_near("unexpected symbol"),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.primary_expr()?;
+        let levels = self.levels;
+        loop {
+            expr = match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    let key = self.name()?;
+                    Expr::Index(Box::new(expr), Box::new(Expr::Str(key.into_bytes())))
+                }
+                Token::LBracket => {
+                    self.advance();
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket)?;
+                    Expr::Index(Box::new(expr), Box::new(key))
+                }
+                Token::Colon => {
+                    self.advance();
+                    let name = self.name()?;
+                    let args = self.call_args()?;
+                    Expr::Method(Box::new(expr), name, args)
+                }
+                Token::LParen | Token::LBrace | Token::Str(_) => {
+                    let args = self.call_args()?;
+                    Expr::Call(Box::new(expr), args)
+                }
+                _ => break,
+            };
+            // as with operators
+            self.enter_level()?;
+        }
+        self.levels = levels;
+        Ok(expr)
+    }
+
+    fn call_args(&mut self) -> Result<Vec<Expr>, SyntaxError> {
+        match self.peek() {
+            Token::Str(s) => {
+                let arg = Expr::Str(s.clone());
+                self.advance();
+                Ok(vec![arg])
+            }
+            Token::LBrace => Ok(vec![self.table()?]),
+            Token::LParen => {
+                let line = self.line();
+                self.advance();
+                if self.check(&Token::RParen) {
+                    return Ok(vec![]);
+                }
+                let args = self.expr_list()?;
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(args)
+            }
+            _ => self.error_near("function arguments expected"),
+        }
+    }
+
+    fn table(&mut self) -> Result<Expr, SyntaxError> {
+        let line = self.line();
+        self.expect(Token::LBrace)?;
+        let mut fields = Vec::new();
+        while *self.peek() != Token::RBrace {
+            let field = match (self.peek(), self.peek_at(1)) {
+                (Token::LBracket, _) => {
+                    self.advance();
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket)?;
+                    self.expect(Token::Assign)?;
+                    Field::Named(key, self.expr()?)
+                }
+                (Token::Name(_), Token::Assign) => {
+                    let key = self.name()?;
+                    self.advance();
+                    Field::Named(Expr::Str(key.into_bytes()), self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.check(&Token::Comma) && !self.check(&Token::Semi) {
+                break;
+            }
+        }
+        self.expect_match(Token::RBrace, Token::LBrace, line)?;
+        Ok(Expr::Table(fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolves_locals_and_upvalues() {
+        let proto = parse(b"local a = 1 local function f() return a + b end").unwrap();
+        assert_eq!(proto.slots, 2);
+        let StatKind::LocalFunction(1, function) = &proto.body[1].kind else {
+            panic!("expected a local function");
+        };
+        let Expr::Function(f) = function else {
+            panic!("expected a function");
+        };
+        assert_eq!(f.upvalues, vec![Upvalue::Local(0)]);
+        let StatKind::Return(values) = &f.body[0].kind else {
+            panic!("expected a return");
+        };
+        assert!(matches!(
+            &values[0],
+            Expr::Binary(BinOp::Add, a, b)
+                if matches!(**a, Expr::Upvalue(0)) && matches!(&**b, Expr::Global(name) if name == "b")
+        ));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            parse(b"x = ").unwrap_err(),
+            (1, "unexpected symbol near '<eof>'".to_string())
+        );
+        assert_eq!(
+            parse(b"if x then\nreturn 1").unwrap_err(),
+            (
+                2,
+                "'end' expected (to close 'if' at line 1) near '<eof>'".to_string()
+            )
+        );
+        assert_eq!(
+            parse(b"return 1 x = 2").unwrap_err(),
+            (1, "'<eof>' expected near 'x'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_syntax_levels() {
+        let too_many = (1, "chunk has too many syntax levels".to_string());
+        let parse_nested = |open: &str, close: &str, n| {
+            let src = format!("return {}1{}", open.repeat(n), close.repeat(n));
+            crate::lua::on_stack(|| parse(src.as_bytes()))
+        };
+        assert!(parse_nested("(", ")", 150).is_ok());
+        assert_eq!(parse_nested("(", ")", 100_000).unwrap_err(), too_many);
+        assert_eq!(parse_nested("{", "}", 300).unwrap_err(), too_many);
+        assert_eq!(parse_nested("- ", "", 300).unwrap_err(), too_many);
+        assert_eq!(parse_nested("1 + ", "", 300).unwrap_err(), too_many);
+        assert_eq!(parse_nested("f", "()", 300).unwrap_err(), too_many);
+        let src = "do ".repeat(300);
+        let result = crate::lua::on_stack(|| parse(src.as_bytes()));
+        assert_eq!(result.unwrap_err(), too_many);
+    }
+}
@@ -0,0 +1,394 @@
+//! Lua pattern matching, a port of the matcher in Lua 5.1's lstrlib.c.
+
+use super::value::Value;
+
+const MAX_CAPTURES: usize = 32;
+const MAX_DEPTH: usize = 200;
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+type MatchResult<T> = Result<T, String>;
+
+pub struct Matcher<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    // start and length of each capture, or one of the CAP_* markers
+    capture: [(usize, isize); MAX_CAPTURES],
+    depth: usize,
+}
+
+/// Whether `pat` has characters with a special meaning, i.e. `string.find`
+/// cannot use a plain substring search.
+pub fn has_specials(pat: &[u8]) -> bool {
+    pat.iter().any(|c| b"^$*+?.([%-".contains(c))
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let result = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !result
+    } else {
+        result
+    }
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        Matcher {
+            src,
+            pat,
+            level: 0,
+            capture: [(0, 0); MAX_CAPTURES],
+            depth: 0,
+        }
+    }
+
+    /// Tries to match the pattern from `p` against the subject at `s`,
+    /// returning where the match ends.
+    pub fn find_at(&mut self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        self.level = 0;
+        self.depth = 0;
+        self.do_match(s, p)
+    }
+
+    /// The subject text from `s` to `e`.
+    pub fn matched(&self, s: usize, e: usize) -> &'a [u8] {
+        &self.src[s..e]
+    }
+
+    /// The captures of the last match from `s` to `e`: the whole match when
+    /// the pattern has none and `whole` is set.
+    pub fn captures(&self, s: usize, e: usize, whole: bool) -> MatchResult<Vec<Value>> {
+        let n = if self.level == 0 && whole {
+            1
+        } else {
+            self.level
+        };
+        (0..n).map(|i| self.capture_value(i, s, e)).collect()
+    }
+
+    pub fn capture_value(&self, i: usize, s: usize, e: usize) -> MatchResult<Value> {
+        if i >= self.level {
+            if i == 0 {
+                return Ok(Value::str(&self.src[s..e]));
+            }
+            return Err("invalid capture index".to_string());
+        }
+        let (start, len) = self.capture[i];
+        match len {
+            CAP_UNFINISHED => Err("unfinished capture".to_string()),
+            CAP_POSITION => Ok(Value::Number((start + 1) as f64)),
+            len => Ok(Value::str(&self.src[start..start + len as usize])),
+        }
+    }
+
+    fn class_end(&self, mut p: usize) -> MatchResult<usize> {
+        let c = self.pat[p];
+        p += 1;
+        if c == b'%' {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // the first character may be a ']'
+            loop {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = self.pat[p];
+                p += 1;
+                if c == b'%' && p < self.pat.len() {
+                    p += 1;
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    /// `p` is the opening '[' and `end` the closing ']' of a set.
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pat[p + 1] == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pat[p] == b'%' {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return found;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < end {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn do_match(&mut self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        if self.depth >= MAX_DEPTH {
+            return Err("pattern too complex".to_string());
+        }
+        self.depth += 1;
+        let result = self.match_here(s, p);
+        self.depth -= 1;
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> MatchResult<Option<usize>> {
+        loop {
+            if p >= self.pat.len() {
+                return Ok(Some(s));
+            }
+            match self.pat[p] {
+                b'(' => {
+                    return if self.pat.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                b'%' if self.pat.get(p + 1) == Some(&b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                b'%' if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                b'%' if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {}
+            }
+            let ep = self.class_end(p)?;
+            let suffix = self.pat.get(ep).copied();
+            if !self.single_match(s, p, ep) {
+                if matches!(suffix, Some(b'*' | b'?' | b'-')) {
+                    // accept the empty match
+                    p = ep + 1;
+                    continue;
+                }
+                return Ok(None);
+            }
+            match suffix {
+                Some(b'?') => {
+                    if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                        return Ok(Some(end));
+                    }
+                    p = ep + 1;
+                }
+                Some(b'+') => return self.max_expand(s + 1, p, ep),
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult<Option<usize>> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // try with the longest repetition first
+        loop {
+            if let Some(end) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> MatchResult<Option<usize>> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> MatchResult<Option<usize>> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        let Some(l) = (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+        else {
+            return Err("invalid pattern capture".to_string());
+        };
+        self.capture[l].1 = (s - self.capture[l].0) as isize;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> MatchResult<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for i in s + 1..self.src.len() {
+            if self.src[i] == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if self.src[i] == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, digit: u8) -> MatchResult<Option<usize>> {
+        let l = (digit as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.capture[l].1 == CAP_UNFINISHED {
+            return Err("invalid capture index".to_string());
+        }
+        let (start, len) = self.capture[l];
+        let len = len as usize;
+        let end = s + len;
+        Ok(
+            (end <= self.src.len() && self.src[start..start + len] == self.src[s..end])
+                .then_some(end),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(src: &str, pat: &str) -> Option<(usize, usize, Vec<Value>)> {
+        let mut m = Matcher::new(src.as_bytes(), pat.as_bytes());
+        (0..=src.len()).find_map(|s| {
+            let e = m.find_at(s, 0).unwrap()?;
+            Some((s, e, m.captures(s, e, false).unwrap()))
+        })
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(find("hello world", "o w").map(|m| (m.0, m.1)), Some((4, 7)));
+        assert_eq!(
+            find("key:123:x", "(%a+):(%d+)").unwrap().2,
+            vec![Value::str("key"), Value::str("123")]
+        );
+        assert_eq!(find("f(a(b)c)d", "%b()").map(|m| (m.0, m.1)), Some((1, 8)));
+        assert_eq!(
+            find("THE (quick) fox", "%f[%a]%a+").map(|m| (m.0, m.1)),
+            Some((0, 3))
+        );
+        assert_eq!(find("aaab", "a-b").map(|m| (m.0, m.1)), Some((0, 4)));
+        assert_eq!(
+            find("x = 'y'", "(['\"])(.-)%1").unwrap().2[1],
+            Value::str("y")
+        );
+        assert_eq!(
+            find("abc", "()b()").unwrap().2,
+            vec![Value::Number(2.0), Value::Number(3.0)]
+        );
+        assert_eq!(find("a]c", "[]]").map(|m| m.0), Some(1));
+
+        let mut m = Matcher::new(b"abc", b"[a");
+        assert_eq!(
+            m.find_at(0, 0).unwrap_err(),
+            "malformed pattern (missing ']')"
+        );
+    }
+}
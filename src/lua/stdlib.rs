@@ -0,0 +1,1090 @@
+//! The subset of the Lua 5.1 standard library available to scripts: the base
+//! functions, `string`, `table` and `math`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::interp::Interp;
+use super::pattern::{has_specials, Matcher};
+use super::value::{
+    format_e, format_g, format_number, Function, LuaError, LuaResult, Native, NativeFn, Table,
+    TableRef, Value,
+};
+
+/// Results from `unpack` beyond this fail like Lua's stack limit.
+const MAX_RESULTS: usize = 8000;
+/// Strings built by `string.rep` and `string.format` are capped at 512MB,
+/// the limit of a Redis string.
+const MAX_STRING: usize = 512 * 1024 * 1024;
+
+fn lib(functions: &[(&'static str, NativeFn)]) -> TableRef {
+    let mut table = Table::default();
+    for &(name, f) in functions {
+        table.set_str(name, Value::native(name, f));
+    }
+    Rc::new(RefCell::new(table))
+}
+
+/// Registers the libraries in the interpreter's globals.
+pub fn open(interp: &mut Interp) {
+    let base: &[(&'static str, NativeFn)] = &[
+        ("assert", assert),
+        ("error", error),
+        ("ipairs", ipairs),
+        ("next", next),
+        ("pairs", pairs),
+        ("pcall", pcall),
+        ("rawequal", rawequal),
+        ("rawget", rawget),
+        ("rawset", rawset),
+        ("select", select),
+        ("setmetatable", setmetatable),
+        ("getmetatable", getmetatable),
+        ("tonumber", tonumber),
+        ("tostring", tostring),
+        ("type", type_),
+        ("unpack", unpack),
+    ];
+    let string = lib(&[
+        ("byte", str_byte),
+        ("char", str_char),
+        ("find", str_find),
+        ("format", str_format),
+        ("gmatch", str_gmatch),
+        ("gsub", str_gsub),
+        ("len", str_len),
+        ("lower", str_lower),
+        ("match", str_match),
+        ("rep", str_rep),
+        ("reverse", str_reverse),
+        ("sub", str_sub),
+        ("upper", str_upper),
+    ]);
+    let table = lib(&[
+        ("concat", tbl_concat),
+        ("getn", tbl_getn),
+        ("insert", tbl_insert),
+        ("remove", tbl_remove),
+        ("sort", tbl_sort),
+    ]);
+    let math = lib(&[
+        ("abs", |i, n, args| math1(i, n, args, f64::abs)),
+        ("acos", |i, n, args| math1(i, n, args, f64::acos)),
+        ("asin", |i, n, args| math1(i, n, args, f64::asin)),
+        ("atan", |i, n, args| math1(i, n, args, f64::atan)),
+        ("ceil", |i, n, args| math1(i, n, args, f64::ceil)),
+        ("cos", |i, n, args| math1(i, n, args, f64::cos)),
+        ("exp", |i, n, args| math1(i, n, args, f64::exp)),
+        ("floor", |i, n, args| math1(i, n, args, f64::floor)),
+        ("log", |i, n, args| math1(i, n, args, f64::ln)),
+        ("log10", |i, n, args| math1(i, n, args, f64::log10)),
+        ("sin", |i, n, args| math1(i, n, args, f64::sin)),
+        ("sqrt", |i, n, args| math1(i, n, args, f64::sqrt)),
+        ("tan", |i, n, args| math1(i, n, args, f64::tan)),
+        ("atan2", |i, n, args| math2(i, n, args, f64::atan2)),
+        ("fmod", |i, n, args| math2(i, n, args, |a, b| a % b)),
+        ("pow", |i, n, args| math2(i, n, args, f64::powf)),
+        ("max", math_max),
+        ("min", math_min),
+        ("modf", math_modf),
+    ]);
+    {
+        let mut math = math.borrow_mut();
+        math.set_str("pi", Value::Number(std::f64::consts::PI));
+        math.set_str("huge", Value::Number(f64::INFINITY));
+        // both functions share the generator state
+        let seed = Value::table(Table::from_array(vec![Value::Number(0.0)]));
+        math.set_str(
+            "random",
+            Value::native_with("random", math_random, vec![seed.clone()]),
+        );
+        math.set_str(
+            "randomseed",
+            Value::native_with("randomseed", math_randomseed, vec![seed]),
+        );
+    }
+
+    let mut globals = interp.globals.borrow_mut();
+    for &(name, f) in base {
+        globals.set_str(name, Value::native(name, f));
+    }
+    globals.set_str("_G", Value::Table(Rc::clone(&interp.globals)));
+    globals.set_str("_VERSION", Value::str("Lua 5.1"));
+    globals.set_str("string", Value::Table(Rc::clone(&string)));
+    globals.set_str("table", Value::Table(table));
+    globals.set_str("math", Value::Table(math));
+    drop(globals);
+    interp.string_lib = Some(string);
+}
+
+pub fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or_default()
+}
+
+fn bad_arg(interp: &Interp, native: &Native, i: usize, msg: &str) -> LuaError {
+    interp.error(format!(
+        "bad argument #{} to '{}' ({})",
+        i + 1,
+        native.name,
+        msg
+    ))
+}
+
+fn type_error(
+    interp: &Interp,
+    native: &Native,
+    args: &[Value],
+    i: usize,
+    expected: &str,
+) -> LuaError {
+    let got = match args.get(i) {
+        Some(v) => v.type_name(),
+        None => "no value",
+    };
+    bad_arg(
+        interp,
+        native,
+        i,
+        &format!("{} expected, got {}", expected, got),
+    )
+}
+
+pub fn check_str(
+    interp: &Interp,
+    native: &Native,
+    args: &[Value],
+    i: usize,
+) -> LuaResult<Rc<[u8]>> {
+    arg(args, i)
+        .to_bytes()
+        .ok_or_else(|| type_error(interp, native, args, i, "string"))
+}
+
+pub fn check_num(interp: &Interp, native: &Native, args: &[Value], i: usize) -> LuaResult<f64> {
+    arg(args, i)
+        .to_number()
+        .ok_or_else(|| type_error(interp, native, args, i, "number"))
+}
+
+fn check_int(interp: &Interp, native: &Native, args: &[Value], i: usize) -> LuaResult<i64> {
+    Ok(check_num(interp, native, args, i)? as i64)
+}
+
+fn opt_int(
+    interp: &Interp,
+    native: &Native,
+    args: &[Value],
+    i: usize,
+    default: i64,
+) -> LuaResult<i64> {
+    match arg(args, i) {
+        Value::Nil => Ok(default),
+        _ => check_int(interp, native, args, i),
+    }
+}
+
+fn check_table(interp: &Interp, native: &Native, args: &[Value], i: usize) -> LuaResult<TableRef> {
+    match arg(args, i) {
+        Value::Table(t) => Ok(t),
+        _ => Err(type_error(interp, native, args, i, "table")),
+    }
+}
+
+fn writable(interp: &Interp, t: &TableRef) -> LuaResult<()> {
+    if t.borrow().readonly {
+        return Err(interp.error("Attempt to modify a readonly table"));
+    }
+    Ok(())
+}
+
+/// `tostring`, without metamethods.
+pub fn to_display(value: &Value) -> Rc<[u8]> {
+    match value {
+        Value::Nil => Rc::from(&b"nil"[..]),
+        Value::Boolean(b) => Rc::from(b.to_string().as_bytes()),
+        Value::Number(n) => Rc::from(format_number(*n).as_bytes()),
+        Value::Str(s) => Rc::clone(s),
+        Value::Table(t) => Rc::from(format!("table: {:p}", Rc::as_ptr(t)).as_bytes()),
+        Value::Function(f) => {
+            let kind = match &**f {
+                Function::Native(_) => "builtin: ",
+                Function::Lua(_) => "",
+            };
+            Rc::from(format!("function: {}{:p}", kind, Rc::as_ptr(f)).as_bytes())
+        }
+    }
+}
+
+// position arguments count from the end of the string when negative
+fn relative_position(pos: i64, len: usize) -> i64 {
+    if pos < 0 {
+        len as i64 + pos + 1
+    } else {
+        pos
+    }
+}
+
+fn assert(_: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if arg(&args, 0).truthy() {
+        return Ok(args);
+    }
+    let msg = match arg(&args, 1) {
+        Value::Nil => Value::str("assertion failed!"),
+        msg => msg,
+    };
+    Err(LuaError::Error(msg))
+}
+
+fn error(interp: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let level = arg(&args, 1).to_number().unwrap_or(1.0);
+    match arg(&args, 0) {
+        Value::Str(s) if level > 0.0 => Err(interp.error(String::from_utf8_lossy(&s))),
+        msg => Err(LuaError::Error(msg)),
+    }
+}
+
+fn ipairs(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    Ok(vec![
+        Value::native("ipairs_aux", ipairs_aux),
+        Value::Table(t),
+        Value::Number(0.0),
+    ])
+}
+
+fn ipairs_aux(_: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let (Value::Table(t), Value::Number(i)) = (arg(&args, 0), arg(&args, 1)) else {
+        return Ok(vec![Value::Nil]);
+    };
+    let key = Value::Number(i + 1.0);
+    let value = t.borrow().get(&key);
+    match value {
+        Value::Nil => Ok(vec![Value::Nil]),
+        value => Ok(vec![key, value]),
+    }
+}
+
+fn next(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let next = t.borrow().next(&arg(&args, 1));
+    match next {
+        Some((Value::Nil, _)) => Ok(vec![Value::Nil]),
+        Some((key, value)) => Ok(vec![key, value]),
+        None => Err(interp.error("invalid key to 'next'")),
+    }
+}
+
+fn pairs(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    Ok(vec![
+        Value::native("next", next),
+        Value::Table(t),
+        Value::Nil,
+    ])
+}
+
+fn pcall(interp: &mut Interp, native: &Native, mut args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Err(bad_arg(interp, native, 0, "value expected"));
+    }
+    let func = args.remove(0);
+    match interp.call(&func, args) {
+        Ok(mut results) => {
+            results.insert(0, Value::Boolean(true));
+            Ok(results)
+        }
+        Err(LuaError::Error(e)) => Ok(vec![Value::Boolean(false), e]),
+        Err(abort) => Err(abort),
+    }
+}
+
+fn rawequal(_: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    Ok(vec![Value::Boolean(arg(&args, 0) == arg(&args, 1))])
+}
+
+fn rawget(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let value = t.borrow().get(&arg(&args, 1));
+    Ok(vec![value])
+}
+
+fn rawset(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    interp.raw_set(&t, arg(&args, 1), arg(&args, 2))?;
+    Ok(vec![Value::Table(t)])
+}
+
+fn select(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let count = args.len().saturating_sub(1);
+    if let Value::Str(s) = arg(&args, 0) {
+        if &*s == b"#" {
+            return Ok(vec![Value::Number(count as f64)]);
+        }
+    }
+    let mut n = check_int(interp, native, &args, 0)?;
+    if n < 0 {
+        n += count as i64 + 1;
+    }
+    if n < 1 {
+        return Err(bad_arg(interp, native, 0, "index out of range"));
+    }
+    Ok(args.into_iter().skip(n as usize).collect())
+}
+
+fn setmetatable(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let meta = match arg(&args, 1) {
+        Value::Nil => None,
+        Value::Table(meta) => Some(meta),
+        _ => return Err(type_error(interp, native, &args, 1, "nil or table")),
+    };
+    writable(interp, &t)?;
+    let protected = t
+        .borrow()
+        .metatable
+        .as_ref()
+        .is_some_and(|m| !m.borrow().get_str("__metatable").is_nil());
+    if protected {
+        return Err(interp.error("cannot change a protected metatable"));
+    }
+    t.borrow_mut().metatable = meta;
+    Ok(vec![Value::Table(t)])
+}
+
+fn getmetatable(_: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let Value::Table(t) = arg(&args, 0) else {
+        return Ok(vec![Value::Nil]);
+    };
+    let meta = t.borrow().metatable.clone();
+    Ok(vec![match meta {
+        Some(meta) => match meta.borrow().get_str("__metatable") {
+            Value::Nil => Value::Table(Rc::clone(&meta)),
+            protected => protected,
+        },
+        None => Value::Nil,
+    }])
+}
+
+fn tonumber(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let base = opt_int(interp, native, &args, 1, 10)?;
+    if base == 10 {
+        return Ok(vec![arg(&args, 0)
+            .to_number()
+            .map_or(Value::Nil, Value::Number)]);
+    }
+    if !(2..=36).contains(&base) {
+        return Err(bad_arg(interp, native, 1, "base out of range"));
+    }
+    let s = check_str(interp, native, &args, 0)?;
+    let parsed = std::str::from_utf8(&s)
+        .ok()
+        .and_then(|s| i64::from_str_radix(s.trim(), base as u32).ok());
+    Ok(vec![parsed.map_or(Value::Nil, |n| Value::Number(n as f64))])
+}
+
+fn tostring(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Err(bad_arg(interp, native, 0, "value expected"));
+    }
+    let value = arg(&args, 0);
+    if let Value::Table(t) = &value {
+        let handler = t
+            .borrow()
+            .metatable
+            .as_ref()
+            .map(|m| m.borrow().get_str("__tostring"));
+        if let Some(handler @ Value::Function(_)) = handler {
+            let result = interp.call(&handler, vec![value.clone()])?;
+            return Ok(vec![arg(&result, 0)]);
+        }
+    }
+    Ok(vec![Value::Str(to_display(&value))])
+}
+
+fn type_(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        Some(value) => Ok(vec![Value::str(value.type_name())]),
+        None => Err(bad_arg(interp, native, 0, "value expected")),
+    }
+}
+
+fn unpack(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let t = t.borrow();
+    let i = opt_int(interp, native, &args, 1, 1)?;
+    let j = opt_int(interp, native, &args, 2, t.len() as i64)?;
+    if i > j {
+        return Ok(vec![]);
+    }
+    if j - i >= MAX_RESULTS as i64 {
+        return Err(interp.error("too many results to unpack"));
+    }
+    Ok((i..=j).map(|k| t.get(&Value::Number(k as f64))).collect())
+}
+
+fn str_len(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    Ok(vec![Value::Number(s.len() as f64)])
+}
+
+fn str_sub(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let start = relative_position(opt_int(interp, native, &args, 1, 1)?, s.len()).max(1);
+    let end =
+        relative_position(opt_int(interp, native, &args, 2, -1)?, s.len()).min(s.len() as i64);
+    if start > end {
+        return Ok(vec![Value::str("")]);
+    }
+    Ok(vec![Value::str(&s[start as usize - 1..end as usize])])
+}
+
+fn str_upper(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    Ok(vec![Value::str(s.to_ascii_uppercase())])
+}
+
+fn str_lower(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    Ok(vec![Value::str(s.to_ascii_lowercase())])
+}
+
+fn str_rep(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let n = check_int(interp, native, &args, 1)?.max(0) as usize;
+    if s.len().saturating_mul(n) > MAX_STRING {
+        return Err(interp.error("resulting string too large"));
+    }
+    Ok(vec![Value::str(s.repeat(n))])
+}
+
+fn str_reverse(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    Ok(vec![Value::str(
+        s.iter().rev().copied().collect::<Vec<u8>>(),
+    )])
+}
+
+fn str_byte(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let i = relative_position(opt_int(interp, native, &args, 1, 1)?, s.len());
+    let j = relative_position(opt_int(interp, native, &args, 2, i)?, s.len());
+    let (i, j) = (i.max(1), j.min(s.len() as i64));
+    if i > j {
+        return Ok(vec![]);
+    }
+    Ok(s[i as usize - 1..j as usize]
+        .iter()
+        .map(|&b| Value::Number(b as f64))
+        .collect())
+}
+
+fn str_char(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut out = Vec::with_capacity(args.len());
+    for i in 0..args.len() {
+        let c = check_int(interp, native, &args, i)?;
+        if !(0..=255).contains(&c) {
+            return Err(bad_arg(interp, native, i, "invalid value"));
+        }
+        out.push(c as u8);
+    }
+    Ok(vec![Value::str(out)])
+}
+
+fn pattern_error(interp: &Interp, msg: String) -> LuaError {
+    interp.error(msg)
+}
+
+fn str_find(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    find_aux(interp, native, args, true)
+}
+
+fn str_match(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    find_aux(interp, native, args, false)
+}
+
+fn find_aux(
+    interp: &mut Interp,
+    native: &Native,
+    args: Vec<Value>,
+    find: bool,
+) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let pat = check_str(interp, native, &args, 1)?;
+    let init = relative_position(opt_int(interp, native, &args, 2, 1)?, s.len()).max(1) as usize;
+    if init > s.len() + 1 {
+        return Ok(vec![Value::Nil]);
+    }
+    let init = init - 1;
+    if find && (arg(&args, 3).truthy() || !has_specials(&pat)) {
+        let found = if pat.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(pat.len()).position(|w| w == &*pat)
+        };
+        return Ok(match found {
+            Some(i) => vec![
+                Value::Number((init + i + 1) as f64),
+                Value::Number((init + i + pat.len()) as f64),
+            ],
+            None => vec![Value::Nil],
+        });
+    }
+    let anchor = pat.first() == Some(&b'^');
+    let mut matcher = Matcher::new(&s, &pat);
+    let mut start = init;
+    loop {
+        let found = matcher
+            .find_at(start, anchor as usize)
+            .map_err(|e| pattern_error(interp, e))?;
+        if let Some(end) = found {
+            let captures = matcher
+                .captures(start, end, !find)
+                .map_err(|e| pattern_error(interp, e))?;
+            if !find {
+                return Ok(captures);
+            }
+            let mut results = vec![Value::Number((start + 1) as f64), Value::Number(end as f64)];
+            results.extend(captures);
+            return Ok(results);
+        }
+        start += 1;
+        if anchor || start > s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+    }
+}
+
+fn str_gmatch(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let pat = check_str(interp, native, &args, 1)?;
+    Ok(vec![Value::native_with(
+        "gmatch_aux",
+        gmatch_aux,
+        vec![Value::Str(s), Value::Str(pat), Value::Number(0.0)],
+    )])
+}
+
+fn gmatch_aux(interp: &mut Interp, native: &Native, _: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut state = native.upvalues.borrow_mut();
+    let (Value::Str(s), Value::Str(pat), Value::Number(pos)) =
+        (state[0].clone(), state[1].clone(), state[2].clone())
+    else {
+        unreachable!("gmatch state is set on creation");
+    };
+    let mut matcher = Matcher::new(&s, &pat);
+    for start in pos as usize..=s.len() {
+        let found = matcher
+            .find_at(start, 0)
+            .map_err(|e| pattern_error(interp, e))?;
+        if let Some(end) = found {
+            // an empty match must not be found again
+            let next = if end == start { end + 1 } else { end };
+            state[2] = Value::Number(next as f64);
+            return matcher
+                .captures(start, end, true)
+                .map_err(|e| pattern_error(interp, e));
+        }
+    }
+    state[2] = Value::Number((s.len() + 1) as f64);
+    Ok(vec![Value::Nil])
+}
+
+fn str_gsub(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let s = check_str(interp, native, &args, 0)?;
+    let pat = check_str(interp, native, &args, 1)?;
+    let repl = arg(&args, 2);
+    if !matches!(
+        repl,
+        Value::Number(_) | Value::Str(_) | Value::Table(_) | Value::Function(_)
+    ) {
+        return Err(type_error(
+            interp,
+            native,
+            &args,
+            2,
+            "string/function/table",
+        ));
+    }
+    let max = opt_int(interp, native, &args, 3, s.len() as i64 + 1)?;
+    let anchor = pat.first() == Some(&b'^');
+    let mut matcher = Matcher::new(&s, &pat);
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut n = 0;
+    while n < max {
+        let found = matcher
+            .find_at(start, anchor as usize)
+            .map_err(|e| pattern_error(interp, e))?;
+        if let Some(end) = found {
+            n += 1;
+            add_replacement(interp, &matcher, &mut out, start, end, &repl)?;
+        }
+        match found {
+            Some(end) if end > start => start = end,
+            _ if start < s.len() => {
+                out.push(s[start]);
+                start += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&s[start..]);
+    Ok(vec![Value::str(out), Value::Number(n as f64)])
+}
+
+fn add_replacement(
+    interp: &mut Interp,
+    matcher: &Matcher,
+    out: &mut Vec<u8>,
+    start: usize,
+    end: usize,
+    repl: &Value,
+) -> LuaResult<()> {
+    let capture = |interp: &Interp, i| {
+        matcher
+            .capture_value(i, start, end)
+            .map_err(|e| pattern_error(interp, e))
+    };
+    let value = match repl {
+        Value::Table(_) => {
+            let key = capture(interp, 0)?;
+            interp.index(repl, &key)?
+        }
+        Value::Function(_) => {
+            let captures = matcher
+                .captures(start, end, true)
+                .map_err(|e| pattern_error(interp, e))?;
+            arg(&interp.call(repl, captures)?, 0)
+        }
+        _ => {
+            let repl = repl.to_bytes().unwrap();
+            let mut i = 0;
+            while i < repl.len() {
+                let c = repl[i];
+                i += 1;
+                if c != b'%' || i == repl.len() {
+                    out.push(c);
+                    continue;
+                }
+                let d = repl[i];
+                i += 1;
+                if d == b'0' {
+                    out.extend_from_slice(matcher.matched(start, end));
+                } else if d.is_ascii_digit() {
+                    let value = capture(interp, (d - b'1') as usize)?;
+                    out.extend_from_slice(&value.to_bytes().unwrap());
+                } else {
+                    out.push(d);
+                }
+            }
+            return Ok(());
+        }
+    };
+    match value {
+        Value::Nil | Value::Boolean(false) => out.extend_from_slice(matcher.matched(start, end)),
+        Value::Str(_) | Value::Number(_) => out.extend_from_slice(&value.to_bytes().unwrap()),
+        other => {
+            return Err(interp.error(format!(
+                "invalid replacement value (a {})",
+                other.type_name()
+            )))
+        }
+    }
+    Ok(())
+}
+
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Pads a formatted number, `sign` being its "-", "+" or " " prefix.
+    /// Zero padding goes between the sign and the digits.
+    fn pad_number(&self, sign: &str, digits: String, zero: bool) -> Vec<u8> {
+        let len = sign.len() + digits.len();
+        let fill = self.width.saturating_sub(len);
+        let s = if self.left {
+            format!("{}{}{}", sign, digits, " ".repeat(fill))
+        } else if zero {
+            format!("{}{}{}", sign, "0".repeat(fill), digits)
+        } else {
+            format!("{}{}{}", " ".repeat(fill), sign, digits)
+        };
+        s.into_bytes()
+    }
+
+    fn pad(&self, s: &[u8]) -> Vec<u8> {
+        let fill = self.width.saturating_sub(s.len());
+        let mut out = Vec::with_capacity(s.len() + fill);
+        if !self.left {
+            out.resize(fill, b' ');
+        }
+        out.extend_from_slice(s);
+        if self.left {
+            out.resize(out.len() + fill, b' ');
+        }
+        out
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+}
+
+fn str_format(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let fmt = check_str(interp, native, &args, 0)?;
+    let mut out = Vec::new();
+    let mut next_arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let mut spec = Spec {
+            left: false,
+            plus: false,
+            space: false,
+            alternate: false,
+            zero: false,
+            width: 0,
+            precision: None,
+        };
+        while let Some(&flag) = fmt.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let digits = |i: &mut usize| {
+            let mut n = 0;
+            let mut count = 0;
+            while let Some(d) = fmt.get(*i).filter(|d| d.is_ascii_digit()) {
+                n = n * 10 + (d - b'0') as usize;
+                *i += 1;
+                count += 1;
+            }
+            (n, count)
+        };
+        let (width, width_digits) = digits(&mut i);
+        spec.width = width;
+        let mut precision_digits = 0;
+        if fmt.get(i) == Some(&b'.') {
+            i += 1;
+            let (precision, count) = digits(&mut i);
+            spec.precision = Some(precision);
+            precision_digits = count;
+        }
+        if width_digits > 2 || precision_digits > 2 {
+            return Err(interp.error("invalid format (width or precision too long)"));
+        }
+        let Some(&conversion) = fmt.get(i) else {
+            return Err(interp.error("invalid option '%' to 'format'"));
+        };
+        i += 1;
+        let n = next_arg;
+        next_arg += 1;
+        if n >= args.len() {
+            return Err(bad_arg(interp, native, n, "no value"));
+        }
+        match conversion {
+            b'd' | b'i' => {
+                let v = check_num(interp, native, &args, n)? as i64;
+                let mut digits = v.unsigned_abs().to_string();
+                if let Some(p) = spec.precision {
+                    if digits.len() < p {
+                        digits = format!("{}{}", "0".repeat(p - digits.len()), digits);
+                    }
+                }
+                // C ignores the 0 flag when an integer has a precision
+                let zero = spec.zero && spec.precision.is_none();
+                out.extend(spec.pad_number(spec.sign(v < 0), digits, zero));
+            }
+            b'u' | b'o' | b'x' | b'X' => {
+                let v = check_num(interp, native, &args, n)? as i64 as u64;
+                let mut digits = match conversion {
+                    b'u' => v.to_string(),
+                    b'o' => format!("{:o}", v),
+                    b'x' => format!("{:x}", v),
+                    _ => format!("{:X}", v),
+                };
+                if let Some(p) = spec.precision {
+                    if digits.len() < p {
+                        digits = format!("{}{}", "0".repeat(p - digits.len()), digits);
+                    }
+                }
+                let prefix = match conversion {
+                    b'x' if spec.alternate && v != 0 => "0x",
+                    b'X' if spec.alternate && v != 0 => "0X",
+                    b'o' if spec.alternate && !digits.starts_with('0') => "0",
+                    _ => "",
+                };
+                let zero = spec.zero && spec.precision.is_none();
+                out.extend(spec.pad_number(prefix, digits, zero));
+            }
+            b'c' => {
+                let v = check_num(interp, native, &args, n)? as i64;
+                out.extend(spec.pad(&[v as u8]));
+            }
+            b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let v = check_num(interp, native, &args, n)?;
+                let precision = spec.precision.unwrap_or(6);
+                let body = if !v.is_finite() {
+                    format_g(v.abs(), 1, false)
+                } else {
+                    match conversion {
+                        b'e' | b'E' => format_e(v.abs(), precision),
+                        b'f' | b'F' => format!("{:.*}", precision, v.abs()),
+                        _ => format_g(v.abs(), precision, spec.alternate),
+                    }
+                };
+                let body = if conversion.is_ascii_uppercase() {
+                    body.to_ascii_uppercase()
+                } else {
+                    body
+                };
+                let sign = spec.sign(v.is_sign_negative() && !v.is_nan());
+                out.extend(spec.pad_number(sign, body, spec.zero && v.is_finite()));
+            }
+            b'q' => {
+                let s = check_str(interp, native, &args, n)?;
+                out.push(b'"');
+                for &b in s.iter() {
+                    match b {
+                        b'"' | b'\\' | b'\n' => {
+                            out.push(b'\\');
+                            out.push(b);
+                        }
+                        b'\r' => out.extend_from_slice(b"\\r"),
+                        0 => out.extend_from_slice(b"\\000"),
+                        _ => out.push(b),
+                    }
+                }
+                out.push(b'"');
+            }
+            b's' => {
+                let s = check_str(interp, native, &args, n)?;
+                let s = match spec.precision {
+                    Some(p) if p < s.len() => &s[..p],
+                    _ => &s[..],
+                };
+                out.extend(spec.pad(s));
+            }
+            other => {
+                return Err(interp.error(format!("invalid option '%{}' to 'format'", other as char)))
+            }
+        }
+        if out.len() > MAX_STRING {
+            return Err(interp.error("resulting string too large"));
+        }
+    }
+    Ok(vec![Value::str(out)])
+}
+
+fn tbl_getn(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let n = t.borrow().len();
+    Ok(vec![Value::Number(n as f64)])
+}
+
+fn tbl_insert(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    writable(interp, &t)?;
+    let n = t.borrow().len();
+    let (pos, value) = match args.len() {
+        2 => (n + 1, arg(&args, 1)),
+        3 => {
+            let pos = check_int(interp, native, &args, 1)?;
+            if pos < 1 || pos as usize > n + 1 {
+                return Err(bad_arg(interp, native, 1, "position out of bounds"));
+            }
+            (pos as usize, arg(&args, 2))
+        }
+        _ => return Err(interp.error("wrong number of arguments to 'insert'")),
+    };
+    t.borrow_mut().insert_at(pos, value);
+    Ok(vec![])
+}
+
+fn tbl_remove(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    writable(interp, &t)?;
+    let n = t.borrow().len();
+    if n == 0 {
+        return Ok(vec![Value::Nil]);
+    }
+    let pos = opt_int(interp, native, &args, 1, n as i64)?;
+    if pos < 1 {
+        return Ok(vec![Value::Nil]);
+    }
+    let value = t.borrow_mut().remove_at(pos as usize);
+    Ok(vec![value])
+}
+
+fn tbl_concat(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    let sep = match arg(&args, 1) {
+        Value::Nil => Rc::from(&b""[..]),
+        _ => check_str(interp, native, &args, 1)?,
+    };
+    let i = opt_int(interp, native, &args, 2, 1)?;
+    let j = opt_int(interp, native, &args, 3, t.borrow().len() as i64)?;
+    let t = t.borrow();
+    let mut out = Vec::new();
+    for k in i..=j {
+        let value = t.get(&Value::Number(k as f64));
+        match value {
+            Value::Str(_) | Value::Number(_) => out.extend_from_slice(&value.to_bytes().unwrap()),
+            _ => {
+                return Err(interp.error(format!(
+                    "invalid value (at index {}) in table for 'concat'",
+                    k
+                )))
+            }
+        }
+        if k < j {
+            out.extend_from_slice(&sep);
+        }
+    }
+    Ok(vec![Value::str(out)])
+}
+
+fn tbl_sort(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let t = check_table(interp, native, &args, 0)?;
+    writable(interp, &t)?;
+    let cmp = arg(&args, 1);
+    if !matches!(cmp, Value::Nil | Value::Function(_)) {
+        return Err(type_error(interp, native, &args, 1, "function"));
+    }
+    // sort a copy: the comparison function may look at the table
+    let mut sorted = Table::from_array(t.borrow().array().to_vec());
+    sorted.sort_array(|a, b| match &cmp {
+        Value::Nil => interp.less_than(a, b),
+        f => Ok(arg(&interp.call(f, vec![a.clone(), b.clone()])?, 0).truthy()),
+    })?;
+    let mut t = t.borrow_mut();
+    for (i, value) in sorted.array().iter().enumerate() {
+        t.set(Value::Number((i + 1) as f64), value.clone());
+    }
+    Ok(vec![])
+}
+
+fn math1(
+    interp: &mut Interp,
+    native: &Native,
+    args: Vec<Value>,
+    f: fn(f64) -> f64,
+) -> LuaResult<Vec<Value>> {
+    let x = check_num(interp, native, &args, 0)?;
+    Ok(vec![Value::Number(f(x))])
+}
+
+fn math2(
+    interp: &mut Interp,
+    native: &Native,
+    args: Vec<Value>,
+    f: fn(f64, f64) -> f64,
+) -> LuaResult<Vec<Value>> {
+    let x = check_num(interp, native, &args, 0)?;
+    let y = check_num(interp, native, &args, 1)?;
+    Ok(vec![Value::Number(f(x, y))])
+}
+
+fn math_max(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut max = check_num(interp, native, &args, 0)?;
+    for i in 1..args.len() {
+        max = max.max(check_num(interp, native, &args, i)?);
+    }
+    Ok(vec![Value::Number(max)])
+}
+
+fn math_min(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let mut min = check_num(interp, native, &args, 0)?;
+    for i in 1..args.len() {
+        min = min.min(check_num(interp, native, &args, i)?);
+    }
+    Ok(vec![Value::Number(min)])
+}
+
+fn math_modf(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let x = check_num(interp, native, &args, 0)?;
+    Ok(vec![Value::Number(x.trunc()), Value::Number(x.fract())])
+}
+
+// a 48-bit linear congruential generator like drand48, so that scripts get
+// the same sequence on every run
+fn next_random(seed: &Value) -> f64 {
+    let Value::Table(state) = seed else {
+        unreachable!("the generator state is a table");
+    };
+    let mut state = state.borrow_mut();
+    let x = state.get(&Value::Number(1.0)).to_number().unwrap_or(0.0) as u64;
+    let x = (x.wrapping_mul(0x5DEECE66D).wrapping_add(0xB)) & ((1 << 48) - 1);
+    state.set(Value::Number(1.0), Value::Number(x as f64));
+    x as f64 / (1u64 << 48) as f64
+}
+
+fn math_random(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let r = next_random(&native.upvalues.borrow()[0]);
+    let (low, high) = match args.len() {
+        0 => return Ok(vec![Value::Number(r)]),
+        1 => (1, check_int(interp, native, &args, 0)?),
+        2 => (
+            check_int(interp, native, &args, 0)?,
+            check_int(interp, native, &args, 1)?,
+        ),
+        _ => return Err(interp.error("wrong number of arguments")),
+    };
+    if low > high {
+        return Err(bad_arg(interp, native, args.len() - 1, "interval is empty"));
+    }
+    Ok(vec![Value::Number(
+        (r * (high - low + 1) as f64).floor() + low as f64,
+    )])
+}
+
+fn math_randomseed(
+    interp: &mut Interp,
+    native: &Native,
+    args: Vec<Value>,
+) -> LuaResult<Vec<Value>> {
+    let seed = check_int(interp, native, &args, 0)?;
+    let Value::Table(state) = &native.upvalues.borrow()[0] else {
+        unreachable!("the generator state is a table");
+    };
+    // like srand48: the seed goes into the high 32 bits
+    let x = (((seed as u64) << 16) | 0x330E) & ((1 << 48) - 1);
+    state
+        .borrow_mut()
+        .set(Value::Number(1.0), Value::Number(x as f64));
+    Ok(vec![])
+}
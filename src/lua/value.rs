@@ -0,0 +1,453 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use super::ast::Proto;
+use super::interp::Interp;
+
+pub type TableRef = Rc<RefCell<Table>>;
+pub type LuaResult<T> = Result<T, LuaError>;
+
+#[derive(Clone, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    Str(Rc<[u8]>),
+    Table(TableRef),
+    Function(Rc<Function>),
+}
+
+pub enum LuaError {
+    /// Raised by `error` or a runtime failure; catchable with `pcall`.
+    Error(Value),
+    /// Stops the script unconditionally, e.g. SCRIPT KILL.
+    Abort(String),
+}
+
+pub enum Function {
+    Lua(Closure),
+    Native(Native),
+}
+
+pub struct Closure {
+    pub proto: Arc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<Value>>>,
+}
+
+pub type NativeFn = fn(&mut Interp, &Native, Vec<Value>) -> LuaResult<Vec<Value>>;
+
+/// A function implemented in Rust. `upvalues` holds per-closure state, e.g.
+/// the position of a `string.gmatch` iterator.
+pub struct Native {
+    pub name: &'static str,
+    pub f: NativeFn,
+    pub upvalues: RefCell<Vec<Value>>,
+}
+
+impl Value {
+    pub fn str(s: impl AsRef<[u8]>) -> Value {
+        Value::Str(Rc::from(s.as_ref()))
+    }
+
+    pub fn native(name: &'static str, f: NativeFn) -> Value {
+        Value::native_with(name, f, vec![])
+    }
+
+    pub fn native_with(name: &'static str, f: NativeFn, upvalues: Vec<Value>) -> Value {
+        Value::Function(Rc::new(Function::Native(Native {
+            name,
+            f,
+            upvalues: RefCell::new(upvalues),
+        })))
+    }
+
+    pub fn table(table: Table) -> Value {
+        Value::Table(Rc::new(RefCell::new(table)))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    /// Numbers, and strings that convert to numbers.
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Str(s) => parse_number(s),
+            _ => None,
+        }
+    }
+
+    /// Strings, and numbers formatted as strings.
+    pub fn to_bytes(&self) -> Option<Rc<[u8]>> {
+        match self {
+            Value::Str(s) => Some(Rc::clone(s)),
+            Value::Number(n) => Some(Rc::from(format_number(*n).as_bytes())),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    /// Raw equality: tables and functions compare by identity.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+// NaN is never used as a key, so equality is reflexive for keys
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Nil => {}
+            Value::Boolean(b) => b.hash(state),
+            // +0.0 turns -0.0 into +0.0, which compare equal
+            Value::Number(n) => (n + 0.0).to_bits().hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Table(t) => (Rc::as_ptr(t) as usize).hash(state),
+            Value::Function(f) => (Rc::as_ptr(f) as *const u8 as usize).hash(state),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::Boolean(b) => write!(f, "{}", b),
+            other => write!(f, "{}", other.type_name()),
+        }
+    }
+}
+
+/// A Lua table: an array part for the keys 1..n, and an insertion-ordered
+/// hash part so that `next` can resume from any key in constant time.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    entries: Vec<(Value, Value)>,
+    index: HashMap<Value, usize>,
+    // entries whose value was set to nil; kept so traversal can continue
+    removed: usize,
+    pub metatable: Option<TableRef>,
+    /// Set on the sandbox's globals and libraries.
+    pub readonly: bool,
+}
+
+fn array_index(key: &Value) -> Option<usize> {
+    match key {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 1.0 && *n <= usize::MAX as f64 => {
+            Some(*n as usize)
+        }
+        _ => None,
+    }
+}
+
+impl Table {
+    pub fn from_array(values: Vec<Value>) -> Table {
+        let mut table = Table::default();
+        for value in values {
+            let n = table.len() + 1;
+            table.set(Value::Number(n as f64), value);
+        }
+        table
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(i) = array_index(key) {
+            if i <= self.array.len() {
+                return self.array[i - 1].clone();
+            }
+        }
+        match self.index.get(key) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => Value::Nil,
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get(&Value::str(key))
+    }
+
+    /// Sets a field; the key must not be nil or NaN.
+    pub fn set(&mut self, key: Value, value: Value) {
+        if let Some(i) = array_index(&key) {
+            if i <= self.array.len() {
+                self.array[i - 1] = value;
+                while self.array.last().is_some_and(Value::is_nil) {
+                    self.array.pop();
+                }
+                return;
+            }
+            if i == self.array.len() + 1 && !value.is_nil() {
+                self.remove_entry(&key);
+                self.array.push(value);
+                // move the keys that now continue the sequence
+                loop {
+                    let next = Value::Number((self.array.len() + 1) as f64);
+                    match self.remove_entry(&next) {
+                        Some(v) => self.array.push(v),
+                        None => break,
+                    }
+                }
+                return;
+            }
+        }
+        match self.index.get(&key) {
+            Some(&i) => {
+                if self.entries[i].1.is_nil() && !value.is_nil() {
+                    self.removed -= 1;
+                } else if !self.entries[i].1.is_nil() && value.is_nil() {
+                    self.removed += 1;
+                }
+                self.entries[i].1 = value;
+            }
+            None if value.is_nil() => {}
+            None => {
+                if self.removed > 16 && self.removed * 2 > self.entries.len() {
+                    self.compact();
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        self.set(Value::str(key), value);
+    }
+
+    fn remove_entry(&mut self, key: &Value) -> Option<Value> {
+        let i = *self.index.get(key)?;
+        let value = std::mem::take(&mut self.entries[i].1);
+        if value.is_nil() {
+            return None;
+        }
+        self.removed += 1;
+        Some(value)
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, v)| !v.is_nil());
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (k.clone(), i))
+            .collect();
+        self.removed = 0;
+    }
+
+    /// The length operator: the array part never ends with nil.
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    /// The field after `key` in traversal order, or `None` when `key` is not
+    /// in the table.
+    pub fn next(&self, key: &Value) -> Option<(Value, Value)> {
+        let start = match key {
+            Value::Nil => 0,
+            _ => match array_index(key) {
+                Some(i) if i <= self.array.len() => i,
+                _ => self.array.len() + 1 + *self.index.get(key)?,
+            },
+        };
+        for i in start..self.array.len() {
+            if !self.array[i].is_nil() {
+                return Some((Value::Number((i + 1) as f64), self.array[i].clone()));
+            }
+        }
+        let hash_start = start.saturating_sub(self.array.len());
+        let found = self.entries[hash_start..]
+            .iter()
+            .find(|(_, v)| !v.is_nil())
+            .cloned();
+        Some(found.unwrap_or((Value::Nil, Value::Nil)))
+    }
+
+    pub fn array(&self) -> &[Value] {
+        &self.array
+    }
+
+    /// Removes and returns the element at `pos` (1-based) of the sequence,
+    /// shifting the following ones down.
+    pub fn remove_at(&mut self, pos: usize) -> Value {
+        if pos == 0 || pos > self.array.len() {
+            return Value::Nil;
+        }
+        let value = self.array.remove(pos - 1);
+        while self.array.last().is_some_and(Value::is_nil) {
+            self.array.pop();
+        }
+        value
+    }
+
+    /// Inserts at `pos` (1-based, at most `len() + 1`), shifting elements up.
+    pub fn insert_at(&mut self, pos: usize, value: Value) {
+        if value.is_nil() || pos > self.array.len() {
+            self.set(Value::Number(pos as f64), value);
+        } else {
+            self.array.insert(pos - 1, value);
+        }
+    }
+
+    /// Sorts the sequence with a fallible `less`. A merge sort never gets
+    /// confused by an inconsistent comparison function.
+    pub fn sort_array<F>(&mut self, mut less: F) -> LuaResult<()>
+    where
+        F: FnMut(&Value, &Value) -> LuaResult<bool>,
+    {
+        let n = self.array.len();
+        let mut src = std::mem::take(&mut self.array);
+        let mut dst = src.clone();
+        let mut width = 1;
+        while width < n {
+            for lo in (0..n).step_by(2 * width) {
+                let mid = (lo + width).min(n);
+                let hi = (lo + 2 * width).min(n);
+                let (mut a, mut b) = (lo, mid);
+                for slot in &mut dst[lo..hi] {
+                    let take_b = a == mid || (b < hi && less(&src[b], &src[a])?);
+                    if take_b {
+                        *slot = src[b].clone();
+                        b += 1;
+                    } else {
+                        *slot = src[a].clone();
+                        a += 1;
+                    }
+                }
+            }
+            std::mem::swap(&mut src, &mut dst);
+            width *= 2;
+        }
+        self.array = src;
+        Ok(())
+    }
+}
+
+/// Parses a number the way Lua converts strings: decimal or hexadecimal,
+/// surrounded by optional whitespace.
+pub fn parse_number(s: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(s).ok()?.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let n = hex.bytes().fold(0.0, |n, b| {
+            n * 16.0 + (b as char).to_digit(16).unwrap() as f64
+        });
+        return Some(if negative { -n } else { n });
+    }
+    // Rust also accepts "inf" and "nan", Lua does not
+    if digits.is_empty()
+        || !digits
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Formats a number like Lua's `%.14g`.
+pub fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e14 {
+        // the common case: integers print without exponent or decimals
+        return format!("{}", n as i64);
+    }
+    format_g(n, 14, false)
+}
+
+/// C's `%g`: `precision` significant digits, exponent notation for very
+/// large or small numbers, trailing zeros removed unless `alternate`.
+pub fn format_g(n: f64, precision: usize, alternate: bool) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let precision = precision.max(1);
+    if n == 0.0 {
+        let zero = if n.is_sign_negative() { "-0" } else { "0" };
+        return if alternate {
+            format!("{}.{}", zero, "0".repeat(precision - 1))
+        } else {
+            zero.to_string()
+        };
+    }
+    let exp_form = format!("{:.*e}", precision - 1, n);
+    let exponent: i32 = exp_form[exp_form.find('e').unwrap() + 1..].parse().unwrap();
+    let s = if exponent < -4 || exponent >= precision as i32 {
+        format_e(n, precision - 1)
+    } else {
+        format!("{:.*}", (precision as i32 - 1 - exponent) as usize, n)
+    };
+    if alternate {
+        return s;
+    }
+    match s.find('e') {
+        Some(e) => {
+            let (mantissa, exp) = s.split_at(e);
+            format!("{}{}", trim_fraction(mantissa), exp)
+        }
+        None => trim_fraction(&s).to_string(),
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// C's `%e`: an exponent with a sign and at least two digits.
+pub fn format_e(n: f64, precision: usize) -> String {
+    let s = format!("{:.*e}", precision, n);
+    let (mantissa, exp) = s.split_at(s.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exp.abs())
+}
@@ -0,0 +1,142 @@
+// LZF, the compression Redis uses for long strings in RDB files.
+//
+// The output is a sequence of chunks, each starting with a control byte:
+// 000LLLLL is a run of L+1 literal bytes, and LLLooooo is a back reference
+// to L+2 bytes starting o*256+next+1 bytes back (with L = 7 meaning that
+// the next byte holds L-7).
+
+const HASH_LOG: u32 = 14;
+const MAX_LIT: usize = 1 << 5;
+const MAX_OFF: usize = 1 << 13;
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (v.wrapping_mul(0x9E3779B1) >> (32 - HASH_LOG)) as usize
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    // the control byte of the current literal run is filled in once it ends
+    let mut lit = 0;
+    out.push(0);
+    let mut ip = 0;
+    while ip < input.len() {
+        if ip + 2 < input.len() {
+            let h = hash(&input[ip..]);
+            let candidate = table[h];
+            table[h] = ip;
+            if candidate != usize::MAX
+                && ip - candidate - 1 < MAX_OFF
+                && input[candidate..candidate + 3] == input[ip..ip + 3]
+            {
+                let off = ip - candidate - 1;
+                let max = (input.len() - ip).min(MAX_REF);
+                let mut len = 3;
+                while len < max && input[candidate + len] == input[ip + len] {
+                    len += 1;
+                }
+                end_literals(&mut out, lit);
+                let l = len - 2;
+                if l < 7 {
+                    out.push(((l << 5) | (off >> 8)) as u8);
+                } else {
+                    out.push(((7 << 5) | (off >> 8)) as u8);
+                    out.push((l - 7) as u8);
+                }
+                out.push(off as u8);
+                out.push(0);
+                lit = 0;
+                ip += len;
+                continue;
+            }
+        }
+        out.push(input[ip]);
+        lit += 1;
+        ip += 1;
+        if lit == MAX_LIT {
+            end_literals(&mut out, lit);
+            out.push(0);
+            lit = 0;
+        }
+    }
+    end_literals(&mut out, lit);
+    out
+}
+
+/// Sets the control byte of a literal run, or drops it if the run is empty.
+fn end_literals(out: &mut Vec<u8>, lit: usize) {
+    if lit == 0 {
+        out.pop();
+    } else {
+        let control = out.len() - lit - 1;
+        out[control] = (lit - 1) as u8;
+    }
+}
+
+/// Decompresses `input`, which must expand to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // no chunk expands more than a maximal reference does, so a larger
+    // length can only come from a corrupted header
+    if len > input.len().saturating_mul(MAX_REF) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let control = input[ip] as usize;
+        ip += 1;
+        if control < MAX_LIT {
+            out.extend_from_slice(input.get(ip..ip + control + 1)?);
+            ip += control + 1;
+            continue;
+        }
+        let mut l = control >> 5;
+        if l == 7 {
+            l += *input.get(ip)? as usize;
+            ip += 1;
+        }
+        let off = ((control & 0x1F) << 8) + *input.get(ip)? as usize + 1;
+        ip += 1;
+        let start = out.len().checked_sub(off)?;
+        // the reference may overlap the bytes it produces
+        for i in 0..l + 2 {
+            out.push(out[start + i]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let long: Vec<u8> = (0..5000u32).map(|i| (i % 7 * 31 + i / 100) as u8).collect();
+        let inputs: [&[u8]; 5] = [
+            b"",
+            b"ab",
+            b"hello hello hello hello hello",
+            &[b'x'; 1000],
+            &long,
+        ];
+        for input in inputs {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+        assert!(compress(&[b'x'; 1000]).len() < 20);
+    }
+
+    #[test]
+    fn test_decompress() {
+        // a literal 'a', then 19 bytes copied from one byte back
+        assert_eq!(
+            decompress(&[0x00, b'a', 0xE0, 0x0A, 0x00], 20).unwrap(),
+            b"aaaaaaaaaaaaaaaaaaaa"
+        );
+        assert!(decompress(&[0x20, 0x00], 2).is_none());
+        assert!(decompress(&[0x01, b'a'], 2).is_none());
+    }
+}
@@ -1,28 +1,65 @@
+mod aof;
+mod bitmap;
+mod client;
+mod cluster;
 mod command;
+mod crc64;
+mod db;
+mod functions;
+mod geo;
+mod glob;
+mod hyperloglog;
+mod listpack;
+mod lua;
+mod lzf;
+mod migrate;
+mod multi;
+mod pubsub;
+mod rax;
 mod rdb;
+mod replication;
 mod reply;
 mod resp;
+mod save;
+mod scripting;
+mod sha1;
+mod stream;
+mod value;
+mod zset;
 
-use redis_starter_rust::ThreadPool;
+use aof::{Aof, AofContents, AofState};
 use reply::Reply;
 use std::path::PathBuf;
 use std::time;
-use std::{io::Read, io::Write, net::TcpListener};
+use std::{io::Read, net::TcpListener};
 
+use client::Client;
+use cluster::{Cluster, ClusterState, Subcommand as ClusterCommand};
 use command::Command;
+use multi::{Execution, Expiry, Transaction, WatchState, Watches, Write};
+use pubsub::{PubSubState, Subscriptions};
+use replication::{Handshake, MasterLink, Replication, ReplicationState, Sync};
+use save::SnapshotState;
+use scripting::{ScriptState, Scripts};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use value::Value;
 
-pub type Duration = Arc<Mutex<HashMap<String, time::Instant>>>;
-pub type State = Arc<Mutex<HashMap<String, String>>>;
+// one map per database, indexed like the keyspace
+pub type Duration = Arc<Mutex<Vec<HashMap<Vec<u8>, time::Instant>>>>;
+// one keyspace per database, selected by each connection with SELECT
+pub type State = Arc<Mutex<Vec<HashMap<Vec<u8>, Value>>>>;
+// signalled (paired with the State mutex) whenever a key blocked clients may
+// wait on is written
+pub type Notifier = Arc<Condvar>;
 type Config = Arc<HashMap<String, String>>;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
-    let pool = ThreadPool::new(2);
-
-    let state: State = Arc::new(Mutex::new(HashMap::new()));
-    let durations: Duration = Arc::new(Mutex::new(HashMap::new()));
+    let notifier: Notifier = Arc::new(Condvar::new());
+    let pubsub: PubSubState = Arc::default();
+    let watches: WatchState = Arc::default();
+    let scripts: ScriptState = Arc::default();
+    let snapshots: SnapshotState = Arc::default();
 
     let args: Vec<String> = std::env::args().collect();
     let mut arg_pairs = HashMap::new();
@@ -35,108 +72,2438 @@ fn main() {
             "--dbfilename" => {
                 arg_pairs.insert("dbfilename".to_owned(), args_iter.next().cloned().unwrap());
             }
+            "--rdbcompression" => {
+                arg_pairs.insert(
+                    "rdbcompression".to_owned(),
+                    args_iter.next().cloned().unwrap(),
+                );
+            }
+            "--save" => {
+                arg_pairs.insert("save".to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--sanitize-dump-payload" => {
+                arg_pairs.insert(
+                    "sanitize-dump-payload".to_owned(),
+                    args_iter.next().cloned().unwrap(),
+                );
+            }
+            // "host port", or host and port as two arguments
+            "--replicaof" => {
+                let mut master = args_iter.next().cloned().unwrap();
+                if !master.contains(' ') {
+                    master = format!("{} {}", master, args_iter.next().unwrap());
+                }
+                arg_pairs.insert("replicaof".to_owned(), master);
+            }
+            "--port" => {
+                arg_pairs.insert("port".to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--databases" => {
+                arg_pairs.insert("databases".to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--appendonly"
+            | "--appendfilename"
+            | "--appenddirname"
+            | "--appendfsync"
+            | "--aof-load-truncated" => {
+                arg_pairs.insert(arg[2..].to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--repl-backlog-size" => {
+                arg_pairs.insert(
+                    "repl-backlog-size".to_owned(),
+                    args_iter.next().cloned().unwrap(),
+                );
+            }
+            "--replica-read-only"
+            | "--replica-serve-stale-data"
+            | "--repl-diskless-sync"
+            | "--repl-diskless-sync-delay"
+            | "--repl-diskless-load" => {
+                arg_pairs.insert(arg[2..].to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--cluster-enabled" | "--cluster-config-file" => {
+                arg_pairs.insert(arg[2..].to_owned(), args_iter.next().cloned().unwrap());
+            }
+            "--client-output-buffer-limit" => {
+                arg_pairs.insert(
+                    "client-output-buffer-limit".to_owned(),
+                    args_iter.next().cloned().unwrap(),
+                );
+            }
+            "--busy-reply-threshold" => {
+                arg_pairs.insert(
+                    "busy-reply-threshold".to_owned(),
+                    args_iter.next().cloned().unwrap(),
+                );
+            }
             _ => {}
         }
     }
 
-    if arg_pairs.contains_key("dir") && arg_pairs.contains_key("dbfilename") {
-        let mut path = PathBuf::new();
-        path.push(arg_pairs.get("dir").unwrap());
-        path.push(arg_pairs.get("dbfilename").unwrap());
-        rdb::load_from_rdb(path.as_path(), Arc::clone(&state), Arc::clone(&durations)).unwrap();
-    }
+    let port: u16 = arg_pairs
+        .entry("port".to_owned())
+        .or_insert_with(|| "6379".to_string())
+        .parse()
+        .unwrap();
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+
+    let databases: usize = arg_pairs
+        .entry("databases".to_owned())
+        .or_insert_with(|| db::DEFAULT_DATABASES.to_string())
+        .parse()
+        .unwrap();
+    arg_pairs
+        .entry("rdbcompression".to_owned())
+        .or_insert_with(|| "yes".to_string());
+    arg_pairs
+        .entry("sanitize-dump-payload".to_owned())
+        .or_insert_with(|| "no".to_string());
+    arg_pairs
+        .entry("dir".to_owned())
+        .or_insert_with(|| ".".to_string());
+    arg_pairs
+        .entry("dbfilename".to_owned())
+        .or_insert_with(|| "dump.rdb".to_string());
+    let save_points = save::parse_save_points(
+        arg_pairs
+            .entry("save".to_owned())
+            .or_insert_with(|| save::DEFAULT_SAVE_POINTS.to_string()),
+    )
+    .expect("invalid save points");
+    arg_pairs
+        .entry("replica-read-only".to_owned())
+        .or_insert_with(|| "yes".to_string());
+    arg_pairs
+        .entry("replica-serve-stale-data".to_owned())
+        .or_insert_with(|| "yes".to_string());
+    arg_pairs
+        .entry("repl-diskless-sync".to_owned())
+        .or_insert_with(|| "yes".to_string());
+    arg_pairs
+        .entry("repl-diskless-sync-delay".to_owned())
+        .or_insert_with(|| "5".to_string())
+        .parse::<u64>()
+        .expect("invalid repl-diskless-sync-delay");
+    let diskless_load = arg_pairs
+        .entry("repl-diskless-load".to_owned())
+        .or_insert_with(|| "disabled".to_string());
+    assert!(
+        ["disabled", "on-empty-db", "swapdb"].contains(&diskless_load.as_str()),
+        "invalid repl-diskless-load"
+    );
+    arg_pairs
+        .entry("cluster-enabled".to_owned())
+        .or_insert_with(|| "no".to_string());
+    arg_pairs
+        .entry("cluster-config-file".to_owned())
+        .or_insert_with(|| "nodes.conf".to_string());
+    arg_pairs
+        .entry("appendonly".to_owned())
+        .or_insert_with(|| "no".to_string());
+    arg_pairs
+        .entry("appendfilename".to_owned())
+        .or_insert_with(|| "appendonly.aof".to_string());
+    arg_pairs
+        .entry("appenddirname".to_owned())
+        .or_insert_with(|| "appendonlydir".to_string());
+    arg_pairs
+        .entry("aof-load-truncated".to_owned())
+        .or_insert_with(|| "yes".to_string());
+    let fsync = aof::Fsync::parse(
+        arg_pairs
+            .entry("appendfsync".to_owned())
+            .or_insert_with(|| "everysec".to_string()),
+    )
+    .expect("invalid appendfsync");
+    let backlog_size = replication::parse_size(
+        arg_pairs
+            .entry("repl-backlog-size".to_owned())
+            .or_insert_with(|| replication::DEFAULT_BACKLOG_SIZE.to_string()),
+    )
+    .expect("invalid repl-backlog-size");
+    let (pubsub_limit, replica_limit) = client::parse_output_limits(
+        arg_pairs
+            .entry("client-output-buffer-limit".to_owned())
+            .or_insert_with(|| client::DEFAULT_OUTPUT_LIMITS.to_string()),
+        Default::default(),
+    )
+    .expect("invalid client-output-buffer-limit");
+    pubsub.lock().unwrap().limit = pubsub_limit;
+    let replication: ReplicationState = Arc::new(Replication::new(backlog_size, replica_limit));
+    let state: State = Arc::new(Mutex::new(vec![HashMap::new(); databases]));
+    let durations: Duration = Arc::new(Mutex::new(vec![HashMap::new(); databases]));
 
     let shared_args: Config = Arc::new(arg_pairs);
 
+    let cluster: ClusterState = if shared_args["cluster-enabled"].eq_ignore_ascii_case("yes") {
+        let path = PathBuf::from(&shared_args["dir"]).join(&shared_args["cluster-config-file"]);
+        match Cluster::load(path, port) {
+            Ok(cluster) => Arc::new(cluster),
+            Err(e) => {
+                eprintln!("Error loading the cluster config: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        Arc::new(Cluster::disabled())
+    };
+
+    let aof: AofState = Arc::new(Aof::new(
+        PathBuf::from(&shared_args["dir"]).join(&shared_args["appenddirname"]),
+        shared_args["appendfilename"].clone(),
+        fsync,
+    ));
+    let appendonly = shared_args["appendonly"].eq_ignore_ascii_case("yes");
+    {
+        let mut state = state.lock().unwrap();
+        let mut durations = durations.lock().unwrap();
+        // "clients" only applies to payloads sent with RESTORE
+        let sanitize = shared_args["sanitize-dump-payload"].eq_ignore_ascii_case("yes");
+        // the AOF, when on, is more recent than the snapshot
+        let mut loaded = false;
+        if appendonly {
+            // a single-file AOF, from before the manifest
+            let legacy = PathBuf::from(&shared_args["dir"]).join(&shared_args["appendfilename"]);
+            let load_truncated = shared_args["aof-load-truncated"].eq_ignore_ascii_case("yes");
+            let result = aof
+                .load(&legacy, sanitize, load_truncated)
+                .map_err(|e| e.to_string())
+                .and_then(|files| match files {
+                    Some(files) => {
+                        loaded = true;
+                        replay_aof(
+                            files,
+                            &mut state,
+                            &mut durations,
+                            &shared_args,
+                            &notifier,
+                            &pubsub,
+                            &scripts,
+                            &aof,
+                            &replication,
+                        )
+                    }
+                    None => Ok(()),
+                });
+            if let Err(e) = result {
+                eprintln!("Error loading the append only file: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if !loaded {
+            let path = rdb_path(&shared_args);
+            if let Err(e) =
+                rdb::load_from_rdb(&path, &mut state, &mut durations, &scripts, sanitize)
+            {
+                eprintln!("Error loading {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        if appendonly {
+            // a new AOF starts from the dataset loaded from the snapshot
+            let snapshot = || rdb::snapshot(&state, &durations, scripts.library_codes());
+            if let Err(e) = aof.open(snapshot, rdb_compression(&shared_args)) {
+                eprintln!("Can't open the append only file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if appendonly && fsync == aof::Fsync::EverySec {
+        let aof = Arc::clone(&aof);
+        let replication = Arc::clone(&replication);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(time::Duration::from_secs(1));
+            if let Some(offset) = aof.fsync_everysec() {
+                replication.set_aof_offset(offset);
+            }
+        });
+    }
+
+    if !save_points.is_empty() {
+        let state = Arc::clone(&state);
+        let durations = Arc::clone(&durations);
+        let scripts = Arc::clone(&scripts);
+        let snapshots = Arc::clone(&snapshots);
+        let config = Arc::clone(&shared_args);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(time::Duration::from_millis(100));
+            if snapshots.should_save(&save_points) {
+                let state = state.lock().unwrap();
+                let durations = durations.lock().unwrap();
+                snapshots.bgsave(
+                    &state,
+                    &durations,
+                    &scripts,
+                    rdb_path(&config),
+                    rdb_compression(&config),
+                );
+            }
+        });
+    }
+
+    if let Some(master) = shared_args.get("replicaof") {
+        let (host, port) = master.split_once(' ').expect("invalid replicaof");
+        let master = (
+            host.to_string(),
+            port.parse().expect("invalid replicaof port"),
+        );
+        if let Some(generation) = replication.replicaof(Some(master)) {
+            start_replica(
+                generation,
+                &state,
+                &shared_args,
+                &durations,
+                &notifier,
+                &pubsub,
+                &watches,
+                &scripts,
+                &snapshots,
+                &aof,
+                &replication,
+            );
+        }
+    }
+
+    let mut next_client_id = 0;
     for stream in listener.incoming() {
         match stream {
             Ok(s) => {
                 let state_clone = Arc::clone(&state);
                 let config = Arc::clone(&shared_args);
                 let durations = Arc::clone(&durations);
+                let notifier = Arc::clone(&notifier);
+                let pubsub = Arc::clone(&pubsub);
+                let watches = Arc::clone(&watches);
+                let scripts = Arc::clone(&scripts);
+                let snapshots = Arc::clone(&snapshots);
+                let aof = Arc::clone(&aof);
+                let replication = Arc::clone(&replication);
+                let cluster = Arc::clone(&cluster);
+                next_client_id += 1;
+                let mut client = Client::new(next_client_id, Box::new(s.try_clone().unwrap()));
+                client.addr = s.peer_addr().ok();
+                client.socket = s.try_clone().ok();
+                let client = Arc::new(client);
 
-                pool.execute(|| {
-                    handle_client(s, state_clone, config, durations).unwrap();
-                })
+                // a thread per connection, for as many as there are
+                std::thread::spawn(move || {
+                    // writes what other connections push to this one
+                    let drainer = {
+                        let client = Arc::clone(&client);
+                        std::thread::spawn(move || client.drain())
+                    };
+                    let result = handle_client(
+                        s,
+                        Arc::clone(&client),
+                        state_clone,
+                        config,
+                        durations,
+                        notifier,
+                        Arc::clone(&pubsub),
+                        Arc::clone(&watches),
+                        scripts,
+                        snapshots,
+                        aof,
+                        Arc::clone(&replication),
+                        cluster,
+                    );
+                    pubsub.lock().unwrap().remove_client(client.id);
+                    watches.lock().unwrap().unwatch(client.id);
+                    replication.remove_replica(client.id);
+                    client.stop();
+                    let _ = drainer.join();
+                    // e.g. a peer that reset the connection
+                    if let Err(e) = result {
+                        eprintln!("Connection error: {}", e);
+                    }
+                });
             }
             Err(e) => {
-                println!("error: {}", e);
+                eprintln!("Can't accept a connection: {}", e);
             }
         }
     }
 }
 
-fn handle_client<T: Write + Read>(
+/// Serves one connection, reading commands from `stream` and writing replies
+/// through `client`, which other connections may also push messages to.
+#[allow(clippy::too_many_arguments)]
+fn handle_client<T: Read>(
     mut stream: T,
+    client: Arc<Client>,
     state: State,
     config: Config,
     durations: Duration,
+    notifier: Notifier,
+    pubsub: PubSubState,
+    watches: WatchState,
+    scripts: ScriptState,
+    snapshots: SnapshotState,
+    aof: AofState,
+    replication: ReplicationState,
+    cluster: ClusterState,
 ) -> std::io::Result<()> {
+    let busy_threshold = config
+        .get("busy-reply-threshold")
+        .and_then(|ms| ms.parse().ok())
+        .map_or(scripting::BUSY_THRESHOLD, time::Duration::from_millis);
+    let mut pending: Vec<u8> = Vec::new();
+    let mut subs = Subscriptions::default();
+    let mut transaction: Option<Transaction> = None;
+    // the database selected with SELECT
+    let mut db = 0;
+    // given with REPLCONF by a replica, until it sends PSYNC
+    let mut handshake = Handshake::default();
+    // whether the connection is a replica's, which gets no replies
+    let mut replica = false;
+    // set by ASKING, for the next command only
+    let mut asking = false;
+    // an UNWATCH left to the next WATCH or EXEC, as a script held the watches
+    let mut unwatch_pending = false;
     loop {
-        let mut buf: [u8; 64] = [0; 64];
-        let bytes_read = stream.read(&mut buf)?;
-        if bytes_read == 0 {
-            break;
+        // commands may be split across reads or pipelined in a single one
+        let (name, command, argv) = {
+            match resp::parse_resp(&pending) {
+                Ok((rest, resp_cmd)) => {
+                    let consumed = pending.len() - rest.len();
+                    let name = match resp_cmd.first() {
+                        Some(resp::Type::String(s, _)) => String::from_utf8_lossy(s).to_lowercase(),
+                        _ => String::new(),
+                    };
+                    let argv = resp::args(&resp_cmd);
+                    let command = Command::try_from(resp_cmd);
+                    pending.drain(..consumed);
+                    (name, command, argv)
+                }
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                    // the rest of the stream cannot be made sense of
+                    let message = resp::protocol_error(&pending, e.input);
+                    client.send(Reply::ErrorCode(
+                        "ERR",
+                        format!("Protocol error: {}", message),
+                    ))?;
+                    break;
+                }
+                Err(nom::Err::Incomplete(_)) => {
+                    let mut buf: [u8; 1024] = [0; 1024];
+                    let bytes_read = stream.read(&mut buf)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    pending.extend_from_slice(&buf[..bytes_read]);
+                    continue;
+                }
+            }
+        };
+
+        let reply;
+        // RESP2 connections can only receive pushes once they subscribe
+        let subscribed = subs.count() > 0 && !client.resp3();
+        let asked = std::mem::take(&mut asking) || name == "restore-asking";
+        // keys of slots served by other nodes are redirected
+        let exists = |key: &[u8]| state.lock().unwrap()[0].contains_key(key);
+        let mut redirect = match &command {
+            Ok(command) => cluster.redirect(&command.keys(), asked, exists),
+            Err(_) => None,
+        };
+
+        match command {
+            Err(emsg) => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = Reply::Error(emsg);
+            }
+            Ok(ref command) if subscribed && !allowed_when_subscribed(command) => {
+                reply = Reply::ErrorCode(
+                    "ERR",
+                    format!(
+                        "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                        name
+                    ),
+                );
+            }
+            Ok(Command::Ping) if subscribed => {
+                reply = Reply::Array(vec!["pong".to_string(), String::new()]);
+            }
+            Ok(_) if redirect.is_some() => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = redirect.take().unwrap();
+            }
+            // checked before locking the keyspace, which the script holds
+            Ok(ref command) if !allowed_when_busy(command) && scripts.busy(busy_threshold) => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = busy_error();
+            }
+            Ok(ref command) if command.is_write() && replica_read_only(&config, &replication) => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = read_only_error();
+            }
+            Ok(ref command) if !allowed_when_stale(command) && stale(&config, &replication) => {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = Reply::ErrorCode(
+                    "MASTERDOWN",
+                    "Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                        .to_string(),
+                );
+            }
+            Ok(Command::Multi) if transaction.is_some() => {
+                transaction.as_mut().unwrap().dirty = true;
+                reply = Reply::Error("MULTI calls can not be nested");
+            }
+            Ok(Command::Multi) => {
+                transaction = Some(Transaction::default());
+                reply = Reply::Simple("OK".to_string());
+            }
+            Ok(Command::Exec) => {
+                // the queued commands must all be served here, together
+                redirect = transaction.as_ref().and_then(|transaction| {
+                    let keys: Vec<&[u8]> = transaction
+                        .queue
+                        .iter()
+                        .flat_map(|(command, _)| command.keys())
+                        .collect();
+                    cluster.redirect(&keys, asked, exists)
+                });
+                reply = match transaction.take() {
+                    None => Reply::Error("EXEC without MULTI"),
+                    Some(transaction) if transaction.dirty => {
+                        unwatch(&watches, client.id, &mut unwatch_pending);
+                        Reply::ErrorCode(
+                            "EXECABORT",
+                            "Transaction discarded because of previous errors.".to_string(),
+                        )
+                    }
+                    Some(_) if redirect.is_some() => {
+                        unwatch(&watches, client.id, &mut unwatch_pending);
+                        redirect.take().unwrap()
+                    }
+                    Some(transaction) => 'exec: {
+                        // no other client can interleave while the queue runs
+                        let mut state = match lock_unless_busy(&state, &scripts, busy_threshold) {
+                            Ok(state) => state,
+                            Err(busy) => {
+                                unwatch(&watches, client.id, &mut unwatch_pending);
+                                break 'exec busy;
+                            }
+                        };
+                        let mut durations = durations.lock().unwrap();
+                        let mut watches = watches.lock().unwrap();
+                        if std::mem::take(&mut unwatch_pending) {
+                            watches.unwatch(client.id);
+                        }
+                        // keys that expired count as modified, even if no
+                        // one accessed them since
+                        let now = time::Instant::now();
+                        let expired = watches
+                            .watched(client.id)
+                            .any(|(db, key)| durations[*db].get(key).is_some_and(|at| *at <= now));
+                        let aborted = watches.is_dirty(client.id) || expired;
+                        watches.unwatch(client.id);
+                        if aborted {
+                            Reply::NullArray
+                        } else {
+                            Reply::Nested(exec_queue(
+                                transaction.queue,
+                                &mut state,
+                                &mut durations,
+                                &mut db,
+                                &mut watches,
+                                &mut Execution::new(client_expiry(&replication, Expiry::Hide)),
+                                &config,
+                                &notifier,
+                                &pubsub,
+                                &scripts,
+                                &snapshots,
+                                &aof,
+                                &replication,
+                                Some(&cluster),
+                            ))
+                        }
+                    }
+                };
+            }
+            Ok(Command::Discard) => {
+                reply = match transaction.take() {
+                    None => Reply::Error("DISCARD without MULTI"),
+                    Some(_) => {
+                        unwatch(&watches, client.id, &mut unwatch_pending);
+                        Reply::Simple("OK".to_string())
+                    }
+                };
+            }
+            Ok(
+                Command::Subscribe(..)
+                | Command::Unsubscribe(..)
+                | Command::Hello(_)
+                | Command::Watch(_)
+                | Command::Shutdown(_)
+                | Command::ReplConf(_)
+                | Command::Psync(..)
+                | Command::ReplicaOf(_)
+                | Command::Cluster(_)
+                | Command::Asking,
+            ) if transaction.is_some() => {
+                transaction.as_mut().unwrap().dirty = true;
+                reply = Reply::Error("Command not allowed inside a transaction");
+            }
+            // a cluster node only has database 0
+            Ok(Command::Select(1..) | Command::Move(..) | Command::SwapDb(..))
+                if cluster.enabled() =>
+            {
+                if let Some(transaction) = &mut transaction {
+                    transaction.dirty = true;
+                }
+                reply = Reply::ErrorCode(
+                    "ERR",
+                    format!("{} is not allowed in cluster mode", name.to_uppercase()),
+                );
+            }
+            Ok(command) if transaction.is_some() => {
+                transaction.as_mut().unwrap().queue.push((command, argv));
+                reply = Reply::Simple("QUEUED".to_string());
+            }
+            Ok(Command::Subscribe(kind, names)) => {
+                let mut pubsub = pubsub.lock().unwrap();
+                // confirmed before releasing the registry, so that no message
+                // published to the new subscription can overtake the reply
+                client.send(pubsub::subscribe(
+                    &mut pubsub,
+                    &client,
+                    &mut subs,
+                    kind,
+                    &names,
+                ))?;
+                continue;
+            }
+            Ok(Command::Unsubscribe(kind, names)) => {
+                let mut pubsub = pubsub.lock().unwrap();
+                reply = pubsub::unsubscribe(&mut pubsub, &client, &mut subs, kind, &names);
+            }
+            Ok(Command::Hello(version)) => reply = client.hello(version),
+            Ok(Command::Watch(keys)) => {
+                reply = match lock_unless_busy(&watches, &scripts, busy_threshold) {
+                    Ok(mut watches) => {
+                        if std::mem::take(&mut unwatch_pending) {
+                            watches.unwatch(client.id);
+                        }
+                        for key in &keys {
+                            watches.watch(client.id, db, key);
+                        }
+                        Reply::Simple("OK".to_string())
+                    }
+                    Err(busy) => busy,
+                };
+            }
+            Ok(Command::Unwatch) => {
+                unwatch(&watches, client.id, &mut unwatch_pending);
+                reply = Reply::Simple("OK".to_string());
+            }
+            Ok(Command::ScriptKill) => reply = scripts.kill(),
+            Ok(Command::Shutdown(save)) => {
+                reply = shutdown(
+                    save, &state, &durations, &config, &scripts, &snapshots, &aof,
+                );
+            }
+            Ok(Command::ReplConf(options)) => {
+                reply = replication.replconf(client.id, &options, &mut handshake);
+            }
+            Ok(Command::Psync(replid, offset)) => {
+                replica = true;
+                let listening_port = handshake.listening_port;
+                if replication.try_continue(&client, listening_port, &replid, offset) {
+                    continue;
+                }
+                // a full resync: the replica gets the whole dataset
+                let compress = rdb_compression(&config);
+                if handshake.eof && config["repl-diskless-sync"].eq_ignore_ascii_case("yes") {
+                    // replicas that ask within the delay share a snapshot,
+                    // sent by a thread the first one starts
+                    if replication.join_diskless(Arc::clone(&client), listening_port) {
+                        let delay = config["repl-diskless-sync-delay"].parse().unwrap();
+                        start_diskless_sync(
+                            time::Duration::from_secs(delay),
+                            compress,
+                            &state,
+                            &durations,
+                            &scripts,
+                            &replication,
+                        );
+                    }
+                    continue;
+                }
+                let (header, snapshot) = {
+                    let state = state.lock().unwrap();
+                    let durations = durations.lock().unwrap();
+                    let snapshot = rdb::snapshot(&state, &durations, scripts.library_codes());
+                    // writes from now on are held back until the snapshot
+                    // is sent
+                    let header = replication.add_replica(Arc::clone(&client), listening_port);
+                    (header, snapshot)
+                };
+                // saved to disk first, and sent from there
+                let mut rdb = Vec::new();
+                let result = rdb::save_and_open(&rdb_path(&config), &snapshot, compress)
+                    .and_then(|mut file| file.read_to_end(&mut rdb));
+                if let Err(e) = result {
+                    eprintln!("Can't save the snapshot for a replica: {}", e);
+                    replication.remove_replica(client.id);
+                    return Ok(());
+                }
+                replication.send_snapshot(&client, &header, &rdb, false)?;
+                continue;
+            }
+            Ok(Command::Cluster(ClusterCommand::Meet(host, port))) => {
+                reply = cluster.meet(&host, port, config["port"].parse().unwrap());
+            }
+            Ok(Command::Cluster(subcommand)) => {
+                let assigns_slots = subcommand.assigns_slots();
+                reply = match lock_unless_busy(&state, &scripts, busy_threshold) {
+                    Ok(state) => {
+                        let durations = durations.lock().unwrap();
+                        cluster.command(subcommand, &state[0], &durations[0])
+                    }
+                    Err(busy) => busy,
+                };
+                // once the keyspace is free for the other nodes to ask
+                if assigns_slots && !reply.is_error() {
+                    cluster.announce(config["port"].parse().unwrap());
+                }
+            }
+            Ok(Command::Asking) if !cluster.enabled() => {
+                reply = Reply::Error("This instance has cluster support disabled");
+            }
+            Ok(Command::Asking) => {
+                asking = true;
+                reply = Reply::Simple("OK".to_string());
+            }
+            Ok(Command::ReplicaOf(master)) => {
+                reply = match master {
+                    Some((host, port)) if replication.is_master(&host, port) => {
+                        Reply::Simple("OK Already connected to specified master".to_string())
+                    }
+                    master => {
+                        if let Some(generation) = replication.replicaof(master) {
+                            start_replica(
+                                generation,
+                                &state,
+                                &config,
+                                &durations,
+                                &notifier,
+                                &pubsub,
+                                &watches,
+                                &scripts,
+                                &snapshots,
+                                &aof,
+                                &replication,
+                            );
+                        }
+                        Reply::Simple("OK".to_string())
+                    }
+                };
+            }
+            Ok(Command::XRead {
+                count,
+                block: Some(block),
+                keys,
+                ids,
+            }) => {
+                let mut state = match lock_unless_busy(&state, &scripts, busy_threshold) {
+                    Ok(state) => state,
+                    Err(busy) => {
+                        client.send(busy)?;
+                        continue;
+                    }
+                };
+                let mut durations = durations.lock().unwrap();
+                let mut watches = watches.lock().unwrap();
+                // a replica cannot hide keys while it waits, as the master
+                // may write them in the meantime
+                let mut execution = Execution::new(client_expiry(&replication, Expiry::Keep));
+                for key in &keys {
+                    expire_if_needed(&mut state[db], &mut durations[db], &mut execution, db, key);
+                }
+                propagate_expired(&mut watches, &mut execution, &snapshots, &aof, &replication);
+                // writers need the expiry map and watches while we wait
+                drop((durations, watches));
+                reply = match stream::resolve_xread_ids(&state[db], &keys, &ids) {
+                    Err(e) => e,
+                    Ok(ids) => blocking_read(state, &notifier, db, block, |state| {
+                        stream::xread(state, &keys, &ids, count)
+                    }),
+                };
+            }
+            Ok(Command::XReadGroup {
+                group,
+                consumer,
+                count,
+                block: Some(block),
+                noack,
+                keys,
+                ids,
+            }) => {
+                let mut state = match lock_unless_busy(&state, &scripts, busy_threshold) {
+                    Ok(state) => state,
+                    Err(busy) => {
+                        client.send(busy)?;
+                        continue;
+                    }
+                };
+                let mut durations = durations.lock().unwrap();
+                let mut guard = watches.lock().unwrap();
+                let mut execution = Execution::new(client_expiry(&replication, Expiry::Keep));
+                for key in &keys {
+                    expire_if_needed(&mut state[db], &mut durations[db], &mut execution, db, key);
+                }
+                propagate_expired(&mut guard, &mut execution, &snapshots, &aof, &replication);
+                drop((durations, guard));
+                reply = blocking_read(state, &notifier, db, block, |state| {
+                    stream::xreadgroup(state, &group, &consumer, &keys, &ids, count, noack)
+                });
+                if !reply.is_error() {
+                    let mut watches = watches.lock().unwrap();
+                    for key in &keys {
+                        watches.touch(db, key);
+                    }
+                    propagate(
+                        &mut execution,
+                        &snapshots,
+                        &aof,
+                        &replication,
+                        db,
+                        argv,
+                        &reply,
+                    );
+                }
+            }
+            // block without holding the keyspace
+            Ok(Command::Wait(numreplicas, timeout)) => {
+                reply = replication.wait(numreplicas, Some(timeout));
+            }
+            Ok(Command::WaitAof(numlocal, numreplicas, timeout)) => {
+                reply = replication.waitaof(
+                    numlocal,
+                    numreplicas,
+                    config["appendonly"].eq_ignore_ascii_case("yes"),
+                    Some(timeout),
+                );
+            }
+            Ok(command) => {
+                let mut state = match lock_unless_busy(&state, &scripts, busy_threshold) {
+                    Ok(state) => state,
+                    Err(busy) => {
+                        client.send(busy)?;
+                        continue;
+                    }
+                };
+                let mut durations = durations.lock().unwrap();
+                let mut watches = watches.lock().unwrap();
+                reply = execute(
+                    command,
+                    argv,
+                    &mut state,
+                    &mut durations,
+                    &mut db,
+                    &mut watches,
+                    &mut Execution::new(client_expiry(&replication, Expiry::Hide)),
+                    &config,
+                    &notifier,
+                    &pubsub,
+                    &scripts,
+                    &snapshots,
+                    &aof,
+                    &replication,
+                    Some(&cluster),
+                );
+            }
         }
 
-        let mut reply: Option<Reply> = None;
+        if !replica {
+            client.send(reply)?;
+        }
+    }
 
-        let (_, resp_cmd): (&str, Vec<resp::Type>) =
-            resp::parse_resp(std::str::from_utf8(&buf).unwrap()).unwrap();
+    Ok(())
+}
 
-        let command = Command::try_from(resp_cmd);
-        if command.is_err() {
-            let emsg: &str = command.unwrap_err();
-            reply = Some(Reply::Error(emsg));
-        } else {
-            let mut state = state.lock().unwrap();
-            let mut durations = durations.lock().unwrap();
+/// Runs a command with `run_command`, propagating it if it is a write that
+/// succeeded. `argv` is the command as it was sent.
+#[allow(clippy::too_many_arguments)]
+fn execute(
+    command: Command,
+    argv: Vec<Vec<u8>>,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, time::Instant>],
+    db: &mut usize,
+    watches: &mut Watches,
+    execution: &mut Execution,
+    config: &Config,
+    notifier: &Condvar,
+    pubsub: &PubSubState,
+    scripts: &Scripts,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+    cluster: Option<&Cluster>,
+) -> Reply<'static> {
+    let write = command.is_write();
+    // MOVE is logged in the database it moves from
+    let current = *db;
+    // hidden keys the command writes are not put back over its writes
+    let written: Vec<Vec<u8>> = if execution.expiry == Expiry::Hide {
+        command
+            .written_keys()
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect()
+    } else {
+        vec![]
+    };
+    let reply = run_command(
+        command,
+        state,
+        durations,
+        db,
+        watches,
+        execution,
+        config,
+        notifier,
+        pubsub,
+        scripts,
+        snapshots,
+        aof,
+        replication,
+        cluster,
+    );
+    propagate_expired(watches, execution, snapshots, aof, replication);
+    if write && !reply.is_error() {
+        propagate(
+            execution,
+            snapshots,
+            aof,
+            replication,
+            current,
+            argv,
+            &reply,
+        );
+    }
+    // back until the master deletes them
+    for (db, key, value, at) in execution.take_hidden() {
+        if state[db].contains_key(&key) || (db == current && written.contains(&key)) {
+            continue;
+        }
+        state[db].insert(key.clone(), value);
+        durations[db].insert(key, at);
+    }
+    reply
+}
 
-            match command.unwrap() {
-                Command::ConfigGet(key) => {
-                    if let Some(val) = config.get(&key) {
-                        reply = Some(Reply::Array(vec![key, val.to_owned()]));
-                    }
+/// Propagates the deletion of the keys found expired, before the command
+/// that found them. Their watchers see them modified.
+fn propagate_expired(
+    watches: &mut Watches,
+    execution: &mut Execution,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+) {
+    for (db, key) in execution.take_expired() {
+        watches.touch(db, &key);
+        let argv = vec![b"DEL".to_vec(), key];
+        propagate(
+            execution,
+            snapshots,
+            aof,
+            replication,
+            db,
+            argv,
+            &Reply::Integer(1),
+        );
+    }
+}
+
+/// Accounts for a write: it counts towards the next automatic snapshot,
+/// and is appended to the AOF and sent to replicas, or held back with the
+/// rest of the transaction or script running.
+fn propagate(
+    execution: &mut Execution,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+    db: usize,
+    argv: Vec<Vec<u8>>,
+    reply: &Reply,
+) {
+    snapshots.add_dirty(1);
+    let argv = aof::propagated_args(argv, reply);
+    match execution.batch() {
+        Some(batch) => batch.push((db, argv)),
+        None => propagate_batch(vec![(db, argv)], aof, replication),
+    }
+}
+
+/// Appends writes to the AOF and sends them to replicas, wrapped in
+/// MULTI/EXEC if there are several, so they are applied as one.
+fn propagate_batch(mut batch: Vec<Write>, aof: &AofState, replication: &ReplicationState) {
+    let (Some(&(first, _)), Some(&(last, _))) = (batch.first(), batch.last()) else {
+        return;
+    };
+    if batch.len() > 1 {
+        batch.insert(0, (first, vec![b"MULTI".to_vec()]));
+        batch.push((last, vec![b"EXEC".to_vec()]));
+    }
+    let commands = || batch.iter().map(|(db, argv)| (*db, argv.as_slice()));
+    aof.feed(commands());
+    replication.feed(commands());
+    if let Some(offset) = aof.mark(replication.offset()) {
+        replication.set_aof_offset(offset);
+    }
+}
+
+/// Runs the commands queued by a transaction, propagating their writes
+/// together.
+#[allow(clippy::too_many_arguments)]
+fn exec_queue(
+    queue: Vec<(Command, Vec<Vec<u8>>)>,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, time::Instant>],
+    db: &mut usize,
+    watches: &mut Watches,
+    execution: &mut Execution,
+    config: &Config,
+    notifier: &Condvar,
+    pubsub: &PubSubState,
+    scripts: &Scripts,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+    cluster: Option<&Cluster>,
+) -> Vec<Reply<'static>> {
+    execution.start_batch();
+    let replies = queue
+        .into_iter()
+        .map(|(command, argv)| {
+            execute(
+                command,
+                argv,
+                state,
+                durations,
+                db,
+                watches,
+                execution,
+                config,
+                notifier,
+                pubsub,
+                scripts,
+                snapshots,
+                aof,
+                replication,
+                cluster,
+            )
+        })
+        .collect();
+    propagate_batch(execution.take_batch(), aof, replication);
+    replies
+}
+
+/// Loads the AOF files: the dataset saved by the last rewrite, then the
+/// commands written since, run as if sent by a client, one per file.
+/// Transactions are applied as one, once their EXEC is read.
+#[allow(clippy::too_many_arguments)]
+fn replay_aof(
+    files: Vec<AofContents>,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, time::Instant>],
+    config: &Config,
+    notifier: &Condvar,
+    pubsub: &PubSubState,
+    scripts: &Scripts,
+    aof: &AofState,
+    replication: &ReplicationState,
+) -> Result<(), String> {
+    // loading is not a change to save, and the AOF is not open yet
+    let snapshots = SnapshotState::default();
+    let mut watches = Watches::default();
+    // as they were when the commands ran
+    let mut execution = Execution::new(Expiry::Keep);
+    for contents in files {
+        if let Some(rdb) = contents.preamble {
+            rdb::load(rdb, state, durations, scripts).map_err(|e| e.to_string())?;
+        }
+        let mut db = 0;
+        // one left open at the end of the file was already cut from it
+        let mut transaction: Option<Vec<(Command, Vec<Vec<u8>>)>> = None;
+        for (command, argv) in contents.commands {
+            match (command, &mut transaction) {
+                (Command::Multi, _) => transaction = Some(Vec::new()),
+                (Command::Discard, _) => transaction = None,
+                (Command::Exec, transaction @ Some(_)) => {
+                    exec_queue(
+                        transaction.take().unwrap(),
+                        state,
+                        durations,
+                        &mut db,
+                        &mut watches,
+                        &mut execution,
+                        config,
+                        notifier,
+                        pubsub,
+                        scripts,
+                        &snapshots,
+                        aof,
+                        replication,
+                        None,
+                    );
                 }
-                Command::Set(key, val, px) => {
-                    if px.is_some() {
-                        durations.insert(
-                            key.clone(),
-                            time::Instant::now() + time::Duration::from_millis(px.unwrap()),
-                        );
+                (command, Some(queue)) => queue.push((command, argv)),
+                (command, None) => {
+                    execute(
+                        command,
+                        argv,
+                        state,
+                        durations,
+                        &mut db,
+                        &mut watches,
+                        &mut execution,
+                        config,
+                        notifier,
+                        pubsub,
+                        scripts,
+                        &snapshots,
+                        aof,
+                        replication,
+                        None,
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command against the locked keyspace, in database `db` unless
+/// it selects another one. Commands that need the connection itself are
+/// handled by `handle_client` instead.
+#[allow(clippy::too_many_arguments)]
+fn run_command(
+    command: Command,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, time::Instant>],
+    db: &mut usize,
+    watches: &mut Watches,
+    execution: &mut Execution,
+    config: &Config,
+    notifier: &Condvar,
+    pubsub: &PubSubState,
+    scripts: &Scripts,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+    cluster: Option<&Cluster>,
+) -> Reply<'static> {
+    // commands spanning databases, or running others in any of them
+    let command = match command {
+        Command::Select(index) => {
+            return match db::index(index, state.len()) {
+                Some(index) => {
+                    *db = index;
+                    Reply::Simple("OK".to_string())
+                }
+                None => Reply::Error("DB index is out of range"),
+            };
+        }
+        Command::Move(key, dest) => {
+            for (i, (state, durations)) in state.iter_mut().zip(durations.iter_mut()).enumerate() {
+                expire_if_needed(state, durations, execution, i, &key);
+            }
+            let reply = db::move_key(state, durations, watches, *db, dest, &key);
+            notifier.notify_all();
+            return reply;
+        }
+        Command::SwapDb(first, second) => {
+            let reply = db::swapdb(state, durations, watches, first, second);
+            // clients blocked on either database may now be served
+            notifier.notify_all();
+            return reply;
+        }
+        Command::FlushAll => return db::flushall(state, durations, watches),
+        Command::Save => {
+            let path = rdb_path(config);
+            return snapshots.save(state, durations, scripts, path, rdb_compression(config));
+        }
+        Command::BgSave => {
+            let path = rdb_path(config);
+            return snapshots.bgsave(state, durations, scripts, path, rdb_compression(config));
+        }
+        Command::BgRewriteAof => {
+            return aof.rewrite(state, durations, scripts, rdb_compression(config));
+        }
+        Command::Eval { script, keys, args } => {
+            // commands called by the script run within this same lock; a
+            // SELECT in the script does not change the caller's database
+            let mut db = *db;
+            // its writes reach the AOF and replicas as one transaction
+            let started = execution.start_batch();
+            let mut run = |command: Command, argv| {
+                if command.is_write() && replica_read_only(config, replication) {
+                    return read_only_error();
+                }
+                let exists = |key: &[u8]| state[0].contains_key(key);
+                if let Some(error) =
+                    cluster.and_then(|cluster| cluster.check_script_keys(&command.keys(), exists))
+                {
+                    return error;
+                }
+                execute(
+                    command,
+                    argv,
+                    state,
+                    durations,
+                    &mut db,
+                    watches,
+                    execution,
+                    config,
+                    notifier,
+                    pubsub,
+                    scripts,
+                    snapshots,
+                    aof,
+                    replication,
+                    cluster,
+                )
+            };
+            let reply = scripts.eval(script, keys, args, &mut run);
+            if started {
+                propagate_batch(execution.take_batch(), aof, replication);
+            }
+            return reply;
+        }
+        Command::FCall {
+            function,
+            keys,
+            args,
+            read_only,
+        } => {
+            let mut db = *db;
+            // its writes reach the AOF and replicas as one transaction
+            let started = execution.start_batch();
+            let mut run = |command: Command, argv| {
+                if command.is_write() && replica_read_only(config, replication) {
+                    return read_only_error();
+                }
+                let exists = |key: &[u8]| state[0].contains_key(key);
+                if let Some(error) =
+                    cluster.and_then(|cluster| cluster.check_script_keys(&command.keys(), exists))
+                {
+                    return error;
+                }
+                execute(
+                    command,
+                    argv,
+                    state,
+                    durations,
+                    &mut db,
+                    watches,
+                    execution,
+                    config,
+                    notifier,
+                    pubsub,
+                    scripts,
+                    snapshots,
+                    aof,
+                    replication,
+                    cluster,
+                )
+            };
+            let reply = scripts.fcall(&function, keys, args, read_only, &mut run);
+            if started {
+                propagate_batch(execution.take_batch(), aof, replication);
+            }
+            return reply;
+        }
+        command => command,
+    };
+    let db = *db;
+    let state = &mut state[db];
+    let durations = &mut durations[db];
+
+    let written: Vec<Vec<u8>> = if watches.is_empty() {
+        vec![]
+    } else {
+        command
+            .written_keys()
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect()
+    };
+    let mut reply = None;
+    match command {
+        Command::ConfigGet(key) => {
+            if let Some(val) = config.get(&key) {
+                reply = Some(Reply::Array(vec![key, val.to_owned()]));
+            }
+        }
+        Command::Set(key, val, px) => {
+            // without an expiry, the key loses the one it had
+            match px {
+                Some(px) => durations.insert(
+                    key.clone(),
+                    time::Instant::now() + time::Duration::from_millis(px),
+                ),
+                None => durations.remove(&key),
+            };
+            state.insert(key, Value::String(val.into()));
+            reply = Some(Reply::Simple("OK".to_string()));
+        }
+        Command::Get(key) => {
+            if state.contains_key(&key) {
+                reply = Some(if expire_if_needed(state, durations, execution, db, &key) {
+                    Reply::NullBulk
+                } else {
+                    match &state[&key] {
+                        Value::String(s) => Reply::BulkBytes(s.to_vec()),
+                        _ => Reply::ErrorCode("WRONGTYPE", reply::WRONGTYPE.to_string()),
                     }
-                    state.insert(key, val);
-                    reply = Some(Reply::Simple("OK".to_string()));
-                }
-                Command::Get(key) => {
-                    if let Some(val) = state.get(&key) {
-                        if durations.get(&key).is_some_and(|ins| {
-                            ins.checked_duration_since(time::Instant::now()).is_none()
-                        }) {
-                            let _ = durations.remove(&key);
-                            let _ = state.remove(&key);
-                            reply = Some(Reply::NullBulk);
-                        } else {
-                            reply = Some(Reply::Bulk(val.to_owned()));
+                });
+            }
+        }
+        Command::Del(keys) => {
+            let mut deleted = 0;
+            for key in &keys {
+                expire_if_needed(state, durations, execution, db, key);
+                durations.remove(key);
+                deleted += state.remove(key).is_some() as i64;
+            }
+            reply = Some(Reply::Integer(deleted));
+        }
+        Command::FlushDb => {
+            watches.touch_db(db, |key| state.contains_key(key));
+            state.clear();
+            durations.clear();
+            reply = Some(Reply::Simple("OK".to_string()));
+        }
+        Command::Keys() => {
+            let keys: Vec<Vec<u8>> = state.keys().cloned().collect();
+            let keys = keys
+                .into_iter()
+                .filter(|key| !expire_if_needed(state, durations, execution, db, key))
+                .map(Reply::BulkBytes)
+                .collect();
+            reply = Some(Reply::Nested(keys));
+        }
+        Command::Ping => reply = Some(Reply::Pong),
+        Command::Echo(s) => {
+            reply = Some(Reply::Echo(s));
+        }
+        Command::Dump(key) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(migrate::dump(state, &key, rdb_compression(config)));
+        }
+        Command::Restore(restore) => {
+            expire_if_needed(state, durations, execution, db, &restore.key);
+            // "clients" means every payload sent over a connection
+            let sanitize = !config["sanitize-dump-payload"].eq_ignore_ascii_case("no");
+            reply = Some(migrate::restore(state, durations, restore, sanitize));
+            notifier.notify_all();
+        }
+        Command::Migrate(options) => {
+            for key in &options.keys {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(migrate::migrate(
+                state,
+                durations,
+                &options,
+                rdb_compression(config),
+                config["cluster-enabled"].eq_ignore_ascii_case("yes"),
+            ));
+        }
+        // in a transaction, where they do not block
+        Command::Wait(numreplicas, _) => reply = Some(replication.wait(numreplicas, None)),
+        Command::WaitAof(numlocal, numreplicas, _) => {
+            reply = Some(replication.waitaof(
+                numlocal,
+                numreplicas,
+                config["appendonly"].eq_ignore_ascii_case("yes"),
+                None,
+            ));
+        }
+        // only the replication and cluster sections are reported for now
+        Command::Info(sections) => {
+            let wanted = |name: &str| {
+                sections.is_empty()
+                    || sections.iter().any(|section| {
+                        section == name
+                            || matches!(section.as_str(), "all" | "everything" | "default")
+                    })
+            };
+            let mut info = Vec::new();
+            if wanted("replication") {
+                info.push(replication.info());
+            }
+            if wanted("cluster") {
+                let enabled = config["cluster-enabled"].eq_ignore_ascii_case("yes");
+                info.push(format!(
+                    "# Cluster\r\ncluster_enabled:{}\r\n",
+                    enabled as u8
+                ));
+            }
+            reply = Some(Reply::Bulk(info.join("\r\n")));
+        }
+        Command::Type(key) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            let type_name = state.get(&key).map_or("none", Value::type_name);
+            reply = Some(Reply::Simple(type_name.to_string()));
+        }
+        Command::XAdd {
+            key,
+            id,
+            fields,
+            nomkstream,
+            trim,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xadd(state, key, id, fields, nomkstream, trim));
+            notifier.notify_all();
+        }
+        Command::XRange {
+            key,
+            start,
+            end,
+            count,
+            rev,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xrange(state, &key, start, end, count, rev));
+        }
+        Command::XLen(key) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xlen(state, &key));
+        }
+        Command::XTrim(key, trim) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xtrim(state, &key, trim));
+        }
+        Command::XDel(key, ids) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xdel(state, &key, &ids));
+        }
+        Command::XRead {
+            count, keys, ids, ..
+        } => {
+            // blocking reads are served by the connection loop; here (e.g.
+            // inside a transaction) they behave as if BLOCK was not given
+            for key in &keys {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(match stream::resolve_xread_ids(state, &keys, &ids) {
+                Err(e) => e,
+                Ok(ids) => read_once(stream::xread(state, &keys, &ids, count)),
+            });
+        }
+        Command::XGroupCreate {
+            key,
+            group,
+            id,
+            mkstream,
+            entries_read,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xgroup_create(
+                state,
+                &key,
+                &group,
+                id,
+                mkstream,
+                entries_read,
+            ));
+        }
+        Command::XGroupSetId {
+            key,
+            group,
+            id,
+            entries_read,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xgroup_setid(state, &key, &group, id, entries_read));
+            notifier.notify_all();
+        }
+        Command::XGroupDestroy(key, group) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xgroup_destroy(state, &key, &group));
+            notifier.notify_all();
+        }
+        Command::XGroupCreateConsumer(key, group, consumer) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xgroup_createconsumer(
+                state, &key, &group, &consumer,
+            ));
+        }
+        Command::XGroupDelConsumer(key, group, consumer) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xgroup_delconsumer(state, &key, &group, &consumer));
+        }
+        Command::XReadGroup {
+            group,
+            consumer,
+            count,
+            noack,
+            keys,
+            ids,
+            ..
+        } => {
+            for key in &keys {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(read_once(stream::xreadgroup(
+                state, &group, &consumer, &keys, &ids, count, noack,
+            )));
+        }
+        Command::XAck(key, group, ids) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xack(state, &key, &group, &ids));
+        }
+        Command::XPending(key, group, range) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xpending(state, &key, &group, range));
+        }
+        Command::XClaim {
+            key,
+            group,
+            consumer,
+            min_idle,
+            ids,
+            options,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xclaim(
+                state, &key, &group, &consumer, min_idle, &ids, options,
+            ));
+        }
+        Command::XAutoClaim {
+            key,
+            group,
+            consumer,
+            min_idle,
+            start,
+            count,
+            just_id,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xautoclaim(
+                state, &key, &group, &consumer, min_idle, start, count, just_id,
+            ));
+        }
+        Command::XInfoStream(key, full) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xinfo_stream(state, &key, full));
+        }
+        Command::XInfoGroups(key) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xinfo_groups(state, &key));
+        }
+        Command::XInfoConsumers(key, group) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(stream::xinfo_consumers(state, &key, &group));
+        }
+        Command::SetBit(key, offset, value) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(bitmap::setbit(state, &key, offset, value));
+        }
+        Command::GetBit(key, offset) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(bitmap::getbit(state, &key, offset));
+        }
+        Command::BitCount(key, range) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(bitmap::bitcount(state, &key, range));
+        }
+        Command::BitPos(key, bit, range) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(bitmap::bitpos(state, &key, bit, range));
+        }
+        Command::BitOp(op, dest, keys) => {
+            for key in keys.iter().chain([&dest]) {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(bitmap::bitop(state, op, &dest, &keys));
+            // the destination is overwritten, along with its TTL
+            durations.remove(&dest);
+        }
+        Command::BitField(key, ops) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(bitmap::bitfield(state, &key, &ops));
+        }
+        Command::PfAdd(key, elements) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(hyperloglog::pfadd(state, &key, &elements));
+        }
+        Command::PfCount(keys) => {
+            for key in &keys {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(hyperloglog::pfcount(state, &keys));
+        }
+        Command::PfMerge(dest, keys) => {
+            for key in keys.iter().chain([&dest]) {
+                expire_if_needed(state, durations, execution, db, key);
+            }
+            reply = Some(hyperloglog::pfmerge(state, &dest, &keys));
+        }
+        Command::GeoAdd {
+            key,
+            nx,
+            xx,
+            ch,
+            items,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(geo::geoadd(state, &key, &items, nx, xx, ch));
+        }
+        Command::GeoDist(key, member1, member2, unit) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(geo::geodist(state, &key, &member1, &member2, unit));
+        }
+        Command::GeoPos(key, members) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(geo::geopos(state, &key, &members));
+        }
+        Command::GeoHash(key, members) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(geo::geohash(state, &key, &members));
+        }
+        Command::GeoSearch(key, query) => {
+            expire_if_needed(state, durations, execution, db, &key);
+            reply = Some(geo::geosearch(state, &key, &query));
+        }
+        Command::GeoSearchStore {
+            dest,
+            key,
+            query,
+            store_dist,
+        } => {
+            expire_if_needed(state, durations, execution, db, &key);
+            expire_if_needed(state, durations, execution, db, &dest);
+            reply = Some(geo::geosearchstore(state, &dest, &key, &query, store_dist));
+            durations.remove(&dest);
+        }
+        Command::Publish(channel, message) => {
+            reply = Some(pubsub::publish(&pubsub.lock().unwrap(), &channel, &message));
+        }
+        Command::SPublish(channel, message) => {
+            reply = Some(pubsub::spublish(
+                &pubsub.lock().unwrap(),
+                &channel,
+                &message,
+            ));
+        }
+        Command::PubSubChannels(kind, pattern) => {
+            let pubsub = pubsub.lock().unwrap();
+            reply = Some(pubsub::channels(&pubsub, kind, pattern.as_deref()));
+        }
+        Command::PubSubNumSub(kind, channels) => {
+            reply = Some(pubsub::numsub(&pubsub.lock().unwrap(), kind, &channels));
+        }
+        Command::PubSubNumPat => reply = Some(pubsub::numpat(&pubsub.lock().unwrap())),
+        // EXEC unwatches every key once it is done anyway
+        Command::Unwatch => reply = Some(Reply::Simple("OK".to_string())),
+        Command::ScriptLoad(source) => {
+            reply = Some(match scripts.load(&source) {
+                Ok((sha, _)) => Reply::Bulk(sha),
+                Err(e) => e,
+            });
+        }
+        Command::ScriptExists(shas) => reply = Some(scripts.exists(&shas)),
+        Command::ScriptFlush => {
+            scripts.flush();
+            reply = Some(Reply::Simple("OK".to_string()));
+        }
+        Command::ScriptKill => reply = Some(scripts.kill()),
+        Command::LastSave => reply = Some(snapshots.last_save()),
+        Command::FunctionLoad { code, replace } => {
+            reply = Some(match scripts.function_load(&code, replace) {
+                Ok(name) => Reply::Bulk(name),
+                Err(msg) => Reply::ErrorCode("ERR", msg),
+            });
+        }
+        Command::FunctionList { pattern, with_code } => {
+            reply = Some(scripts.function_list(pattern.as_deref(), with_code));
+        }
+        Command::FunctionDelete(library) => reply = Some(scripts.function_delete(&library)),
+        Command::FunctionDump => reply = Some(scripts.function_dump(rdb_compression(config))),
+        Command::FunctionRestore(payload, policy) => {
+            reply = Some(scripts.function_restore(&payload, policy));
+        }
+        Command::FunctionFlush => {
+            scripts.function_flush();
+            reply = Some(Reply::Simple("OK".to_string()));
+        }
+        Command::Select(_)
+        | Command::Move(..)
+        | Command::SwapDb(..)
+        | Command::FlushAll
+        | Command::Save
+        | Command::BgSave
+        | Command::BgRewriteAof
+        | Command::Eval { .. }
+        | Command::FCall { .. } => unreachable!("handled above"),
+        Command::Subscribe(..)
+        | Command::Unsubscribe(..)
+        | Command::Hello(_)
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::Watch(_)
+        | Command::Shutdown(_)
+        | Command::ReplConf(_)
+        | Command::Psync(..)
+        | Command::ReplicaOf(_)
+        | Command::Cluster(_)
+        // only from the AOF or a master, which never send them outside
+        // a transaction or connection
+        | Command::Asking => reply = Some(Reply::Error("Command not allowed here")),
+    }
+    if !reply.as_ref().is_some_and(Reply::is_error) {
+        for key in &written {
+            watches.touch(db, key);
+        }
+    }
+    reply.unwrap_or(Reply::Null)
+}
+
+/// The reply of a single, non-blocking read attempt.
+fn read_once(read: Result<Option<Reply<'static>>, Reply<'static>>) -> Reply<'static> {
+    match read {
+        Ok(Some(reply)) | Err(reply) => reply,
+        Ok(None) => Reply::NullArray,
+    }
+}
+
+/// Commands served while a script has been running for too long; none of
+/// them needs the keyspace.
+fn allowed_when_busy(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::ScriptKill
+            | Command::Shutdown(Some(false))
+            | Command::Hello(_)
+            | Command::Multi
+            | Command::Discard
+            | Command::Unwatch
+            | Command::Subscribe(..)
+            | Command::Unsubscribe(..)
+    )
+}
+
+fn busy_error() -> Reply<'static> {
+    Reply::ErrorCode(
+        "BUSY",
+        "Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+            .to_string(),
+    )
+}
+
+/// Locks what a running script may hold, giving up with -BUSY once it has
+/// run for longer than `threshold`, even if the command came in before.
+fn lock_unless_busy<'a, T>(
+    mutex: &'a Mutex<T>,
+    scripts: &Scripts,
+    threshold: time::Duration,
+) -> Result<MutexGuard<'a, T>, Reply<'static>> {
+    let mut attempts = 0;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            Err(TryLockError::WouldBlock) if scripts.busy(threshold) => return Err(busy_error()),
+            // other commands hold it briefly; scripts for longer
+            Err(TryLockError::WouldBlock) if attempts < 100 => std::thread::yield_now(),
+            Err(TryLockError::WouldBlock) => std::thread::sleep(time::Duration::from_millis(1)),
+        }
+        attempts += 1;
+    }
+}
+
+/// UNWATCH, left to the next WATCH or EXEC of the client if a script holds
+/// the watches.
+fn unwatch(watches: &WatchState, id: u64, pending: &mut bool) {
+    match watches.try_lock() {
+        Ok(mut watches) => watches.unwatch(id),
+        Err(_) => *pending = true,
+    }
+}
+
+/// SHUTDOWN: saves the dataset, unless NOSAVE is given or SAVE is not and
+/// there are no save points, syncs the AOF and exits. Only returns if the
+/// save failed.
+fn shutdown(
+    save: Option<bool>,
+    state: &State,
+    durations: &Duration,
+    config: &Config,
+    scripts: &Scripts,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+) -> Reply<'static> {
+    let save = save.unwrap_or_else(|| {
+        save::parse_save_points(&config["save"]).is_some_and(|points| !points.is_empty())
+    });
+    if save {
+        let state = state.lock().unwrap();
+        let durations = durations.lock().unwrap();
+        let path = rdb_path(config);
+        let reply = snapshots.save(&state, &durations, scripts, path, rdb_compression(config));
+        if reply.is_error() {
+            let error = String::from_utf8_lossy(&reply.into_bytes())
+                .trim_end()
+                .to_string();
+            eprintln!("Error trying to save the DB, can't exit: {}", error);
+            return Reply::Error("Errors trying to SHUTDOWN. Check logs.");
+        }
+    }
+    aof.fsync();
+    eprintln!("Redis is now ready to exit, bye bye...");
+    std::process::exit(0);
+}
+
+/// Commands served by a replica while its data is stale, with
+/// `replica-serve-stale-data no`.
+fn allowed_when_stale(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Ping
+            | Command::Info(_)
+            | Command::ReplicaOf(_)
+            | Command::ReplConf(_)
+            | Command::Hello(_)
+            | Command::ConfigGet(_)
+            | Command::Subscribe(..)
+            | Command::Unsubscribe(..)
+    )
+}
+
+/// Whether clients may not write: on a replica, unless `replica-read-only`
+/// is `no`.
+fn replica_read_only(config: &Config, replication: &Replication) -> bool {
+    replication.is_replica() && config["replica-read-only"].eq_ignore_ascii_case("yes")
+}
+
+fn read_only_error() -> Reply<'static> {
+    Reply::ErrorCode(
+        "READONLY",
+        "You can't write against a read only replica.".to_string(),
+    )
+}
+
+/// Whether clients are refused while the replica is not in sync with its
+/// master, with `replica-serve-stale-data no`.
+fn stale(config: &Config, replication: &Replication) -> bool {
+    replication.is_stale() && config["replica-serve-stale-data"].eq_ignore_ascii_case("no")
+}
+
+/// How the commands of clients handle expired keys: on a replica, as
+/// `on_replica` says.
+fn client_expiry(replication: &Replication, on_replica: Expiry) -> Expiry {
+    if replication.is_replica() {
+        on_replica
+    } else {
+        Expiry::Delete
+    }
+}
+
+/// Starts the thread that waits `delay` for more replicas to ask for a
+/// diskless sync, then sends them all the same snapshot.
+fn start_diskless_sync(
+    delay: time::Duration,
+    compress: bool,
+    state: &State,
+    durations: &Duration,
+    scripts: &ScriptState,
+    replication: &ReplicationState,
+) {
+    let state = Arc::clone(state);
+    let durations = Arc::clone(durations);
+    let scripts = Arc::clone(scripts);
+    let replication = Arc::clone(replication);
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        let (header, replicas, snapshot) = {
+            let state = state.lock().unwrap();
+            let durations = durations.lock().unwrap();
+            let snapshot = rdb::snapshot(&state, &durations, scripts.library_codes());
+            let (header, replicas) = replication.start_diskless();
+            (header, replicas, snapshot)
+        };
+        let rdb = rdb::encode_rdb(&snapshot, compress);
+        for replica in replicas {
+            // the others are served on
+            let _ = replication.send_snapshot(&replica, &header, &rdb, true);
+        }
+    });
+}
+
+/// Starts the thread replicating the master set by REPLICAOF, as
+/// `generation`.
+#[allow(clippy::too_many_arguments)]
+fn start_replica(
+    generation: u64,
+    state: &State,
+    config: &Config,
+    durations: &Duration,
+    notifier: &Notifier,
+    pubsub: &PubSubState,
+    watches: &WatchState,
+    scripts: &ScriptState,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+) {
+    let state = Arc::clone(state);
+    let config = Arc::clone(config);
+    let durations = Arc::clone(durations);
+    let notifier = Arc::clone(notifier);
+    let pubsub = Arc::clone(pubsub);
+    let watches = Arc::clone(watches);
+    let scripts = Arc::clone(scripts);
+    let snapshots = Arc::clone(snapshots);
+    let aof = Arc::clone(aof);
+    let replication = Arc::clone(replication);
+    std::thread::spawn(move || {
+        let listening_port = config["port"].parse().unwrap();
+        // the database the stream left selected, for a partial resync
+        let mut db = 0;
+        // until another REPLICAOF replaces this one
+        while let Some((host, port)) = replication.master(generation) {
+            let resume = replication.resume_point();
+            match MasterLink::connect(&host, port, listening_port, resume) {
+                Ok((link, sync)) => {
+                    if !replication.set_link(generation, &link) {
+                        break;
+                    }
+                    let result = replicate(
+                        link,
+                        sync,
+                        &mut db,
+                        &state,
+                        &config,
+                        &durations,
+                        &notifier,
+                        &pubsub,
+                        &watches,
+                        &scripts,
+                        &snapshots,
+                        &aof,
+                        &replication,
+                    );
+                    replication.link_down(generation);
+                    if let Err(e) = result {
+                        eprintln!("Lost the link with the master {}:{}: {}", host, port, e);
+                    }
+                }
+                Err(e) => eprintln!("Error syncing with the master {}:{}: {}", host, port, e),
+            }
+            std::thread::sleep(time::Duration::from_secs(1));
+        }
+    });
+}
+
+/// Replaces the dataset with the master's snapshot on a full resync, then
+/// runs the write commands the master streams until the link breaks, and
+/// reports the offset it processed every `ACK_PERIOD`.
+#[allow(clippy::too_many_arguments)]
+fn replicate(
+    mut link: MasterLink,
+    sync: Sync,
+    db: &mut usize,
+    state: &State,
+    config: &Config,
+    durations: &Duration,
+    notifier: &Notifier,
+    pubsub: &PubSubState,
+    watches: &WatchState,
+    scripts: &Scripts,
+    snapshots: &SnapshotState,
+    aof: &AofState,
+    replication: &ReplicationState,
+) -> Result<(), String> {
+    match sync {
+        Sync::Full {
+            replid,
+            offset,
+            rdb,
+        } => {
+            let sanitize = config["sanitize-dump-payload"].eq_ignore_ascii_case("yes");
+            let diskless = match config["repl-diskless-load"].as_str() {
+                "swapdb" => true,
+                "on-empty-db" => state.lock().unwrap().iter().all(HashMap::is_empty),
+                _ => false,
+            };
+            let path = rdb_path(config);
+            let loaded = if diskless {
+                let databases = state.lock().unwrap().len();
+                Some(load_in_memory(&rdb, databases, sanitize, scripts)?)
+            } else {
+                // saved as this server's RDB file, then loaded from there
+                rdb::write_and_open(&path, &rdb).map_err(|e| e.to_string())?;
+                None
+            };
+            let mut state = state.lock().unwrap();
+            let mut durations = durations.lock().unwrap();
+            let mut watches = watches.lock().unwrap();
+            match loaded {
+                Some((new_state, new_durations, functions)) => {
+                    let old_functions = scripts.library_codes();
+                    scripts.function_flush();
+                    let result = functions
+                        .iter()
+                        .try_for_each(|code| scripts.function_load(code, false).map(|_| ()));
+                    if let Err(e) = result {
+                        // the previous dataset stays
+                        scripts.function_flush();
+                        for code in &old_functions {
+                            let _ = scripts.function_load(code, false);
                         }
+                        return Err(e);
+                    }
+                    // keys in either dataset change
+                    for (db, (old, new)) in state.iter().zip(&new_state).enumerate() {
+                        watches.touch_db(db, |key| old.contains_key(key) || new.contains_key(key));
                     }
+                    *state = new_state;
+                    *durations = new_durations;
                 }
-                Command::Keys() => {
-                    reply = Some(Reply::Array(state.keys().cloned().collect()));
+                None => {
+                    db::flushall(&mut state, &mut durations, &mut watches);
+                    scripts.function_flush();
+                    rdb::load_from_rdb(&path, &mut state, &mut durations, scripts, sanitize)
+                        .map_err(|e| e.to_string())?;
+                    for (db, keys) in state.iter().enumerate() {
+                        watches.touch_db(db, |key| keys.contains_key(key));
+                    }
                 }
-                Command::Ping => reply = Some(Reply::Pong),
-                Command::Echo(s) => {
-                    reply = Some(Reply::Echo(s));
+            }
+            // the AOF must start over from the new dataset
+            if config["appendonly"].eq_ignore_ascii_case("yes") {
+                let compress = rdb_compression(config);
+                let reply = aof.rewrite(&state, &durations, scripts, compress);
+                if reply.is_error() {
+                    eprintln!(
+                        "Can't rewrite the AOF after syncing with the master: {:?}",
+                        reply
+                    );
                 }
             }
+            replication.synced(replid, offset);
+            notifier.notify_all();
+            *db = 0;
         }
+        Sync::Continue(replid) => replication.resumed(replid),
+    }
 
-        if let Some(b) = reply {
-            stream.write(&b.into_bytes())?;
+    let mut acked = None;
+    // the commands of a transaction, and the bytes they came in
+    let mut transaction: Option<(Vec<_>, Vec<u8>)> = None;
+    loop {
+        let command = link.read_command().map_err(|e| e.to_string())?;
+        if acked.is_none_or(|at: time::Instant| at.elapsed() >= replication::ACK_PERIOD) {
+            link.ack(replication).map_err(|e| e.to_string())?;
+            acked = Some(time::Instant::now());
+        }
+        let Some((argv, bytes)) = command else {
+            continue;
+        };
+        let name = argv.first().map(|name| name.to_ascii_lowercase());
+        let getack = name.as_deref() == Some(b"replconf")
+            && argv
+                .get(1)
+                .is_some_and(|arg| arg.eq_ignore_ascii_case(b"getack"));
+        if getack {
+            // the offset before this command
+            link.ack(replication).map_err(|e| e.to_string())?;
+        }
+        let command = if getack || name.as_deref() == Some(b"ping") {
+            None
         } else {
-            stream.write(&Reply::Null.into_bytes())?;
+            Command::from_args(&argv)
+                .map_err(|e| eprintln!("Invalid command from the master: {}", e))
+                .ok()
+        };
+        // a transaction is applied as one, and counted as processed, once
+        // its EXEC arrives
+        let (queue, bytes) = match (command, transaction.take()) {
+            (Some(Command::Multi), _) => {
+                transaction = Some((Vec::new(), bytes));
+                continue;
+            }
+            (Some(Command::Exec), Some((queue, mut buffered))) => {
+                buffered.extend(bytes);
+                (queue, buffered)
+            }
+            (Some(Command::Discard), Some((_, mut buffered))) => {
+                buffered.extend(bytes);
+                (Vec::new(), buffered)
+            }
+            (command, Some((mut queue, mut buffered))) => {
+                queue.extend(command.map(|command| (command, argv)));
+                buffered.extend(bytes);
+                transaction = Some((queue, buffered));
+                continue;
+            }
+            (command, None) => (
+                command.map(|command| (command, argv)).into_iter().collect(),
+                bytes,
+            ),
+        };
+        let mut state = state.lock().unwrap();
+        let mut durations = durations.lock().unwrap();
+        let mut watches = watches.lock().unwrap();
+        exec_queue(
+            queue,
+            &mut state,
+            &mut durations,
+            db,
+            &mut watches,
+            // keys expire when the master deletes them
+            &mut Execution::new(Expiry::Keep),
+            config,
+            notifier,
+            pubsub,
+            scripts,
+            snapshots,
+            aof,
+            replication,
+            None,
+        );
+        // under the keyspace lock, in order with snapshots sent to replicas
+        replication.processed(&bytes);
+        if let Some(offset) = aof.mark(replication.offset()) {
+            replication.set_aof_offset(offset);
         }
     }
+}
 
-    Ok(())
+/// Loads a snapshot received from the master into a new keyspace of
+/// `databases`, to be swapped in with the function libraries it has once
+/// it loaded completely.
+#[allow(clippy::type_complexity)]
+fn load_in_memory(
+    rdb: &[u8],
+    databases: usize,
+    sanitize: bool,
+    scripts: &Scripts,
+) -> Result<
+    (
+        Vec<HashMap<Vec<u8>, Value>>,
+        Vec<HashMap<Vec<u8>, time::Instant>>,
+        Vec<Vec<u8>>,
+    ),
+    String,
+> {
+    let (mut rdb, _) = rdb::parse_rdb(rdb, sanitize).map_err(|e| e.to_string())?;
+    let functions = std::mem::take(&mut rdb.functions);
+    let mut state = vec![HashMap::new(); databases];
+    let mut durations = vec![HashMap::new(); databases];
+    rdb::load(rdb, &mut state, &mut durations, scripts).map_err(|e| e.to_string())?;
+    Ok((state, durations, functions))
+}
+
+/// Where snapshots are saved to and loaded from: `--dir` and
+/// `--dbfilename`.
+fn rdb_path(config: &Config) -> PathBuf {
+    PathBuf::from(&config["dir"]).join(&config["dbfilename"])
+}
+
+/// Whether long strings are LZF compressed when serialized, as set by
+/// `--rdbcompression yes|no`.
+fn rdb_compression(config: &Config) -> bool {
+    config
+        .get("rdbcompression")
+        .is_none_or(|value| !value.eq_ignore_ascii_case("no"))
+}
+
+fn allowed_when_subscribed(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Subscribe(..) | Command::Unsubscribe(..) | Command::Ping
+    )
+}
+
+/// Runs `read` until it produces a reply, waiting for writes from other
+/// clients in between. It gives up with a null reply after `block` ms (0
+/// meaning never).
+fn blocking_read<F>(
+    mut state: MutexGuard<Vec<HashMap<Vec<u8>, Value>>>,
+    notifier: &Condvar,
+    db: usize,
+    block: u64,
+    mut read: F,
+) -> Reply<'static>
+where
+    F: FnMut(&mut HashMap<Vec<u8>, Value>) -> Result<Option<Reply<'static>>, Reply<'static>>,
+{
+    let deadline = Some(block)
+        .filter(|ms| *ms > 0)
+        .map(|ms| time::Instant::now() + time::Duration::from_millis(ms));
+    loop {
+        match read(&mut state[db]) {
+            Err(e) => return e,
+            Ok(Some(r)) => return r,
+            Ok(None) => {}
+        }
+        state = match deadline {
+            None => notifier.wait(state).unwrap(),
+            Some(deadline) => {
+                let now = time::Instant::now();
+                if now >= deadline {
+                    return Reply::NullArray;
+                }
+                notifier.wait_timeout(state, deadline - now).unwrap().0
+            }
+        };
+    }
+}
+
+/// Handles `key` in database `db` as `execution.expiry` says if its TTL
+/// has passed, returning whether the command must see it as missing.
+fn expire_if_needed(
+    state: &mut HashMap<Vec<u8>, Value>,
+    durations: &mut HashMap<Vec<u8>, time::Instant>,
+    execution: &mut Execution,
+    db: usize,
+    key: &[u8],
+) -> bool {
+    let expired = durations
+        .get(key)
+        .is_some_and(|ins| ins.checked_duration_since(time::Instant::now()).is_none());
+    if !expired {
+        return false;
+    }
+    match execution.expiry {
+        Expiry::Delete => {
+            durations.remove(key);
+            state.remove(key);
+            execution.expired(db, key);
+        }
+        Expiry::Hide => {
+            let at = durations.remove(key).unwrap();
+            if let Some(value) = state.remove(key) {
+                execution.hide(db, key, value, at);
+            }
+        }
+        Expiry::Keep => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Only the settings commands look at, and `settings`.
+    fn config(settings: &[(&str, &str)]) -> Config {
+        let config = [
+            ("port", "6379"),
+            ("save", ""),
+            ("appendonly", "no"),
+            ("replica-read-only", "yes"),
+            ("replica-serve-stale-data", "yes"),
+        ];
+        Arc::new(
+            config
+                .iter()
+                .chain(settings)
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn aof() -> AofState {
+        Arc::new(Aof::new(PathBuf::new(), String::new(), aof::Fsync::No))
+    }
+
+    /// Runs `args` on the keyspace of a single database, handling expired
+    /// keys as `expiry` says.
+    fn run(
+        state: &mut [HashMap<Vec<u8>, Value>],
+        durations: &mut [HashMap<Vec<u8>, time::Instant>],
+        expiry: Expiry,
+        args: &[impl AsRef<[u8]>],
+    ) -> Reply<'static> {
+        let argv: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_ref().to_vec()).collect();
+        execute(
+            Command::from_args(&argv).unwrap(),
+            argv,
+            state,
+            durations,
+            &mut 0,
+            &mut Watches::default(),
+            &mut Execution::new(expiry),
+            &config(&[]),
+            &Condvar::new(),
+            &Arc::default(),
+            &Scripts::default(),
+            &Arc::default(),
+            &aof(),
+            &Arc::new(Replication::new(0, Default::default())),
+            None,
+        )
+    }
+
+    /// The replies to `commands` sent on a connection, with the settings of
+    /// `config`.
+    fn serve(commands: &[&[&str]], settings: &[(&str, &str)]) -> String {
+        let mut input = Vec::new();
+        for command in commands {
+            let argv: Vec<Vec<u8>> = command.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+            aof::encode_command(&argv, &mut input);
+        }
+        let output = Buffer::default();
+        let client = Arc::new(Client::new(1, Box::new(output.clone())));
+        handle_client(
+            input.as_slice(),
+            client,
+            Arc::new(Mutex::new(vec![HashMap::new()])),
+            config(settings),
+            Arc::new(Mutex::new(vec![HashMap::new()])),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            aof(),
+            Arc::new(Replication::new(0, Default::default())),
+            Arc::new(Cluster::disabled()),
+        )
+        .unwrap();
+        let output = output.0.lock().unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    #[test]
+    fn test_nested_multi() {
+        let replies = serve(
+            &[
+                &["multi"],
+                &["set", "k", "v"],
+                &["multi"],
+                &["exec"],
+                &["keys", "*"],
+            ],
+            &[],
+        );
+        assert_eq!(
+            replies,
+            "+OK\r\n+QUEUED\r\n-ERR MULTI calls can not be nested\r\n\
+             -EXECABORT Transaction discarded because of previous errors.\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_binary_keys() {
+        let mut state = vec![HashMap::new()];
+        let mut durations = vec![HashMap::new()];
+        for (key, value) in [(b"\xff", b"1"), (b"\xfe", b"2")] {
+            let reply = run(
+                &mut state,
+                &mut durations,
+                Expiry::Keep,
+                &[b"set".as_slice(), key, value],
+            );
+            assert_eq!(reply.into_bytes(), b"+OK\r\n");
+        }
+        assert_eq!(state[0].len(), 2);
+        for (key, value) in [(b"\xff", "$1\r\n1\r\n"), (b"\xfe", "$1\r\n2\r\n")] {
+            let reply = run(
+                &mut state,
+                &mut durations,
+                Expiry::Keep,
+                &[b"get".as_slice(), key],
+            );
+            assert_eq!(reply.into_bytes(), value.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_set_removes_ttl() {
+        let dir = std::env::temp_dir().join(format!("set-ttl-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = [
+            ("dir", dir.to_str().unwrap()),
+            ("dbfilename", "dump.rdb"),
+            ("rdbcompression", "yes"),
+        ];
+        let replies = serve(
+            &[
+                &["set", "k", "a", "px", "100000"],
+                &["set", "k", "b"],
+                &["save"],
+            ],
+            &settings,
+        );
+        assert_eq!(replies, "+OK\r\n+OK\r\n+OK\r\n");
+        let mut state = vec![HashMap::new()];
+        let mut durations = vec![HashMap::new()];
+        let path = dir.join("dump.rdb");
+        rdb::load_from_rdb(&path, &mut state, &mut durations, &Scripts::default(), true).unwrap();
+        assert_eq!(
+            state[0][b"k".as_slice()],
+            Value::String(b"b".to_vec().into())
+        );
+        assert!(durations[0].is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_to_hidden_key() {
+        let mut state = vec![HashMap::new()];
+        let mut durations = vec![HashMap::new()];
+        let past = time::Instant::now() - time::Duration::from_millis(10);
+        for key in [b"a", b"b"] {
+            state[0].insert(key.to_vec(), Value::String(b"old".to_vec().into()));
+            durations[0].insert(key.to_vec(), past);
+        }
+        // read, it stays for the master to delete
+        assert_eq!(
+            // as on a replica
+            run(&mut state, &mut durations, Expiry::Hide, &["get", "a"]).into_bytes(),
+            b"$-1\r\n"
+        );
+        assert_eq!(durations[0].get(b"a".as_slice()), Some(&past));
+        // written, the new value stays
+        run(
+            &mut state,
+            &mut durations,
+            Expiry::Hide,
+            &["setbit", "b", "0", "1"],
+        );
+        assert_eq!(
+            state[0][b"b".as_slice()],
+            Value::String(b"\x80".to_vec().into())
+        );
+        assert!(!durations[0].contains_key(b"b".as_slice()));
+    }
 }
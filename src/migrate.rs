@@ -0,0 +1,291 @@
+// Moving keys between instances: DUMP payloads, created again with RESTORE,
+// which MIGRATE sends to the target over a new connection.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::aof;
+use crate::rdb;
+use crate::reply::Reply;
+use crate::value::Value;
+
+/// How long MIGRATE waits on the target when given a timeout of 0, in ms.
+const DEFAULT_TIMEOUT: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restore {
+    pub key: Vec<u8>,
+    // in ms, 0 for no TTL
+    pub ttl: u64,
+    pub payload: Vec<u8>,
+    pub replace: bool,
+    // `ttl` is a Unix time rather than a duration
+    pub absttl: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migrate {
+    pub host: String,
+    pub port: u16,
+    pub db: usize,
+    // in ms, for connecting and for each read or write
+    pub timeout: u64,
+    // keep the keys here too
+    pub copy: bool,
+    pub replace: bool,
+    // the arguments of the AUTH sent first, if any
+    pub auth: Vec<String>,
+    pub keys: Vec<Vec<u8>>,
+}
+
+/// DUMP: the value of `key` serialized, or a null reply if there is none.
+pub fn dump(state: &HashMap<Vec<u8>, Value>, key: &[u8], compress: bool) -> Reply<'static> {
+    match state.get(key) {
+        Some(value) => Reply::BulkBytes(rdb::dump_payload(value, compress)),
+        None => Reply::NullBulk,
+    }
+}
+
+/// RESTORE: creates a key from a DUMP payload. A key whose absolute TTL
+/// has already passed is not created (but is still replaced).
+pub fn restore(
+    state: &mut HashMap<Vec<u8>, Value>,
+    durations: &mut HashMap<Vec<u8>, Instant>,
+    restore: Restore,
+    sanitize: bool,
+) -> Reply<'static> {
+    if !restore.replace && state.contains_key(&restore.key) {
+        return Reply::ErrorCode("BUSYKEY", "Target key name already exists.".to_string());
+    }
+    let value = match rdb::parse_dump_payload(&restore.payload, sanitize) {
+        Ok(value) => value,
+        Err(e) => return Reply::Error(e),
+    };
+    let ttl = match (restore.ttl, restore.absttl) {
+        (0, _) => None,
+        (at, true) => match at.checked_sub(rdb::unix_time_ms()) {
+            Some(ttl) if ttl > 0 => Some(ttl),
+            _ => {
+                state.remove(&restore.key);
+                durations.remove(&restore.key);
+                return Reply::Simple("OK".to_string());
+            }
+        },
+        (ttl, false) => Some(ttl),
+    };
+    durations.remove(&restore.key);
+    if let Some(ttl) = ttl {
+        durations.insert(
+            restore.key.clone(),
+            Instant::now() + Duration::from_millis(ttl),
+        );
+    }
+    state.insert(restore.key, value);
+    Reply::Simple("OK".to_string())
+}
+
+/// MIGRATE: sends the keys that exist to the target with RESTORE, then
+/// deletes the ones it accepted unless COPY is given. Replies NOKEY if none
+/// of the keys exist. In cluster mode, RESTORE-ASKING has the target take
+/// them even if it is still importing their slot.
+pub fn migrate(
+    state: &mut HashMap<Vec<u8>, Value>,
+    durations: &mut HashMap<Vec<u8>, Instant>,
+    migrate: &Migrate,
+    compress: bool,
+    cluster: bool,
+) -> Reply<'static> {
+    let now = Instant::now();
+    let keys: Vec<&Vec<u8>> = migrate
+        .keys
+        .iter()
+        .filter(|key| state.contains_key(*key))
+        .collect();
+    if keys.is_empty() {
+        return Reply::Simple("NOKEY".to_string());
+    }
+
+    let mut commands = Vec::new();
+    let mut argv = |args: Vec<Vec<u8>>| aof::encode_command(&args, &mut commands);
+    if !migrate.auth.is_empty() {
+        let mut auth = vec![b"AUTH".to_vec()];
+        auth.extend(migrate.auth.iter().map(|arg| arg.as_bytes().to_vec()));
+        argv(auth);
+    }
+    argv(vec![
+        b"SELECT".to_vec(),
+        migrate.db.to_string().into_bytes(),
+    ]);
+    for key in &keys {
+        // a TTL about to run out is sent as 1ms rather than as no TTL
+        let ttl = durations.get(*key).map_or(0, |at| {
+            at.saturating_duration_since(now).as_millis().max(1) as u64
+        });
+        let name: &[u8] = if cluster {
+            b"RESTORE-ASKING"
+        } else {
+            b"RESTORE"
+        };
+        let mut restore = vec![
+            name.to_vec(),
+            key.to_vec(),
+            ttl.to_string().into_bytes(),
+            rdb::dump_payload(&state[*key], compress),
+        ];
+        if migrate.replace {
+            restore.push(b"REPLACE".to_vec());
+        }
+        argv(restore);
+    }
+
+    let replies = match send(
+        migrate,
+        &commands,
+        1 + !migrate.auth.is_empty() as usize + keys.len(),
+    ) {
+        Ok(replies) => replies,
+        Err(reply) => return reply,
+    };
+    let (setup, restored) = replies.split_at(replies.len() - keys.len());
+    if let Some(Err(e)) = setup.iter().find(|reply| reply.is_err()) {
+        return target_error(e);
+    }
+    let mut error = None;
+    for (key, reply) in keys.into_iter().zip(restored) {
+        match reply {
+            Ok(()) if !migrate.copy => {
+                state.remove(key);
+                durations.remove(key);
+            }
+            Ok(()) => {}
+            Err(e) => error = error.or(Some(e)),
+        }
+    }
+    match error {
+        Some(e) => target_error(e),
+        None => Reply::Simple("OK".to_string()),
+    }
+}
+
+fn target_error(e: &str) -> Reply<'static> {
+    Reply::ErrorCode("ERR", format!("Target instance replied with error: {}", e))
+}
+
+/// Sends the commands to the target and reads `count` replies: Ok for
+/// each simple string, and the message of each error.
+#[allow(clippy::type_complexity)]
+fn send(
+    migrate: &Migrate,
+    commands: &[u8],
+    count: usize,
+) -> Result<Vec<Result<(), String>>, Reply<'static>> {
+    let ioerr = |action: &str| {
+        Reply::ErrorCode(
+            "IOERR",
+            format!("error or timeout {} target instance", action),
+        )
+    };
+    let timeout = Duration::from_millis(match migrate.timeout {
+        0 => DEFAULT_TIMEOUT,
+        ms => ms,
+    });
+    let addr = (migrate.host.as_str(), migrate.port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| ioerr("connecting to"))?;
+    let mut stream =
+        TcpStream::connect_timeout(&addr, timeout).map_err(|_| ioerr("connecting to"))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .and_then(|_| stream.write_all(commands))
+        .map_err(|_| ioerr("writing to"))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut replies = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(n) if n > 0 => {}
+            _ => return Err(ioerr("reading from")),
+        }
+        let line = line.trim_end();
+        replies.push(match line.strip_prefix('-') {
+            Some(e) => Err(e.to_string()),
+            None if line.starts_with('+') => Ok(()),
+            None => Err(format!("unexpected reply '{}'", line)),
+        });
+    }
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_restore() {
+        let mut state = HashMap::from([(
+            b"k".to_vec(),
+            Value::List(
+                ["a", "b"]
+                    .map(|s| s.as_bytes().to_vec())
+                    .into_iter()
+                    .collect(),
+            ),
+        )]);
+        let mut durations = HashMap::new();
+        let Reply::BulkBytes(payload) = dump(&state, b"k", true) else {
+            panic!("no payload")
+        };
+        let restore_as = |key: &[u8], ttl, replace, absttl| Restore {
+            key: key.to_vec(),
+            ttl,
+            payload: payload.clone(),
+            replace,
+            absttl,
+        };
+
+        assert!(restore(
+            &mut state,
+            &mut durations,
+            restore_as(b"k", 0, false, false),
+            true
+        )
+        .is_error());
+        restore(
+            &mut state,
+            &mut durations,
+            restore_as(b"c", 5000, false, false),
+            true,
+        );
+        assert_eq!(state[b"c".as_slice()], state[b"k".as_slice()]);
+        assert!(durations.contains_key(b"c".as_slice()));
+
+        // an absolute TTL in the past deletes the key
+        restore(
+            &mut state,
+            &mut durations,
+            restore_as(b"c", 1, true, true),
+            true,
+        );
+        assert!(!state.contains_key(b"c".as_slice()) && !durations.contains_key(b"c".as_slice()));
+
+        let mut corrupted = payload.clone();
+        corrupted[2] ^= 1;
+        let reply = restore(
+            &mut state,
+            &mut durations,
+            Restore {
+                payload: corrupted,
+                ..restore_as(b"d", 0, false, false)
+            },
+            true,
+        );
+        assert!(reply.is_error());
+        assert!(matches!(dump(&state, b"d", true), Reply::NullBulk));
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::command::Command;
+use crate::value::Value;
+
+/// The commands a connection queued between MULTI and EXEC, along with
+/// their arguments.
+#[derive(Default)]
+pub struct Transaction {
+    pub queue: Vec<(Command, Vec<Vec<u8>>)>,
+    /// Set when a command could not be queued; EXEC then aborts.
+    pub dirty: bool,
+}
+
+pub type WatchState = Arc<Mutex<Watches>>;
+
+/// What a lookup does with a key whose TTL has passed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Expiry {
+    /// Deletes it, which is propagated as a DEL: on a master.
+    #[default]
+    Delete,
+    /// Hides it from the current command only: a replica waits for the
+    /// master's DEL.
+    Hide,
+    /// Leaves it, e.g. for the writes of the master, which apply to the
+    /// key as the master sees it.
+    Keep,
+}
+
+/// A write to propagate: the database it ran in, and its arguments.
+pub type Write = (usize, Vec<Vec<u8>>);
+
+/// Keys watched by connections for EXEC's check-and-set, along with the
+/// database they are in. Any modification of a watched key makes the
+/// watching connections' next EXEC fail.
+#[derive(Default)]
+pub struct Watches {
+    watchers: HashMap<(usize, Vec<u8>), HashSet<u64>>,
+    watched: HashMap<u64, HashSet<(usize, Vec<u8>)>>,
+    dirty: HashSet<u64>,
+}
+
+/// What a command being executed carries besides its arguments: how it
+/// handles expired keys, the keys it found expired, and within a
+/// transaction or script, the writes to propagate together once it ends.
+#[derive(Default)]
+pub struct Execution {
+    pub expiry: Expiry,
+    // deleted, to propagate
+    expired: Vec<(usize, Vec<u8>)>,
+    // hidden, to put back once the command ran
+    hidden: Vec<(usize, Vec<u8>, Value, Instant)>,
+    batch: Option<Vec<Write>>,
+}
+
+impl Execution {
+    pub fn new(expiry: Expiry) -> Self {
+        Execution {
+            expiry,
+            ..Default::default()
+        }
+    }
+
+    /// Records a key deleted because it expired.
+    pub fn expired(&mut self, db: usize, key: &[u8]) {
+        self.expired.push((db, key.to_vec()));
+    }
+
+    pub fn take_expired(&mut self) -> Vec<(usize, Vec<u8>)> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Records an expired key taken out of the keyspace for the current
+    /// command.
+    pub fn hide(&mut self, db: usize, key: &[u8], value: Value, at: Instant) {
+        self.hidden.push((db, key.to_vec(), value, at));
+    }
+
+    pub fn take_hidden(&mut self) -> Vec<(usize, Vec<u8>, Value, Instant)> {
+        std::mem::take(&mut self.hidden)
+    }
+
+    /// Holds back the writes to propagate from now on, unless an enclosing
+    /// transaction or script already does. True if this one started.
+    pub fn start_batch(&mut self) -> bool {
+        if self.batch.is_some() {
+            return false;
+        }
+        self.batch = Some(Vec::new());
+        true
+    }
+
+    /// The writes held back, if any are.
+    pub fn batch(&mut self) -> Option<&mut Vec<Write>> {
+        self.batch.as_mut()
+    }
+
+    pub fn take_batch(&mut self) -> Vec<Write> {
+        self.batch.take().unwrap_or_default()
+    }
+}
+
+impl Watches {
+    pub fn is_empty(&self) -> bool {
+        self.watched.is_empty()
+    }
+
+    pub fn watch(&mut self, id: u64, db: usize, key: &[u8]) {
+        let key = (db, key.to_vec());
+        self.watchers.entry(key.clone()).or_default().insert(id);
+        self.watched.entry(id).or_default().insert(key);
+    }
+
+    /// Forgets a client's watched keys, along with whether they changed.
+    pub fn unwatch(&mut self, id: u64) {
+        for key in self.watched.remove(&id).unwrap_or_default() {
+            if let Some(watchers) = self.watchers.get_mut(&key) {
+                watchers.remove(&id);
+                if watchers.is_empty() {
+                    self.watchers.remove(&key);
+                }
+            }
+        }
+        self.dirty.remove(&id);
+    }
+
+    pub fn watched(&self, id: u64) -> impl Iterator<Item = &(usize, Vec<u8>)> {
+        self.watched.get(&id).into_iter().flatten()
+    }
+
+    /// Marks the clients watching `key` in database `db` as dirty.
+    pub fn touch(&mut self, db: usize, key: &[u8]) {
+        if let Some(watchers) = self.watchers.get(&(db, key.to_vec())) {
+            self.dirty.extend(watchers);
+        }
+    }
+
+    /// Marks the clients watching a key of database `db` for which
+    /// `exists` holds as dirty, e.g. when it is flushed or swapped: keys
+    /// missing before and after are not modified.
+    pub fn touch_db(&mut self, db: usize, exists: impl Fn(&[u8]) -> bool) {
+        for ((watched_db, key), watchers) in &self.watchers {
+            if *watched_db == db && exists(key) {
+                self.dirty.extend(watchers);
+            }
+        }
+    }
+
+    pub fn is_dirty(&self, id: u64) -> bool {
+        self.dirty.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_touch_marks_watchers_dirty() {
+        let mut watches = Watches::default();
+        watches.watch(1, 0, b"a");
+        watches.watch(2, 0, b"a");
+        watches.watch(2, 0, b"b");
+        watches.touch(0, b"b");
+        assert!(!watches.is_dirty(1));
+        assert!(watches.is_dirty(2));
+
+        watches.unwatch(2);
+        assert!(!watches.is_dirty(2));
+        watches.touch(0, b"b");
+        assert!(!watches.is_dirty(2));
+        assert_eq!(
+            watches.watched(1).collect::<Vec<_>>(),
+            vec![&(0, b"a".to_vec())]
+        );
+
+        watches.touch_db(0, |key| key == b"b");
+        assert!(!watches.is_dirty(1));
+        watches.touch_db(0, |key| key == b"a");
+        assert!(watches.is_dirty(1));
+        watches.unwatch(1);
+        assert!(watches.is_empty());
+    }
+
+    #[test]
+    fn test_databases_are_separate() {
+        let mut watches = Watches::default();
+        watches.watch(1, 0, b"a");
+        watches.watch(2, 1, b"a");
+        watches.touch(1, b"a");
+        assert!(!watches.is_dirty(1));
+        assert!(watches.is_dirty(2));
+        watches.touch_db(0, |_| true);
+        assert!(watches.is_dirty(1));
+    }
+
+    #[test]
+    fn test_expired_keys() {
+        let mut execution = Execution::new(Expiry::Hide);
+        execution.expired(0, b"a");
+        assert_eq!(execution.take_expired(), vec![(0, b"a".to_vec())]);
+        assert!(execution.take_expired().is_empty());
+
+        let at = Instant::now();
+        execution.hide(2, b"b", Value::String(b"1".to_vec().into()), at);
+        let hidden = execution.take_hidden();
+        assert!(
+            matches!(&hidden[..], [(2, key, Value::String(_), when)] if key == b"b" && *when == at)
+        );
+        assert!(execution.take_hidden().is_empty());
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut execution = Execution::default();
+        assert!(execution.batch().is_none());
+        assert!(execution.start_batch());
+        // a script within a transaction adds to its batch
+        assert!(!execution.start_batch());
+        execution.batch().unwrap().push((0, vec![b"DEL".to_vec()]));
+        assert_eq!(execution.take_batch(), vec![(0, vec![b"DEL".to_vec()])]);
+        assert!(execution.batch().is_none());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::client::{Client, OutputLimit};
+use crate::glob;
+use crate::reply::Reply;
+
+pub type PubSubState = Arc<Mutex<PubSub>>;
+
+type Subscribers = HashMap<u64, Arc<Client>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+    /// Shard channels are a namespace of their own, scoped to the slot owning
+    /// the channel name.
+    Shard,
+}
+
+impl Kind {
+    fn subscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "subscribe",
+            Kind::Pattern => "psubscribe",
+            Kind::Shard => "ssubscribe",
+        }
+    }
+
+    fn unsubscribe_reply(self) -> &'static str {
+        match self {
+            Kind::Channel => "unsubscribe",
+            Kind::Pattern => "punsubscribe",
+            Kind::Shard => "sunsubscribe",
+        }
+    }
+}
+
+/// Server-wide registry of subscribed connections. Entries are removed as
+/// soon as they have no subscribers left.
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<String, Subscribers>,
+    patterns: HashMap<String, Subscribers>,
+    shard_channels: HashMap<String, Subscribers>,
+    // of the messages a subscriber hasn't read yet
+    pub limit: OutputLimit,
+}
+
+/// What a single connection is subscribed to.
+#[derive(Default)]
+pub struct Subscriptions {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    shard_channels: HashSet<String>,
+}
+
+impl Subscriptions {
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    /// The count reported in (un)subscribe replies: shard subscriptions are
+    /// counted apart from the classic ones.
+    fn reply_count(&self, kind: Kind) -> i64 {
+        let count = match kind {
+            Kind::Channel | Kind::Pattern => self.channels.len() + self.patterns.len(),
+            Kind::Shard => self.shard_channels.len(),
+        };
+        count as i64
+    }
+
+    fn names(&mut self, kind: Kind) -> &mut HashSet<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+}
+
+impl PubSub {
+    fn registry(&mut self, kind: Kind) -> &mut HashMap<String, Subscribers> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+            Kind::Shard => &mut self.shard_channels,
+        }
+    }
+
+    /// Drops every subscription of a disconnected client.
+    pub fn remove_client(&mut self, id: u64) {
+        for registry in [
+            &mut self.channels,
+            &mut self.patterns,
+            &mut self.shard_channels,
+        ] {
+            registry.retain(|_, subscribers| {
+                subscribers.remove(&id);
+                !subscribers.is_empty()
+            });
+        }
+    }
+}
+
+/// Subscribes to channels or patterns, replying once per name.
+pub fn subscribe(
+    pubsub: &mut PubSub,
+    client: &Arc<Client>,
+    subs: &mut Subscriptions,
+    kind: Kind,
+    names: &[String],
+) -> Reply<'static> {
+    let replies = names
+        .iter()
+        .map(|name| {
+            if subs.names(kind).insert(name.clone()) {
+                pubsub
+                    .registry(kind)
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(client.id, Arc::clone(client));
+            }
+            Reply::Push(vec![
+                Reply::Bulk(kind.subscribe_reply().to_string()),
+                Reply::Bulk(name.clone()),
+                Reply::Integer(subs.reply_count(kind)),
+            ])
+        })
+        .collect();
+    Reply::Many(replies)
+}
+
+/// Unsubscribes from the given channels or patterns, or from all of them if
+/// none is given.
+pub fn unsubscribe(
+    pubsub: &mut PubSub,
+    client: &Client,
+    subs: &mut Subscriptions,
+    kind: Kind,
+    names: &[String],
+) -> Reply<'static> {
+    let names: Vec<String> = if names.is_empty() {
+        subs.names(kind).iter().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+    if names.is_empty() {
+        return Reply::Push(vec![
+            Reply::Bulk(kind.unsubscribe_reply().to_string()),
+            Reply::NullBulk,
+            Reply::Integer(subs.reply_count(kind)),
+        ]);
+    }
+    let replies = names
+        .into_iter()
+        .map(|name| {
+            if subs.names(kind).remove(&name) {
+                let registry = pubsub.registry(kind);
+                if let Some(subscribers) = registry.get_mut(&name) {
+                    subscribers.remove(&client.id);
+                    if subscribers.is_empty() {
+                        registry.remove(&name);
+                    }
+                }
+            }
+            Reply::Push(vec![
+                Reply::Bulk(kind.unsubscribe_reply().to_string()),
+                Reply::Bulk(name),
+                Reply::Integer(subs.reply_count(kind)),
+            ])
+        })
+        .collect();
+    Reply::Many(replies)
+}
+
+/// Delivers a message, returning the number of clients that received it.
+pub fn publish(pubsub: &PubSub, channel: &str, message: &[u8]) -> Reply<'static> {
+    let mut receivers = 0;
+    if let Some(subscribers) = pubsub.channels.get(channel) {
+        for client in subscribers.values() {
+            client.push(
+                Reply::Push(vec![
+                    Reply::Bulk("message".to_string()),
+                    Reply::Bulk(channel.to_string()),
+                    Reply::BulkBytes(message.to_vec()),
+                ]),
+                pubsub.limit,
+            );
+            receivers += 1;
+        }
+    }
+    for (pattern, subscribers) in &pubsub.patterns {
+        if !glob::matches(pattern.as_bytes(), channel.as_bytes()) {
+            continue;
+        }
+        for client in subscribers.values() {
+            client.push(
+                Reply::Push(vec![
+                    Reply::Bulk("pmessage".to_string()),
+                    Reply::Bulk(pattern.clone()),
+                    Reply::Bulk(channel.to_string()),
+                    Reply::BulkBytes(message.to_vec()),
+                ]),
+                pubsub.limit,
+            );
+            receivers += 1;
+        }
+    }
+    Reply::Integer(receivers)
+}
+
+/// SPUBLISH: delivers a message to the subscribers of a shard channel.
+pub fn spublish(pubsub: &PubSub, channel: &str, message: &[u8]) -> Reply<'static> {
+    let subscribers = pubsub.shard_channels.get(channel);
+    for client in subscribers.into_iter().flat_map(|s| s.values()) {
+        client.push(
+            Reply::Push(vec![
+                Reply::Bulk("smessage".to_string()),
+                Reply::Bulk(channel.to_string()),
+                Reply::BulkBytes(message.to_vec()),
+            ]),
+            pubsub.limit,
+        );
+    }
+    Reply::Integer(subscribers.map_or(0, |s| s.len() as i64))
+}
+
+/// Active channels (or shard channels) matching an optional pattern.
+pub fn channels(pubsub: &PubSub, kind: Kind, pattern: Option<&str>) -> Reply<'static> {
+    let registry = match kind {
+        Kind::Shard => &pubsub.shard_channels,
+        _ => &pubsub.channels,
+    };
+    let channels = registry
+        .keys()
+        .filter(|c| pattern.is_none_or(|p| glob::matches(p.as_bytes(), c.as_bytes())))
+        .cloned()
+        .collect();
+    Reply::Array(channels)
+}
+
+pub fn numsub(pubsub: &PubSub, kind: Kind, channels: &[String]) -> Reply<'static> {
+    let registry = match kind {
+        Kind::Shard => &pubsub.shard_channels,
+        _ => &pubsub.channels,
+    };
+    let replies = channels
+        .iter()
+        .flat_map(|c| {
+            let count = registry.get(c).map_or(0, |s| s.len());
+            [Reply::Bulk(c.clone()), Reply::Integer(count as i64)]
+        })
+        .collect();
+    Reply::Nested(replies)
+}
+
+pub fn numpat(pubsub: &PubSub) -> Reply<'static> {
+    Reply::Integer(pubsub.patterns.len() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_publish_to_channels_and_patterns() {
+        let mut pubsub = PubSub::default();
+        let buffer = Buffer::default();
+        let client = Arc::new(Client::new(1, Box::new(buffer.clone())));
+        let mut subs = Subscriptions::default();
+        let names = ["news.*".to_string()];
+        subscribe(&mut pubsub, &client, &mut subs, Kind::Pattern, &names);
+        let names = ["news.tech".to_string()];
+        subscribe(&mut pubsub, &client, &mut subs, Kind::Channel, &names);
+        assert_eq!(subs.count(), 2);
+
+        assert!(matches!(
+            publish(&pubsub, "news.tech", b"hi"),
+            Reply::Integer(2)
+        ));
+        assert!(matches!(
+            publish(&pubsub, "sport", b"hi"),
+            Reply::Integer(0)
+        ));
+        // written by the subscriber's connection
+        assert!(buffer.0.lock().unwrap().is_empty());
+        client.flush().unwrap();
+        let sent = buffer.0.lock().unwrap().clone();
+        assert_eq!(
+            sent,
+            b"*3\r\n$7\r\nmessage\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n\
+              *4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n"
+                .to_vec()
+        );
+
+        unsubscribe(&mut pubsub, &client, &mut subs, Kind::Channel, &[]);
+        assert!(pubsub.channels.is_empty());
+        pubsub.remove_client(client.id);
+        assert!(pubsub.patterns.is_empty());
+    }
+
+    #[test]
+    fn test_shard_channels_are_a_separate_namespace() {
+        let mut pubsub = PubSub::default();
+        let client = Arc::new(Client::new(1, Box::new(Buffer::default())));
+        let mut subs = Subscriptions::default();
+        let names = ["orders".to_string()];
+        subscribe(&mut pubsub, &client, &mut subs, Kind::Shard, &names);
+        let reply = subscribe(&mut pubsub, &client, &mut subs, Kind::Channel, &names);
+        assert_eq!(
+            reply.into_bytes(),
+            b"*3\r\n$9\r\nsubscribe\r\n$6\r\norders\r\n:1\r\n".to_vec()
+        );
+        assert_eq!(subs.count(), 2);
+
+        assert!(matches!(
+            spublish(&pubsub, "orders", b"x"),
+            Reply::Integer(1)
+        ));
+        unsubscribe(&mut pubsub, &client, &mut subs, Kind::Channel, &[]);
+        assert!(matches!(
+            publish(&pubsub, "orders", b"x"),
+            Reply::Integer(0)
+        ));
+        assert!(matches!(
+            spublish(&pubsub, "orders", b"x"),
+            Reply::Integer(1)
+        ));
+    }
+}
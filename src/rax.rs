@@ -0,0 +1,334 @@
+// Compressed radix tree keyed by byte strings, kept in lexicographic order so
+// that big-endian encoded keys (e.g. stream IDs) can be range-scanned.
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seek {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node<V> {
+    prefix: Vec<u8>,
+    value: Option<V>,
+    // sorted by the first byte of their prefix
+    children: Vec<Node<V>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rax<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+impl<V> Default for Rax<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Node<V> {
+    fn leaf(prefix: Vec<u8>, value: V) -> Self {
+        Node {
+            prefix,
+            value: Some(value),
+            children: Vec::new(),
+        }
+    }
+
+    fn child_index(&self, byte: u8) -> Result<usize, usize> {
+        self.children.binary_search_by_key(&byte, |c| c.prefix[0])
+    }
+
+    fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        if key.is_empty() {
+            return self.value.replace(value);
+        }
+        let idx = match self.child_index(key[0]) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.children.insert(idx, Node::leaf(key.to_vec(), value));
+                return None;
+            }
+        };
+        let child = &mut self.children[idx];
+        let common = child
+            .prefix
+            .iter()
+            .zip(key)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if common < child.prefix.len() {
+            // split: `child` keeps the common part, the rest moves one level down
+            let tail = child.prefix.split_off(common);
+            let lower = Node {
+                prefix: tail,
+                value: child.value.take(),
+                children: std::mem::take(&mut child.children),
+            };
+            child.children.push(lower);
+        }
+        child.insert(&key[common..], value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&V> {
+        if key.is_empty() {
+            return self.value.as_ref();
+        }
+        let child = &self.children[self.child_index(key[0]).ok()?];
+        key.strip_prefix(child.prefix.as_slice())
+            .and_then(|rest| child.get(rest))
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        if key.is_empty() {
+            return self.value.as_mut();
+        }
+        let idx = self.child_index(key[0]).ok()?;
+        let child = &mut self.children[idx];
+        let plen = child.prefix.len();
+        if key.len() < plen || key[..plen] != child.prefix[..] {
+            return None;
+        }
+        child.get_mut(&key[plen..])
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<V> {
+        if key.is_empty() {
+            return self.value.take();
+        }
+        let idx = self.child_index(key[0]).ok()?;
+        let child = &mut self.children[idx];
+        let plen = child.prefix.len();
+        if key.len() < plen || key[..plen] != child.prefix[..] {
+            return None;
+        }
+        let removed = child.remove(&key[plen..])?;
+        if child.value.is_none() {
+            match child.children.len() {
+                0 => {
+                    self.children.remove(idx);
+                }
+                1 => {
+                    let grandchild = child.children.pop().unwrap();
+                    child.prefix.extend(grandchild.prefix);
+                    child.value = grandchild.value;
+                    child.children = grandchild.children;
+                }
+                _ => {}
+            }
+        }
+        Some(removed)
+    }
+
+    fn min<'a>(&'a self, acc: &mut Vec<u8>) -> Option<&'a V> {
+        acc.extend_from_slice(&self.prefix);
+        if self.value.is_some() {
+            return self.value.as_ref();
+        }
+        self.children.first()?.min(acc)
+    }
+
+    fn max<'a>(&'a self, acc: &mut Vec<u8>) -> Option<&'a V> {
+        acc.extend_from_slice(&self.prefix);
+        match self.children.last() {
+            Some(child) => child.max(acc),
+            None => self.value.as_ref(),
+        }
+    }
+
+    /// Smallest key in this subtree that is >= (or > when `strict`) the
+    /// search key; `rem` is what is left of the search key below this node.
+    fn ge<'a>(&'a self, acc: &mut Vec<u8>, rem: &[u8], strict: bool) -> Option<&'a V> {
+        if rem.is_empty() {
+            if !strict && self.value.is_some() {
+                return self.value.as_ref();
+            }
+            return self.children.first()?.min(acc);
+        }
+        for child in &self.children {
+            let n = child.prefix.len().min(rem.len());
+            match child.prefix[..n].cmp(&rem[..n]) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Greater => return child.min(acc),
+                std::cmp::Ordering::Equal if child.prefix.len() > rem.len() => {
+                    return child.min(acc)
+                }
+                std::cmp::Ordering::Equal => {
+                    let len = acc.len();
+                    acc.extend_from_slice(&child.prefix);
+                    if let Some(v) = child.ge(acc, &rem[n..], strict) {
+                        return Some(v);
+                    }
+                    acc.truncate(len);
+                }
+            }
+        }
+        None
+    }
+
+    /// Largest key in this subtree that is <= (or < when `strict`) the
+    /// search key.
+    fn le<'a>(&'a self, acc: &mut Vec<u8>, rem: &[u8], strict: bool) -> Option<&'a V> {
+        if rem.is_empty() {
+            return if strict { None } else { self.value.as_ref() };
+        }
+        for child in self.children.iter().rev() {
+            let n = child.prefix.len().min(rem.len());
+            match child.prefix[..n].cmp(&rem[..n]) {
+                std::cmp::Ordering::Greater => continue,
+                std::cmp::Ordering::Less => return child.max(acc),
+                std::cmp::Ordering::Equal if child.prefix.len() > rem.len() => continue,
+                std::cmp::Ordering::Equal => {
+                    let len = acc.len();
+                    acc.extend_from_slice(&child.prefix);
+                    if let Some(v) = child.le(acc, &rem[n..], strict) {
+                        return Some(v);
+                    }
+                    acc.truncate(len);
+                }
+            }
+        }
+        self.value.as_ref()
+    }
+}
+
+#[allow(unused)]
+impl<V> Rax<V> {
+    pub fn new() -> Self {
+        Rax {
+            root: Node {
+                prefix: Vec::new(),
+                value: None,
+                children: Vec::new(),
+            },
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of tree nodes, including the root.
+    pub fn node_count(&self) -> usize {
+        fn count<V>(node: &Node<V>) -> usize {
+            1 + node.children.iter().map(count).sum::<usize>()
+        }
+        count(&self.root)
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        let old = self.root.insert(key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.root.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        self.root.get_mut(key)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let old = self.root.remove(key);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    pub fn first(&self) -> Option<(Vec<u8>, &V)> {
+        let mut acc = Vec::new();
+        self.root.min(&mut acc).map(|v| (acc, v))
+    }
+
+    pub fn last(&self) -> Option<(Vec<u8>, &V)> {
+        let mut acc = Vec::new();
+        self.root.max(&mut acc).map(|v| (acc, v))
+    }
+
+    pub fn seek(&self, key: &[u8], op: Seek) -> Option<(Vec<u8>, &V)> {
+        let mut acc = Vec::new();
+        let found = match op {
+            Seek::Ge => self.root.ge(&mut acc, key, false),
+            Seek::Gt => self.root.ge(&mut acc, key, true),
+            Seek::Le => self.root.le(&mut acc, key, false),
+            Seek::Lt => self.root.le(&mut acc, key, true),
+        };
+        found.map(|v| (acc, v))
+    }
+
+    /// Iterates over all keys in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, &V)> {
+        self.iter_from(&[], Seek::Ge)
+    }
+
+    /// Iterates in ascending order, starting from the key found by seeking
+    /// `key` with `op` (which should be `Ge` or `Gt`).
+    pub fn iter_from(&self, key: &[u8], op: Seek) -> impl Iterator<Item = (Vec<u8>, &V)> {
+        let mut next = self.seek(key, op);
+        std::iter::from_fn(move || {
+            let (key, value) = next.take()?;
+            next = self.seek(&key, Seek::Gt);
+            Some((key, value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut rax = Rax::new();
+        for key in ["romane", "romanus", "romulus", "rubens", "ruber", "rom"] {
+            assert_eq!(rax.insert(key.as_bytes(), key.len()), None);
+        }
+        assert_eq!(rax.len(), 6);
+        assert_eq!(rax.get(b"rom"), Some(&3));
+        assert_eq!(rax.get(b"roma"), None);
+        assert_eq!(rax.remove(b"romanus"), Some(7));
+        assert_eq!(rax.remove(b"romanus"), None);
+        assert_eq!(rax.get(b"romane"), Some(&6));
+        let keys: Vec<Vec<u8>> = rax.iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"rom".to_vec(),
+                b"romane".to_vec(),
+                b"romulus".to_vec(),
+                b"rubens".to_vec(),
+                b"ruber".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut rax = Rax::new();
+        for i in [10u64, 20, 30] {
+            rax.insert(&i.to_be_bytes(), i);
+        }
+        let seek = |k: u64, op| rax.seek(&k.to_be_bytes(), op).map(|(_, v)| *v);
+        assert_eq!(seek(20, Seek::Ge), Some(20));
+        assert_eq!(seek(20, Seek::Gt), Some(30));
+        assert_eq!(seek(25, Seek::Le), Some(20));
+        assert_eq!(seek(20, Seek::Lt), Some(10));
+        assert_eq!(seek(5, Seek::Lt), None);
+        assert_eq!(seek(31, Seek::Ge), None);
+        assert_eq!(rax.last().map(|(_, v)| *v), Some(30));
+    }
+}
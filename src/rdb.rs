@@ -1,87 +1,1160 @@
-use std::io::Cursor;
-use std::{error::Error, fs::File, io::Read, path::Path};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{Duration, State};
-use nom::bytes::complete::{take, take_while};
+use crate::crc64::crc64;
+use crate::listpack::{string_to_i64, Listpack};
+use crate::lzf;
+use crate::rax::Rax;
+use crate::scripting::Scripts;
+use crate::stream::{self, Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use crate::value::Value;
+use crate::zset::SortedSet;
+use nom::bytes::complete::take;
 use nom::combinator::peek;
-use nom::number::complete::{be_u16, be_u32, be_u8};
+use nom::error::{Error as NomError, ErrorKind};
+use nom::number::complete::{be_u32, be_u64, be_u8, le_f64, le_i16, le_i32, le_i8, le_u32, le_u64};
 use nom::{bytes::complete::tag, combinator::map_res, IResult};
+use thiserror::Error;
 
-pub fn load_from_rdb(path: &Path, state: State, durations: Duration) -> Result<(), Box<dyn Error>> {
-    let mut file = File::open(path)?;
-    let mut buf: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let cursor = Cursor::new(buf);
+/// The newest RDB format version this server reads.
+pub const RDB_VERSION: u32 = 11;
 
-    let (_, (_, _version)) = parse_rdb_header(&cursor.get_ref()[0..9]).unwrap();
-    let (rest, (hash_size, _expiry_size)) = parse_resize_db(&cursor.get_ref()[9..]).unwrap();
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 0xF6;
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
 
-    let mut state = state.lock().unwrap();
-    let mut rest_of_bytes = rest;
-    for _ in 0..hash_size {
-        let (rest, (key, value)) = parse_key_value_pair(rest_of_bytes).unwrap();
-        state.insert(key.to_string(), value.as_str().to_owned());
-        rest_of_bytes = rest;
+// the special string encoding of LZF compressed strings
+const RDB_ENC_LZF: u8 = 3;
+// shorter strings are never compressed
+const LZF_MIN_LEN: usize = 20;
+
+// quicklist 2 node containers
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: usize = 2;
+
+// the opcodes of values saved by modules
+const RDB_MODULE_OPCODE_EOF: usize = 0;
+const RDB_MODULE_OPCODE_SINT: usize = 1;
+const RDB_MODULE_OPCODE_UINT: usize = 2;
+const RDB_MODULE_OPCODE_FLOAT: usize = 3;
+const RDB_MODULE_OPCODE_DOUBLE: usize = 4;
+const RDB_MODULE_OPCODE_STRING: usize = 5;
+
+/// A key stored in an RDB file.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub db: usize,
+    pub key: Vec<u8>,
+    pub value: Value,
+    /// Unix time in milliseconds.
+    pub expire_at: Option<u64>,
+}
+
+/// The contents of an RDB file, in file order.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rdb {
+    pub version: u32,
+    pub aux: Vec<(Vec<u8>, Vec<u8>)>,
+    pub functions: Vec<Vec<u8>>,
+    pub entries: Vec<Entry>,
+}
+
+/// Why an RDB file (or a DUMP payload) could not be loaded. Parse errors
+/// carry the offset they were found at, and the opcode or value type of
+/// the record being parsed.
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error("Can't read the RDB file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Wrong signature trying to load DB from file")]
+    Signature,
+    #[error("Can't handle RDB format version {0}")]
+    Version(u32),
+    #[error("Wrong RDB checksum expected: ({expected:#018x}) got ({computed:#018x})")]
+    Checksum { expected: u64, computed: u64 },
+    #[error("Short read or truncated RDB file at offset {offset}, loading {}", record_name(*.opcode))]
+    Truncated { offset: usize, opcode: u8 },
+    #[error("Bad or unsupported data in the RDB file at offset {offset}, loading {}", record_name(*.opcode))]
+    Corrupt { offset: usize, opcode: u8 },
+    #[error("RDB database index {0} is out of range")]
+    Database(usize),
+    #[error("Failed loading library: {0}")]
+    Function(String),
+}
+
+impl RdbError {
+    /// Converts the error of a record starting with `opcode` in `buf`.
+    fn at(buf: &[u8], opcode: u8, err: nom::Err<NomError<&[u8]>>) -> Self {
+        match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                let offset = buf.len() - e.input.len();
+                if e.code == ErrorKind::Eof {
+                    RdbError::Truncated { offset, opcode }
+                } else {
+                    RdbError::Corrupt { offset, opcode }
+                }
+            }
+            nom::Err::Incomplete(_) => RdbError::Truncated {
+                offset: buf.len(),
+                opcode,
+            },
+        }
+    }
+}
+
+fn record_name(opcode: u8) -> String {
+    let name = match opcode {
+        RDB_TYPE_STRING => "a string",
+        RDB_TYPE_LIST
+        | RDB_TYPE_LIST_ZIPLIST
+        | RDB_TYPE_LIST_QUICKLIST
+        | RDB_TYPE_LIST_QUICKLIST_2 => "a list",
+        RDB_TYPE_SET | RDB_TYPE_SET_INTSET | RDB_TYPE_SET_LISTPACK => "a set",
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 | RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+            "a sorted set"
+        }
+        RDB_TYPE_HASH | RDB_TYPE_HASH_ZIPMAP | RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+            "a hash"
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            "a stream"
+        }
+        RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => "a module value",
+        RDB_OPCODE_FUNCTION_PRE_GA | RDB_OPCODE_FUNCTION2 => "a function library",
+        RDB_OPCODE_MODULE_AUX => "module data",
+        RDB_OPCODE_IDLE => "IDLE",
+        RDB_OPCODE_FREQ => "FREQ",
+        RDB_OPCODE_AUX => "AUX",
+        RDB_OPCODE_RESIZEDB => "RESIZEDB",
+        RDB_OPCODE_EXPIRETIME_MS => "EXPIRETIME_MS",
+        RDB_OPCODE_EXPIRETIME => "EXPIRETIME",
+        RDB_OPCODE_SELECTDB => "SELECTDB",
+        RDB_OPCODE_EOF => "EOF",
+        _ => return format!("unknown type {}", opcode),
+    };
+    format!("{} (opcode {})", name, opcode)
+}
+
+/// Loads an RDB file into the keyspace. A missing file is an empty
+/// dataset; with `sanitize` the internals of compact encodings are checked
+/// in depth, not just enough to decode them.
+pub fn load_from_rdb(
+    path: &Path,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, Instant>],
+    scripts: &Scripts,
+    sanitize: bool,
+) -> Result<(), RdbError> {
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let (rdb, _) = parse_rdb(&buf, sanitize)?;
+    load(rdb, state, durations, scripts)
+}
+
+/// Adds the contents of an RDB file to the keyspace.
+pub fn load(
+    rdb: Rdb,
+    state: &mut [HashMap<Vec<u8>, Value>],
+    durations: &mut [HashMap<Vec<u8>, Instant>],
+    scripts: &Scripts,
+) -> Result<(), RdbError> {
+    for code in &rdb.functions {
+        scripts
+            .function_load(code, false)
+            .map_err(RdbError::Function)?;
+    }
+
+    let now = unix_time_ms();
+    for entry in rdb.entries {
+        if entry.db >= state.len() {
+            return Err(RdbError::Database(entry.db));
+        }
+        match entry.expire_at {
+            // keys that expired while the server was down are not loaded
+            Some(at) if at <= now => continue,
+            Some(at) => {
+                let ttl = std::time::Duration::from_millis(at - now);
+                durations[entry.db].insert(entry.key.clone(), Instant::now() + ttl);
+            }
+            None => {}
+        }
+        state[entry.db].insert(entry.key, entry.value);
     }
 
     Ok(())
 }
 
-enum OwnedOrBorrowed<'a> {
-    Owned(String),
-    Borrowed(&'a str),
+/// A point-in-time copy of the dataset and the function libraries, ready
+/// to be saved. Keys whose TTL has passed are left out. Values share their
+/// contents with the dataset, so the copy is cheap to take.
+pub fn snapshot(
+    state: &[HashMap<Vec<u8>, Value>],
+    durations: &[HashMap<Vec<u8>, Instant>],
+    functions: Vec<Vec<u8>>,
+) -> Rdb {
+    let now = Instant::now();
+    let now_ms = unix_time_ms();
+    let mut entries = Vec::new();
+    for (db, (keys, ttls)) in state.iter().zip(durations).enumerate() {
+        for (key, value) in keys {
+            let expire_at = match ttls.get(key) {
+                Some(at) if *at <= now => continue,
+                Some(at) => Some(now_ms + (*at - now).as_millis() as u64),
+                None => None,
+            };
+            entries.push(Entry {
+                db,
+                key: key.clone(),
+                value: value.clone(),
+                expire_at,
+            });
+        }
+    }
+    let aux = [
+        ("redis-ver", "7.2.0".to_string()),
+        ("redis-bits", (usize::BITS).to_string()),
+        ("ctime", (now_ms / 1000).to_string()),
+        ("used-mem", used_memory().to_string()),
+        ("aof-base", "0".to_string()),
+    ];
+    Rdb {
+        version: RDB_VERSION,
+        aux: aux
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value.into_bytes()))
+            .collect(),
+        functions,
+        entries,
+    }
+}
+
+/// The resident memory of the process, as reported by the OS (0 when it
+/// can't be found out).
+fn used_memory() -> u64 {
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4096)
+}
+
+/// Writes an RDB file through a temporary file in the same directory, so
+/// that a failure halfway never leaves a truncated file behind.
+pub fn save_to_rdb(path: &Path, rdb: &Rdb, compress: bool) -> std::io::Result<()> {
+    save_and_open(path, rdb, compress).map(|_| ())
+}
+
+/// Like `save_to_rdb`, and returns the file open for reading, which stays
+/// this snapshot even if another save replaces it before it is read.
+pub fn save_and_open(path: &Path, rdb: &Rdb, compress: bool) -> std::io::Result<File> {
+    write_and_open(path, &encode_rdb(rdb, compress))
+}
+
+/// Writes an encoded RDB file, e.g. one received from a master, the way
+/// `save_and_open` does.
+pub fn write_and_open(path: &Path, buf: &[u8]) -> std::io::Result<File> {
+    static SAVES: AtomicU64 = AtomicU64::new(0);
+    // saves may run concurrently, e.g. a BGSAVE and a sync with a replica
+    let tmp = path.with_file_name(format!(
+        "temp-{}-{}.rdb",
+        std::process::id(),
+        SAVES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(buf)?;
+            file.sync_all()
+        })
+        .and_then(|_| File::open(&tmp))
+        .and_then(|file| std::fs::rename(&tmp, path).map(|_| file));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Milliseconds since the Unix epoch, the unit of RDB expire times.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
-impl<'a> OwnedOrBorrowed<'a> {
-    fn as_str(&self) -> &str {
-        match self {
-            OwnedOrBorrowed::Owned(s) => s,
-            OwnedOrBorrowed::Borrowed(s) => s,
+/// Parses a whole RDB file, from the header to the EOF opcode, and
+/// verifies the checksum after it (unless it was saved as 0, which means
+/// the file was written without one). Also returns what follows the file,
+/// e.g. the commands after the RDB preamble of an AOF.
+pub fn parse_rdb(buf: &[u8], sanitize: bool) -> Result<(Rdb, &[u8]), RdbError> {
+    let (mut input, version) = parse_rdb_header(buf).map_err(|_| RdbError::Signature)?;
+    if !(1..=RDB_VERSION).contains(&version) {
+        return Err(RdbError::Version(version));
+    }
+    let mut rdb = Rdb {
+        version,
+        ..Rdb::default()
+    };
+    let mut cursor = Cursor {
+        db: 0,
+        expire_at: None,
+        sanitize,
+    };
+    loop {
+        let opcode = *input.first().ok_or(RdbError::Truncated {
+            offset: buf.len(),
+            opcode: RDB_OPCODE_EOF,
+        })?;
+        if opcode == RDB_OPCODE_EOF {
+            // the checksum is only there since version 5
+            let end = buf.len() - input.len() + 1;
+            if version < 5 {
+                return Ok((rdb, &buf[end..]));
+            }
+            let stored = buf.get(end..end + 8).ok_or(RdbError::Truncated {
+                offset: end,
+                opcode,
+            })?;
+            let expected = u64::from_le_bytes(stored.try_into().unwrap());
+            let computed = crc64(0, &buf[..end]);
+            if expected != 0 && expected != computed {
+                return Err(RdbError::Checksum { expected, computed });
+            }
+            return Ok((rdb, &buf[end + 8..]));
         }
+        input = parse_record(input, &mut rdb, &mut cursor)
+            .map_err(|e| RdbError::at(buf, opcode, e))?
+            .0;
     }
 }
 
-fn parse_key_value_pair(input: &[u8]) -> IResult<&[u8], (&str, OwnedOrBorrowed)> {
-    let (rest, value_type) = be_u8(input)?;
+/// What the records parsed so far say about the next ones.
+struct Cursor {
+    db: usize,
+    expire_at: Option<u64>,
+    sanitize: bool,
+}
+
+/// Parses one record other than EOF: an opcode and its arguments, or a key
+/// and its value.
+fn parse_record<'a>(input: &'a [u8], rdb: &mut Rdb, cursor: &mut Cursor) -> IResult<&'a [u8], ()> {
+    let (rest, opcode) = be_u8(input)?;
+    let rest = match opcode {
+        RDB_OPCODE_SELECTDB => {
+            let (rest, index) = parse_length(rest)?;
+            cursor.db = index;
+            rest
+        }
+        RDB_OPCODE_RESIZEDB => {
+            // only sizing hints, the keys are counted as they come
+            let (rest, _) = parse_length(rest)?;
+            parse_length(rest)?.0
+        }
+        RDB_OPCODE_AUX => {
+            let (rest, key) = parse_string(rest)?;
+            let (rest, value) = parse_string(rest)?;
+            rdb.aux.push((key.into_owned(), value.into_owned()));
+            rest
+        }
+        RDB_OPCODE_EXPIRETIME_MS => {
+            let (rest, at) = le_u64(rest)?;
+            cursor.expire_at = Some(at);
+            rest
+        }
+        RDB_OPCODE_EXPIRETIME => {
+            let (rest, at) = le_u32(rest)?;
+            cursor.expire_at = Some(at as u64 * 1000);
+            rest
+        }
+        // eviction hints of the next key
+        RDB_OPCODE_IDLE => parse_length(rest)?.0,
+        RDB_OPCODE_FREQ => be_u8(rest)?.0,
+        RDB_OPCODE_MODULE_AUX => {
+            // no modules are loaded, so their data is skipped
+            let (rest, _module_id) = parse_length(rest)?;
+            let (rest, _when_opcode) = parse_length(rest)?;
+            let (rest, _when) = parse_length(rest)?;
+            skip_module_value(rest)?.0
+        }
+        RDB_OPCODE_FUNCTION2 => {
+            let (rest, code) = parse_function(input)?;
+            rdb.functions.push(code);
+            rest
+        }
+        RDB_OPCODE_FUNCTION_PRE_GA => return Err(corrupt(input)),
+        value_type => {
+            let (rest, key) = parse_string(rest)?;
+            let (rest, value) = parse_value(rest, value_type, cursor.sanitize)?;
+            rdb.entries.push(Entry {
+                db: cursor.db,
+                key: key.into_owned(),
+                value,
+                expire_at: cursor.expire_at.take(),
+            });
+            rest
+        }
+    };
+    Ok((rest, ()))
+}
+
+/// Parses a value of the given type, in any of the encodings versions 1
+/// to 11 use. With `sanitize`, compact encodings must also be internally
+/// consistent and free of duplicate members.
+fn parse_value(input: &[u8], value_type: u8, sanitize: bool) -> IResult<&[u8], Value> {
     match value_type {
-        0x00 => {
-            let (rest, length) = parse_length(rest)?;
-            let (rest, string) = take(length)(rest)?;
-            let (rest, key) = (rest, std::str::from_utf8(string).unwrap_or("NULL"));
-
-            // check if value length byte has special format
-            let (_, next) = peek(be_u8)(rest)?;
-            let next = next >> 6;
-            let (rest, value) = if next == 0b11 {
-                let (rest, next) = be_u8(rest)?;
-                 let (rest, value) = match next & 0b00111111 {
-                    0b00 => {
-                        let (rest, number) = be_u8(rest)?;
-                        (rest, number as usize)
+        RDB_TYPE_STRING => {
+            let (rest, value) = parse_string(input)?;
+            Ok((rest, Value::String(value.into_owned().into())))
+        }
+        RDB_TYPE_LIST => {
+            let (rest, items) = parse_strings(input, 1)?;
+            Ok((rest, Value::List(items.into_iter().collect())))
+        }
+        RDB_TYPE_SET => {
+            let (rest, items) = parse_strings(input, 1)?;
+            Ok((rest, Value::Set(items.into_iter().collect())))
+        }
+        RDB_TYPE_HASH => {
+            let (rest, items) = parse_strings(input, 2)?;
+            Ok((rest, Value::Hash(to_hash(items).into())))
+        }
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+            let (mut rest, len) = parse_length(input)?;
+            let mut zset = SortedSet::new();
+            for _ in 0..len {
+                let (r, member) = parse_string(rest)?;
+                let (r, score) = if value_type == RDB_TYPE_ZSET_2 {
+                    le_f64(r)?
+                } else {
+                    parse_double(r)?
+                };
+                zset.insert(&member, score);
+                rest = r;
+            }
+            Ok((rest, Value::ZSet(zset.into())))
+        }
+        RDB_TYPE_HASH_ZIPMAP => {
+            let (rest, blob) = parse_string(input)?;
+            let items = decode_zipmap(&blob, sanitize).ok_or_else(|| corrupt(input))?;
+            let len = items.len() / 2;
+            let hash = to_hash(items);
+            if sanitize && hash.len() != len {
+                return Err(corrupt(input));
+            }
+            Ok((rest, Value::Hash(hash.into())))
+        }
+        RDB_TYPE_LIST_ZIPLIST
+        | RDB_TYPE_SET_INTSET
+        | RDB_TYPE_ZSET_ZIPLIST
+        | RDB_TYPE_HASH_ZIPLIST
+        | RDB_TYPE_HASH_LISTPACK
+        | RDB_TYPE_ZSET_LISTPACK
+        | RDB_TYPE_SET_LISTPACK => {
+            let (rest, blob) = parse_string(input)?;
+            let items = match value_type {
+                RDB_TYPE_SET_INTSET => decode_intset(&blob, sanitize),
+                RDB_TYPE_HASH_LISTPACK | RDB_TYPE_ZSET_LISTPACK | RDB_TYPE_SET_LISTPACK => {
+                    decode_listpack(&blob)
+                }
+                _ => decode_ziplist(&blob, sanitize),
+            }
+            .ok_or_else(|| corrupt(input))?;
+            let len = items.len();
+            let value = match value_type {
+                RDB_TYPE_LIST_ZIPLIST => Value::List(items.into_iter().collect()),
+                RDB_TYPE_SET_INTSET | RDB_TYPE_SET_LISTPACK => {
+                    Value::Set(items.into_iter().collect())
+                }
+                RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK if items.len() % 2 == 0 => {
+                    Value::Hash(to_hash(items).into())
+                }
+                RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK if items.len() % 2 == 0 => {
+                    let mut zset = SortedSet::new();
+                    for pair in items.chunks(2) {
+                        let score = std::str::from_utf8(&pair[1])
+                            .ok()
+                            .and_then(|s| s.parse().ok())
+                            .ok_or_else(|| corrupt(input))?;
+                        zset.insert(&pair[0], score);
                     }
-                    0b01 => {
-                        let (rest, number) = be_u16(rest)?;
-                        (rest, number as usize)
+                    Value::ZSet(zset.into())
+                }
+                _ => return Err(corrupt(input)),
+            };
+            let members = match &value {
+                Value::Set(set) => set.len(),
+                Value::Hash(hash) => hash.len() * 2,
+                Value::ZSet(zset) => zset.len() * 2,
+                _ => len,
+            };
+            if sanitize && members != len {
+                return Err(corrupt(input));
+            }
+            Ok((rest, value))
+        }
+        RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+            let (mut rest, nodes) = parse_length(input)?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                let container = if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                    let (r, container) = parse_length(rest)?;
+                    rest = r;
+                    container
+                } else {
+                    QUICKLIST_NODE_CONTAINER_PACKED
+                };
+                let (r, node) = parse_string(rest)?;
+                match container {
+                    QUICKLIST_NODE_CONTAINER_PLAIN => list.push_back(node.into_owned()),
+                    QUICKLIST_NODE_CONTAINER_PACKED => {
+                        let items = if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                            decode_listpack(&node)
+                        } else {
+                            decode_ziplist(&node, sanitize)
+                        }
+                        .ok_or_else(|| corrupt(rest))?;
+                        if sanitize && items.is_empty() {
+                            return Err(corrupt(rest));
+                        }
+                        list.extend(items);
                     }
-                    0b10 => {
-                        let (rest, number) = be_u32(rest)?;
-                        (rest, number as usize)
+                    _ => return Err(corrupt(rest)),
+                }
+                rest = r;
+            }
+            Ok((rest, Value::List(list.into())))
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            let (rest, stream) = parse_stream(input, value_type, sanitize)?;
+            Ok((rest, Value::Stream(stream.into())))
+        }
+        // module values can only be loaded by the module that saved them
+        RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => Err(corrupt(input)),
+        _ => Err(corrupt(input)),
+    }
+}
+
+/// Parses a length followed by that many groups of `per_item` strings.
+fn parse_strings(input: &[u8], per_item: usize) -> IResult<&[u8], Vec<Vec<u8>>> {
+    let (mut rest, len) = parse_length(input)?;
+    let len = len.checked_mul(per_item).ok_or_else(|| corrupt(input))?;
+    let mut items = Vec::new();
+    for _ in 0..len {
+        let (r, item) = parse_string(rest)?;
+        items.push(item.into_owned());
+        rest = r;
+    }
+    Ok((rest, items))
+}
+
+fn to_hash(items: Vec<Vec<u8>>) -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut items = items.into_iter();
+    let mut hash = HashMap::new();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        hash.insert(field, value);
+    }
+    hash
+}
+
+/// Parses a score of the original sorted set type: a string, with three
+/// special lengths for NaN and the infinities.
+fn parse_double(input: &[u8]) -> IResult<&[u8], f64> {
+    let (rest, len) = be_u8(input)?;
+    match len {
+        253 => Ok((rest, f64::NAN)),
+        254 => Ok((rest, f64::INFINITY)),
+        255 => Ok((rest, f64::NEG_INFINITY)),
+        _ => {
+            let (rest, digits) = take(len)(rest)?;
+            let score = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| corrupt(input))?;
+            Ok((rest, score))
+        }
+    }
+}
+
+/// Skips a value saved by a module, up to its EOF opcode.
+fn skip_module_value(mut input: &[u8]) -> IResult<&[u8], ()> {
+    loop {
+        let (rest, opcode) = parse_length(input)?;
+        input = match opcode {
+            RDB_MODULE_OPCODE_EOF => return Ok((rest, ())),
+            RDB_MODULE_OPCODE_SINT | RDB_MODULE_OPCODE_UINT => parse_length(rest)?.0,
+            RDB_MODULE_OPCODE_FLOAT => take(4usize)(rest)?.0,
+            RDB_MODULE_OPCODE_DOUBLE => take(8usize)(rest)?.0,
+            RDB_MODULE_OPCODE_STRING => parse_string(rest)?.0,
+            _ => return Err(corrupt(input)),
+        };
+    }
+}
+
+/// The elements of a ziplist, the encoding of small lists, hashes and
+/// sorted sets before version 10:
+///
+/// <zlbytes:u32le> <zltail:u32le> <zllen:u16le> <entry> ... <entry> <0xFF>
+///
+/// where every entry is <prevlen> <encoding> <data>. With `sanitize`, the
+/// header and the prevlen of every entry must match the entries.
+fn decode_ziplist(buf: &[u8], sanitize: bool) -> Option<Vec<Vec<u8>>> {
+    let mut items = Vec::new();
+    let mut pos = 10;
+    let mut last = None;
+    loop {
+        let start = pos;
+        let prevlen = match *buf.get(pos)? {
+            0xFF => break,
+            0xFE => {
+                pos += 5;
+                u32::from_le_bytes(buf.get(start + 1..pos)?.try_into().ok()?) as usize
+            }
+            len => {
+                pos += 1;
+                len as usize
+            }
+        };
+        if sanitize && prevlen != last.map_or(0, |last| start - last) {
+            return None;
+        }
+        last = Some(start);
+        let encoding = *buf.get(pos)?;
+        let (header, len) = match encoding >> 6 {
+            0b00 => (1, (encoding & 0x3F) as usize),
+            0b01 => (
+                2,
+                ((encoding as usize & 0x3F) << 8) | *buf.get(pos + 1)? as usize,
+            ),
+            0b10 => {
+                let len = buf.get(pos + 1..pos + 5)?;
+                (5, u32::from_be_bytes(len.try_into().ok()?) as usize)
+            }
+            _ => {
+                let (size, value) = match encoding {
+                    0xC0 => (
+                        2,
+                        i16::from_le_bytes(buf.get(pos + 1..pos + 3)?.try_into().ok()?) as i64,
+                    ),
+                    0xD0 => (
+                        4,
+                        i32::from_le_bytes(buf.get(pos + 1..pos + 5)?.try_into().ok()?) as i64,
+                    ),
+                    0xE0 => (
+                        8,
+                        i64::from_le_bytes(buf.get(pos + 1..pos + 9)?.try_into().ok()?),
+                    ),
+                    0xF0 => {
+                        let b = buf.get(pos + 1..pos + 4)?;
+                        (3, (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64)
                     }
-                    _ => panic!("Unexpected integer type"),
+                    0xFE => (1, *buf.get(pos + 1)? as i8 as i64),
+                    0xF1..=0xFD => (0, (encoding & 0x0F) as i64 - 1),
+                    _ => return None,
                 };
-                // FIX: parsed integer is converted to &str
-                (rest, OwnedOrBorrowed::Owned(value.to_string()))
+                items.push(value.to_string().into_bytes());
+                pos += 1 + size;
+                continue;
+            }
+        };
+        items.push(buf.get(pos + header..pos + header + len)?.to_vec());
+        pos += header + len;
+    }
+    if sanitize {
+        let header = |range: std::ops::Range<usize>| {
+            buf.get(range)
+                .map(|b| b.iter().rev().fold(0, |n, &b| n << 8 | b as usize))
+        };
+        let zllen = header(8..10)?;
+        if header(0..4)? != buf.len()
+            || pos != buf.len() - 1
+            || header(4..8)? != last.unwrap_or(10)
+            || (zllen != u16::MAX as usize && zllen != items.len())
+        {
+            return None;
+        }
+    }
+    Some(items)
+}
+
+/// The elements of a listpack.
+fn decode_listpack(buf: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let lp = Listpack::from_bytes(buf.to_vec())?;
+    Some(lp.iter().map(|elem| elem.to_vec()).collect())
+}
+
+/// The members of an intset: <encoding:u32le> <length:u32le> <integers>,
+/// each integer taking `encoding` bytes. With `sanitize`, the integers
+/// must fill the buffer and be sorted without duplicates.
+fn decode_intset(buf: &[u8], sanitize: bool) -> Option<Vec<Vec<u8>>> {
+    let width = u32::from_le_bytes(buf.get(0..4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(buf.get(4..8)?.try_into().ok()?) as usize;
+    if ![2, 4, 8].contains(&width) {
+        return None;
+    }
+    let ints = buf.get(8..8 + len.checked_mul(width)?)?;
+    if sanitize && 8 + ints.len() != buf.len() {
+        return None;
+    }
+    let ints: Vec<i64> = ints
+        .chunks(width)
+        .map(|int| match width {
+            2 => i16::from_le_bytes([int[0], int[1]]) as i64,
+            4 => i32::from_le_bytes(int.try_into().unwrap()) as i64,
+            _ => i64::from_le_bytes(int.try_into().unwrap()),
+        })
+        .collect();
+    if sanitize && ints.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return None;
+    }
+    Some(ints.iter().map(|i| i.to_string().into_bytes()).collect())
+}
+
+/// The fields and values of a zipmap, the encoding of small hashes before
+/// version 4: <zmlen> <len> <field> <len> <free> <value> ... <0xFF>. With
+/// `sanitize`, the end marker must be the last byte and zmlen (when under
+/// 254) the number of pairs.
+fn decode_zipmap(buf: &[u8], sanitize: bool) -> Option<Vec<Vec<u8>>> {
+    let mut items = Vec::new();
+    let mut pos = 1;
+    let read_len = |pos: &mut usize| -> Option<usize> {
+        let len = *buf.get(*pos)? as usize;
+        if len < 254 {
+            *pos += 1;
+            Some(len)
+        } else {
+            let len = u32::from_le_bytes(buf.get(*pos + 1..*pos + 5)?.try_into().ok()?);
+            *pos += 5;
+            Some(len as usize)
+        }
+    };
+    while *buf.get(pos)? != 0xFF {
+        let len = read_len(&mut pos)?;
+        items.push(buf.get(pos..pos + len)?.to_vec());
+        pos += len;
+        let len = read_len(&mut pos)?;
+        let free = *buf.get(pos)? as usize;
+        pos += 1;
+        items.push(buf.get(pos..pos + len)?.to_vec());
+        pos += len + free;
+    }
+    let zmlen = buf[0] as usize;
+    if sanitize && (pos != buf.len() - 1 || (zmlen < 254 && zmlen * 2 != items.len())) {
+        return None;
+    }
+    (items.len() % 2 == 0).then_some(items)
+}
+
+fn corrupt(input: &[u8]) -> nom::Err<NomError<&[u8]>> {
+    nom::Err::Failure(NomError::new(input, ErrorKind::Verify))
+}
+
+/// Parses a string object, which may be stored as an integer or LZF
+/// compressed.
+fn parse_string(input: &[u8]) -> IResult<&[u8], Cow<'_, [u8]>> {
+    let (_, first_byte) = peek(be_u8)(input)?;
+    if first_byte >> 6 != 0b11 {
+        let (rest, length) = parse_length(input)?;
+        let (rest, string) = take(length)(rest)?;
+        return Ok((rest, Cow::Borrowed(string)));
+    }
+    let (rest, _) = be_u8(input)?;
+    let (rest, number) = match first_byte & 0b00111111 {
+        0 => le_i8(rest).map(|(rest, n)| (rest, n as i64))?,
+        1 => le_i16(rest).map(|(rest, n)| (rest, n as i64))?,
+        2 => le_i32(rest).map(|(rest, n)| (rest, n as i64))?,
+        RDB_ENC_LZF => {
+            let (rest, compressed_len) = parse_length(rest)?;
+            let (rest, len) = parse_length(rest)?;
+            let (rest, compressed) = take(compressed_len)(rest)?;
+            let string = lzf::decompress(compressed, len).ok_or_else(|| corrupt(input))?;
+            return Ok((rest, Cow::Owned(string)));
+        }
+        _ => return Err(corrupt(input)),
+    };
+    Ok((rest, Cow::Owned(number.to_string().into_bytes())))
+}
+
+fn parse_stream_id(input: &[u8]) -> IResult<&[u8], StreamId> {
+    let (rest, ms) = parse_length(input)?;
+    let (rest, seq) = parse_length(rest)?;
+    Ok((rest, StreamId::new(ms as u64, seq as u64)))
+}
+
+fn parse_stream(input: &[u8], rdb_type: u8, sanitize: bool) -> IResult<&[u8], Stream> {
+    let mut stream = Stream::new();
+    let (mut rest, nodes) = parse_length(input)?;
+    for _ in 0..nodes {
+        let (r, key) = parse_string(rest)?;
+        let (r, lp) = parse_string(r)?;
+        let lp = Listpack::from_bytes(lp.into_owned()).ok_or_else(|| corrupt(rest))?;
+        if key.len() != 16 || lp.is_empty() || (sanitize && !stream::validate_node(&lp)) {
+            return Err(corrupt(rest));
+        }
+        stream.rax.insert(&key, lp);
+        rest = r;
+    }
+    let (r, length) = parse_length(rest)?;
+    let (r, last_id) = parse_stream_id(r)?;
+    stream.length = length as u64;
+    stream.last_id = last_id;
+    rest = r;
+    if rdb_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+        let (r, first_id) = parse_stream_id(rest)?;
+        let (r, max_deleted_entry_id) = parse_stream_id(r)?;
+        let (r, entries_added) = parse_length(r)?;
+        stream.first_id = first_id;
+        stream.max_deleted_entry_id = max_deleted_entry_id;
+        stream.entries_added = entries_added as u64;
+        rest = r;
+    } else {
+        stream.update_first_id();
+        stream.entries_added = stream.length;
+    }
+
+    let (r, groups) = parse_length(rest)?;
+    rest = r;
+    for _ in 0..groups {
+        let (r, name) = parse_string(rest)?;
+        let (r, last_id) = parse_stream_id(r)?;
+        rest = r;
+        let entries_read = if rdb_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            let (r, entries_read) = parse_length(rest)?;
+            rest = r;
+            // -1 (unknown) is saved as an unsigned length
+            Some(entries_read as u64).filter(|n| *n != u64::MAX)
+        } else {
+            stream.estimate_distance_from_first_ever_entry(last_id)
+        };
+        let mut group = ConsumerGroup {
+            last_id,
+            entries_read,
+            pel: Rax::new(),
+            consumers: Rax::new(),
+        };
+
+        let (r, pel_size) = parse_length(rest)?;
+        rest = r;
+        for _ in 0..pel_size {
+            let (r, id) = take(16usize)(rest)?;
+            let (r, delivery_time) = le_u64(r)?;
+            let (r, delivery_count) = parse_length(r)?;
+            let pending = PendingEntry {
+                consumer: String::new(),
+                delivery_time,
+                delivery_count: delivery_count as u64,
+            };
+            group.pel.insert(id, pending);
+            rest = r;
+        }
+
+        let (r, consumers) = parse_length(rest)?;
+        rest = r;
+        for _ in 0..consumers {
+            let (r, consumer_name) = parse_string(rest)?;
+            let (r, seen_time) = le_u64(r)?;
+            rest = r;
+            let active_time = if rdb_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                let (r, active_time) = le_u64(rest)?;
+                rest = r;
+                Some(active_time).filter(|t| *t != u64::MAX)
             } else {
-                let (rest, length) = parse_length(rest)?;
-                let (rest, string) = take(length)(rest)?;
-                (rest, OwnedOrBorrowed::Borrowed(std::str::from_utf8(string).unwrap_or("NULL")))
+                Some(seen_time)
             };
-            Ok((rest, (key, value)))
+            let mut consumer = Consumer {
+                seen_time,
+                active_time,
+                pel: Rax::new(),
+            };
+            let consumer_name = String::from_utf8_lossy(&consumer_name).into_owned();
+            let (r, pel_size) = parse_length(rest)?;
+            rest = r;
+            for _ in 0..pel_size {
+                let (r, id) = take(16usize)(rest)?;
+                // every entry of a consumer PEL must be in the group PEL
+                let pending = group.pel.get_mut(id).ok_or_else(|| corrupt(rest))?;
+                pending.consumer = consumer_name.clone();
+                consumer.pel.insert(id, ());
+                rest = r;
+            }
+            group.consumers.insert(consumer_name.as_bytes(), consumer);
+        }
+        stream.cgroups.insert(&name, group);
+    }
+    Ok((rest, stream))
+}
+
+fn encode_length(len: u64, out: &mut Vec<u8>) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&[((len >> 8) as u8) | 0x40, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Serializes a string: as an integer if it is the canonical form of one
+/// that fits 32 bits, else LZF compressed if `compress` and that saves at
+/// least 4 bytes.
+fn encode_string(s: &[u8], compress: bool, out: &mut Vec<u8>) {
+    if let Some(int) = Some(s).filter(|s| s.len() <= 11).and_then(string_to_i64) {
+        if let Ok(int) = i8::try_from(int) {
+            out.extend_from_slice(&[0xC0, int as u8]);
+            return;
+        } else if let Ok(int) = i16::try_from(int) {
+            out.push(0xC1);
+            out.extend_from_slice(&int.to_le_bytes());
+            return;
+        } else if let Ok(int) = i32::try_from(int) {
+            out.push(0xC2);
+            out.extend_from_slice(&int.to_le_bytes());
+            return;
+        }
+    }
+    if compress && s.len() > LZF_MIN_LEN {
+        let compressed = lzf::compress(s);
+        if compressed.len() + 4 <= s.len() {
+            out.push(0xC0 | RDB_ENC_LZF);
+            encode_length(compressed.len() as u64, out);
+            encode_length(s.len() as u64, out);
+            out.extend_from_slice(&compressed);
+            return;
+        }
+    }
+    encode_length(s.len() as u64, out);
+    out.extend_from_slice(s);
+}
+
+fn encode_stream_id(id: StreamId, out: &mut Vec<u8>) {
+    encode_length(id.ms, out);
+    encode_length(id.seq, out);
+}
+
+/// Serializes a whole RDB file: the aux fields and function libraries,
+/// then the keys of each database, then the EOF opcode and the checksum.
+pub fn encode_rdb(rdb: &Rdb, compress: bool) -> Vec<u8> {
+    let mut out = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+    for (key, value) in &rdb.aux {
+        out.push(RDB_OPCODE_AUX);
+        encode_string(key, compress, &mut out);
+        encode_string(value, compress, &mut out);
+    }
+    for code in &rdb.functions {
+        encode_function(code, compress, &mut out);
+    }
+    let mut db = None;
+    for (i, entry) in rdb.entries.iter().enumerate() {
+        if db != Some(entry.db) {
+            db = Some(entry.db);
+            let (keys, expires) = rdb.entries[i..]
+                .iter()
+                .take_while(|e| e.db == entry.db)
+                .fold((0, 0), |(keys, expires), e| {
+                    (keys + 1, expires + e.expire_at.is_some() as u64)
+                });
+            out.push(RDB_OPCODE_SELECTDB);
+            encode_length(entry.db as u64, &mut out);
+            out.push(RDB_OPCODE_RESIZEDB);
+            encode_length(keys, &mut out);
+            encode_length(expires, &mut out);
+        }
+        if let Some(at) = entry.expire_at {
+            out.push(RDB_OPCODE_EXPIRETIME_MS);
+            out.extend_from_slice(&at.to_le_bytes());
+        }
+        encode_value(&entry.key, &entry.value, compress, &mut out);
+    }
+    out.push(RDB_OPCODE_EOF);
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Serializes a key and its value, type byte first, in the plain
+/// encodings of each type.
+fn encode_value(key: &[u8], value: &Value, compress: bool, out: &mut Vec<u8>) {
+    out.push(value_type(value));
+    encode_string(key, compress, out);
+    encode_object(value, compress, out);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => RDB_TYPE_STRING,
+        Value::List(_) => RDB_TYPE_LIST,
+        Value::Set(_) => RDB_TYPE_SET,
+        Value::Hash(_) => RDB_TYPE_HASH,
+        Value::ZSet(_) => RDB_TYPE_ZSET_2,
+        Value::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+/// Serializes a value, without its type byte.
+fn encode_object(value: &Value, compress: bool, out: &mut Vec<u8>) {
+    match value {
+        Value::String(s) => encode_string(s, compress, out),
+        Value::List(items) => {
+            encode_length(items.len() as u64, out);
+            for item in items.iter() {
+                encode_string(item, compress, out);
+            }
+        }
+        Value::Set(members) => {
+            encode_length(members.len() as u64, out);
+            for member in members.iter() {
+                encode_string(member, compress, out);
+            }
+        }
+        Value::Hash(hash) => {
+            encode_length(hash.len() as u64, out);
+            for (field, value) in hash.iter() {
+                encode_string(field, compress, out);
+                encode_string(value, compress, out);
+            }
+        }
+        Value::ZSet(zset) => {
+            encode_length(zset.len() as u64, out);
+            for (member, score) in zset.iter() {
+                encode_string(member, compress, out);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
         }
-        _ => todo!("not implemented: {:#02x}", &value_type),
+        Value::Stream(stream) => encode_stream(stream, compress, out),
     }
 }
 
+/// Serializes a stream (including its consumer groups) in the
+/// RDB_TYPE_STREAM_LISTPACKS_3 format, without the type byte.
+pub fn encode_stream(stream: &Stream, compress: bool, out: &mut Vec<u8>) {
+    encode_length(stream.rax.len() as u64, out);
+    for (key, lp) in stream.rax.iter() {
+        encode_string(&key, compress, out);
+        encode_string(lp.as_bytes(), compress, out);
+    }
+    encode_length(stream.length, out);
+    encode_stream_id(stream.last_id, out);
+    encode_stream_id(stream.first_id, out);
+    encode_stream_id(stream.max_deleted_entry_id, out);
+    encode_length(stream.entries_added, out);
+
+    encode_length(stream.cgroups.len() as u64, out);
+    for (name, group) in stream.cgroups.iter() {
+        encode_string(&name, compress, out);
+        encode_stream_id(group.last_id, out);
+        encode_length(group.entries_read.unwrap_or(u64::MAX), out);
+        encode_length(group.pel.len() as u64, out);
+        for (id, pending) in group.pel.iter() {
+            out.extend_from_slice(&id);
+            out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            encode_length(pending.delivery_count, out);
+        }
+        encode_length(group.consumers.len() as u64, out);
+        for (name, consumer) in group.consumers.iter() {
+            encode_string(&name, compress, out);
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.active_time.unwrap_or(u64::MAX).to_le_bytes());
+            encode_length(consumer.pel.len() as u64, out);
+            for (id, _) in consumer.pel.iter() {
+                out.extend_from_slice(&id);
+            }
+        }
+    }
+}
+
+/// DUMP: a value as its type byte and serialization, followed by the RDB
+/// version and a checksum of the whole.
+pub fn dump_payload(value: &Value, compress: bool) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    encode_object(value, compress, &mut out);
+    add_dump_footer(&mut out);
+    out
+}
+
+/// The value in a DUMP payload, for RESTORE.
+pub fn parse_dump_payload(payload: &[u8], sanitize: bool) -> Result<Value, &'static str> {
+    const BAD_FORMAT: &str = "Bad data format";
+    let body = check_dump_footer(payload)?;
+    let (rest, value_type) = be_u8::<_, NomError<&[u8]>>(body).map_err(|_| BAD_FORMAT)?;
+    match parse_value(rest, value_type, sanitize) {
+        Ok(([], value)) => Ok(value),
+        _ => Err(BAD_FORMAT),
+    }
+}
+
+/// Appends the footer of DUMP payloads: the RDB version (2 bytes) and the
+/// CRC64 of everything before the checksum (8 bytes), little-endian.
+pub fn add_dump_footer(out: &mut Vec<u8>) {
+    out.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+    let crc = crc64(0, out);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+/// Checks the footer of a DUMP payload, returning what precedes it.
+pub fn check_dump_footer(payload: &[u8]) -> Result<&[u8], &'static str> {
+    const WRONG: &str = "DUMP payload version or checksum are wrong";
+    if payload.len() < 10 {
+        return Err(WRONG);
+    }
+    let (data, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    if version as u32 > RDB_VERSION || crc64(0, data).to_le_bytes() != crc {
+        return Err(WRONG);
+    }
+    Ok(&data[..data.len() - 2])
+}
+
+/// Serializes a function library record, opcode included.
+pub fn encode_function(code: &[u8], compress: bool, out: &mut Vec<u8>) {
+    out.push(RDB_OPCODE_FUNCTION2);
+    encode_string(code, compress, out);
+}
+
+/// Parses a function library record, opcode included.
+pub fn parse_function(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let (rest, _) = tag([RDB_OPCODE_FUNCTION2])(input)?;
+    let (rest, code) = parse_string(rest)?;
+    Ok((rest, code.into_owned()))
+}
+
 fn parse_length(input: &[u8]) -> IResult<&[u8], usize> {
     let (rest, first_byte) = be_u8(input)?;
     let (rest, length) = match first_byte >> 6 {
@@ -93,25 +1166,576 @@ fn parse_length(input: &[u8]) -> IResult<&[u8], usize> {
                 (((first_byte & 0b00111111) as usize) << 8) | second_byte as usize,
             )
         }
-        _ => {
-            todo!("Not implemented {:#08b}", first_byte >> 6);
+        0b10 if first_byte == 0x80 => {
+            let (rest, length) = be_u32(rest)?;
+            (rest, length as usize)
         }
+        0b10 if first_byte == 0x81 => {
+            let (rest, length) = be_u64(rest)?;
+            (rest, length as usize)
+        }
+        // 0b11 prefixes special string encodings, not lengths
+        _ => return Err(corrupt(input)),
     };
     Ok((rest, length))
 }
 
-fn parse_rdb_header(input: &[u8]) -> IResult<&[u8], (&str, u32)> {
+fn parse_rdb_header(input: &[u8]) -> IResult<&[u8], u32> {
     let (rest, _) = tag(b"REDIS")(input)?;
-    let (rest, version_str) = map_res(take(4usize), std::str::from_utf8)(rest)?;
-    let version = u32::from_str_radix(version_str, 10).unwrap();
-
-    Ok((rest, ("REDIS", version)))
+    map_res(map_res(take(4usize), std::str::from_utf8), str::parse)(rest)
 }
 
-fn parse_resize_db(input: &[u8]) -> IResult<&[u8], (usize, usize)> {
-    let (input, _) = take_while(|b| b != 0xFB)(input)?;
-    let (input, _) = be_u8(input)?; // Consume the FB byte
-    let (input, hash_size) = parse_length(input)?;
-    let (input, expire_size) = parse_length(input)?;
-    Ok((input, (hash_size, expire_size)))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::XAddId;
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let mut stream = Stream::new();
+        for i in 1..=150u64 {
+            let fields = vec![(b"sensor".to_vec(), i.to_string().into_bytes())];
+            stream
+                .append(XAddId::Explicit(StreamId::new(i, 0)), &fields)
+                .unwrap();
+        }
+        stream.delete(StreamId::new(3, 0));
+        stream.create_group("workers", StreamId::new(2, 0), Some(2));
+        let group = stream.cgroups.get_mut(b"workers").unwrap();
+        group.touch_consumer("alice", 1000);
+        group.assign(StreamId::new(1, 0), "alice", 1000, 2);
+        group.touch_consumer("bob", 1000);
+
+        let mut buf = Vec::new();
+        encode_stream(&stream, true, &mut buf);
+        let (rest, loaded) = parse_stream(&buf, RDB_TYPE_STREAM_LISTPACKS_3, true).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(loaded, stream);
+    }
+
+    #[test]
+    fn test_parse_string_integer_encodings() {
+        assert_eq!(parse_string(&[0xC0, 0xFE]).unwrap().1.as_ref(), b"-2");
+        assert_eq!(
+            parse_string(&[0xC1, 0x39, 0x30]).unwrap().1.as_ref(),
+            b"12345"
+        );
+        assert_eq!(
+            parse_string(&[0x03, b'a', b'b', b'c']).unwrap().1.as_ref(),
+            b"abc"
+        );
+    }
+
+    #[test]
+    fn test_lzf_strings() {
+        let long = b"abcabcabcabcabcabcabcabcabcabcabcabc".repeat(3);
+        let mut buf = Vec::new();
+        encode_string(&long, true, &mut buf);
+        assert_eq!(buf[0], 0xC3);
+        assert!(buf.len() < long.len());
+        let (rest, parsed) = parse_string(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.as_ref(), long);
+
+        let mut buf = Vec::new();
+        encode_string(&long, false, &mut buf);
+        assert_eq!(buf.len(), long.len() + 2);
+        // too short, or not compressible enough
+        let mut buf = Vec::new();
+        encode_string(b"aaaaaaaaaaaaaaaaaaaa", true, &mut buf);
+        assert_eq!(buf[0], 20);
+        // a declared length that does not match the decompressed string
+        assert!(parse_string(&[0xC3, 0x02, 0x03, 0x01, b'a', b'b']).is_err());
+    }
+
+    #[test]
+    fn test_parse_rdb() {
+        let mut buf = b"REDIS0011".to_vec();
+        buf.push(RDB_OPCODE_AUX);
+        encode_string(b"redis-ver", false, &mut buf);
+        encode_string(b"7.2.0", false, &mut buf);
+        encode_function(b"#!lua name=a", false, &mut buf);
+        buf.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB, 1, 1]);
+        buf.push(RDB_OPCODE_EXPIRETIME_MS);
+        buf.extend_from_slice(&1234u64.to_le_bytes());
+        buf.push(RDB_TYPE_STRING);
+        encode_string(b"s", false, &mut buf);
+        buf.extend_from_slice(&[0xC1, 0x39, 0x30]);
+        buf.extend_from_slice(&[RDB_OPCODE_SELECTDB, 2, RDB_TYPE_LIST]);
+        encode_string(b"l", false, &mut buf);
+        buf.push(2);
+        encode_string(b"x", false, &mut buf);
+        encode_string(b"y", false, &mut buf);
+        buf.push(RDB_TYPE_ZSET_2);
+        encode_string(b"z", false, &mut buf);
+        buf.push(1);
+        encode_string(b"m", false, &mut buf);
+        buf.extend_from_slice(&1.5f64.to_le_bytes());
+        buf.push(RDB_OPCODE_EOF);
+        buf.extend_from_slice(&[0; 8]);
+
+        let (rdb, rest) = parse_rdb(&buf, true).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(rdb.version, 11);
+        assert_eq!(rdb.aux, vec![(b"redis-ver".to_vec(), b"7.2.0".to_vec())]);
+        assert_eq!(rdb.functions, vec![b"#!lua name=a".to_vec()]);
+        assert_eq!(
+            rdb.entries[0],
+            Entry {
+                db: 0,
+                key: b"s".to_vec(),
+                value: Value::String(b"12345".to_vec().into()),
+                expire_at: Some(1234),
+            }
+        );
+        assert_eq!(rdb.entries[1].db, 2);
+        assert_eq!(
+            rdb.entries[1].value,
+            Value::List([b"x".to_vec(), b"y".to_vec()].into_iter().collect())
+        );
+        let Value::ZSet(zset) = &rdb.entries[2].value else {
+            panic!("not a sorted set")
+        };
+        assert_eq!(zset.score(b"m"), Some(1.5));
+        assert_eq!(rdb.entries[2].expire_at, None);
+
+        assert!(matches!(
+            parse_rdb(b"REDIS0012\xff", false),
+            Err(RdbError::Version(12))
+        ));
+        assert!(matches!(parse_rdb(b"RDB", false), Err(RdbError::Signature)));
+        // cut in the middle of the score
+        let err = parse_rdb(&buf[..buf.len() - 12], false).unwrap_err();
+        assert!(matches!(
+            err,
+            RdbError::Truncated { offset, opcode: RDB_TYPE_ZSET_2 } if offset == buf.len() - 17
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Short read or truncated RDB file at offset {}, loading a sorted set (opcode 5)",
+                buf.len() - 17
+            )
+        );
+    }
+
+    #[test]
+    fn test_rdb_roundtrip() {
+        let mut zset = SortedSet::new();
+        zset.insert(b"m", -2.5);
+        let mut stream = Stream::new();
+        let fields = vec![(b"f".to_vec(), b"v".to_vec())];
+        stream.append(XAddId::Auto, &fields).unwrap();
+        let values = [
+            Value::String(b"12345".to_vec().into()),
+            Value::String((vec![b'x'; 100]).into()),
+            Value::List([b"a".to_vec(), b"-7".to_vec()].into_iter().collect()),
+            Value::Set([b"a".to_vec()].into_iter().collect()),
+            Value::Hash([(b"f".to_vec(), b"v".to_vec())].into_iter().collect()),
+            Value::ZSet(zset.into()),
+            Value::Stream(stream.into()),
+        ];
+        let entries = values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| Entry {
+                db: i / 4,
+                key: i.to_string().into_bytes(),
+                value,
+                expire_at: (i == 0).then_some(u64::MAX / 2),
+            })
+            .collect();
+        let rdb = Rdb {
+            version: RDB_VERSION,
+            aux: vec![(b"redis-ver".to_vec(), b"7.2.0".to_vec())],
+            functions: vec![b"#!lua name=a\n".to_vec()],
+            entries,
+        };
+        for compress in [false, true] {
+            let buf = encode_rdb(&rdb, compress);
+            assert_eq!(parse_rdb(&buf, true).unwrap().0, rdb);
+        }
+
+        let state = vec![HashMap::from([(
+            b"k".to_vec(),
+            Value::String(b"v".to_vec().into()),
+        )])];
+        let durations = vec![HashMap::from([(
+            b"k".to_vec(),
+            Instant::now() + std::time::Duration::from_secs(60),
+        )])];
+        let snapshot = snapshot(&state, &durations, vec![]);
+        assert_eq!(snapshot.entries.len(), 1);
+        assert!(snapshot.entries[0].expire_at.unwrap() > unix_time_ms());
+        assert!(snapshot.aux.iter().any(|(key, _)| key == b"used-mem"));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut buf = b"REDIS0011".to_vec();
+        buf.push(RDB_TYPE_STRING);
+        encode_string(b"k", false, &mut buf);
+        encode_string(b"v", false, &mut buf);
+        buf.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(parse_rdb(&buf, false).unwrap().0.entries.len(), 1);
+
+        buf[13] = b'w';
+        assert!(matches!(
+            parse_rdb(&buf, false),
+            Err(RdbError::Checksum { expected, .. }) if expected == checksum
+        ));
+        // an unknown value type
+        buf[9] = 0x42;
+        assert!(matches!(
+            parse_rdb(&buf, false),
+            Err(RdbError::Corrupt {
+                offset: 12,
+                opcode: 0x42
+            })
+        ));
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        let ziplist = [
+            &[25, 0, 0, 0, 19, 0, 0, 0, 4, 0][..],
+            &[0, 0x01, b'a'],
+            &[3, 0xF6],
+            &[2, 0xC0, 0x2C, 0x01],
+            &[4, 0xF0, 0xFF, 0xFF, 0xFF],
+            &[0xFF],
+        ]
+        .concat();
+        assert_eq!(
+            decode_ziplist(&ziplist, true).unwrap(),
+            vec![
+                b"a".to_vec(),
+                b"5".to_vec(),
+                b"300".to_vec(),
+                b"-1".to_vec()
+            ]
+        );
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 0xFF, 0xFF, 1, 0];
+        assert_eq!(
+            decode_intset(&intset, true).unwrap(),
+            vec![b"-1".to_vec(), b"1".to_vec()]
+        );
+        let zipmap = [1, 1, b'f', 2, 1, b'v', b'w', 0, 0xFF];
+        assert_eq!(
+            decode_zipmap(&zipmap, true).unwrap(),
+            vec![b"f".to_vec(), b"vw".to_vec()]
+        );
+
+        let mut lp = Listpack::new();
+        lp.append(b"b");
+        lp.append_int(7);
+        let mut buf = vec![2, QUICKLIST_NODE_CONTAINER_PLAIN as u8];
+        encode_string(b"a", false, &mut buf);
+        buf.push(QUICKLIST_NODE_CONTAINER_PACKED as u8);
+        encode_string(lp.as_bytes(), false, &mut buf);
+        let (_, list) = parse_value(&buf, RDB_TYPE_LIST_QUICKLIST_2, true).unwrap();
+        assert_eq!(
+            list,
+            Value::List(
+                [b"a".to_vec(), b"b".to_vec(), b"7".to_vec()]
+                    .into_iter()
+                    .collect()
+            )
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        // a wrong prevlen, element count and unsorted integers can still be
+        // decoded, but are rejected when sanitizing
+        let ziplist = [
+            &[17, 0, 0, 0, 13, 0, 0, 0, 2, 0][..],
+            &[0, 0x01, b'a'],
+            &[2, 0x01, b'b'],
+            &[0xFF],
+        ]
+        .concat();
+        assert!(decode_ziplist(&ziplist, false).is_some());
+        assert!(decode_ziplist(&ziplist, true).is_none());
+        let mut ziplist = ziplist;
+        ziplist[13] = 3;
+        assert!(decode_ziplist(&ziplist, true).is_some());
+        ziplist[8] = 3;
+        assert!(decode_ziplist(&ziplist, true).is_none());
+
+        let intset = [2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0xFF, 0xFF];
+        assert!(decode_intset(&intset, false).is_some());
+        assert!(decode_intset(&intset, true).is_none());
+        assert!(decode_intset(&[0, 0, 0, 0, 0, 0, 0, 0], false).is_none());
+
+        // a set listpack with a duplicate member
+        let mut lp = Listpack::new();
+        lp.append(b"m");
+        lp.append(b"m");
+        let mut buf = Vec::new();
+        encode_string(lp.as_bytes(), false, &mut buf);
+        assert!(parse_value(&buf, RDB_TYPE_SET_LISTPACK, false).is_ok());
+        assert!(parse_value(&buf, RDB_TYPE_SET_LISTPACK, true).is_err());
+    }
+
+    /// A ziplist of strings, with valid headers and prevlens.
+    fn ziplist(items: &[&[u8]]) -> Vec<u8> {
+        let (mut entries, mut tail, mut prevlen) = (Vec::new(), 10, 0);
+        for item in items {
+            tail = 10 + entries.len();
+            entries.extend_from_slice(&[prevlen as u8, item.len() as u8]);
+            entries.extend_from_slice(item);
+            prevlen = 2 + item.len();
+        }
+        let mut buf = ((11 + entries.len()) as u32).to_le_bytes().to_vec();
+        buf.extend_from_slice(&(tail as u32).to_le_bytes());
+        buf.extend_from_slice(&(items.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&entries);
+        buf.push(0xFF);
+        buf
+    }
+
+    fn listpack(items: &[&[u8]]) -> Vec<u8> {
+        let mut lp = Listpack::new();
+        for item in items {
+            lp.append(item);
+        }
+        lp.as_bytes().to_vec()
+    }
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_string(s, false, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_parse_every_encoding() {
+        let list = Value::List([b"\xff".to_vec(), b"a".to_vec()].into_iter().collect());
+        let set = Value::Set([b"\xff".to_vec(), b"a".to_vec()].into_iter().collect());
+        let hash = Value::Hash([(b"\xfe".to_vec(), b"\xff".to_vec())].into_iter().collect());
+        let mut zset = SortedSet::new();
+        zset.insert(b"\xff", 1.5);
+        zset.insert(b"a", 2.0);
+        let zset = Value::ZSet(zset.into());
+        let mut stream = Stream::new();
+        let fields = vec![(b"\xfe".to_vec(), b"\xff".to_vec())];
+        stream
+            .append(XAddId::Explicit(StreamId::new(1, 0)), &fields)
+            .unwrap();
+        let long = b"\xff".repeat(100);
+        let mut lzf = Vec::new();
+        encode_string(&long, true, &mut lzf);
+
+        let cases = [
+            (
+                RDB_TYPE_STRING,
+                string(b"\xff"),
+                Value::String(b"\xff".to_vec().into()),
+            ),
+            (
+                RDB_TYPE_STRING,
+                vec![0xC0, 0xFE],
+                Value::String(b"-2".to_vec().into()),
+            ),
+            (RDB_TYPE_STRING, lzf, Value::String(long.into())),
+            (
+                RDB_TYPE_LIST,
+                [&[2][..], &string(b"\xff"), &string(b"a")].concat(),
+                list.clone(),
+            ),
+            (
+                RDB_TYPE_SET,
+                [&[2][..], &string(b"\xff"), &string(b"a")].concat(),
+                set.clone(),
+            ),
+            (
+                RDB_TYPE_HASH,
+                [&[1][..], &string(b"\xfe"), &string(b"\xff")].concat(),
+                hash.clone(),
+            ),
+            (
+                RDB_TYPE_ZSET,
+                [
+                    &[2][..],
+                    &string(b"\xff"),
+                    &[3],
+                    b"1.5",
+                    &string(b"a"),
+                    &[1],
+                    b"2",
+                ]
+                .concat(),
+                zset.clone(),
+            ),
+            (
+                RDB_TYPE_ZSET_2,
+                [
+                    &[2][..],
+                    &string(b"\xff"),
+                    &1.5f64.to_le_bytes(),
+                    &string(b"a"),
+                    &2f64.to_le_bytes(),
+                ]
+                .concat(),
+                zset.clone(),
+            ),
+            (
+                RDB_TYPE_HASH_ZIPMAP,
+                string(&[1, 1, 0xFE, 1, 0, 0xFF, 0xFF]),
+                hash.clone(),
+            ),
+            (
+                RDB_TYPE_LIST_ZIPLIST,
+                string(&ziplist(&[b"\xff", b"a"])),
+                list.clone(),
+            ),
+            (
+                RDB_TYPE_SET_INTSET,
+                string(&[2, 0, 0, 0, 1, 0, 0, 0, 7, 0]),
+                Value::Set([b"7".to_vec()].into_iter().collect()),
+            ),
+            (
+                RDB_TYPE_ZSET_ZIPLIST,
+                string(&ziplist(&[b"\xff", b"1.5", b"a", b"2"])),
+                zset.clone(),
+            ),
+            (
+                RDB_TYPE_HASH_ZIPLIST,
+                string(&ziplist(&[b"\xfe", b"\xff"])),
+                hash.clone(),
+            ),
+            (
+                RDB_TYPE_LIST_QUICKLIST,
+                [&[1][..], &string(&ziplist(&[b"\xff", b"a"]))].concat(),
+                list.clone(),
+            ),
+            (
+                RDB_TYPE_HASH_LISTPACK,
+                string(&listpack(&[b"\xfe", b"\xff"])),
+                hash,
+            ),
+            (
+                RDB_TYPE_ZSET_LISTPACK,
+                string(&listpack(&[b"\xff", b"1.5", b"a", b"2"])),
+                zset,
+            ),
+            (
+                RDB_TYPE_LIST_QUICKLIST_2,
+                [
+                    &[1, QUICKLIST_NODE_CONTAINER_PACKED as u8][..],
+                    &string(&listpack(&[b"\xff", b"a"])),
+                ]
+                .concat(),
+                list,
+            ),
+            (
+                RDB_TYPE_SET_LISTPACK,
+                string(&listpack(&[b"\xff", b"a"])),
+                set,
+            ),
+        ];
+        for (value_type, buf, expected) in cases {
+            let (rest, value) = parse_value(&buf, value_type, true).unwrap();
+            assert!(rest.is_empty(), "type {value_type}");
+            assert_eq!(value, expected, "type {value_type}");
+        }
+
+        // the stream types differ in their metadata, which the first one
+        // lacks; without groups, the last two are the same
+        let mut nodes = vec![1];
+        for (key, lp) in stream.rax.iter() {
+            nodes.extend(string(&key));
+            nodes.extend(string(lp.as_bytes()));
+        }
+        nodes.extend_from_slice(&[1, 1, 0]);
+        let v1 = [&nodes[..], &[0]].concat();
+        let v2 = [&nodes[..], &[1, 0, 0, 0, 1, 0]].concat();
+        for (value_type, buf) in [
+            (RDB_TYPE_STREAM_LISTPACKS, v1),
+            (RDB_TYPE_STREAM_LISTPACKS_2, v2.clone()),
+            (RDB_TYPE_STREAM_LISTPACKS_3, v2),
+        ] {
+            let (rest, value) = parse_value(&buf, value_type, true).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(
+                value,
+                Value::Stream(stream.clone().into()),
+                "type {value_type}"
+            );
+        }
+
+        // module values can't be loaded without their module
+        assert!(parse_value(&[0], RDB_TYPE_MODULE_2, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_opcodes() {
+        let mut buf = b"REDIS0011".to_vec();
+        encode_function(b"#!lua name=a", false, &mut buf);
+        // module id, when opcode and when, then a value of each kind
+        buf.push(RDB_OPCODE_MODULE_AUX);
+        encode_length(1 << 40, &mut buf);
+        buf.extend_from_slice(&[2, 2]);
+        buf.extend_from_slice(&[RDB_MODULE_OPCODE_SINT as u8, 5]);
+        buf.extend_from_slice(&[RDB_MODULE_OPCODE_UINT as u8, 6]);
+        buf.push(RDB_MODULE_OPCODE_FLOAT as u8);
+        buf.extend_from_slice(&1f32.to_le_bytes());
+        buf.push(RDB_MODULE_OPCODE_DOUBLE as u8);
+        buf.extend_from_slice(&1f64.to_le_bytes());
+        buf.push(RDB_MODULE_OPCODE_STRING as u8);
+        buf.extend(string(b"data"));
+        buf.push(RDB_MODULE_OPCODE_EOF as u8);
+        buf.extend_from_slice(&[RDB_OPCODE_SELECTDB, 1, RDB_OPCODE_RESIZEDB]);
+        encode_length(300, &mut buf);
+        encode_length(2, &mut buf);
+        buf.push(RDB_OPCODE_EXPIRETIME_MS);
+        buf.extend_from_slice(&1234u64.to_le_bytes());
+        buf.extend_from_slice(&[RDB_OPCODE_IDLE, 9, RDB_OPCODE_FREQ, 3]);
+        buf.push(RDB_TYPE_STRING);
+        buf.extend(string(b"\xff"));
+        buf.extend(string(b"v"));
+        buf.push(RDB_OPCODE_EXPIRETIME);
+        buf.extend_from_slice(&5u32.to_le_bytes());
+        buf.push(RDB_TYPE_STRING);
+        buf.extend(string(b"\xfe"));
+        buf.extend(string(b"w"));
+        buf.push(RDB_TYPE_STRING);
+        buf.extend(string(b"k"));
+        buf.extend(string(b"x"));
+        buf.push(RDB_OPCODE_EOF);
+        buf.extend_from_slice(&crc64(0, &buf).to_le_bytes());
+
+        let (rdb, rest) = parse_rdb(&buf, true).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(rdb.functions, vec![b"#!lua name=a".to_vec()]);
+        let entries: Vec<_> = rdb
+            .entries
+            .iter()
+            .map(|e| (e.db, e.key.as_slice(), e.expire_at))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (1, b"\xff".as_slice(), Some(1234)),
+                (1, b"\xfe", Some(5000)),
+                (1, b"k", None)
+            ]
+        );
+
+        // an unknown module opcode
+        let module = [RDB_OPCODE_MODULE_AUX, 1, 2, 2, 9];
+        assert!(parse_record(
+            &module,
+            &mut Rdb::default(),
+            &mut Cursor {
+                db: 0,
+                expire_at: None,
+                sanitize: false,
+            }
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,1116 @@
+// Master-replica replication. A replica connects to its master, runs the
+// handshake (PING, REPLCONF, PSYNC), loads the RDB snapshot the master sends
+// for a full resync, then runs the stream of write commands that follows. A
+// master streams the writes it runs to each of its replicas, in RESP form
+// with SELECTs as needed; a replica forwards the stream of its master as is
+// to its own replicas.
+//
+// The stream is identified by a replication ID and an offset, and its last
+// bytes are kept in a backlog. A replica that reconnects asks for the bytes
+// after its offset: when they are still in the backlog the master sends
+// just those (a partial resync, +CONTINUE) rather than a new snapshot. After
+// a failover the previous ID is kept as the secondary ID, so that replicas
+// of the old master can continue with the new one.
+//
+// A replica that announces the `eof` capability gets its snapshot straight
+// from memory, framed by a random 40-byte mark instead of a length
+// (diskless sync); replicas asking within the configured delay share it.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::aof;
+use crate::client::{Client, OutputLimit};
+use crate::reply::Reply;
+use crate::sha1::sha1_hex;
+
+/// How long the replica waits on its master during the handshake, which
+/// includes the diskless sync delay and preparing the snapshot.
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a replica reports its offset to its master with REPLCONF ACK.
+pub const ACK_PERIOD: Duration = Duration::from_secs(1);
+pub const DEFAULT_BACKLOG_SIZE: &str = "1mb";
+
+const REPLICA_WAIT: &str = "WAIT cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.";
+
+pub struct Replication {
+    inner: Mutex<Inner>,
+    // signaled when a replica acknowledges an offset, or the local AOF
+    // reaches one, for WAIT and WAITAOF
+    acks: Condvar,
+}
+
+struct Inner {
+    // the ID of the replication stream, and how many bytes of it were
+    // produced, or on a replica, received from the master
+    replid: String,
+    offset: u64,
+    // the ID of the stream this one continues, and the first offset that
+    // is not part of it
+    replid2: Option<(String, u64)>,
+    // created when the first replica attaches
+    backlog: Option<Backlog>,
+    backlog_size: usize,
+    // of the stream a replica hasn't read yet
+    output_limit: OutputLimit,
+    // the offset reached by the writes fsynced to the AOF, and by all the
+    // writes (the stream also has e.g. GETACKs, which the AOF does not)
+    aof_offset: u64,
+    write_offset: u64,
+    // the database of the last command sent to replicas
+    selected: Option<usize>,
+    replicas: Vec<Replica>,
+    // replicas waiting for the next diskless sync
+    diskless: Vec<(Arc<Client>, Option<u16>)>,
+    master: Option<Master>,
+    // bumped by each REPLICAOF, so that the thread of a previous one stops
+    generation: u64,
+}
+
+struct Replica {
+    client: Arc<Client>,
+    listening_port: Option<u16>,
+    // whether the RDB snapshot was sent; the stream waits in `pending` until
+    online: bool,
+    pending: Vec<u8>,
+    // the offsets the replica last acknowledged as processed and as
+    // fsynced to its AOF, and when
+    ack_offset: u64,
+    ack_aof_offset: u64,
+    ack_time: Instant,
+}
+
+struct Master {
+    host: String,
+    port: u16,
+    generation: u64,
+    // set once the handshake started, to break the link on REPLICAOF
+    link: Option<TcpStream>,
+    synced: bool,
+}
+
+/// What a replica told about itself with REPLCONF, kept by the connection
+/// until PSYNC.
+#[derive(Default)]
+pub struct Handshake {
+    pub listening_port: Option<u16>,
+    // whether it can load a snapshot framed by an EOF mark
+    pub eof: bool,
+}
+
+pub type ReplicationState = Arc<Replication>;
+
+/// A new replication ID, or EOF mark: 40 random-looking hex characters.
+fn new_replid() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    sha1_hex(format!("{}:{}", now, std::process::id()).as_bytes())
+}
+
+/// The last bytes of the replication stream, in a ring buffer.
+struct Backlog {
+    buf: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(size: usize) -> Self {
+        Backlog {
+            buf: VecDeque::with_capacity(size),
+            size,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.size)..];
+        let excess = (self.buf.len() + bytes.len()).saturating_sub(self.size);
+        self.buf.drain(..excess);
+        self.buf.extend(bytes);
+    }
+}
+
+/// Parses a size in bytes, e.g. for `repl-backlog-size`: a number, or one
+/// with a unit (kb, mb or gb, in powers of 1024).
+pub fn parse_size(size: &str) -> Option<usize> {
+    let size = size.to_lowercase();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize, output_limit: OutputLimit) -> Self {
+        Replication {
+            inner: Mutex::new(Inner {
+                replid: new_replid(),
+                offset: 0,
+                replid2: None,
+                backlog: None,
+                backlog_size,
+                output_limit,
+                aof_offset: 0,
+                write_offset: 0,
+                selected: None,
+                replicas: Vec::new(),
+                diskless: Vec::new(),
+                master: None,
+                generation: 0,
+            }),
+            acks: Condvar::new(),
+        }
+    }
+
+    /// Sends write commands, each run in the database it comes with, to the
+    /// replicas, with nothing between them. Replicas only forward the
+    /// stream of their master instead.
+    pub fn feed<'a>(&self, commands: impl IntoIterator<Item = (usize, &'a [Vec<u8>])>) {
+        let mut inner = self.inner.lock().unwrap();
+        // a replica's stream is its master's, forwarded as it comes
+        if inner.master.is_some() {
+            return;
+        }
+        let mut buf = Vec::new();
+        for (db, argv) in commands {
+            if inner.selected != Some(db) {
+                aof::encode_command(&[b"SELECT".to_vec(), db.to_string().into_bytes()], &mut buf);
+                inner.selected = Some(db);
+            }
+            aof::encode_command(argv, &mut buf);
+        }
+        // the offset still counts writes without replicas, for WAITAOF
+        inner.send(&buf);
+        inner.write_offset = inner.offset;
+    }
+
+    /// PSYNC from a new replica: it will get the RDB snapshot the caller
+    /// took while still holding the keyspace, then everything fed from now
+    /// on. Returns the reply that precedes the snapshot.
+    pub fn add_replica(&self, client: Arc<Client>, listening_port: Option<u16>) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        inner.add_replica(client, listening_port, false);
+        // the replica starts in database 0
+        inner.selected = None;
+        format!("FULLRESYNC {} {}", inner.replid, inner.offset)
+    }
+
+    /// PSYNC from a replica that will take a diskless snapshot: it waits
+    /// for the next one. True for the first to wait, which is to start it.
+    pub fn join_diskless(&self, client: Arc<Client>, listening_port: Option<u16>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.diskless.push((client, listening_port));
+        inner.diskless.len() == 1
+    }
+
+    /// Adds the replicas waiting for a diskless snapshot, to be sent the
+    /// one the caller took while still holding the keyspace. Returns them
+    /// and the reply that precedes the snapshot.
+    pub fn start_diskless(&self) -> (String, Vec<Arc<Client>>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut clients = Vec::new();
+        for (client, listening_port) in std::mem::take(&mut inner.diskless) {
+            inner.add_replica(Arc::clone(&client), listening_port, false);
+            clients.push(client);
+        }
+        inner.selected = None;
+        (
+            format!("FULLRESYNC {} {}", inner.replid, inner.offset),
+            clients,
+        )
+    }
+
+    /// Sends a new replica `header` and the RDB snapshot, framed by an EOF
+    /// mark or by its length, then puts it online. A replica that can't
+    /// take it is disconnected.
+    pub fn send_snapshot(
+        &self,
+        client: &Client,
+        header: &str,
+        rdb: &[u8],
+        eof: bool,
+    ) -> io::Result<()> {
+        let result = (|| {
+            client.send_raw(format!("+{}\r\n", header).as_bytes())?;
+            if eof {
+                let mark = new_replid();
+                client.send_raw(format!("$EOF:{}\r\n", mark).as_bytes())?;
+                client.send_raw(rdb)?;
+                client.send_raw(mark.as_bytes())?;
+            } else {
+                client.send_raw(format!("${}\r\n", rdb.len()).as_bytes())?;
+                client.send_raw(rdb)?;
+            }
+            self.set_online(client.id);
+            Ok(())
+        })();
+        if result.is_err() {
+            self.remove_replica(client.id);
+            client.close();
+        }
+        result
+    }
+
+    /// PSYNC from a replica that has the stream `replid` up to `offset`
+    /// (excluded): if the rest of it is still in the backlog, sends
+    /// +CONTINUE and that rest, and adds the replica. False if it needs a
+    /// full resync instead.
+    pub fn try_continue(
+        &self,
+        client: &Arc<Client>,
+        listening_port: Option<u16>,
+        replid: &str,
+        offset: i64,
+    ) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Ok(offset) = u64::try_from(offset) else {
+            return false;
+        };
+        let known = replid == inner.replid
+            || inner
+                .replid2
+                .as_ref()
+                .is_some_and(|(replid2, end)| replid == replid2 && offset <= *end);
+        let Some(backlog) = inner.backlog.as_ref().filter(|_| known) else {
+            return false;
+        };
+        // the offset of the first byte not received yet, in the backlog or
+        // just after it
+        let first = inner.offset + 1 - backlog.buf.len() as u64;
+        if offset < first || offset > inner.offset + 1 {
+            return false;
+        }
+        let rest: Vec<u8> = backlog
+            .buf
+            .range((offset - first) as usize..)
+            .copied()
+            .collect();
+        let limit = inner.output_limit;
+        client.push_raw(format!("+CONTINUE {}\r\n", inner.replid).as_bytes(), limit);
+        client.push_raw(&rest, limit);
+        inner.add_replica(Arc::clone(client), listening_port, true);
+        true
+    }
+
+    /// REPLCONF: options sent by replicas during the handshake, and then
+    /// with ACK, the offset they processed.
+    pub fn replconf(
+        &self,
+        client_id: u64,
+        options: &[(String, String)],
+        handshake: &mut Handshake,
+    ) -> Reply<'static> {
+        for (name, value) in options {
+            match name.to_lowercase().as_str() {
+                "listening-port" => match value.parse() {
+                    Ok(port) => handshake.listening_port = Some(port),
+                    Err(_) => return Reply::Error("value is not an integer or out of range"),
+                },
+                "ack" | "fack" => {
+                    let Ok(offset) = value.parse() else {
+                        return Reply::Error("value is not an integer or out of range");
+                    };
+                    let mut inner = self.inner.lock().unwrap();
+                    if let Some(replica) =
+                        inner.replicas.iter_mut().find(|r| r.client.id == client_id)
+                    {
+                        if name.eq_ignore_ascii_case("ack") {
+                            replica.ack_offset = replica.ack_offset.max(offset);
+                        } else {
+                            replica.ack_aof_offset = replica.ack_aof_offset.max(offset);
+                        }
+                        replica.ack_time = Instant::now();
+                    }
+                    self.acks.notify_all();
+                }
+                "capa" => handshake.eof |= value.eq_ignore_ascii_case("eof"),
+                "ip-address" | "getack" => {}
+                _ => {
+                    return Reply::ErrorCode(
+                        "ERR",
+                        format!("Unrecognized REPLCONF option: {}", name),
+                    )
+                }
+            }
+        }
+        Reply::Simple("OK".to_string())
+    }
+
+    /// Sends the replica what was fed while its snapshot was being sent.
+    pub fn set_online(&self, client_id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let limit = inner.output_limit;
+        let Some(replica) = inner.replicas.iter_mut().find(|r| r.client.id == client_id) else {
+            return;
+        };
+        replica.online = true;
+        replica
+            .client
+            .push_raw(&std::mem::take(&mut replica.pending), limit);
+    }
+
+    pub fn remove_replica(&self, client_id: u64) {
+        self.inner.lock().unwrap().remove_replica(client_id);
+    }
+
+    /// REPLICAOF: replicates another server from now on, or stops with
+    /// `None`. Returns the generation the new replication thread runs
+    /// as, if one must be started.
+    pub fn replicaof(&self, master: Option<(String, u16)>) -> Option<u64> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        let generation = inner.generation;
+        let was_replica = inner.master.is_some();
+        if let Some(link) = inner.master.take().and_then(|m| m.link) {
+            let _ = link.shutdown(Shutdown::Both);
+        }
+        match master {
+            Some((host, port)) => {
+                // they resync with this server once it has synced itself
+                inner.disconnect_replicas();
+                inner.master = Some(Master {
+                    host,
+                    port,
+                    generation,
+                    link: None,
+                    synced: false,
+                });
+                Some(generation)
+            }
+            None if was_replica => {
+                // a new history starts from this dataset, which continues
+                // the one of the master so far
+                let replid = std::mem::replace(&mut inner.replid, new_replid());
+                inner.replid2 = Some((replid, inner.offset + 1));
+                inner.selected = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.inner.lock().unwrap().master.is_some()
+    }
+
+    /// Whether this is a replica not in sync with its master, because the
+    /// link is down or the first sync is not done.
+    pub fn is_stale(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.master.as_ref().is_some_and(|m| !m.synced)
+    }
+
+    /// Whether REPLICAOF `host port` would change nothing.
+    pub fn is_master(&self, host: &str, port: u16) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .master
+            .as_ref()
+            .is_some_and(|m| m.host == host && m.port == port)
+    }
+
+    /// The master a replication thread should connect to, unless another
+    /// REPLICAOF replaced it.
+    pub fn master(&self, generation: u64) -> Option<(String, u16)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .master
+            .as_ref()
+            .filter(|m| m.generation == generation)
+            .map(|m| (m.host.clone(), m.port))
+    }
+
+    /// Records the connection to the master, for REPLICAOF to break it.
+    /// False if the thread has been replaced already.
+    pub fn set_link(&self, generation: u64, link: &MasterLink) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.master.as_mut() {
+            Some(master) if master.generation == generation => {
+                master.link = link.stream.try_clone().ok();
+                master.synced = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// What to ask the master for with PSYNC: the stream this server has,
+    /// and the offset it is at plus one, if it has a history to continue.
+    pub fn resume_point(&self) -> Option<(String, u64)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .backlog
+            .as_ref()
+            .map(|_| (inner.replid.clone(), inner.offset + 1))
+    }
+
+    /// The link with the master broke: the data is stale until it syncs
+    /// again.
+    pub fn link_down(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(master) = inner.master.as_mut().filter(|m| m.generation == generation) {
+            master.link = None;
+            master.synced = false;
+        }
+    }
+
+    /// The full resync is done: the stream continues from the master's,
+    /// and replicas of this server must resync too.
+    pub fn synced(&self, replid: String, offset: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.replid = replid;
+        inner.replid2 = None;
+        inner.offset = offset;
+        inner.backlog = Some(Backlog::new(inner.backlog_size));
+        inner.disconnect_replicas();
+        if let Some(master) = inner.master.as_mut() {
+            master.synced = true;
+        }
+    }
+
+    /// The master accepted to continue the stream, under a new ID if it was
+    /// failed over to. Replicas of this server then reconnect to learn it.
+    pub fn resumed(&self, replid: String) {
+        let mut inner = self.inner.lock().unwrap();
+        if replid != inner.replid {
+            let previous = std::mem::replace(&mut inner.replid, replid);
+            inner.replid2 = Some((previous, inner.offset + 1));
+            inner.disconnect_replicas();
+        }
+        if let Some(master) = inner.master.as_mut() {
+            master.synced = true;
+        }
+    }
+
+    /// Accounts for bytes of the master's stream once processed, and
+    /// forwards them to this replica's own replicas.
+    pub fn processed(&self, bytes: &[u8]) {
+        self.inner.lock().unwrap().send(bytes);
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.inner.lock().unwrap().offset
+    }
+
+    /// Records the offset reached by the writes fsynced to the AOF.
+    pub fn set_aof_offset(&self, offset: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.aof_offset != offset {
+            inner.aof_offset = offset;
+            self.acks.notify_all();
+        }
+    }
+
+    /// WAIT: blocks until `numreplicas` replicas processed all the writes
+    /// made so far, for up to `timeout` ms (0 for no limit, `None` not to
+    /// block). Replies with the number of replicas that did.
+    pub fn wait(&self, numreplicas: usize, timeout: Option<u64>) -> Reply<'static> {
+        if self.inner.lock().unwrap().master.is_some() {
+            return Reply::Error(REPLICA_WAIT);
+        }
+        let (_, acked) = self.wait_for(numreplicas, false, false, timeout);
+        Reply::Integer(acked as i64)
+    }
+
+    /// WAITAOF: like WAIT, but for the writes to be fsynced to the AOF,
+    /// locally if `numlocal` is 1 and on `numreplicas` replicas. Replies
+    /// with whether the local AOF has them, and on how many replicas.
+    pub fn waitaof(
+        &self,
+        numlocal: usize,
+        numreplicas: usize,
+        appendonly: bool,
+        timeout: Option<u64>,
+    ) -> Reply<'static> {
+        if self.inner.lock().unwrap().master.is_some() {
+            return Reply::Error(REPLICA_WAIT);
+        }
+        if numlocal > 0 && !appendonly {
+            return Reply::Error(
+                "WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+            );
+        }
+        let (local, acked) = self.wait_for(numreplicas, numlocal > 0, true, timeout);
+        Reply::Nested(vec![
+            Reply::Integer(local as i64),
+            Reply::Integer(acked as i64),
+        ])
+    }
+
+    /// Waits for the current offset to be acknowledged by `numreplicas`
+    /// replicas, as processed or with `aof` as fsynced, and with `local` by
+    /// the local AOF. Returns whether the local AOF reached it and the
+    /// number of replicas that did.
+    fn wait_for(
+        &self,
+        numreplicas: usize,
+        local: bool,
+        aof: bool,
+        timeout: Option<u64>,
+    ) -> (bool, usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let target = inner.offset;
+        let local_target = inner.write_offset;
+        let reached = move |inner: &Inner| {
+            let acked = inner
+                .replicas
+                .iter()
+                .filter(|r| if aof { r.ack_aof_offset } else { r.ack_offset } >= target)
+                .count();
+            (inner.aof_offset >= local_target, acked)
+        };
+        let done = |inner: &Inner| {
+            let (synced, acked) = reached(inner);
+            (synced || !local) && acked >= numreplicas
+        };
+        if let Some(timeout) = timeout.filter(|_| !done(&inner)) {
+            // replicas acknowledge right away rather than within a second
+            if inner.replicas.iter().any(|r| r.ack_offset < target) {
+                let mut getack = Vec::new();
+                aof::encode_command(
+                    &[b"REPLCONF".to_vec(), b"GETACK".to_vec(), b"*".to_vec()],
+                    &mut getack,
+                );
+                inner.send(&getack);
+            }
+            inner = match timeout {
+                0 => self.acks.wait_while(inner, |inner| !done(inner)).unwrap(),
+                ms => {
+                    let timeout = Duration::from_millis(ms);
+                    let wait = self
+                        .acks
+                        .wait_timeout_while(inner, timeout, |inner| !done(inner));
+                    wait.unwrap().0
+                }
+            };
+        }
+        reached(&inner)
+    }
+
+    /// The replication section of INFO.
+    pub fn info(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut info = String::from("# Replication\r\n");
+        match &inner.master {
+            Some(master) => {
+                info += "role:slave\r\n";
+                info += &format!("master_host:{}\r\n", master.host);
+                info += &format!("master_port:{}\r\n", master.port);
+                let status = if master.synced { "up" } else { "down" };
+                info += &format!("master_link_status:{}\r\n", status);
+                info += &format!("master_sync_in_progress:{}\r\n", !master.synced as u8);
+                info += &format!("slave_repl_offset:{}\r\n", inner.offset);
+            }
+            None => info += "role:master\r\n",
+        }
+        info += &format!("connected_slaves:{}\r\n", inner.replicas.len());
+        for (i, replica) in inner.replicas.iter().enumerate() {
+            let ip = replica
+                .client
+                .addr
+                .map_or("?".to_string(), |addr| addr.ip().to_string());
+            let port = replica.listening_port.unwrap_or(0);
+            let state = if replica.online {
+                "online"
+            } else {
+                "wait_bgsave"
+            };
+            info += &format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                ip,
+                port,
+                state,
+                replica.ack_offset,
+                replica.ack_time.elapsed().as_secs()
+            );
+        }
+        info += &format!("master_replid:{}\r\n", inner.replid);
+        let (replid2, second_offset) = match &inner.replid2 {
+            Some((replid2, offset)) => (replid2.as_str(), *offset as i64),
+            None => ("0000000000000000000000000000000000000000", -1),
+        };
+        info += &format!("master_replid2:{}\r\n", replid2);
+        info += &format!("master_repl_offset:{}\r\n", inner.offset);
+        info += &format!("second_repl_offset:{}\r\n", second_offset);
+        let histlen = inner
+            .backlog
+            .as_ref()
+            .map_or(0, |backlog| backlog.buf.len());
+        info += &format!("repl_backlog_active:{}\r\n", inner.backlog.is_some() as u8);
+        info += &format!("repl_backlog_size:{}\r\n", inner.backlog_size);
+        info += &format!(
+            "repl_backlog_first_byte_offset:{}\r\n",
+            inner.offset + 1 - histlen as u64
+        );
+        info += &format!("repl_backlog_histlen:{}\r\n", histlen);
+        info
+    }
+}
+
+impl Inner {
+    /// Appends to the stream sent to replicas.
+    fn send(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.push(bytes);
+        }
+        self.send_replicas(bytes);
+    }
+
+    fn add_replica(&mut self, client: Arc<Client>, listening_port: Option<u16>, online: bool) {
+        self.remove_replica(client.id);
+        if self.backlog.is_none() {
+            self.backlog = Some(Backlog::new(self.backlog_size));
+        }
+        self.replicas.push(Replica {
+            client,
+            listening_port,
+            online,
+            pending: Vec::new(),
+            ack_offset: 0,
+            ack_aof_offset: 0,
+            ack_time: Instant::now(),
+        });
+    }
+
+    /// Closes the connections of all replicas, for them to sync again.
+    fn disconnect_replicas(&mut self) {
+        for replica in self.replicas.drain(..) {
+            replica.client.close();
+        }
+    }
+
+    /// Queues the stream for the replicas, which their connections write.
+    /// One that falls too far behind is disconnected.
+    fn send_replicas(&mut self, bytes: &[u8]) {
+        let limit = self.output_limit;
+        self.replicas.retain_mut(|replica| {
+            if replica.online {
+                replica.client.push_raw(bytes, limit);
+                return true;
+            }
+            replica.pending.extend_from_slice(bytes);
+            if limit.hard > 0 && replica.pending.len() > limit.hard {
+                eprintln!(
+                    "Replica id={} closed for overcoming of output buffer limits.",
+                    replica.client.id
+                );
+                replica.client.close();
+                return false;
+            }
+            true
+        });
+    }
+
+    fn remove_replica(&mut self, client_id: u64) -> Option<Replica> {
+        self.diskless.retain(|(client, _)| client.id != client_id);
+        let i = self
+            .replicas
+            .iter()
+            .position(|r| r.client.id == client_id)?;
+        Some(self.replicas.remove(i))
+    }
+}
+
+/// How the master answered PSYNC.
+pub enum Sync {
+    /// The master's stream ID and offset, and a snapshot of its dataset.
+    Full {
+        replid: String,
+        offset: u64,
+        rdb: Vec<u8>,
+    },
+    /// The stream continues, with the master's ID.
+    Continue(String),
+}
+
+/// A replica's connection to its master, after the handshake.
+pub struct MasterLink {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl MasterLink {
+    /// Connects to the master and runs the handshake, asking to continue
+    /// from `resume` if given, up to the RDB snapshot the master sends for a
+    /// full resync.
+    pub fn connect(
+        host: &str,
+        port: u16,
+        listening_port: u16,
+        resume: Option<(String, u64)>,
+    ) -> io::Result<(MasterLink, Sync)> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown master host"))?;
+        let stream = TcpStream::connect_timeout(&addr, REPL_TIMEOUT)?;
+        stream.set_read_timeout(Some(REPL_TIMEOUT))?;
+        let mut link = MasterLink {
+            stream,
+            buf: Vec::new(),
+        };
+
+        link.command(&["PING"])?;
+        let port = listening_port.to_string();
+        link.command(&["REPLCONF", "listening-port", &port])?;
+        link.command(&["REPLCONF", "capa", "eof", "capa", "psync2"])?;
+        let (replid, offset) = resume
+            .map_or(("?".to_string(), "-1".to_string()), |(replid, offset)| {
+                (replid, offset.to_string())
+            });
+        let reply = link.command(&["PSYNC", &replid, &offset])?;
+        if let Some(new_replid) = reply.strip_prefix("+CONTINUE") {
+            link.stream.set_read_timeout(Some(ACK_PERIOD))?;
+            // without an ID, the stream continues under the same one
+            let replid = match new_replid.trim() {
+                "" => replid,
+                new_replid => new_replid.to_string(),
+            };
+            return Ok((link, Sync::Continue(replid)));
+        }
+        let resync = reply.strip_prefix("+FULLRESYNC ").and_then(|rest| {
+            let (replid, offset) = rest.split_once(' ')?;
+            Some((replid.to_string(), offset.parse().ok()?))
+        });
+        let Some((replid, offset)) = resync else {
+            return Err(protocol_error(&reply));
+        };
+
+        // the master may send newlines to keep the link alive while it
+        // prepares the snapshot
+        let mut header = String::new();
+        while header.is_empty() {
+            header = link.read_line()?;
+        }
+        let rdb = match header.strip_prefix("$EOF:") {
+            Some(mark) if mark.len() == 40 => link.read_until(mark.as_bytes())?,
+            _ => {
+                let len = header
+                    .strip_prefix('$')
+                    .and_then(|len| len.parse().ok())
+                    .ok_or_else(|| protocol_error(&header))?;
+                link.read_exact(len)?
+            }
+        };
+        // to send acknowledgements while the stream is idle
+        link.stream.set_read_timeout(Some(ACK_PERIOD))?;
+        Ok((
+            link,
+            Sync::Full {
+                replid,
+                offset,
+                rdb,
+            },
+        ))
+    }
+
+    /// Sends a command and reads its one-line reply, which must not be an
+    /// error.
+    fn command(&mut self, args: &[&str]) -> io::Result<String> {
+        let argv: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        self.send(&argv)?;
+        let reply = self.read_line()?;
+        if reply.starts_with('-') {
+            return Err(protocol_error(&reply));
+        }
+        Ok(reply)
+    }
+
+    pub fn send(&mut self, argv: &[Vec<u8>]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        aof::encode_command(argv, &mut buf);
+        self.stream.write_all(&buf)
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 16 * 1024];
+        let n = self.stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                return Ok(String::from_utf8_lossy(&line).trim_end().to_string());
+            }
+            self.fill()?;
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// The bytes up to `mark`, which is consumed too.
+    fn read_until(&mut self, mark: &[u8]) -> io::Result<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buf[searched..]
+                .windows(mark.len())
+                .position(|window| window == mark)
+            {
+                let end = searched + i;
+                let data = self.buf[..end].to_vec();
+                self.buf.drain(..end + mark.len());
+                return Ok(data);
+            }
+            // the mark may start in what was read so far
+            searched = self.buf.len().saturating_sub(mark.len() - 1);
+            self.fill()?;
+        }
+    }
+
+    /// Reports the offsets processed and fsynced to the AOF so far to the
+    /// master.
+    pub fn ack(&mut self, replication: &Replication) -> io::Result<()> {
+        let (offset, aof_offset) = {
+            let inner = replication.inner.lock().unwrap();
+            (inner.offset, inner.aof_offset)
+        };
+        self.send(&[
+            b"REPLCONF".to_vec(),
+            b"ACK".to_vec(),
+            offset.to_string().into_bytes(),
+            b"FACK".to_vec(),
+            aof_offset.to_string().into_bytes(),
+        ])
+    }
+
+    /// The next command of the stream, and its bytes as received, or None
+    /// if none came for `ACK_PERIOD`.
+    #[allow(clippy::type_complexity)]
+    pub fn read_command(&mut self) -> io::Result<Option<(Vec<Vec<u8>>, Vec<u8>)>> {
+        loop {
+            match aof::parse_command(&self.buf) {
+                Ok(Some((argv, rest))) => {
+                    let len = self.buf.len() - rest.len();
+                    return Ok(Some((argv, self.buf.drain(..len).collect())));
+                }
+                Ok(None) => match self.fill() {
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        return Ok(None)
+                    }
+                    result => result?,
+                },
+                Err(e) => return Err(protocol_error(e)),
+            }
+        }
+    }
+}
+
+fn protocol_error(reply: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply from the master: {}", reply),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_feed() {
+        let replication = Replication::new(1 << 20, OutputLimit::default());
+        // counted, but not kept without replicas
+        replication.feed([(0, &[b"SET".to_vec()][..])]);
+        assert_eq!(replication.offset(), 36);
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let client = Arc::new(Client::new(1, Box::new(Sink(Arc::clone(&out)))));
+        let reply = replication.add_replica(Arc::clone(&client), Some(6380));
+        assert!(reply.starts_with("FULLRESYNC ") && reply.ends_with(" 36"));
+        replication.feed([(2, &[b"DEL".to_vec(), b"k".to_vec()][..])]);
+        // held back until the snapshot is sent
+        assert!(out.lock().unwrap().is_empty());
+        replication.set_online(1);
+        client.flush().unwrap();
+        let expected = b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n";
+        assert_eq!(out.lock().unwrap().as_slice(), expected);
+        assert_eq!(replication.offset(), 36 + expected.len() as u64);
+        assert!(replication
+            .info()
+            .contains("connected_slaves:1\r\nslave0:ip=?,port=6380,state=online,offset=0,"));
+    }
+
+    #[test]
+    fn test_partial_resync() {
+        let replication = Replication::new(16, OutputLimit::default());
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let client = Arc::new(Client::new(1, Box::new(Sink(Arc::clone(&out)))));
+        let reply = replication.add_replica(Arc::clone(&client), None);
+        let replid = reply.split(' ').nth(1).unwrap().to_string();
+        replication.set_online(1);
+        // 43 bytes with the SELECT: only the last 16 are kept
+        replication.feed([(0, &[b"DEL".to_vec(), b"k".to_vec()][..])]);
+        replication.remove_replica(1);
+        client.flush().unwrap();
+        out.lock().unwrap().clear();
+
+        let try_continue = |replid: &str, offset| {
+            let resumed = replication.try_continue(&client, None, replid, offset);
+            client.flush().unwrap();
+            resumed
+        };
+        assert!(!try_continue("?", -1));
+        assert!(!try_continue(&replid, 27));
+        assert!(!try_continue(&replid, 45));
+        assert!(try_continue(&replid, 37));
+        let expected = format!("+CONTINUE {}\r\n$1\r\nk\r\n", replid);
+        assert_eq!(out.lock().unwrap().as_slice(), expected.as_bytes());
+
+        // after a failover, the old ID is accepted up to where it ended
+        replication.replicaof(Some(("127.0.0.1".to_string(), 6380)));
+        replication.replicaof(None);
+        assert!(replication.resume_point().unwrap().0 != replid);
+        assert!(try_continue(&replid, 44));
+        assert!(!try_continue(&replid, 45));
+    }
+
+    #[test]
+    fn test_wait() {
+        let replication = Arc::new(Replication::new(1 << 20, OutputLimit::default()));
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let client = Arc::new(Client::new(1, Box::new(Sink(Arc::clone(&out)))));
+        replication.add_replica(Arc::clone(&client), None);
+        replication.set_online(1);
+        assert!(matches!(replication.wait(1, None), Reply::Integer(1)));
+        replication.feed([(0, &[b"DEL".to_vec(), b"k".to_vec()][..])]);
+        assert!(matches!(replication.wait(1, None), Reply::Integer(0)));
+
+        let offset = replication.offset().to_string();
+        let ack = {
+            let replication = Arc::clone(&replication);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                let options = [("ACK".to_string(), offset)];
+                replication.replconf(1, &options, &mut Handshake::default());
+            })
+        };
+        assert!(matches!(replication.wait(1, Some(0)), Reply::Integer(1)));
+        ack.join().unwrap();
+        // the replica was asked to acknowledge
+        client.flush().unwrap();
+        assert!(out
+            .lock()
+            .unwrap()
+            .ends_with(b"$6\r\nGETACK\r\n$1\r\n*\r\n"));
+
+        let Reply::Nested(replies) = replication.waitaof(0, 1, false, Some(10)) else {
+            panic!("not an array")
+        };
+        assert!(matches!(
+            replies[..],
+            [Reply::Integer(0), Reply::Integer(0)]
+        ));
+        assert!(replication.waitaof(1, 0, false, None).is_error());
+    }
+
+    #[test]
+    fn test_diskless_sync() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let master = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let client = Client::new(1, Box::new(stream.try_clone().unwrap()));
+            let replication = Replication::new(1 << 20, OutputLimit::default());
+            let mut handshake = Handshake::default();
+            let mut reader = io::BufReader::new(stream);
+            // PING, two REPLCONFs and PSYNC, answered with the snapshot
+            for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n", ""] {
+                let mut buf = Vec::new();
+                while aof::parse_command(&buf).unwrap().is_none() {
+                    io::BufRead::read_until(&mut reader, b'\n', &mut buf).unwrap();
+                }
+                let (argv, _) = aof::parse_command(&buf).unwrap().unwrap();
+                if argv[0] == b"REPLCONF" {
+                    let options: Vec<(String, String)> = argv[1..]
+                        .chunks(2)
+                        .map(|pair| {
+                            let text = |arg: &[u8]| String::from_utf8_lossy(arg).to_string();
+                            (text(&pair[0]), text(&pair[1]))
+                        })
+                        .collect();
+                    replication.replconf(1, &options, &mut handshake);
+                }
+                client.send_raw(reply.as_bytes()).unwrap();
+            }
+            assert_eq!(handshake.listening_port, Some(6380));
+            assert!(handshake.eof);
+            // the snapshot, then the stream right after the mark
+            let client = Arc::new(client);
+            assert!(replication.join_diskless(Arc::clone(&client), None));
+            let (header, clients) = replication.start_diskless();
+            replication.feed([(0, &[b"DEL".to_vec(), b"k".to_vec()][..])]);
+            replication
+                .send_snapshot(&clients[0], &header, b"REDIS0011", true)
+                .unwrap();
+            clients[0].flush().unwrap();
+            (header, reader)
+        });
+
+        let (mut link, sync) = MasterLink::connect("127.0.0.1", port, 6380, None).unwrap();
+        let (header, _connection) = master.join().unwrap();
+        let Sync::Full {
+            replid,
+            offset,
+            rdb,
+        } = sync
+        else {
+            panic!("not a full resync")
+        };
+        assert_eq!(header, format!("FULLRESYNC {} {}", replid, offset));
+        assert_eq!(rdb, b"REDIS0011");
+        let (argv, _) = link.read_command().unwrap().unwrap();
+        assert_eq!(argv, [b"SELECT".to_vec(), b"0".to_vec()]);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("1mb"), Some(1 << 20));
+        assert_eq!(parse_size("2KB"), Some(2048));
+        assert_eq!(parse_size("1tb"), None);
+        assert_eq!(parse_size("mb"), None);
+    }
+}
@@ -1,25 +1,72 @@
+pub const WRONGTYPE: &str = "Operation against a key holding the wrong kind of value";
+
+#[derive(Debug)]
 pub enum Reply<'a> {
     Simple(String),
     Error(&'a str),
+    // error with a code other than ERR, e.g. WRONGTYPE
+    ErrorCode(&'a str, String),
+    // a complete error line, code included, e.g. one returned by a script
+    ErrorString(String),
+    Integer(i64),
     Pong,
     Echo(String),
     Null,
     NullBulk,
     Bulk(String),
+    // binary-safe bulk string, e.g. a string value
+    BulkBytes(Vec<u8>),
     // TODO: for now it only supports bulk strings
     Array(Vec<String>),
+    NullArray,
+    Nested(Vec<Reply<'a>>),
+    // out-of-band data such as pub/sub messages, a plain array in RESP2
+    Push(Vec<Reply<'a>>),
+    // a flat array of key/value pairs in RESP2
+    Map(Vec<(String, Reply<'a>)>),
+    // several replies sent back to back, e.g. one per SUBSCRIBE channel
+    Many(Vec<Reply<'a>>),
 }
 
 impl<'a> Reply<'a> {
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Reply::Error(_) | Reply::ErrorCode(..) | Reply::ErrorString(_)
+        )
+    }
+
     pub fn into_bytes(self) -> Vec<u8> {
+        self.encode(false)
+    }
+
+    /// Serializes the reply for a connection speaking RESP3 or RESP2.
+    pub fn encode(self, resp3: bool) -> Vec<u8> {
+        let aggregate = |prefix: char, v: Vec<Reply<'a>>| {
+            let mut resp = format!("{}{}\r\n", prefix, v.len()).into_bytes();
+            for r in v {
+                resp.extend(r.encode(resp3));
+            }
+            resp
+        };
         match self {
+            Reply::NullBulk | Reply::NullArray if resp3 => Reply::Null.encode(resp3),
             Reply::Simple(s) => format!("+{}\r\n", s).into_bytes(),
             Reply::Pong => Reply::Simple("PONG".to_string()).into_bytes(),
             Reply::Echo(s) => Reply::Simple(s).into_bytes(),
             Reply::Error(msg) => format!("-ERR {}\r\n", msg).into_bytes(),
+            Reply::ErrorCode(code, msg) => format!("-{} {}\r\n", code, msg).into_bytes(),
+            Reply::ErrorString(msg) => format!("-{}\r\n", msg).into_bytes(),
+            Reply::Integer(i) => format!(":{}\r\n", i).into_bytes(),
             Reply::Null => String::from("_\r\n").into_bytes(),
             Reply::NullBulk => String::from("$-1\r\n").into_bytes(),
             Reply::Bulk(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            Reply::BulkBytes(b) => {
+                let mut resp = format!("${}\r\n", b.len()).into_bytes();
+                resp.extend(b);
+                resp.extend_from_slice(b"\r\n");
+                resp
+            }
             Reply::Array(v) => {
                 let mut resp = format!("*{}\r\n", v.len());
                 for s in v {
@@ -27,6 +74,23 @@ impl<'a> Reply<'a> {
                 }
                 resp.into_bytes()
             }
+            Reply::NullArray => String::from("*-1\r\n").into_bytes(),
+            Reply::Nested(v) => aggregate('*', v),
+            Reply::Push(v) => aggregate(if resp3 { '>' } else { '*' }, v),
+            Reply::Map(pairs) => {
+                let mut resp = if resp3 {
+                    format!("%{}\r\n", pairs.len())
+                } else {
+                    format!("*{}\r\n", pairs.len() * 2)
+                }
+                .into_bytes();
+                for (k, v) in pairs {
+                    resp.extend(Reply::Bulk(k).encode(resp3));
+                    resp.extend(v.encode(resp3));
+                }
+                resp
+            }
+            Reply::Many(v) => v.into_iter().flat_map(|r| r.encode(resp3)).collect(),
         }
     }
 }
@@ -55,4 +119,32 @@ mod tests {
             Reply::Array(vec!["dir".to_string(), "/tmp/redis-files".to_string()]).into_bytes()
         );
     }
+
+    #[test]
+    fn test_nested_array() {
+        let expected = b"*2\r\n$3\r\n0-1\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_eq!(
+            expected.to_vec(),
+            Reply::Nested(vec![
+                Reply::Bulk("0-1".to_string()),
+                Reply::Array(vec!["a".to_string(), "b".to_string()])
+            ])
+            .into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_push_reply() {
+        let push = || {
+            Reply::Push(vec![
+                Reply::Bulk("message".to_string()),
+                Reply::Bulk("ch".to_string()),
+                Reply::NullBulk,
+            ])
+        };
+        let expected = b"*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$-1\r\n";
+        assert_eq!(expected.to_vec(), push().encode(false));
+        let expected = b">3\r\n$7\r\nmessage\r\n$2\r\nch\r\n_\r\n";
+        assert_eq!(expected.to_vec(), push().encode(true));
+    }
 }
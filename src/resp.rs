@@ -1,20 +1,25 @@
 use std::borrow::Cow;
 use std::rc::Rc;
 
+// streaming parsers: a request cut short is Incomplete, anything else that
+// does not parse is a protocol error
 use nom::{
-    branch::alt,
-    bytes::complete::take,
-    character::complete::{char, digit1, line_ending, not_line_ending, one_of, u32},
-    combinator::{map, map_res, opt, recognize},
+    bytes::streaming::take,
+    character::streaming::{char, digit1, line_ending, not_line_ending, one_of, u32},
+    combinator::{map, map_res, opt, recognize, verify},
+    error::{Error, ErrorKind},
     multi::count,
     sequence::{delimited, terminated, tuple},
-    IResult,
+    IResult, Needed,
 };
 
+/// The longest bulk string accepted, as `proto-max-bulk-len` in Redis.
+const MAX_BULK_LEN: u32 = 512 * 1024 * 1024;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Type<'a> {
     Integer(i64),
-    String(Cow<'a, str>, StrType),
+    String(Cow<'a, [u8]>, StrType),
 
     // TODO: REST arrays may be nested
     #[allow(unused)]
@@ -36,54 +41,84 @@ pub enum StrType {
 // }
 
 #[allow(unused)]
-pub fn parse_resp(input: &str) -> IResult<&str, Vec<Type>> {
+pub fn parse_resp(input: &[u8]) -> IResult<&[u8], Vec<Type<'_>>> {
     parse_array(input)
 }
 
 #[allow(unused)]
-pub fn parse_array(input: &str) -> IResult<&str, Vec<Type>> {
+pub fn parse_array(input: &[u8]) -> IResult<&[u8], Vec<Type<'_>>> {
     let (rest, arr_len) = delimited(char('*'), u32, line_ending)(input)?;
 
     // N times array
-    let (rest, obj) = count(
-        alt((
-            map(parse_string, |s| Type::String(Cow::from(s), StrType::Basic)),
-            map(parse_bulk_string, |s| {
-                Type::String(Cow::from(s), StrType::Bulk)
-            }),
-            map(parse_integer, Type::Integer),
-        )),
-        arr_len as usize,
-    )(rest)?;
+    let (rest, obj) = count(parse_element, arr_len as usize)(rest)?;
 
     Ok((rest, obj))
 }
 
+/// An element of an array, told by its first byte, so that an error is
+/// reported where it is rather than at the start of the element.
+fn parse_element(input: &[u8]) -> IResult<&[u8], Type<'_>> {
+    match input.first() {
+        None => Err(nom::Err::Incomplete(Needed::new(1))),
+        Some(b'+') => map(parse_string, |s| Type::String(Cow::from(s), StrType::Basic))(input),
+        Some(b'$') => map(parse_bulk_string, |s| {
+            Type::String(Cow::from(s), StrType::Bulk)
+        })(input),
+        Some(b':') => map(parse_integer, Type::Integer)(input),
+        Some(_) => Err(nom::Err::Error(Error::new(input, ErrorKind::Char))),
+    }
+}
+
+/// Why `parse_resp` failed on `input`, with `at` where it did.
+#[allow(unused)]
+pub fn protocol_error(input: &[u8], at: &[u8]) -> String {
+    let offset = input.len() - at.len();
+    match at.first() {
+        Some(&byte) if offset == 0 => format!("expected '*', got '{}'", byte as char),
+        Some(&byte) => format!("unexpected '{}' at offset {}", byte as char, offset),
+        None => "unexpected end of request".to_string(),
+    }
+}
+
+/// The arguments of a parsed command, e.g. to write it to the AOF.
+#[allow(unused)]
+pub fn args(cmd: &[Type]) -> Vec<Vec<u8>> {
+    cmd.iter()
+        .filter_map(|t| match t {
+            Type::String(s, _) => Some(s.to_vec()),
+            Type::Integer(i) => Some(i.to_string().into_bytes()),
+            Type::Array(_) => None,
+        })
+        .collect()
+}
+
 #[allow(unused)]
-pub fn parse_error(input: &str) -> IResult<&str, &str> {
+pub fn parse_error(input: &[u8]) -> IResult<&[u8], &[u8]> {
     delimited(char('-'), not_line_ending, line_ending)(input)
 }
 
 #[allow(unused)]
-pub fn parse_string(input: &str) -> IResult<&str, &str> {
+pub fn parse_string(input: &[u8]) -> IResult<&[u8], &[u8]> {
     delimited(char('+'), not_line_ending, line_ending)(input)
 }
 
 #[allow(unused)]
-pub fn parse_bulk_string(input: &str) -> IResult<&str, &str> {
-    let (rest, length) = delimited(char('$'), u32, line_ending)(input)?;
+pub fn parse_bulk_string(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let length = verify(u32, |&length| length <= MAX_BULK_LEN);
+    let (rest, length) = delimited(char('$'), length, line_ending)(input)?;
     terminated(take(length), line_ending)(rest)
 }
 
 #[allow(unused)]
-fn digit(input: &str) -> IResult<&str, i64> {
-    map_res(recognize(tuple((opt(one_of("+-")), digit1))), |s: &str| {
-        i64::from_str_radix(s, 10)
-    })(input)
+fn digit(input: &[u8]) -> IResult<&[u8], i64> {
+    map_res(
+        recognize(tuple((opt(one_of("+-")), digit1))),
+        |s: &[u8]| std::str::from_utf8(s).unwrap_or_default().parse::<i64>(),
+    )(input)
 }
 
 #[allow(unused)]
-pub fn parse_integer(input: &str) -> IResult<&str, i64> {
+pub fn parse_integer(input: &[u8]) -> IResult<&[u8], i64> {
     delimited(char(':'), digit, line_ending)(input)
 }
 
@@ -94,44 +129,83 @@ mod tests {
 
     #[test]
     fn test_parse_string() {
-        let (remaining_input, output) = parse_string("+OK\r\n").unwrap();
-        assert_eq!(remaining_input, "");
-        assert_eq!(output, "OK");
+        let (remaining_input, output) = parse_string(b"+OK\r\n").unwrap();
+        assert!(remaining_input.is_empty());
+        assert_eq!(output, b"OK");
     }
 
     #[test]
     fn test_parse_bulk_string() {
-        let (remaining_input, output) = parse_bulk_string("$7\r\nabc\r\n89\r\n").unwrap();
-        assert_eq!(remaining_input, "");
-        assert_eq!(output, "abc\r\n89");
+        let (remaining_input, output) = parse_bulk_string(b"$7\r\nabc\r\n89\r\n").unwrap();
+        assert!(remaining_input.is_empty());
+        assert_eq!(output, b"abc\r\n89");
     }
 
     #[test]
     fn test_parse_integer() {
-        let (remaining_input, output) = parse_integer(":100\r\n").unwrap();
-        assert_eq!(remaining_input, "");
+        let (remaining_input, output) = parse_integer(b":100\r\n").unwrap();
+        assert!(remaining_input.is_empty());
         assert_eq!(output, 100);
-        let (remaining_input, output) = parse_integer(":-100\r\n").unwrap();
-        assert_eq!(remaining_input, "");
+        let (remaining_input, output) = parse_integer(b":-100\r\n").unwrap();
+        assert!(remaining_input.is_empty());
         assert_eq!(output, -100);
     }
 
     #[test]
     fn test_parse_array_of_integers() {
-        let (remaining_input, output) = parse_array("*3\r\n:1\r\n:2\r\n:3\r\n").unwrap();
-        assert_eq!(remaining_input, "");
+        let (remaining_input, output) = parse_array(b"*3\r\n:1\r\n:2\r\n:3\r\n").unwrap();
+        assert!(remaining_input.is_empty());
         assert_eq!(output, vec![Integer(1), Integer(2), Integer(3)]);
     }
 
+    #[test]
+    fn test_parse_binary_bulk_string() {
+        let (remaining_input, output) = parse_bulk_string(b"$3\r\n\xff\x00\x80\r\n").unwrap();
+        assert!(remaining_input.is_empty());
+        assert_eq!(output, b"\xff\x00\x80");
+    }
+
+    #[test]
+    fn test_incomplete_and_invalid() {
+        let request = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
+        for len in 0..request.len() {
+            assert!(matches!(
+                parse_resp(&request[..len]),
+                Err(nom::Err::Incomplete(_))
+            ));
+        }
+        let invalid = b"*2\r\n$4\r\nECHO\r\nhey\r\n";
+        let Err(nom::Err::Error(e)) = parse_resp(invalid) else {
+            panic!("parsed");
+        };
+        assert_eq!(
+            protocol_error(invalid, e.input),
+            "unexpected 'h' at offset 14"
+        );
+        assert!(matches!(
+            parse_resp(b"*1\r\n$999999999999\r\n"),
+            Err(nom::Err::Error(_))
+        ));
+        let invalid = b"*1\r\n$4\r\nPINGX\r\n";
+        let Err(nom::Err::Error(e)) = parse_resp(invalid) else {
+            panic!("parsed");
+        };
+        assert_eq!(
+            protocol_error(invalid, e.input),
+            "unexpected 'X' at offset 12"
+        );
+        assert!(matches!(parse_resp(b"PING\r\n"), Err(nom::Err::Error(_))));
+    }
+
     #[test]
     fn test_parse_resp_echo() {
-        let (remaining_input, output) = parse_array("*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n").unwrap();
-        assert_eq!(remaining_input, "");
+        let (remaining_input, output) = parse_array(b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n").unwrap();
+        assert!(remaining_input.is_empty());
         assert_eq!(
             output,
             vec![
-                Type::String(Cow::from("ECHO"), StrType::Bulk),
-                Type::String(Cow::from("hey"), StrType::Bulk)
+                Type::String(Cow::from(&b"ECHO"[..]), StrType::Bulk),
+                Type::String(Cow::from(&b"hey"[..]), StrType::Bulk)
             ]
         );
     }
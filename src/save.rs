@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rdb;
+use crate::reply::Reply;
+use crate::scripting::Scripts;
+use crate::value::Value;
+
+/// The `save` setting when none is given: snapshot after an hour if a key
+/// changed, after 5 minutes if 100 did, or after a minute if 10000 did.
+pub const DEFAULT_SAVE_POINTS: &str = "3600 1 300 100 60 10000";
+
+/// Seconds to wait before retrying a failed automatic snapshot.
+const RETRY_DELAY: u64 = 5;
+
+const IN_PROGRESS: &str = "Background save already in progress";
+
+/// Snapshot bookkeeping shared by the connections and the cron thread.
+pub struct Snapshots {
+    // writes since the last successful save
+    dirty: AtomicU64,
+    status: Mutex<Status>,
+}
+
+struct Status {
+    // unix time of the last successful save, or of startup
+    last_save: u64,
+    last_attempt: u64,
+    last_ok: bool,
+    // the writes the running BGSAVE will account for once done
+    in_progress: Option<u64>,
+}
+
+pub type SnapshotState = Arc<Snapshots>;
+
+/// Parses `save` as <seconds> <changes> pairs; an empty string disables
+/// automatic snapshots.
+pub fn parse_save_points(save: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = save
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Snapshots {
+            dirty: AtomicU64::new(0),
+            status: Mutex::new(Status {
+                last_save: unix_time(),
+                last_attempt: 0,
+                last_ok: true,
+                in_progress: None,
+            }),
+        }
+    }
+}
+
+impl Snapshots {
+    /// Counts writes towards the save points.
+    pub fn add_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    /// LASTSAVE.
+    pub fn last_save(&self) -> Reply<'static> {
+        Reply::Integer(self.status.lock().unwrap().last_save as i64)
+    }
+
+    /// Whether a save point has been reached: enough seconds since the last
+    /// save and enough writes since. After a failure, the next attempt
+    /// waits a few seconds even if a save point is reached.
+    pub fn should_save(&self, points: &[(u64, u64)]) -> bool {
+        let status = self.status.lock().unwrap();
+        let now = unix_time();
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        status.in_progress.is_none()
+            && (status.last_ok || now.saturating_sub(status.last_attempt) >= RETRY_DELAY)
+            && points.iter().any(|&(seconds, changes)| {
+                dirty >= changes && now.saturating_sub(status.last_save) >= seconds
+            })
+    }
+
+    /// SAVE: writes the snapshot before replying, with the keyspace locked
+    /// by the caller.
+    pub fn save(
+        &self,
+        state: &[HashMap<Vec<u8>, Value>],
+        durations: &[HashMap<Vec<u8>, Instant>],
+        scripts: &Scripts,
+        path: PathBuf,
+        compress: bool,
+    ) -> Reply<'static> {
+        let mut status = self.status.lock().unwrap();
+        if status.in_progress.is_some() {
+            return Reply::Error(IN_PROGRESS);
+        }
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        let snapshot = rdb::snapshot(state, durations, scripts.library_codes());
+        let result = rdb::save_to_rdb(&path, &snapshot, compress);
+        self.finish(&mut status, dirty, result.is_ok());
+        match result {
+            Ok(()) => Reply::Simple("OK".to_string()),
+            Err(e) => Reply::ErrorCode("ERR", format!("Error saving the RDB file: {}", e)),
+        }
+    }
+
+    /// BGSAVE: copies the dataset while the caller holds the keyspace, and
+    /// writes the copy from another thread.
+    pub fn bgsave(
+        self: &Arc<Self>,
+        state: &[HashMap<Vec<u8>, Value>],
+        durations: &[HashMap<Vec<u8>, Instant>],
+        scripts: &Scripts,
+        path: PathBuf,
+        compress: bool,
+    ) -> Reply<'static> {
+        let mut status = self.status.lock().unwrap();
+        if status.in_progress.is_some() {
+            return Reply::Error(IN_PROGRESS);
+        }
+        let dirty = self.dirty.load(Ordering::Relaxed);
+        status.in_progress = Some(dirty);
+        let snapshot = rdb::snapshot(state, durations, scripts.library_codes());
+        let snapshots = Arc::clone(self);
+        std::thread::spawn(move || {
+            let result = rdb::save_to_rdb(&path, &snapshot, compress);
+            if let Err(e) = &result {
+                eprintln!("Background saving error: {}", e);
+            }
+            let mut status = snapshots.status.lock().unwrap();
+            status.in_progress = None;
+            snapshots.finish(&mut status, dirty, result.is_ok());
+        });
+        Reply::Simple("Background saving started".to_string())
+    }
+
+    /// Records the outcome of a save of the dataset as it was after `dirty`
+    /// writes; writes made since still count towards the next one.
+    fn finish(&self, status: &mut Status, dirty: u64, ok: bool) {
+        let now = unix_time();
+        status.last_attempt = now;
+        status.last_ok = ok;
+        if ok {
+            status.last_save = now;
+            self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zset::SortedSet;
+
+    #[test]
+    fn test_parse_save_points() {
+        assert_eq!(
+            parse_save_points(DEFAULT_SAVE_POINTS),
+            Some(vec![(3600, 1), (300, 100), (60, 10000)])
+        );
+        assert_eq!(parse_save_points(""), Some(vec![]));
+        assert_eq!(parse_save_points("10"), None);
+        assert_eq!(parse_save_points("10 x"), None);
+    }
+
+    #[test]
+    fn test_should_save() {
+        let snapshots = Snapshots::default();
+        assert!(!snapshots.should_save(&[(0, 1)]));
+        snapshots.add_dirty(2);
+        assert!(snapshots.should_save(&[(0, 1)]));
+        assert!(!snapshots.should_save(&[(0, 3), (3600, 1)]));
+
+        let mut status = snapshots.status.lock().unwrap();
+        snapshots.finish(&mut status, 1, true);
+        drop(status);
+        // the write made after the snapshot was taken is still pending
+        assert!(snapshots.should_save(&[(0, 1)]));
+        assert!(!snapshots.should_save(&[(0, 2)]));
+    }
+
+    #[test]
+    fn test_save_binary_keys() {
+        let dir = std::env::temp_dir().join(format!("save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut zset = SortedSet::new();
+        zset.insert(b"\xfe", 1.0);
+        let entries = [
+            (b"\xff", Value::String(b"v".to_vec().into())),
+            (b"\xfe", Value::ZSet(zset.into())),
+        ];
+        let loaded = rdb::Rdb {
+            entries: entries
+                .iter()
+                .map(|(key, value)| rdb::Entry {
+                    db: 0,
+                    key: key.to_vec(),
+                    value: value.clone(),
+                    expire_at: None,
+                })
+                .collect(),
+            ..rdb::Rdb::default()
+        };
+        let path = dir.join("loaded.rdb");
+        rdb::save_to_rdb(&path, &loaded, false).unwrap();
+
+        let mut state = vec![HashMap::new()];
+        let mut durations = vec![HashMap::new()];
+        let scripts = Scripts::default();
+        rdb::load_from_rdb(&path, &mut state, &mut durations, &scripts, true).unwrap();
+        let path = dir.join("saved.rdb");
+        let reply = Snapshots::default().save(&state, &durations, &scripts, path.clone(), true);
+        assert!(!reply.is_error());
+
+        let (saved, _) = rdb::parse_rdb(&std::fs::read(&path).unwrap(), true).unwrap();
+        assert_eq!(saved.entries.len(), 2);
+        for (key, value) in entries {
+            assert!(saved
+                .entries
+                .iter()
+                .any(|entry| entry.key == key && entry.value == value));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,952 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::command::Command;
+use crate::functions::{self, FunctionInfo, Functions, Library, RestorePolicy};
+use crate::lua::{self, Interp, LuaError, LuaResult, Native, Proto, Table, Value};
+use crate::reply::Reply;
+use crate::sha1::sha1_hex;
+
+/// Scripts are reported as busy to other clients after running this long.
+pub const BUSY_THRESHOLD: Duration = Duration::from_millis(5000);
+
+pub type ScriptState = Arc<Scripts>;
+
+/// Runs a command a script calls. Scripts run on a thread of their own (see
+/// `lua::on_stack`), so it is sent there.
+pub type Run<'a> = dyn FnMut(Command, Vec<Vec<u8>>) -> Reply<'static> + Send + 'a;
+
+/// Where EVAL finds its script: the source, or the SHA1 of a cached one.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Script {
+    Source(Vec<u8>),
+    Sha(String),
+}
+
+/// The script cache, the function libraries, and the script currently
+/// running if any. Scripts run while holding the keyspace lock, so at most
+/// one runs at a time.
+#[derive(Default)]
+pub struct Scripts {
+    cache: Mutex<HashMap<String, Arc<Proto>>>,
+    functions: Mutex<Functions>,
+    running: Mutex<Option<Running>>,
+    // set by SCRIPT KILL, checked by the running script
+    kill: AtomicBool,
+}
+
+struct Running {
+    started: Instant,
+    wrote: bool,
+    // FCALL_RO, or a function flagged no-writes
+    read_only: bool,
+}
+
+impl Scripts {
+    /// Compiles and caches a script, returning its SHA1.
+    pub fn load(&self, source: &[u8]) -> Result<(String, Arc<Proto>), Reply<'static>> {
+        let sha = sha1_hex(source);
+        if let Some(proto) = self.cache.lock().unwrap().get(&sha) {
+            return Ok((sha, Arc::clone(proto)));
+        }
+        let proto = lua::on_stack(|| lua::parse(source)).map_err(|(line, msg)| {
+            Reply::ErrorCode(
+                "ERR",
+                format!(
+                    "Error compiling script (new function): user_script:{}: {}",
+                    line, msg
+                ),
+            )
+        })?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(sha.clone(), Arc::clone(&proto));
+        Ok((sha, proto))
+    }
+
+    pub fn exists(&self, shas: &[String]) -> Reply<'static> {
+        let cache = self.cache.lock().unwrap();
+        Reply::Nested(
+            shas.iter()
+                .map(|sha| Reply::Integer(cache.contains_key(&sha.to_ascii_lowercase()) as i64))
+                .collect(),
+        )
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Whether a script has been running for longer than `threshold`.
+    pub fn busy(&self, threshold: Duration) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|running| running.started.elapsed() >= threshold)
+    }
+
+    /// SCRIPT KILL: stops the running script unless it already wrote, as
+    /// that would break atomicity.
+    pub fn kill(&self) -> Reply<'static> {
+        match &*self.running.lock().unwrap() {
+            None => Reply::ErrorCode("NOTBUSY", "No scripts in execution right now.".to_string()),
+            Some(running) if running.wrote => Reply::ErrorCode(
+                "UNKILLABLE",
+                "Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.".to_string(),
+            ),
+            Some(_) => {
+                self.kill.store(true, Ordering::Relaxed);
+                Reply::Simple("OK".to_string())
+            }
+        }
+    }
+
+    /// EVAL and EVALSHA. `run` executes the commands the script calls,
+    /// against the keyspace locked by the caller.
+    pub fn eval(
+        &self,
+        script: Script,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+        run: &mut Run,
+    ) -> Reply<'static> {
+        let found = match script {
+            Script::Source(source) => self.load(&source),
+            Script::Sha(sha) => {
+                let sha = sha.to_ascii_lowercase();
+                match self.cache.lock().unwrap().get(&sha) {
+                    Some(proto) => Ok((sha, Arc::clone(proto))),
+                    None => Err(Reply::ErrorCode(
+                        "NOSCRIPT",
+                        "No matching script. Please use EVAL.".to_string(),
+                    )),
+                }
+            }
+        };
+        let (sha, proto) = match found {
+            Ok(found) => found,
+            Err(e) => return e,
+        };
+
+        self.run_script("user_script", &sha, false, run, |interp| {
+            let globals = vec![
+                (
+                    "KEYS",
+                    Value::table(Table::from_array(
+                        keys.into_iter().map(Value::str).collect(),
+                    )),
+                ),
+                (
+                    "ARGV",
+                    Value::table(Table::from_array(
+                        args.into_iter().map(Value::str).collect(),
+                    )),
+                ),
+            ];
+            sandbox(interp, redis_table(true), globals);
+            let main = interp.load(proto);
+            interp.call(&main, vec![])
+        })
+    }
+
+    /// FCALL and FCALL_RO.
+    pub fn fcall(
+        &self,
+        function: &str,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+        run: &mut Run,
+    ) -> Reply<'static> {
+        let found = self
+            .functions
+            .lock()
+            .unwrap()
+            .find(function)
+            .map(|(library, info)| (Arc::clone(&library.proto), info.no_writes()));
+        let Some((proto, no_writes)) = found else {
+            return Reply::ErrorCode("ERR", "Function not found".to_string());
+        };
+        if read_only && !no_writes {
+            return Reply::ErrorCode(
+                "ERR",
+                "Can not execute a script with write flag using *_ro command.".to_string(),
+            );
+        }
+        self.run_script(
+            "user_function",
+            function,
+            read_only || no_writes,
+            run,
+            |interp| {
+                // the library is run again to get at the function's closure
+                let callback = register(interp, proto, true)?
+                    .into_iter()
+                    .find(|(info, _)| info.name == function)
+                    .map(|(_, callback)| callback)
+                    .unwrap_or_default();
+                let keys = Table::from_array(keys.into_iter().map(Value::str).collect());
+                let args = Table::from_array(args.into_iter().map(Value::str).collect());
+                interp.call(&callback, vec![Value::table(keys), Value::table(args)])
+            },
+        )
+    }
+
+    /// Runs a script or function as the running script, reporting errors
+    /// with its name and the line that raised them.
+    fn run_script(
+        &self,
+        chunkname: &str,
+        name: &str,
+        read_only: bool,
+        run: &mut Run,
+        body: impl FnOnce(&mut Interp) -> LuaResult<Vec<Value>> + Send,
+    ) -> Reply<'static> {
+        self.kill.store(false, Ordering::Relaxed);
+        *self.running.lock().unwrap() = Some(Running {
+            started: Instant::now(),
+            wrote: false,
+            read_only,
+        });
+        let reply = lua::on_stack(|| {
+            let mut host = |args: Vec<Vec<u8>>| self.call(args, run);
+            let mut interp = Interp::new(chunkname);
+            interp.host = Some(&mut host);
+            interp.kill = Some(&self.kill);
+            let result = body(&mut interp);
+            let line = interp.line;
+            drop(interp);
+            match result {
+                Ok(values) => to_reply(values.into_iter().next().unwrap_or_default()),
+                Err(LuaError::Error(e)) => {
+                    let msg = match error_field(&e, "err") {
+                        Some(msg) => msg,
+                        None => format!("ERR {}", String::from_utf8_lossy(&lua::to_display(&e))),
+                    };
+                    Reply::ErrorString(format!(
+                        "{} script: {}, on @{}:{}.",
+                        msg, name, chunkname, line
+                    ))
+                }
+                Err(LuaError::Abort(msg)) => Reply::ErrorString(format!("ERR {}", msg)),
+            }
+        });
+        *self.running.lock().unwrap() = None;
+        reply
+    }
+
+    /// FUNCTION LOAD: runs the library to find the functions it registers,
+    /// returning the library's name.
+    pub fn function_load(&self, code: &[u8], replace: bool) -> Result<String, String> {
+        let library = compile_library(code)?;
+        self.functions.lock().unwrap().add(library, replace)
+    }
+
+    pub fn function_list(&self, pattern: Option<&str>, with_code: bool) -> Reply<'static> {
+        self.functions.lock().unwrap().list(pattern, with_code)
+    }
+
+    pub fn function_delete(&self, library: &str) -> Reply<'static> {
+        match self.functions.lock().unwrap().delete(library) {
+            true => Reply::Simple("OK".to_string()),
+            false => Reply::ErrorCode("ERR", "Library not found".to_string()),
+        }
+    }
+
+    /// The code of the loaded libraries, for RDB snapshots.
+    pub fn library_codes(&self) -> Vec<Vec<u8>> {
+        self.functions.lock().unwrap().codes()
+    }
+
+    pub fn function_dump(&self, compress: bool) -> Reply<'static> {
+        Reply::BulkBytes(self.functions.lock().unwrap().dump(compress))
+    }
+
+    /// FUNCTION RESTORE. Either every library in the payload is restored,
+    /// or none is.
+    pub fn function_restore(&self, payload: &[u8], policy: RestorePolicy) -> Reply<'static> {
+        let codes = match functions::parse_dump(payload) {
+            Ok(codes) => codes,
+            Err(msg) => return Reply::ErrorCode("ERR", msg.to_string()),
+        };
+        let mut restored = match policy {
+            RestorePolicy::Flush => Functions::default(),
+            _ => self.functions.lock().unwrap().clone(),
+        };
+        for code in codes {
+            let added = compile_library(&code)
+                .and_then(|library| restored.add(library, policy == RestorePolicy::Replace));
+            if let Err(msg) = added {
+                return Reply::ErrorCode("ERR", msg);
+            }
+        }
+        *self.functions.lock().unwrap() = restored;
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn function_flush(&self) {
+        self.functions.lock().unwrap().flush();
+    }
+
+    /// Runs a command for `redis.call`, converting the reply for Lua.
+    fn call(&self, args: Vec<Vec<u8>>, run: &mut Run) -> Value {
+        let command = match Command::from_args(&args) {
+            Ok(command) if !allowed_in_script(&command) => {
+                return error_table("ERR This Redis command is not allowed from script".to_string())
+            }
+            Ok(command) => command,
+            Err("Unrecognized command") => {
+                return error_table("ERR Unknown Redis command called from script".to_string())
+            }
+            Err(msg) => return error_table(format!("ERR {}", msg)),
+        };
+        if command.is_write() {
+            if let Some(running) = &mut *self.running.lock().unwrap() {
+                if running.read_only {
+                    return error_table(
+                        "ERR Write commands are not allowed from read-only scripts.".to_string(),
+                    );
+                }
+                running.wrote = true;
+            }
+        }
+        to_value(run(command, args))
+    }
+}
+
+fn allowed_in_script(command: &Command) -> bool {
+    !matches!(
+        command,
+        Command::Subscribe(..)
+            | Command::Unsubscribe(..)
+            | Command::Hello(_)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Unwatch
+            | Command::Eval { .. }
+            | Command::ScriptLoad(_)
+            | Command::ScriptExists(_)
+            | Command::ScriptFlush
+            | Command::ScriptKill
+            | Command::FunctionLoad { .. }
+            | Command::FunctionList { .. }
+            | Command::FunctionDelete(_)
+            | Command::FunctionDump
+            | Command::FunctionRestore(..)
+            | Command::FunctionFlush
+            | Command::FCall { .. }
+            | Command::Save
+            | Command::BgSave
+            | Command::BgRewriteAof
+            | Command::Shutdown(_)
+            | Command::ReplConf(_)
+            | Command::Psync(..)
+            | Command::ReplicaOf(_)
+            | Command::Wait(..)
+            | Command::WaitAof(..)
+            | Command::Cluster(_)
+            | Command::Asking
+    )
+}
+
+/// Compiles a library and runs it once to collect its functions.
+fn compile_library(code: &[u8]) -> Result<Library, String> {
+    lua::on_stack(|| compile_library_on_stack(code))
+}
+
+fn compile_library_on_stack(code: &[u8]) -> Result<Library, String> {
+    let (name, body) = functions::parse_metadata(code)?;
+    let proto = lua::parse(&body).map_err(|(line, msg)| {
+        format!("Error compiling function: user_function:{}: {}", line, msg)
+    })?;
+    let mut interp = Interp::new("user_function");
+    let functions = match register(&mut interp, Arc::clone(&proto), false) {
+        Ok(registered) => registered
+            .into_iter()
+            .map(|(info, _)| info)
+            .collect::<Vec<_>>(),
+        Err(LuaError::Error(e)) => {
+            return Err(format!(
+                "Error registering functions: {}",
+                String::from_utf8_lossy(&lua::to_display(&e))
+            ))
+        }
+        Err(LuaError::Abort(msg)) => return Err(msg),
+    };
+    if functions.is_empty() {
+        return Err("No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code: code.to_vec(),
+        proto,
+        functions,
+    })
+}
+
+/// Runs a library's code, returning the functions it registered along with
+/// their callbacks. `redis.call` is only available when running a function,
+/// not while loading the library.
+fn register(
+    interp: &mut Interp,
+    proto: Arc<Proto>,
+    calls: bool,
+) -> LuaResult<Vec<(FunctionInfo, Value)>> {
+    let registry = Value::table(Table::default());
+    let mut redis = redis_table(calls);
+    redis.readonly = false;
+    redis.set_str(
+        "register_function",
+        Value::native_with(
+            "register_function",
+            register_function,
+            vec![registry.clone()],
+        ),
+    );
+    redis.readonly = true;
+    sandbox(interp, redis, vec![]);
+    let main = interp.load(proto);
+    interp.call(&main, vec![])?;
+
+    let Value::Table(registry) = registry else {
+        unreachable!()
+    };
+    let registry = registry.borrow();
+    let registered = registry
+        .array()
+        .iter()
+        .map(|entry| {
+            let Value::Table(entry) = entry else {
+                unreachable!()
+            };
+            let entry = entry.borrow();
+            let string = |v: Value| {
+                v.to_bytes()
+                    .map(|s| String::from_utf8_lossy(&s).into_owned())
+            };
+            let flags = match entry.get_str("flags") {
+                Value::Table(flags) => flags
+                    .borrow()
+                    .array()
+                    .iter()
+                    .filter_map(|f| string(f.clone()))
+                    .collect(),
+                _ => vec![],
+            };
+            let info = FunctionInfo {
+                name: string(entry.get_str("name")).unwrap_or_default(),
+                description: string(entry.get_str("description")),
+                flags,
+            };
+            (info, entry.get_str("callback"))
+        })
+        .collect();
+    Ok(registered)
+}
+
+/// `redis.register_function(name, callback)`, or with a table of
+/// function_name, callback, flags and description.
+fn register_function(
+    interp: &mut Interp,
+    native: &Native,
+    args: Vec<Value>,
+) -> LuaResult<Vec<Value>> {
+    let (name, callback, flags, description) = match args.as_slice() {
+        [name, callback] => (name.clone(), callback.clone(), Value::Nil, Value::Nil),
+        [Value::Table(t)] => {
+            let t = t.borrow();
+            let mut key = Value::Nil;
+            while let Some((k, _)) = t.next(&key).filter(|(k, _)| !k.is_nil()) {
+                match k.to_bytes().as_deref() {
+                    Some(b"function_name" | b"callback" | b"flags" | b"description") => {}
+                    _ => {
+                        return Err(
+                            interp.error("unknown argument given to redis.register_function")
+                        )
+                    }
+                }
+                key = k;
+            }
+            (
+                t.get_str("function_name"),
+                t.get_str("callback"),
+                t.get_str("flags"),
+                t.get_str("description"),
+            )
+        }
+        _ => return Err(interp.error("wrong number of arguments to redis.register_function")),
+    };
+    let name = match name {
+        Value::Str(s) if functions::valid_name(&String::from_utf8_lossy(&s)) => s,
+        Value::Nil => return Err(interp.error("redis.register_function must get a function name argument")),
+        _ => return Err(interp.error("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long")),
+    };
+    if !matches!(callback, Value::Function(_)) {
+        return Err(interp.error("redis.register_function must get a callback argument"));
+    }
+    let flags = match flags {
+        Value::Nil => Table::default(),
+        Value::Table(t) => {
+            let flags = t.borrow().array().to_vec();
+            for flag in &flags {
+                let known = flag.to_bytes().is_some_and(|f| {
+                    functions::FUNCTION_FLAGS
+                        .iter()
+                        .any(|known| known.as_bytes() == &*f)
+                });
+                if !known {
+                    return Err(interp.error("unknown flag given"));
+                }
+            }
+            Table::from_array(flags)
+        }
+        _ => return Err(interp.error(
+            "flags argument to redis.register_function must be a table representing function flags",
+        )),
+    };
+    if !matches!(description, Value::Nil | Value::Str(_)) {
+        return Err(
+            interp.error("description argument given to redis.register_function must be a string")
+        );
+    }
+
+    let Value::Table(registry) = &native.upvalues.borrow()[0] else {
+        unreachable!()
+    };
+    let mut registry = registry.borrow_mut();
+    let exists = registry.array().iter().any(|entry| match entry {
+        Value::Table(entry) => entry.borrow().get_str("name").to_bytes() == Some(Rc::clone(&name)),
+        _ => false,
+    });
+    if exists {
+        return Err(interp.error("Function already exists in the library"));
+    }
+    let mut entry = Table::default();
+    entry.set_str("name", Value::Str(name));
+    entry.set_str("callback", callback);
+    entry.set_str("flags", Value::table(flags));
+    entry.set_str("description", description);
+    let n = registry.len();
+    registry.set(Value::Number((n + 1) as f64), Value::table(entry));
+    Ok(vec![])
+}
+
+/// The `redis` table. `call` and `pcall` are left out while loading a
+/// function library.
+fn redis_table(calls: bool) -> Table {
+    let mut redis = Table::default();
+    let functions: [(&'static str, lua::NativeFn); 8] = [
+        ("call", redis_call),
+        ("pcall", redis_pcall),
+        ("error_reply", |_, _, args| {
+            Ok(vec![status_table("err", &lua::arg(&args, 0))])
+        }),
+        ("status_reply", |_, _, args| {
+            Ok(vec![status_table("ok", &lua::arg(&args, 0))])
+        }),
+        ("sha1hex", |interp, native, args| {
+            let s = lua::check_str(interp, native, &args, 0)?;
+            Ok(vec![Value::str(sha1_hex(&s))])
+        }),
+        ("log", redis_log),
+        ("replicate_commands", |_, _, _| {
+            Ok(vec![Value::Boolean(true)])
+        }),
+        ("set_repl", |_, _, _| Ok(vec![])),
+    ];
+    for (name, f) in functions {
+        if calls || !matches!(name, "call" | "pcall") {
+            redis.set_str(name, Value::native(name, f));
+        }
+    }
+    for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+        .into_iter()
+        .enumerate()
+    {
+        redis.set_str(level, Value::Number(i as f64));
+    }
+    for (i, repl) in ["REPL_NONE", "REPL_SLAVE", "REPL_AOF", "REPL_ALL"]
+        .into_iter()
+        .enumerate()
+    {
+        redis.set_str(repl, Value::Number([0, 1, 2, 3][i] as f64));
+    }
+    redis.readonly = true;
+    redis
+}
+
+/// Sets up the globals a script sees: the standard libraries, `redis`, and
+/// `globals` such as `KEYS` and `ARGV`. The libraries and the globals table
+/// are read-only, and reading an undefined global is an error rather than
+/// nil.
+fn sandbox(interp: &mut Interp, redis: Table, globals: Vec<(&str, Value)>) {
+    lua::open(interp);
+
+    let mut meta = Table::default();
+    meta.set_str("__index", Value::native("__index", undefined_global));
+
+    let mut table = interp.globals.borrow_mut();
+    table.set_str("redis", Value::table(redis));
+    for (name, value) in globals {
+        table.set_str(name, value);
+    }
+    for name in ["string", "table", "math"] {
+        if let Value::Table(lib) = table.get_str(name) {
+            lib.borrow_mut().readonly = true;
+        }
+    }
+    table.metatable = Some(Rc::new(std::cell::RefCell::new(meta)));
+    table.readonly = true;
+}
+
+fn undefined_global(interp: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let name = lua::to_display(&lua::arg(&args, 1));
+    Err(interp.error(format!(
+        "Script attempted to access nonexistent global variable '{}'",
+        String::from_utf8_lossy(&name)
+    )))
+}
+
+fn redis_call(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    let result = redis_pcall(interp, native, args)?;
+    match &result[0] {
+        e if error_field(e, "err").is_some() => Err(LuaError::Error(e.clone())),
+        _ => Ok(result),
+    }
+}
+
+fn redis_pcall(interp: &mut Interp, _: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Err(interp.error("Please specify at least one argument for this redis lib call"));
+    }
+    let mut command = Vec::with_capacity(args.len());
+    for arg in &args {
+        match arg {
+            Value::Str(_) | Value::Number(_) => command.push(arg.to_bytes().unwrap().to_vec()),
+            _ => {
+                return Err(
+                    interp.error("Lua redis lib command arguments must be strings or integers")
+                )
+            }
+        }
+    }
+    let host = interp.host.as_mut().expect("scripts run with a host");
+    Ok(vec![host(command)])
+}
+
+fn redis_log(interp: &mut Interp, native: &Native, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+    if args.len() < 2 {
+        return Err(interp.error("redis.log() requires two arguments or more."));
+    }
+    let level = match args[0] {
+        Value::Number(n) if (0.0..=3.0).contains(&n) => n,
+        _ => return Err(interp.error("Invalid debug level.")),
+    };
+    let mut message = Vec::new();
+    for i in 1..args.len() {
+        if i > 1 {
+            message.push(b' ');
+        }
+        message.extend_from_slice(&lua::check_str(interp, native, &args, i)?);
+    }
+    let marker = ['.', '-', '*', '#'][level as usize];
+    println!("{} {}", marker, String::from_utf8_lossy(&message));
+    Ok(vec![])
+}
+
+// {err = "..."} or {ok = "..."}, as built by redis.error_reply and
+// redis.status_reply
+fn status_table(field: &str, message: &Value) -> Value {
+    let mut table = Table::default();
+    table.set_str(field, message.clone());
+    Value::table(table)
+}
+
+fn error_table(message: String) -> Value {
+    status_table("err", &Value::str(message))
+}
+
+/// The string in `field` of a status or error table.
+fn error_field(value: &Value, field: &str) -> Option<String> {
+    match value {
+        Value::Table(t) => match t.borrow().get_str(field) {
+            Value::Str(s) => Some(String::from_utf8_lossy(&s).into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Converts a command's reply for the script, following the RESP2 rules:
+/// nulls become false, status and error replies become tables with an
+/// `ok` or `err` field.
+pub fn to_value(reply: Reply) -> Value {
+    match reply {
+        Reply::Integer(n) => Value::Number(n as f64),
+        Reply::Bulk(s) | Reply::Echo(s) => Value::str(s),
+        Reply::BulkBytes(b) => Value::str(b),
+        Reply::Simple(s) => status_table("ok", &Value::str(s)),
+        Reply::Pong => status_table("ok", &Value::str("PONG")),
+        Reply::Error(msg) => error_table(format!("ERR {}", msg)),
+        Reply::ErrorCode(code, msg) => error_table(format!("{} {}", code, msg)),
+        Reply::ErrorString(msg) => error_table(msg),
+        Reply::Null | Reply::NullBulk | Reply::NullArray => Value::Boolean(false),
+        Reply::Array(v) => Value::table(Table::from_array(v.into_iter().map(Value::str).collect())),
+        Reply::Nested(v) | Reply::Push(v) | Reply::Many(v) => {
+            Value::table(Table::from_array(v.into_iter().map(to_value).collect()))
+        }
+        Reply::Map(pairs) => Value::table(Table::from_array(
+            pairs
+                .into_iter()
+                .flat_map(|(k, v)| [Value::str(k), to_value(v)])
+                .collect(),
+        )),
+    }
+}
+
+/// Converts a script's return value to a reply: numbers are truncated to
+/// integers, tables become arrays up to their first nil.
+pub fn to_reply(value: Value) -> Reply<'static> {
+    match value {
+        Value::Number(n) => Reply::Integer(n as i64),
+        Value::Str(s) => Reply::BulkBytes(s.to_vec()),
+        Value::Boolean(true) => Reply::Integer(1),
+        Value::Table(ref t) => {
+            if let Some(msg) = error_field(&value, "err") {
+                return Reply::ErrorString(msg);
+            }
+            if let Some(msg) = error_field(&value, "ok") {
+                return Reply::Simple(msg);
+            }
+            let items = t.borrow().array().to_vec();
+            Reply::Nested(items.into_iter().map(to_reply).collect())
+        }
+        Value::Nil | Value::Boolean(false) | Value::Function(_) => Reply::NullBulk,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(scripts: &Scripts, source: &str, keys: &[&str], args: &[&str]) -> Vec<u8> {
+        let keys = keys.iter().map(|k| k.as_bytes().to_vec()).collect();
+        let args = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+        // commands are echoed back instead of being run
+        let mut run = |command: Command, _| match command {
+            Command::Get(key) => Reply::BulkBytes(key),
+            Command::Set(..) => Reply::Simple("OK".to_string()),
+            _ => Reply::ErrorCode("WRONGTYPE", "wrong".to_string()),
+        };
+        scripts
+            .eval(
+                Script::Source(source.as_bytes().to_vec()),
+                keys,
+                args,
+                &mut run,
+            )
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_conversions() {
+        let scripts = Scripts::default();
+        assert_eq!(
+            eval(
+                &scripts,
+                "return {1, 2.9, 'a', true, false, {ok='fine'}}",
+                &[],
+                &[]
+            ),
+            b"*6\r\n:1\r\n:2\r\n$1\r\na\r\n:1\r\n$-1\r\n+fine\r\n"
+        );
+        assert_eq!(
+            eval(
+                &scripts,
+                "return redis.call('get', KEYS[1]) .. ARGV[1]",
+                &["k"],
+                &["v"]
+            ),
+            b"$2\r\nkv\r\n"
+        );
+        assert_eq!(
+            eval(&scripts, "return redis.call('set', 'k', 'v')", &[], &[]),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            eval(&scripts, "return redis.error_reply('MY oops')", &[], &[]),
+            b"-MY oops\r\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let scripts = Scripts::default();
+        let sha = sha1_hex(b"return redis.call('xlen', 'k')");
+        assert_eq!(
+            String::from_utf8(eval(&scripts, "return redis.call('xlen', 'k')", &[], &[])).unwrap(),
+            format!("-WRONGTYPE wrong script: {}, on @user_script:1.\r\n", sha)
+        );
+        let reply = eval(
+            &scripts,
+            "local t = redis.pcall('xlen', 'k')\nreturn t.err",
+            &[],
+            &[],
+        );
+        assert_eq!(reply, b"$15\r\nWRONGTYPE wrong\r\n");
+        let reply = String::from_utf8(eval(&scripts, "\nx = 1", &[], &[])).unwrap();
+        assert!(reply.starts_with("-ERR user_script:2: Attempt to modify a readonly table script:"));
+        let reply = String::from_utf8(eval(&scripts, "return y", &[], &[])).unwrap();
+        assert!(reply.contains("Script attempted to access nonexistent global variable 'y'"));
+        assert_eq!(
+            eval(&scripts, "return (", &[], &[]),
+            b"-ERR Error compiling script (new function): user_script:1: unexpected symbol near '<eof>'\r\n"
+        );
+        assert_eq!(
+            eval(&scripts, "return redis.call('multi')", &[], &[])[..50],
+            b"-ERR This Redis command is not allowed from script"[..]
+        );
+        // however deep, on the stack scripts run with
+        let reply = eval(
+            &scripts,
+            "local function f(n) return f(n + 1) + 1 end return f(1)",
+            &[],
+            &[],
+        );
+        assert!(reply.starts_with(b"-ERR user_script:1: stack overflow script:"));
+        let source = format!(
+            "local function f(n) return {}f(n + 1){} end return f(1)",
+            "(".repeat(150),
+            ")".repeat(150)
+        );
+        let reply = eval(&scripts, &source, &[], &[]);
+        assert!(reply.starts_with(b"-ERR user_script:1: stack overflow script:"));
+        let source = format!("return {}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(
+            eval(&scripts, &source, &[], &[]),
+            b"-ERR Error compiling script (new function): user_script:1: chunk has too many syntax levels\r\n"
+        );
+    }
+
+    #[test]
+    fn test_stdlib() {
+        let scripts = Scripts::default();
+        let cases: &[(&str, &[u8])] = &[
+            ("return string.format('%05.1f|%-3d|%x|%q', 3.14159, 7, 255, 'a\"b')", b"$19\r\n003.1|7  |ff|\"a\\\"b\"\r\n"),
+            ("local s = 0 for i = 1, 10 do s = s + i end return s", b":55\r\n"),
+            ("local t = {} for w in string.gmatch('a,b,c', '[^,]+') do t[#t+1] = w:upper() end return table.concat(t, '-')", b"$5\r\nA-B-C\r\n"),
+            ("return (string.gsub('hello world', 'o', '0'))", b"$11\r\nhell0 w0rld\r\n"),
+            ("local t = {3, 1, 2} table.sort(t, function(a, b) return a > b end) return t", b"*3\r\n:3\r\n:2\r\n:1\r\n"),
+            ("local function fib(n) if n < 2 then return n end return fib(n-1) + fib(n-2) end return fib(15)", b":610\r\n"),
+            ("local ok, e = pcall(function() error({code = 7}) end) return {tostring(ok), e.code}", b"*2\r\n$5\r\nfalse\r\n:7\r\n"),
+            ("return select('#', unpack({1, 2, 3}))", b":3\r\n"),
+            ("return tonumber('0x10') + tonumber('z', 36)", b":51\r\n"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(eval(&scripts, source, &[], &[]), *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_cache_and_kill() {
+        let scripts = Scripts::default();
+        let (sha, _) = scripts.load(b"return 1").unwrap();
+        assert_eq!(
+            scripts
+                .exists(&[sha.to_uppercase(), "0".repeat(40)])
+                .into_bytes(),
+            b"*2\r\n:1\r\n:0\r\n"
+        );
+        let mut run = |_, _| Reply::Null;
+        let reply = scripts.eval(Script::Sha(sha), vec![], vec![], &mut run);
+        assert_eq!(reply.into_bytes(), b":1\r\n");
+        scripts.flush();
+        let reply = scripts.eval(Script::Sha("abc".to_string()), vec![], vec![], &mut run);
+        assert_eq!(
+            reply.into_bytes(),
+            b"-NOSCRIPT No matching script. Please use EVAL.\r\n"
+        );
+        assert_eq!(
+            scripts.kill().into_bytes(),
+            b"-NOTBUSY No scripts in execution right now.\r\n"
+        );
+        // the script's own command stands in for a concurrent SCRIPT KILL
+        let mut run = |_, _| {
+            scripts.kill.store(true, Ordering::Relaxed);
+            Reply::Null
+        };
+        let reply = scripts.eval(
+            Script::Source(b"redis.call('get', 'x') while true do end".to_vec()),
+            vec![],
+            vec![],
+            &mut run,
+        );
+        assert_eq!(
+            reply.into_bytes(),
+            b"-ERR Script killed by user with SCRIPT KILL...\r\n"
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let scripts = Scripts::default();
+        let code = b"#!lua name=lib\n\
+            local function get(keys, args) return redis.call('get', keys[1]) end\n\
+            redis.register_function('get', get)\n\
+            redis.register_function{function_name='ro', callback=get, flags={'no-writes'}}\n\
+            redis.register_function('put', function(keys) return redis.call('set', keys[1], 'v') end)";
+        assert_eq!(scripts.function_load(code, false), Ok("lib".to_string()));
+        assert_eq!(
+            scripts.function_load(b"#!lua name=x\nlocal a = 1", false),
+            Err("No functions registered".to_string())
+        );
+        assert_eq!(
+            scripts.function_load(b"#!lua name=x\nredis.call('get', 'k')", false),
+            Err("Error registering functions: user_function:2: attempt to call field 'call' (a nil value)".to_string())
+        );
+
+        let mut run = |command: Command, _| match command {
+            Command::Get(key) => Reply::BulkBytes(key),
+            _ => Reply::Simple("OK".to_string()),
+        };
+        let mut fcall = |function: &str, read_only: bool| {
+            scripts
+                .fcall(function, vec![b"k".to_vec()], vec![], read_only, &mut run)
+                .into_bytes()
+        };
+        assert_eq!(fcall("get", false), b"$1\r\nk\r\n");
+        assert_eq!(fcall("ro", true), b"$1\r\nk\r\n");
+        assert_eq!(fcall("put", false), b"+OK\r\n");
+        assert_eq!(
+            fcall("put", true),
+            b"-ERR Can not execute a script with write flag using *_ro command.\r\n"
+        );
+        assert_eq!(fcall("nope", false), b"-ERR Function not found\r\n");
+
+        let dump = scripts.function_dump(true).into_bytes();
+        let payload =
+            dump[dump.iter().position(|&b| b == b'\n').unwrap() + 1..dump.len() - 2].to_vec();
+        scripts.function_flush();
+        assert_eq!(fcall("get", false), b"-ERR Function not found\r\n");
+        assert_eq!(
+            scripts
+                .function_restore(&payload, RestorePolicy::Append)
+                .into_bytes(),
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            scripts
+                .function_restore(&payload, RestorePolicy::Append)
+                .into_bytes(),
+            b"-ERR Library 'lib' already exists\r\n"
+        );
+        assert_eq!(fcall("get", false), b"$1\r\nk\r\n");
+    }
+}
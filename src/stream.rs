@@ -0,0 +1,1690 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::listpack::{Elem, Listpack};
+use crate::rax::{Rax, Seek};
+use crate::reply::{Reply, WRONGTYPE};
+use crate::value::Value;
+
+// Entries are stored in listpacks ("nodes") indexed by the ID of their first
+// entry (the master ID). Each node starts with a master entry:
+//
+// count | deleted | num-fields | field_1 | ... | field_N | 0
+//
+// followed by the entries, each of them being:
+//
+// flags | ms-diff | seq-diff | num-fields | field_1 | value_1 | ... | lp-count
+//
+// where num-fields and the field names are omitted when the SAMEFIELDS flag is
+// set (the entry has exactly the master fields).
+const STREAM_NODE_MAX_BYTES: usize = 4096;
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const FLAG_NONE: i64 = 0;
+const FLAG_DELETED: i64 = 1;
+const FLAG_SAMEFIELDS: i64 = 2;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut buf = [0; 16];
+        buf[..8].copy_from_slice(&self.ms.to_be_bytes());
+        buf[8..].copy_from_slice(&self.seq.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        StreamId {
+            ms: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+            seq: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+        }
+    }
+
+    pub fn incr(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    pub fn decr(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+
+    /// Parses `<ms>-<seq>` or `<ms>`, in which case the sequence part is
+    /// `missing_seq`.
+    pub fn parse(s: &str, missing_seq: u64) -> Option<Self> {
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(StreamId::new(s.parse().ok()?, missing_seq)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum XAddId {
+    Auto,
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TrimStrategy {
+    MaxLen(u64),
+    MinId(StreamId),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StreamTrim {
+    pub strategy: TrimStrategy,
+    pub approx: bool,
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum XReadId {
+    // `$`: only entries added after the call
+    Last,
+    // `>`: entries never delivered to the consumer group
+    New,
+    After(StreamId),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PendingRange {
+    pub min_idle: Option<u64>,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ClaimOptions {
+    pub idle: Option<u64>,
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+    pub last_id: Option<StreamId>,
+}
+
+pub type StreamEntry = (StreamId, Vec<(Vec<u8>, Vec<u8>)>);
+
+struct NodeEntry {
+    id: StreamId,
+    flags_off: usize,
+    deleted: bool,
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Stream {
+    pub rax: Rax<Listpack>,
+    pub length: u64,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_entry_id: StreamId,
+    pub entries_added: u64,
+    pub cgroups: Rax<ConsumerGroup>,
+}
+
+/// An entry delivered to a consumer and not yet acknowledged.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Consumer {
+    pub seen_time: u64,
+    // unset until the consumer is delivered (or claims) an entry
+    pub active_time: Option<u64>,
+    // IDs of the entries in the group PEL owned by this consumer
+    pub pel: Rax<()>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    // `None` when the number of entries read can't be known (e.g. the last
+    // delivered ID was set arbitrarily)
+    pub entries_read: Option<u64>,
+    pub pel: Rax<PendingEntry>,
+    pub consumers: Rax<Consumer>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn append(
+        &mut self,
+        id: XAddId,
+        fields: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match id {
+            XAddId::Auto => {
+                let now = now_ms();
+                if now > last.ms {
+                    StreamId::new(now, 0)
+                } else {
+                    last.incr().ok_or(
+                        "The stream has exhausted the last possible ID, unable to add more items",
+                    )?
+                }
+            }
+            XAddId::AutoSeq(ms) if ms == last.ms => {
+                if last.seq == u64::MAX {
+                    return Err(XADD_ID_TOO_SMALL);
+                }
+                StreamId::new(ms, last.seq + 1)
+            }
+            XAddId::AutoSeq(ms) => StreamId::new(ms, 0),
+            XAddId::Explicit(id) => id,
+        };
+        if id <= last {
+            return Err(XADD_ID_TOO_SMALL);
+        }
+
+        let entry_size: usize = fields.iter().map(|(f, v)| f.len() + v.len()).sum();
+        let tail = self.rax.last().and_then(|(key, lp)| {
+            let (count, deleted) = node_counters(lp);
+            if lp.bytes() + entry_size >= STREAM_NODE_MAX_BYTES
+                || (count + deleted) as usize >= STREAM_NODE_MAX_ENTRIES
+            {
+                None
+            } else {
+                Some(key)
+            }
+        });
+        let master_key = match tail {
+            Some(key) => key,
+            None => {
+                let mut lp = Listpack::new();
+                lp.append_int(0);
+                lp.append_int(0);
+                lp.append_int(fields.len() as i64);
+                for (field, _) in fields {
+                    lp.append(field.as_slice());
+                }
+                lp.append_int(0);
+                self.rax.insert(&id.to_bytes(), lp);
+                id.to_bytes().to_vec()
+            }
+        };
+        let master_id = StreamId::from_bytes(&master_key);
+        let lp = self.rax.get_mut(&master_key).unwrap();
+
+        let same_fields = master_fields(lp).len() == fields.len()
+            && master_fields(lp)
+                .iter()
+                .zip(fields)
+                .all(|(m, (f, _))| m.as_slice() == f.as_slice());
+        let n = fields.len() as i64;
+        if same_fields {
+            lp.append_int(FLAG_SAMEFIELDS);
+        } else {
+            lp.append_int(FLAG_NONE);
+        }
+        lp.append_int(id.ms.wrapping_sub(master_id.ms) as i64);
+        lp.append_int(id.seq.wrapping_sub(master_id.seq) as i64);
+        if same_fields {
+            for (_, value) in fields {
+                lp.append(value.as_slice());
+            }
+            lp.append_int(n + 3);
+        } else {
+            lp.append_int(n);
+            for (field, value) in fields {
+                lp.append(field.as_slice());
+                lp.append(value.as_slice());
+            }
+            lp.append_int(n + 3 + n + 1);
+        }
+        let (count, deleted) = node_counters(lp);
+        set_node_counters(lp, count + 1, deleted);
+
+        self.length += 1;
+        self.entries_added += 1;
+        self.last_id = id;
+        if self.length == 1 {
+            self.first_id = id;
+        }
+        Ok(id)
+    }
+
+    /// Entries with IDs between `start` and `end` (both inclusive), in
+    /// reverse order when `rev` is set.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<StreamEntry> {
+        let mut result = Vec::new();
+        if start > end || count == Some(0) {
+            return result;
+        }
+        let limit = count.unwrap_or(usize::MAX);
+        let mut node = if rev {
+            self.rax.seek(&end.to_bytes(), Seek::Le)
+        } else {
+            self.rax
+                .seek(&start.to_bytes(), Seek::Le)
+                .or_else(|| self.rax.first())
+        };
+        while let Some((key, lp)) = node {
+            let mut entries = decode_node(StreamId::from_bytes(&key), lp);
+            if rev {
+                entries.reverse();
+            }
+            for entry in entries {
+                if entry.deleted || (!rev && entry.id < start) || (rev && entry.id > end) {
+                    continue;
+                }
+                if (!rev && entry.id > end) || (rev && entry.id < start) {
+                    return result;
+                }
+                result.push(to_stream_entry(entry));
+                if result.len() >= limit {
+                    return result;
+                }
+            }
+            node = self.rax.seek(&key, if rev { Seek::Lt } else { Seek::Gt });
+        }
+        result
+    }
+
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        let key = match self.rax.seek(&id.to_bytes(), Seek::Le) {
+            Some((key, _)) => key,
+            None => return false,
+        };
+        let lp = self.rax.get_mut(&key).unwrap();
+        let entry = decode_node(StreamId::from_bytes(&key), lp)
+            .into_iter()
+            .find(|e| e.id == id && !e.deleted);
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return false,
+        };
+        mark_deleted(lp, &entry);
+        let (count, deleted) = node_counters(lp);
+        set_node_counters(lp, count - 1, deleted + 1);
+        if count == 1 {
+            self.rax.remove(&key);
+        }
+        self.length -= 1;
+        if id > self.max_deleted_entry_id {
+            self.max_deleted_entry_id = id;
+        }
+        if id == self.first_id {
+            self.update_first_id();
+        }
+        true
+    }
+
+    /// Trims the stream, returning the number of evicted entries. Approximate
+    /// trimming only ever removes whole nodes.
+    pub fn trim(&mut self, trim: StreamTrim) -> u64 {
+        let limit = match (trim.approx, trim.limit) {
+            (true, None) => (100 * STREAM_NODE_MAX_ENTRIES) as u64,
+            (true, Some(limit)) => limit,
+            (false, _) => 0,
+        };
+        let mut deleted = 0u64;
+        while let Some((key, lp)) = self.rax.first() {
+            if let TrimStrategy::MaxLen(maxlen) = trim.strategy {
+                if self.length <= maxlen {
+                    break;
+                }
+            }
+            let entries = node_counters(lp).0;
+            if limit != 0 && deleted + entries > limit {
+                break;
+            }
+            let remove_node = match trim.strategy {
+                TrimStrategy::MaxLen(maxlen) => self.length - entries >= maxlen,
+                TrimStrategy::MinId(min_id) => {
+                    let last = decode_node(StreamId::from_bytes(&key), lp)
+                        .last()
+                        .map(|e| e.id)
+                        .unwrap_or(StreamId::MIN);
+                    last < min_id
+                }
+            };
+            if remove_node {
+                self.rax.remove(&key);
+                self.length -= entries;
+                deleted += entries;
+                continue;
+            }
+            if trim.approx {
+                break;
+            }
+
+            let lp = self.rax.get_mut(&key).unwrap();
+            let mut evicted = 0;
+            for entry in decode_node(StreamId::from_bytes(&key), lp) {
+                if entry.deleted {
+                    continue;
+                }
+                let done = match trim.strategy {
+                    TrimStrategy::MaxLen(maxlen) => self.length <= maxlen,
+                    TrimStrategy::MinId(min_id) => entry.id >= min_id,
+                };
+                if done {
+                    break;
+                }
+                mark_deleted(lp, &entry);
+                self.length -= 1;
+                evicted += 1;
+            }
+            // counters are updated last: resizing them shifts the entry offsets
+            let (count, node_deleted) = node_counters(lp);
+            set_node_counters(lp, count - evicted, node_deleted + evicted);
+            deleted += evicted;
+            break;
+        }
+        if deleted > 0 {
+            self.update_first_id();
+        }
+        deleted
+    }
+
+    pub fn update_first_id(&mut self) {
+        self.first_id = self
+            .range(StreamId::MIN, StreamId::MAX, Some(1), false)
+            .first()
+            .map(|(id, _)| *id)
+            .unwrap_or(StreamId::MIN);
+    }
+}
+
+impl Stream {
+    pub fn get(&self, id: StreamId) -> Option<StreamEntry> {
+        self.range(id, id, Some(1), false).pop()
+    }
+
+    /// Number of entries added to the stream before (and including) `id`,
+    /// when it can be deduced without scanning the stream.
+    pub fn estimate_distance_from_first_ever_entry(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.length == 0 && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&self.last_id) {
+            std::cmp::Ordering::Equal => return Some(self.entries_added),
+            std::cmp::Ordering::Greater => return None,
+            std::cmp::Ordering::Less => {}
+        }
+        if self.max_deleted_entry_id == StreamId::MIN || self.max_deleted_entry_id < self.first_id {
+            // there are no tombstones after the first entry
+            match id.cmp(&self.first_id) {
+                std::cmp::Ordering::Less => return Some(self.entries_added - self.length),
+                std::cmp::Ordering::Equal => return Some(self.entries_added - self.length + 1),
+                std::cmp::Ordering::Greater => {}
+            }
+        }
+        None
+    }
+
+    pub fn range_has_tombstones(&self, start: StreamId, end: StreamId) -> bool {
+        if self.length == 0 || self.max_deleted_entry_id == StreamId::MIN {
+            return false;
+        }
+        if self.first_id > self.max_deleted_entry_id {
+            return false;
+        }
+        start <= self.max_deleted_entry_id && end >= self.max_deleted_entry_id
+    }
+
+    /// Number of entries in the stream not yet delivered to the group.
+    pub fn group_lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(read) if !self.range_has_tombstones(group.last_id, StreamId::MAX) => read,
+            _ => self.estimate_distance_from_first_ever_entry(group.last_id)?,
+        };
+        Some(self.entries_added.saturating_sub(entries_read))
+    }
+
+    pub fn create_group(
+        &mut self,
+        name: &str,
+        last_id: StreamId,
+        entries_read: Option<u64>,
+    ) -> bool {
+        if self.cgroups.get(name.as_bytes()).is_some() {
+            return false;
+        }
+        self.cgroups.insert(
+            name.as_bytes(),
+            ConsumerGroup {
+                last_id,
+                entries_read,
+                pel: Rax::new(),
+                consumers: Rax::new(),
+            },
+        );
+        true
+    }
+
+    /// Removes a group so it can be updated alongside the stream entries; it
+    /// must be put back with `insert_group`.
+    fn take_group(&mut self, name: &str) -> Option<ConsumerGroup> {
+        self.cgroups.remove(name.as_bytes())
+    }
+
+    fn insert_group(&mut self, name: &str, group: ConsumerGroup) {
+        self.cgroups.insert(name.as_bytes(), group);
+    }
+}
+
+impl ConsumerGroup {
+    /// Looks up a consumer, creating it if needed; returns whether it was
+    /// created.
+    pub fn touch_consumer(&mut self, name: &str, now: u64) -> bool {
+        match self.consumers.get_mut(name.as_bytes()) {
+            Some(consumer) => {
+                consumer.seen_time = now;
+                false
+            }
+            None => {
+                let consumer = Consumer {
+                    seen_time: now,
+                    ..Consumer::default()
+                };
+                self.consumers.insert(name.as_bytes(), consumer);
+                true
+            }
+        }
+    }
+
+    /// Gives ownership of a (possibly already pending) entry to `consumer`.
+    pub fn assign(
+        &mut self,
+        id: StreamId,
+        consumer: &str,
+        delivery_time: u64,
+        delivery_count: u64,
+    ) {
+        let key = id.to_bytes();
+        let old = self.pel.insert(
+            &key,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count,
+            },
+        );
+        if let Some(old) = old {
+            if let Some(c) = self.consumers.get_mut(old.consumer.as_bytes()) {
+                c.pel.remove(&key);
+            }
+        }
+        if let Some(c) = self.consumers.get_mut(consumer.as_bytes()) {
+            c.pel.insert(&key, ());
+        }
+    }
+
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let key = id.to_bytes();
+        match self.pel.remove(&key) {
+            Some(pending) => {
+                if let Some(c) = self.consumers.get_mut(pending.consumer.as_bytes()) {
+                    c.pel.remove(&key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Deletes a consumer and its pending entries, returning how many
+    /// entries it had pending.
+    pub fn delete_consumer(&mut self, name: &str) -> Option<u64> {
+        let consumer = self.consumers.remove(name.as_bytes())?;
+        for (key, _) in consumer.pel.iter() {
+            self.pel.remove(&key);
+        }
+        Some(consumer.pel.len() as u64)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+const XADD_ID_TOO_SMALL: &str =
+    "The ID specified in XADD is equal or smaller than the target stream top item";
+
+fn node_counters(lp: &Listpack) -> (u64, u64) {
+    let mut iter = lp.iter();
+    let count = iter.next().and_then(Elem::as_int).unwrap_or(0);
+    let deleted = iter.next().and_then(Elem::as_int).unwrap_or(0);
+    (count as u64, deleted as u64)
+}
+
+fn set_node_counters(lp: &mut Listpack, count: u64, deleted: u64) {
+    let first = lp.first().unwrap();
+    let next = lp.replace(first, Elem::Int(count as i64)).unwrap();
+    lp.replace(next, Elem::Int(deleted as i64));
+}
+
+fn master_fields(lp: &Listpack) -> Vec<Vec<u8>> {
+    let mut iter = lp.iter().skip(2);
+    let n = iter.next().and_then(Elem::as_int).unwrap_or(0) as usize;
+    iter.take(n).map(Elem::to_vec).collect()
+}
+
+fn mark_deleted(lp: &mut Listpack, entry: &NodeEntry) {
+    let flags = lp.get(entry.flags_off).as_int().unwrap_or(0);
+    lp.replace(entry.flags_off, Elem::Int(flags | FLAG_DELETED));
+}
+
+fn decode_node(master_id: StreamId, lp: &Listpack) -> Vec<NodeEntry> {
+    let int = |off: usize| lp.get(off).as_int().unwrap_or(0);
+    let mut entries = Vec::new();
+    let mut cur = lp.first().and_then(|o| lp.next(o)).and_then(|o| lp.next(o));
+    let num_master = cur.map(int).unwrap_or(0) as usize;
+    let mut master = Vec::with_capacity(num_master);
+    cur = cur.and_then(|o| lp.next(o));
+    for _ in 0..num_master {
+        let off = cur.unwrap();
+        master.push(lp.get(off).to_vec());
+        cur = lp.next(off);
+    }
+    // skip the master entry terminator
+    cur = cur.and_then(|o| lp.next(o));
+
+    while let Some(flags_off) = cur {
+        let flags = int(flags_off);
+        let ms_off = lp.next(flags_off).unwrap();
+        let seq_off = lp.next(ms_off).unwrap();
+        let id = StreamId::new(
+            master_id.ms.wrapping_add(int(ms_off) as u64),
+            master_id.seq.wrapping_add(int(seq_off) as u64),
+        );
+        let mut off = lp.next(seq_off).unwrap();
+        let mut fields = Vec::new();
+        if flags & FLAG_SAMEFIELDS != 0 {
+            for field in &master {
+                fields.push((field.clone(), lp.get(off).to_vec()));
+                off = lp.next(off).unwrap();
+            }
+        } else {
+            let n = int(off);
+            off = lp.next(off).unwrap();
+            for _ in 0..n {
+                let value_off = lp.next(off).unwrap();
+                fields.push((lp.get(off).to_vec(), lp.get(value_off).to_vec()));
+                off = lp.next(value_off).unwrap();
+            }
+        }
+        // `off` is now at lp-count
+        entries.push(NodeEntry {
+            id,
+            flags_off,
+            deleted: flags & FLAG_DELETED != 0,
+            fields,
+        });
+        cur = lp.next(off);
+    }
+    entries
+}
+
+/// Whether a listpack loaded from outside has the layout of a stream node,
+/// with counters that match its entries, so that decoding it can't fail.
+pub fn validate_node(lp: &Listpack) -> bool {
+    fn int(elem: Option<Elem>) -> Option<usize> {
+        elem.and_then(Elem::as_int)
+            .and_then(|i| usize::try_from(i).ok())
+    }
+    let mut elems = lp.iter();
+    let (Some(count), Some(deleted), Some(num_master)) =
+        (int(elems.next()), int(elems.next()), int(elems.next()))
+    else {
+        return false;
+    };
+    if elems.by_ref().take(num_master).count() != num_master || int(elems.next()) != Some(0) {
+        return false;
+    }
+
+    let (mut valid, mut tombstones) = (0, 0);
+    while let Some(flags) = elems.next() {
+        let Some(flags) = flags.as_int() else {
+            return false;
+        };
+        // the ID deltas
+        if elems.next().and_then(Elem::as_int).is_none()
+            || elems.next().and_then(Elem::as_int).is_none()
+        {
+            return false;
+        }
+        let (values, lp_count) = if flags & FLAG_SAMEFIELDS != 0 {
+            (num_master, num_master + 3)
+        } else {
+            let Some(n) = int(elems.next()).and_then(|n| n.checked_mul(2)) else {
+                return false;
+            };
+            (n, n + 4)
+        };
+        if elems.by_ref().take(values).count() != values || int(elems.next()) != Some(lp_count) {
+            return false;
+        }
+        if flags & FLAG_DELETED != 0 {
+            tombstones += 1;
+        } else {
+            valid += 1;
+        }
+    }
+    valid == count && tombstones == deleted
+}
+
+fn to_stream_entry(entry: NodeEntry) -> StreamEntry {
+    (entry.id, entry.fields)
+}
+
+fn entry_reply(entry: StreamEntry) -> Reply<'static> {
+    let (id, fields) = entry;
+    let flat = fields
+        .into_iter()
+        .flat_map(|(f, v)| [f, v])
+        .map(Reply::BulkBytes)
+        .collect();
+    Reply::Nested(vec![Reply::Bulk(id.to_string()), Reply::Nested(flat)])
+}
+
+fn entries_reply(entries: Vec<StreamEntry>) -> Reply<'static> {
+    Reply::Nested(entries.into_iter().map(entry_reply).collect())
+}
+
+fn get_stream<'a>(
+    state: &'a HashMap<Vec<u8>, Value>,
+    key: &[u8],
+) -> Result<Option<&'a Stream>, Reply<'static>> {
+    match state.get(key) {
+        None => Ok(None),
+        Some(Value::Stream(s)) => Ok(Some(s)),
+        Some(_) => Err(Reply::ErrorCode("WRONGTYPE", WRONGTYPE.to_string())),
+    }
+}
+
+fn get_stream_mut<'a>(
+    state: &'a mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+) -> Result<Option<&'a mut Stream>, Reply<'static>> {
+    match state.get_mut(key) {
+        None => Ok(None),
+        Some(Value::Stream(s)) => Ok(Some(s)),
+        Some(_) => Err(Reply::ErrorCode("WRONGTYPE", WRONGTYPE.to_string())),
+    }
+}
+
+pub fn xadd(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: Vec<u8>,
+    id: XAddId,
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+    nomkstream: bool,
+    trim: Option<StreamTrim>,
+) -> Reply<'static> {
+    if let Err(reply) = get_stream(state, &key) {
+        return reply;
+    }
+    if !state.contains_key(&key) {
+        if nomkstream {
+            return Reply::NullBulk;
+        }
+        state.insert(key.clone(), Value::Stream(Default::default()));
+    }
+    let stream = match state.get_mut(&key) {
+        Some(Value::Stream(s)) => s,
+        _ => unreachable!(),
+    };
+    match stream.append(id, &fields) {
+        Ok(id) => {
+            if let Some(trim) = trim {
+                stream.trim(trim);
+            }
+            Reply::Bulk(id.to_string())
+        }
+        Err(msg) => {
+            if stream.len() == 0 {
+                state.remove(&key);
+            }
+            Reply::Error(msg)
+        }
+    }
+}
+
+pub fn xrange(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    start: StreamId,
+    end: StreamId,
+    count: Option<usize>,
+    rev: bool,
+) -> Reply<'static> {
+    match get_stream(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Nested(vec![]),
+        Ok(Some(_)) if count == Some(0) => Reply::NullArray,
+        Ok(Some(s)) => entries_reply(s.range(start, end, count, rev)),
+    }
+}
+
+pub fn xlen(state: &HashMap<Vec<u8>, Value>, key: &[u8]) -> Reply<'static> {
+    match get_stream(state, key) {
+        Err(reply) => reply,
+        Ok(s) => Reply::Integer(s.map(|s| s.len() as i64).unwrap_or(0)),
+    }
+}
+
+pub fn xtrim(state: &mut HashMap<Vec<u8>, Value>, key: &[u8], trim: StreamTrim) -> Reply<'static> {
+    match get_stream_mut(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Integer(0),
+        Ok(Some(s)) => Reply::Integer(s.trim(trim) as i64),
+    }
+}
+
+pub fn xdel(state: &mut HashMap<Vec<u8>, Value>, key: &[u8], ids: &[StreamId]) -> Reply<'static> {
+    match get_stream_mut(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Integer(0),
+        Ok(Some(s)) => Reply::Integer(ids.iter().filter(|id| s.delete(**id)).count() as i64),
+    }
+}
+
+/// Replaces `$` with the current last ID of each stream, so that a blocked
+/// XREAD only returns entries added after it was called.
+pub fn resolve_xread_ids(
+    state: &HashMap<Vec<u8>, Value>,
+    keys: &[Vec<u8>],
+    ids: &[XReadId],
+) -> Result<Vec<StreamId>, Reply<'static>> {
+    keys.iter()
+        .zip(ids)
+        .map(|(key, id)| match id {
+            XReadId::After(id) => Ok(*id),
+            XReadId::Last | XReadId::New => Ok(get_stream(state, key)?
+                .map(|s| s.last_id())
+                .unwrap_or(StreamId::MIN)),
+        })
+        .collect()
+}
+
+/// Returns `None` when none of the streams has entries after the given IDs.
+pub fn xread(
+    state: &HashMap<Vec<u8>, Value>,
+    keys: &[Vec<u8>],
+    ids: &[StreamId],
+    count: Option<usize>,
+) -> Result<Option<Reply<'static>>, Reply<'static>> {
+    let mut result = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let stream = match get_stream(state, key)? {
+            Some(s) => s,
+            None => continue,
+        };
+        let start = match id.incr() {
+            Some(start) => start,
+            None => continue,
+        };
+        let entries = stream.range(start, StreamId::MAX, count, false);
+        if !entries.is_empty() {
+            result.push(Reply::Nested(vec![
+                Reply::BulkBytes(key.clone()),
+                entries_reply(entries),
+            ]));
+        }
+    }
+    if result.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Reply::Nested(result)))
+    }
+}
+
+fn no_group(key: &[u8], group: &str) -> Reply<'static> {
+    Reply::ErrorCode(
+        "NOGROUP",
+        format!(
+            "No such key '{}' or consumer group '{}'",
+            String::from_utf8_lossy(key),
+            group
+        ),
+    )
+}
+
+fn no_group_for_key(key: &[u8], group: &str) -> Reply<'static> {
+    Reply::ErrorCode(
+        "NOGROUP",
+        format!(
+            "No such consumer group '{}' for key name '{}'",
+            group,
+            String::from_utf8_lossy(key)
+        ),
+    )
+}
+
+const XGROUP_NO_KEY: &str = "The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+fn resolve_group_id(stream: &Stream, id: XReadId) -> StreamId {
+    match id {
+        XReadId::After(id) => id,
+        XReadId::Last | XReadId::New => stream.last_id,
+    }
+}
+
+pub fn xgroup_create(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    id: XReadId,
+    mkstream: bool,
+    entries_read: Option<u64>,
+) -> Reply<'static> {
+    match get_stream(state, key) {
+        Err(reply) => return reply,
+        Ok(None) if !mkstream => return Reply::Error(XGROUP_NO_KEY),
+        Ok(None) => {
+            state.insert(key.to_vec(), Value::Stream(Default::default()));
+        }
+        Ok(Some(_)) => {}
+    }
+    let stream = get_stream_mut(state, key).ok().flatten().unwrap();
+    let id = resolve_group_id(stream, id);
+    if stream.create_group(group, id, entries_read) {
+        Reply::Simple("OK".to_string())
+    } else {
+        Reply::ErrorCode(
+            "BUSYGROUP",
+            "Consumer Group name already exists".to_string(),
+        )
+    }
+}
+
+pub fn xgroup_setid(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    id: XReadId,
+    entries_read: Option<u64>,
+) -> Reply<'static> {
+    let stream = match get_stream_mut(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Error(XGROUP_NO_KEY),
+        Ok(Some(s)) => s,
+    };
+    let id = resolve_group_id(stream, id);
+    match stream.cgroups.get_mut(group.as_bytes()) {
+        Some(g) => {
+            g.last_id = id;
+            g.entries_read = entries_read;
+            Reply::Simple("OK".to_string())
+        }
+        None => no_group_for_key(key, group),
+    }
+}
+
+pub fn xgroup_destroy(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+) -> Reply<'static> {
+    match get_stream_mut(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Error(XGROUP_NO_KEY),
+        Ok(Some(s)) => Reply::Integer(s.cgroups.remove(group.as_bytes()).is_some() as i64),
+    }
+}
+
+pub fn xgroup_createconsumer(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    consumer: &str,
+) -> Reply<'static> {
+    match get_stream_mut(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Error(XGROUP_NO_KEY),
+        Ok(Some(s)) => match s.cgroups.get_mut(group.as_bytes()) {
+            Some(g) => Reply::Integer(g.touch_consumer(consumer, now_ms()) as i64),
+            None => no_group_for_key(key, group),
+        },
+    }
+}
+
+pub fn xgroup_delconsumer(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    consumer: &str,
+) -> Reply<'static> {
+    match get_stream_mut(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Error(XGROUP_NO_KEY),
+        Ok(Some(s)) => match s.cgroups.get_mut(group.as_bytes()) {
+            Some(g) => Reply::Integer(g.delete_consumer(consumer).unwrap_or(0) as i64),
+            None => no_group_for_key(key, group),
+        },
+    }
+}
+
+/// Serves XREADGROUP; returns `None` when only new entries (`>`) were asked
+/// for and there are none, so the caller may block.
+#[allow(clippy::too_many_arguments)]
+pub fn xreadgroup(
+    state: &mut HashMap<Vec<u8>, Value>,
+    group: &str,
+    consumer: &str,
+    keys: &[Vec<u8>],
+    ids: &[XReadId],
+    count: Option<usize>,
+    noack: bool,
+) -> Result<Option<Reply<'static>>, Reply<'static>> {
+    let no_group = |key: &[u8]| {
+        Reply::ErrorCode(
+            "NOGROUP",
+            format!(
+                "No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                String::from_utf8_lossy(key),
+                group
+            ),
+        )
+    };
+    for key in keys {
+        match get_stream(state, key)? {
+            Some(s) if s.cgroups.get(group.as_bytes()).is_some() => {}
+            _ => return Err(no_group(key)),
+        }
+    }
+
+    let now = now_ms();
+    let mut result = Vec::new();
+    for (key, id) in keys.iter().zip(ids) {
+        let stream = get_stream_mut(state, key)?.unwrap();
+        let mut g = stream.take_group(group).unwrap();
+        g.touch_consumer(consumer, now);
+        let entries = match id {
+            XReadId::After(start) => {
+                let pending: Vec<StreamId> = g
+                    .consumers
+                    .get(consumer.as_bytes())
+                    .unwrap()
+                    .pel
+                    .iter_from(&start.to_bytes(), Seek::Gt)
+                    .take(count.unwrap_or(usize::MAX))
+                    .map(|(key, _)| StreamId::from_bytes(&key))
+                    .collect();
+                let mut entries = Vec::with_capacity(pending.len());
+                for id in pending {
+                    match stream.get(id) {
+                        // deleted from the stream while pending
+                        None => entries.push(Reply::Nested(vec![
+                            Reply::Bulk(id.to_string()),
+                            Reply::NullArray,
+                        ])),
+                        Some(entry) => {
+                            let pending = g.pel.get_mut(&id.to_bytes()).unwrap();
+                            pending.delivery_time = now;
+                            pending.delivery_count += 1;
+                            entries.push(entry_reply(entry));
+                        }
+                    }
+                }
+                Some(entries)
+            }
+            _ => {
+                let entries = match g.last_id.incr() {
+                    Some(start) => stream.range(start, StreamId::MAX, count, false),
+                    None => Vec::new(),
+                };
+                for (id, _) in &entries {
+                    g.last_id = *id;
+                    g.entries_read = match g.entries_read {
+                        Some(read) if !stream.range_has_tombstones(*id, StreamId::MAX) => {
+                            Some(read + 1)
+                        }
+                        _ => stream.estimate_distance_from_first_ever_entry(*id),
+                    };
+                    if !noack {
+                        g.assign(*id, consumer, now, 1);
+                    }
+                }
+                if entries.is_empty() {
+                    None
+                } else {
+                    g.consumers
+                        .get_mut(consumer.as_bytes())
+                        .unwrap()
+                        .active_time = Some(now);
+                    Some(entries.into_iter().map(entry_reply).collect())
+                }
+            }
+        };
+        stream.insert_group(group, g);
+        if let Some(entries) = entries {
+            result.push(Reply::Nested(vec![
+                Reply::BulkBytes(key.clone()),
+                Reply::Nested(entries),
+            ]));
+        }
+    }
+    if result.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Reply::Nested(result)))
+    }
+}
+
+pub fn xack(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    ids: &[StreamId],
+) -> Reply<'static> {
+    let stream = match get_stream_mut(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Integer(0),
+        Ok(Some(s)) => s,
+    };
+    match stream.cgroups.get_mut(group.as_bytes()) {
+        Some(g) => Reply::Integer(ids.iter().filter(|id| g.ack(**id)).count() as i64),
+        None => Reply::Integer(0),
+    }
+}
+
+pub fn xpending(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    range: Option<PendingRange>,
+) -> Reply<'static> {
+    let stream = match get_stream(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return no_group(key, group),
+        Ok(Some(s)) => s,
+    };
+    let g = match stream.cgroups.get(group.as_bytes()) {
+        Some(g) => g,
+        None => return no_group(key, group),
+    };
+
+    let range = match range {
+        Some(range) => range,
+        None => {
+            if g.pel.is_empty() {
+                return Reply::Nested(vec![
+                    Reply::Integer(0),
+                    Reply::NullBulk,
+                    Reply::NullBulk,
+                    Reply::NullArray,
+                ]);
+            }
+            let first = StreamId::from_bytes(&g.pel.first().unwrap().0);
+            let last = StreamId::from_bytes(&g.pel.last().unwrap().0);
+            let consumers = g
+                .consumers
+                .iter()
+                .filter(|(_, c)| !c.pel.is_empty())
+                .map(|(name, c)| {
+                    Reply::Array(vec![
+                        String::from_utf8_lossy(&name).into_owned(),
+                        c.pel.len().to_string(),
+                    ])
+                })
+                .collect();
+            return Reply::Nested(vec![
+                Reply::Integer(g.pel.len() as i64),
+                Reply::Bulk(first.to_string()),
+                Reply::Bulk(last.to_string()),
+                Reply::Nested(consumers),
+            ]);
+        }
+    };
+
+    let now = now_ms();
+    let ids: Box<dyn Iterator<Item = Vec<u8>>> = match &range.consumer {
+        Some(name) => match g.consumers.get(name.as_bytes()) {
+            Some(c) => Box::new(
+                c.pel
+                    .iter_from(&range.start.to_bytes(), Seek::Ge)
+                    .map(|(key, _)| key),
+            ),
+            None => return Reply::Nested(vec![]),
+        },
+        None => Box::new(
+            g.pel
+                .iter_from(&range.start.to_bytes(), Seek::Ge)
+                .map(|(key, _)| key),
+        ),
+    };
+    let entries = ids
+        .take_while(|key| StreamId::from_bytes(key) <= range.end)
+        .filter_map(|key| {
+            let pending = g.pel.get(&key)?;
+            let idle = now.saturating_sub(pending.delivery_time);
+            if range.min_idle.is_some_and(|min| idle < min) {
+                return None;
+            }
+            Some(Reply::Nested(vec![
+                Reply::Bulk(StreamId::from_bytes(&key).to_string()),
+                Reply::Bulk(pending.consumer.clone()),
+                Reply::Integer(idle as i64),
+                Reply::Integer(pending.delivery_count as i64),
+            ]))
+        })
+        .take(range.count)
+        .collect();
+    Reply::Nested(entries)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn xclaim(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    consumer: &str,
+    min_idle: u64,
+    ids: &[StreamId],
+    options: ClaimOptions,
+) -> Reply<'static> {
+    let stream = match get_stream_mut(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return no_group(key, group),
+        Ok(Some(s)) => s,
+    };
+    let mut g = match stream.take_group(group) {
+        Some(g) => g,
+        None => return no_group(key, group),
+    };
+
+    let now = now_ms();
+    let delivery_time = options
+        .time
+        .or(options.idle.map(|idle| now.saturating_sub(idle)))
+        .filter(|t| *t <= now)
+        .unwrap_or(now);
+    if let Some(last_id) = options.last_id {
+        if last_id > g.last_id {
+            g.last_id = last_id;
+        }
+    }
+
+    let mut claimed = Vec::new();
+    for id in ids {
+        let key = id.to_bytes();
+        let entry = match stream.get(*id) {
+            Some(entry) => entry,
+            None => {
+                // the entry no longer exists, so it can't be pending either
+                g.ack(*id);
+                continue;
+            }
+        };
+        let pending = match g.pel.get(&key) {
+            Some(pending) => pending.clone(),
+            None if options.force => PendingEntry {
+                consumer: String::new(),
+                delivery_time: now,
+                delivery_count: 0,
+            },
+            None => continue,
+        };
+        // entries created by FORCE have no owner and are not subject to min-idle
+        if !pending.consumer.is_empty()
+            && min_idle > 0
+            && now.saturating_sub(pending.delivery_time) < min_idle
+        {
+            continue;
+        }
+        let delivery_count = match options.retry_count {
+            Some(count) => count,
+            None if !options.just_id => pending.delivery_count + 1,
+            None => pending.delivery_count,
+        };
+        g.touch_consumer(consumer, now);
+        g.assign(*id, consumer, delivery_time, delivery_count);
+        g.consumers
+            .get_mut(consumer.as_bytes())
+            .unwrap()
+            .active_time = Some(now);
+        claimed.push(if options.just_id {
+            Reply::Bulk(id.to_string())
+        } else {
+            entry_reply(entry)
+        });
+    }
+    stream.insert_group(group, g);
+    Reply::Nested(claimed)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn xautoclaim(
+    state: &mut HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    group: &str,
+    consumer: &str,
+    min_idle: u64,
+    start: StreamId,
+    count: usize,
+    just_id: bool,
+) -> Reply<'static> {
+    let stream = match get_stream_mut(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return no_group(key, group),
+        Ok(Some(s)) => s,
+    };
+    let mut g = match stream.take_group(group) {
+        Some(g) => g,
+        None => return no_group(key, group),
+    };
+
+    let now = now_ms();
+    let mut attempts = count.saturating_mul(10);
+    let mut remaining = count;
+    let mut claimed = Vec::new();
+    let mut deleted = Vec::new();
+    let mut next = g.pel.seek(&start.to_bytes(), Seek::Ge).map(|(k, _)| k);
+    while let Some(key) = next.take() {
+        if attempts == 0 || remaining == 0 {
+            next = Some(key);
+            break;
+        }
+        attempts -= 1;
+        next = g.pel.seek(&key, Seek::Gt).map(|(k, _)| k);
+        let id = StreamId::from_bytes(&key);
+        let entry = match stream.get(id) {
+            Some(entry) => entry,
+            None => {
+                g.ack(id);
+                deleted.push(id.to_string());
+                remaining -= 1;
+                continue;
+            }
+        };
+        let pending = g.pel.get(&key).unwrap().clone();
+        if min_idle > 0 && now.saturating_sub(pending.delivery_time) < min_idle {
+            continue;
+        }
+        let delivery_count = if just_id {
+            pending.delivery_count
+        } else {
+            pending.delivery_count + 1
+        };
+        g.touch_consumer(consumer, now);
+        g.assign(id, consumer, now, delivery_count);
+        g.consumers
+            .get_mut(consumer.as_bytes())
+            .unwrap()
+            .active_time = Some(now);
+        claimed.push(if just_id {
+            Reply::Bulk(id.to_string())
+        } else {
+            entry_reply((id, entry.1))
+        });
+        remaining -= 1;
+    }
+    stream.insert_group(group, g);
+    let cursor = next.map_or(StreamId::MIN, |k| StreamId::from_bytes(&k));
+    Reply::Nested(vec![
+        Reply::Bulk(cursor.to_string()),
+        Reply::Nested(claimed),
+        Reply::Array(deleted),
+    ])
+}
+
+fn bulk(s: &str) -> Reply<'static> {
+    Reply::Bulk(s.to_string())
+}
+
+fn optional_integer(i: Option<u64>) -> Reply<'static> {
+    i.map_or(Reply::NullBulk, |i| Reply::Integer(i as i64))
+}
+
+fn group_info(
+    stream: &Stream,
+    name: &[u8],
+    g: &ConsumerGroup,
+    full: Option<usize>,
+) -> Reply<'static> {
+    let name = String::from_utf8_lossy(name).into_owned();
+    let limit = match full {
+        Some(0) | None => usize::MAX,
+        Some(count) => count,
+    };
+    let mut info = vec![bulk("name"), Reply::Bulk(name)];
+    if full.is_none() {
+        info.extend([
+            bulk("consumers"),
+            Reply::Integer(g.consumers.len() as i64),
+            bulk("pending"),
+            Reply::Integer(g.pel.len() as i64),
+        ]);
+    }
+    info.extend([
+        bulk("last-delivered-id"),
+        Reply::Bulk(g.last_id.to_string()),
+        bulk("entries-read"),
+        optional_integer(g.entries_read),
+        bulk("lag"),
+        optional_integer(stream.group_lag(g)),
+    ]);
+    if full.is_some() {
+        let pending = g
+            .pel
+            .iter()
+            .take(limit)
+            .map(|(key, p)| {
+                Reply::Nested(vec![
+                    Reply::Bulk(StreamId::from_bytes(&key).to_string()),
+                    Reply::Bulk(p.consumer.clone()),
+                    Reply::Integer(p.delivery_time as i64),
+                    Reply::Integer(p.delivery_count as i64),
+                ])
+            })
+            .collect();
+        let consumers = g
+            .consumers
+            .iter()
+            .map(|(name, c)| {
+                let pending = c
+                    .pel
+                    .iter()
+                    .take(limit)
+                    .filter_map(|(key, _)| {
+                        let p = g.pel.get(&key)?;
+                        Some(Reply::Nested(vec![
+                            Reply::Bulk(StreamId::from_bytes(&key).to_string()),
+                            Reply::Integer(p.delivery_time as i64),
+                            Reply::Integer(p.delivery_count as i64),
+                        ]))
+                    })
+                    .collect();
+                Reply::Nested(vec![
+                    bulk("name"),
+                    Reply::Bulk(String::from_utf8_lossy(&name).into_owned()),
+                    bulk("seen-time"),
+                    Reply::Integer(c.seen_time as i64),
+                    bulk("active-time"),
+                    Reply::Integer(c.active_time.map_or(-1, |t| t as i64)),
+                    bulk("pel-count"),
+                    Reply::Integer(c.pel.len() as i64),
+                    bulk("pending"),
+                    Reply::Nested(pending),
+                ])
+            })
+            .collect();
+        info.extend([
+            bulk("pel-count"),
+            Reply::Integer(g.pel.len() as i64),
+            bulk("pending"),
+            Reply::Nested(pending),
+            bulk("consumers"),
+            Reply::Nested(consumers),
+        ]);
+    }
+    Reply::Nested(info)
+}
+
+/// XINFO STREAM; `full` holds the COUNT of the FULL form (0 meaning all).
+pub fn xinfo_stream(
+    state: &HashMap<Vec<u8>, Value>,
+    key: &[u8],
+    full: Option<usize>,
+) -> Reply<'static> {
+    let stream = match get_stream(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Error("no such key"),
+        Ok(Some(s)) => s,
+    };
+    let mut info = vec![
+        bulk("length"),
+        Reply::Integer(stream.length as i64),
+        bulk("radix-tree-keys"),
+        Reply::Integer(stream.rax.len() as i64),
+        bulk("radix-tree-nodes"),
+        Reply::Integer(stream.rax.node_count() as i64),
+        bulk("last-generated-id"),
+        Reply::Bulk(stream.last_id.to_string()),
+        bulk("max-deleted-entry-id"),
+        Reply::Bulk(stream.max_deleted_entry_id.to_string()),
+        bulk("entries-added"),
+        Reply::Integer(stream.entries_added as i64),
+        bulk("recorded-first-entry-id"),
+        Reply::Bulk(stream.first_id.to_string()),
+    ];
+    match full {
+        None => {
+            let first = stream
+                .range(StreamId::MIN, StreamId::MAX, Some(1), false)
+                .pop();
+            let last = stream
+                .range(StreamId::MIN, StreamId::MAX, Some(1), true)
+                .pop();
+            info.extend([
+                bulk("groups"),
+                Reply::Integer(stream.cgroups.len() as i64),
+                bulk("first-entry"),
+                first.map_or(Reply::NullBulk, entry_reply),
+                bulk("last-entry"),
+                last.map_or(Reply::NullBulk, entry_reply),
+            ]);
+        }
+        Some(count) => {
+            let count = if count == 0 { None } else { Some(count) };
+            let entries = stream.range(StreamId::MIN, StreamId::MAX, count, false);
+            let groups = stream
+                .cgroups
+                .iter()
+                .map(|(name, g)| group_info(stream, &name, g, Some(count.unwrap_or(0))))
+                .collect();
+            info.extend([
+                bulk("entries"),
+                entries_reply(entries),
+                bulk("groups"),
+                Reply::Nested(groups),
+            ]);
+        }
+    }
+    Reply::Nested(info)
+}
+
+pub fn xinfo_groups(state: &HashMap<Vec<u8>, Value>, key: &[u8]) -> Reply<'static> {
+    match get_stream(state, key) {
+        Err(reply) => reply,
+        Ok(None) => Reply::Error("no such key"),
+        Ok(Some(s)) => Reply::Nested(
+            s.cgroups
+                .iter()
+                .map(|(name, g)| group_info(s, &name, g, None))
+                .collect(),
+        ),
+    }
+}
+
+pub fn xinfo_consumers(state: &HashMap<Vec<u8>, Value>, key: &[u8], group: &str) -> Reply<'static> {
+    let stream = match get_stream(state, key) {
+        Err(reply) => return reply,
+        Ok(None) => return Reply::Error("no such key"),
+        Ok(Some(s)) => s,
+    };
+    let g = match stream.cgroups.get(group.as_bytes()) {
+        Some(g) => g,
+        None => return no_group_for_key(key, group),
+    };
+    let now = now_ms();
+    let consumers = g
+        .consumers
+        .iter()
+        .map(|(name, c)| {
+            Reply::Nested(vec![
+                bulk("name"),
+                Reply::Bulk(String::from_utf8_lossy(&name).into_owned()),
+                bulk("pending"),
+                Reply::Integer(c.pel.len() as i64),
+                bulk("idle"),
+                Reply::Integer(now.saturating_sub(c.seen_time) as i64),
+                bulk("inactive"),
+                Reply::Integer(c.active_time.map_or(-1, |t| now.saturating_sub(t) as i64)),
+            ])
+        })
+        .collect();
+    Reply::Nested(consumers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_append_ids() {
+        let mut s = Stream::new();
+        let f = fields(&[("temperature", "36")]);
+        assert_eq!(s.append(XAddId::AutoSeq(0), &f), Ok(StreamId::new(0, 1)));
+        assert_eq!(s.append(XAddId::AutoSeq(0), &f), Ok(StreamId::new(0, 2)));
+        assert_eq!(s.append(XAddId::AutoSeq(5), &f), Ok(StreamId::new(5, 0)));
+        assert_eq!(
+            s.append(XAddId::Explicit(StreamId::new(5, 0)), &f),
+            Err(XADD_ID_TOO_SMALL)
+        );
+        assert!(s.append(XAddId::Auto, &f).unwrap() > StreamId::new(5, 0));
+        assert_eq!(s.len(), 4);
+    }
+
+    #[test]
+    fn test_validate_node() {
+        let mut s = Stream::new();
+        let id = |ms| XAddId::Explicit(StreamId::new(ms, 0));
+        s.append(id(1), &fields(&[("a", "1")])).unwrap();
+        s.append(id(2), &fields(&[("a", "2")])).unwrap();
+        s.append(id(3), &fields(&[("b", "3"), ("c", "4")])).unwrap();
+        s.delete(StreamId::new(2, 0));
+        let (_, lp) = s.rax.first().unwrap();
+        assert!(validate_node(lp));
+
+        // counters that don't match the entries
+        let mut bad = lp.clone();
+        set_node_counters(&mut bad, 3, 0);
+        assert!(!validate_node(&bad));
+        // an entry cut short
+        let mut cut = Listpack::new();
+        for elem in lp.iter().take(lp.len() - 1) {
+            cut.append(&elem.to_vec());
+        }
+        assert!(!validate_node(&cut));
+    }
+
+    #[test]
+    fn test_range_across_nodes() {
+        let mut s = Stream::new();
+        for i in 1..=250u64 {
+            let f = if i % 2 == 0 {
+                fields(&[("a", "1"), ("b", "x")])
+            } else {
+                fields(&[("a", "2")])
+            };
+            s.append(XAddId::Explicit(StreamId::new(i, 0)), &f).unwrap();
+        }
+        assert!(s.rax.len() >= 3);
+        let all = s.range(StreamId::MIN, StreamId::MAX, None, false);
+        assert_eq!(all.len(), 250);
+        assert_eq!(
+            all[1],
+            (StreamId::new(2, 0), fields(&[("a", "1"), ("b", "x")]))
+        );
+        let rev = s.range(StreamId::new(98, 0), StreamId::new(102, 0), Some(3), true);
+        let ids: Vec<u64> = rev.iter().map(|(id, _)| id.ms).collect();
+        assert_eq!(ids, vec![102, 101, 100]);
+    }
+
+    #[test]
+    fn test_delete_and_trim() {
+        let mut s = Stream::new();
+        for i in 1..=300u64 {
+            s.append(
+                XAddId::Explicit(StreamId::new(i, 0)),
+                &fields(&[("f", "v")]),
+            )
+            .unwrap();
+        }
+        assert!(s.delete(StreamId::new(1, 0)));
+        assert!(!s.delete(StreamId::new(1, 0)));
+        assert_eq!(s.first_id, StreamId::new(2, 0));
+
+        let approx = StreamTrim {
+            strategy: TrimStrategy::MaxLen(150),
+            approx: true,
+            limit: None,
+        };
+        // only whole nodes are evicted
+        assert_eq!(s.trim(approx), 99);
+        assert_eq!(s.len(), 200);
+
+        let exact = StreamTrim {
+            strategy: TrimStrategy::MinId(StreamId::new(250, 0)),
+            approx: false,
+            limit: None,
+        };
+        assert_eq!(s.trim(exact), 149);
+        assert_eq!(s.first_id, StreamId::new(250, 0));
+        assert_eq!(s.range(StreamId::MIN, StreamId::MAX, None, false).len(), 51);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crate::stream::Stream;
+use crate::zset::SortedSet;
+
+// copies share their contents, so that snapshots of the dataset are cheap
+// to take under the keyspace lock
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Value {
+    String(Shared<Vec<u8>>),
+    Stream(Shared<Stream>),
+    ZSet(Shared<SortedSet>),
+    // only loaded from RDB files for now
+    List(Shared<VecDeque<Vec<u8>>>),
+    Set(Shared<HashSet<Vec<u8>>>),
+    Hash(Shared<HashMap<Vec<u8>, Vec<u8>>>),
+}
+
+/// Contents shared by the copies of a value until one of them changes
+/// them, which then gets contents of its own.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Shared<T>(Arc<T>);
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(Arc::clone(&self.0))
+    }
+}
+
+impl<T> From<T> for Shared<T> {
+    fn from(contents: T) -> Self {
+        Shared(Arc::new(contents))
+    }
+}
+
+impl<T: FromIterator<A>, A> FromIterator<A> for Shared<T> {
+    fn from_iter<I: IntoIterator<Item = A>>(iter: I) -> Self {
+        Shared(Arc::new(iter.into_iter().collect()))
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> DerefMut for Shared<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Stream(_) => "stream",
+            Value::ZSet(_) => "zset",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::Hash(_) => "hash",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared() {
+        let mut value = Value::String(b"a".to_vec().into());
+        let copy = value.clone();
+        if let (Value::String(s), Value::String(c)) = (&value, &copy) {
+            assert!(std::ptr::eq(s.as_ptr(), c.as_ptr()));
+        }
+        if let Value::String(s) = &mut value {
+            s.push(b'b');
+        }
+        assert_eq!(copy, Value::String(b"a".to_vec().into()));
+        assert_eq!(value, Value::String(b"ab".to_vec().into()));
+    }
+}
@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// A sorted set score; scores are never NaN, so they can be totally ordered.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then lexicographically.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, Score>,
+    order: BTreeSet<(Score, Vec<u8>)>,
+}
+
+#[allow(unused)]
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).map(|s| s.0)
+    }
+
+    /// Sets the score of a member, returning its previous score.
+    pub fn insert(&mut self, member: &[u8], score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.to_vec(), Score(score));
+        if let Some(old) = old {
+            self.order.remove(&(old, member.to_vec()));
+        }
+        self.order.insert((Score(score), member.to_vec()));
+        old.map(|s| s.0)
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let old = self.scores.remove(member)?;
+        self.order.remove(&(old, member.to_vec()));
+        Some(old.0)
+    }
+
+    /// Iterates in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.order.iter().map(|(s, m)| (m.as_slice(), s.0))
+    }
+
+    /// Members with `min <= score < max`, in ascending order.
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&[u8], f64)> {
+        let range = (
+            Bound::Included((Score(min), Vec::new())),
+            Bound::Excluded((Score(max), Vec::new())),
+        );
+        self.order.range(range).map(|(s, m)| (m.as_slice(), s.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_range() {
+        let mut zset = SortedSet::new();
+        assert_eq!(zset.insert(b"b", 2.0), None);
+        assert_eq!(zset.insert(b"a", 2.0), None);
+        assert_eq!(zset.insert(b"c", 1.0), None);
+        assert_eq!(zset.insert(b"c", 3.0), Some(1.0));
+        let members: Vec<&[u8]> = zset.iter().map(|(m, _)| m).collect();
+        assert_eq!(members, [b"a", b"b", b"c"]);
+        let members: Vec<&[u8]> = zset.range_by_score(2.0, 3.0).map(|(m, _)| m).collect();
+        assert_eq!(members, [b"a", b"b"]);
+        assert_eq!(zset.remove(b"a"), Some(2.0));
+        assert_eq!(zset.len(), 2);
+    }
+}